@@ -0,0 +1,446 @@
+//! Code actions.
+//!
+//! Code actions are refactorings and quick fixes offered for a selection in a
+//! text document. They are computed on syntax level and expressed as text
+//! edits in terms of lines and columns. Quick fixes are derived from messages
+//! of compiler diagnostics which a client sends along with a selection.
+
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use tower_lsp::lsp_types::CodeActionKind;
+use typst::syntax::ast::AstNode;
+use typst::syntax::{ast, is_id_continue, LinkedNode, Source, SyntaxKind};
+
+use crate::package;
+use crate::syntax::{byte_to_position, mode_at, Mode, PositionEncoding};
+
+/// Replacement of text between two positions (line and column) of a file.
+/// Columns are counted in units of a position encoding which edits are made
+/// with.
+#[derive(Clone, Debug)]
+pub struct TextEdit {
+    pub path: PathBuf,
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+    pub text: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct CodeAction {
+    pub title: String,
+    pub kind: CodeActionKind,
+    pub edits: Vec<TextEdit>,
+    /// Files to create before edits are applied.
+    pub create: Vec<PathBuf>,
+    /// Index of a diagnostic which is fixed by an action.
+    pub diagnostic: Option<usize>,
+    /// Texts of edits are Typst snippets (see [`crate::snippet`]) with
+    /// placeholders for the user to fill in.
+    pub snippet: bool,
+}
+
+impl CodeAction {
+    fn rewrite(title: String, edits: Vec<TextEdit>) -> Self {
+        Self {
+            title,
+            kind: CodeActionKind::REFACTOR_REWRITE,
+            edits,
+            create: Vec::new(),
+            diagnostic: None,
+            snippet: false,
+        }
+    }
+
+    fn quick_fix(title: String, diagnostic: usize) -> Self {
+        Self {
+            title,
+            kind: CodeActionKind::QUICKFIX,
+            edits: Vec::new(),
+            create: Vec::new(),
+            diagnostic: Some(diagnostic),
+            snippet: false,
+        }
+    }
+}
+
+/// Collect code actions applicable to a selection. Diagnostics are byte
+/// ranges and messages of diagnostics which intersect a selection.
+pub fn code_actions(
+    source: &Source,
+    path: &Path,
+    range: Range<usize>,
+    diagnostics: &[(Range<usize>, String)],
+    encoding: PositionEncoding,
+) -> Vec<CodeAction> {
+    let mut actions = Vec::<CodeAction>::new();
+    for (index, (range, message)) in diagnostics.iter().enumerate() {
+        let range = range.clone();
+        actions
+            .extend(quick_fix(source, path, range, message, index, encoding));
+    }
+    actions.extend(wrap_in_figure(source, path, range.clone(), encoding));
+    actions.extend(convert_markup(source, path, range.clone(), encoding));
+    actions.extend(string_to_raw(source, path, range, encoding));
+    actions
+}
+
+/// Make a quick fix for a diagnostic if it is known.
+fn quick_fix(
+    source: &Source,
+    path: &Path,
+    range: Range<usize>,
+    message: &str,
+    index: usize,
+    encoding: PositionEncoding,
+) -> Option<CodeAction> {
+    if let Some(rest) = message.strip_prefix("file not found (searched at ") {
+        let missing = rest.strip_suffix(')').unwrap_or(rest);
+        return create_missing_file(Path::new(missing), index);
+    }
+    if let Some(rest) = message.strip_prefix("label `<") {
+        let (label, _) = rest.split_once(">`")?;
+        return add_missing_label(source, path, range, label, index, encoding);
+    }
+    if let Some(name) = message.strip_prefix("unknown variable: ") {
+        let name = name.trim();
+        return import_missing_package(source, path, name, index, encoding);
+    }
+    None
+}
+
+/// Create an empty Typst file which is included or imported but missing.
+fn create_missing_file(missing: &Path, index: usize) -> Option<CodeAction> {
+    if missing.extension().is_none_or(|it| it != "typ") {
+        return None;
+    }
+    let name = missing.file_name()?.to_string_lossy();
+    let mut action = CodeAction::quick_fix(format!("Create `{name}`"), index);
+    action.create.push(missing.to_path_buf());
+    Some(action)
+}
+
+/// Attach a label of an unresolved reference to the closest preceding heading
+/// or to the end of a line of the reference if there is no heading.
+fn add_missing_label(
+    source: &Source,
+    path: &Path,
+    range: Range<usize>,
+    label: &str,
+    index: usize,
+    encoding: PositionEncoding,
+) -> Option<CodeAction> {
+    fn find_heading(node: &LinkedNode, before: usize) -> Option<Range<usize>> {
+        let mut found = None;
+        for child in node.children().take_while(|it| it.offset() < before) {
+            if child.kind() == SyntaxKind::Heading {
+                found = Some(child.range());
+            }
+            if let Some(range) = find_heading(&child, before) {
+                found = Some(range);
+            }
+        }
+        found
+    }
+
+    let root = LinkedNode::new(source.root());
+    let (offset, title) = match find_heading(&root, range.start) {
+        Some(heading) => (heading.end, format!("Add `<{label}>` to heading")),
+        None => {
+            let line = source.byte_to_line(range.start)?;
+            let line = source.line_to_range(line)?;
+            let text = source.get(line.clone())?;
+            let end = line.start + text.trim_end().len();
+            (end, format!("Add `<{label}>` at end of line"))
+        }
+    };
+    let text = format!(" <{label}>");
+    let edit = make_edit(source, path, offset..offset, text, encoding)?;
+    let mut action = CodeAction::quick_fix(title, index);
+    action.edits.push(edit);
+    Some(action)
+}
+
+/// Import a package which is named like an unknown variable if the package
+/// is available locally. The latest version is imported.
+fn import_missing_package(
+    source: &Source,
+    path: &Path,
+    name: &str,
+    index: usize,
+    encoding: PositionEncoding,
+) -> Option<CodeAction> {
+    let version = package::local_versions(package::NAMESPACE, name).pop()?;
+    let spec = format!("@{}/{name}:{version}", package::NAMESPACE);
+    let text = format!("#import \"{spec}\"\n");
+    let edit = make_edit(source, path, 0..0, text, encoding)?;
+    let mut action = CodeAction::quick_fix(format!("Import `{spec}`"), index);
+    action.edits.push(edit);
+    Some(action)
+}
+
+/// Convert a string literal to a raw block (e.g. `#"text"` to `` `text` ``).
+/// Strings with backticks are skipped since raw block with longer fences
+/// would treat their first word as a language tag.
+fn string_to_raw(
+    source: &Source,
+    path: &Path,
+    range: Range<usize>,
+    encoding: PositionEncoding,
+) -> Option<CodeAction> {
+    let leaf = LinkedNode::new(source.root()).leaf_at(range.start + 1)?;
+    let string = leaf.cast::<ast::Str>()?;
+    let value = string.get();
+    if value.contains('`') {
+        return None;
+    }
+    // Paths of modules and keys of dictionaries must be strings.
+    let parent = leaf.parent()?.kind();
+    if matches!(
+        parent,
+        SyntaxKind::ModuleImport
+            | SyntaxKind::ModuleInclude
+            | SyntaxKind::Keyed
+    ) {
+        return None;
+    }
+    let text = match value.contains('\n') {
+        true => format!("```\n{value}\n```"),
+        false => format!("`{value}`"),
+    };
+
+    // Embedded strings in markup are preceded by a hash.
+    let mut range = leaf.range();
+    if let Some(hash) = leaf.prev_sibling() {
+        if hash.kind() == SyntaxKind::Hash && hash.range().end == range.start {
+            range.start = hash.offset();
+        }
+    }
+    let title = "Convert string to raw block".to_string();
+    let edit = make_edit(source, path, range, text, encoding)?;
+    Some(CodeAction::rewrite(title, vec![edit]))
+}
+
+/// Make a text edit which replaces a byte range of a source.
+pub(crate) fn make_edit(
+    source: &Source,
+    path: &Path,
+    range: Range<usize>,
+    text: String,
+    encoding: PositionEncoding,
+) -> Option<TextEdit> {
+    let position = |offset| byte_to_position(source, offset, encoding);
+    Some(TextEdit {
+        path: path.to_path_buf(),
+        start: position(range.start)?,
+        end: position(range.end)?,
+        text,
+    })
+}
+
+/// Wrap a selected image, table, or markup into a figure with caption and
+/// label. Caption and label are placeholders of a snippet: label is named
+/// after a file of an image (if any) or it is a stub to fill in.
+fn wrap_in_figure(
+    source: &Source,
+    path: &Path,
+    range: Range<usize>,
+    encoding: PositionEncoding,
+) -> Option<CodeAction> {
+    let text = source.get(range.clone())?;
+    let body = text.trim();
+    if body.is_empty() {
+        return None;
+    }
+
+    // Figures are placed in markup only.
+    let start = range.start + (text.len() - text.trim_start().len());
+    let end = start + body.len();
+    if mode_at(source, start) != Mode::Markup {
+        return None;
+    }
+
+    let parsed = Source::detached(body);
+    let (body, name) = match embedded_expr(&parsed) {
+        Some(expr) => (expr.to_string(), first_file_stem(&parsed)),
+        None => (format!("[{body}]"), None),
+    };
+    let body = body.replace('\n', "\n  ");
+    let label = name.unwrap_or_else(|| "label".to_string());
+
+    // Body is inserted as is, so it must not look like a placeholder.
+    let snippet = !body.contains("${");
+    let text = match snippet {
+        true => format!(
+            "#figure(\n  {body},\n  caption: [${{}}],\n) <fig:${{{label}}}>"
+        ),
+        false => {
+            format!("#figure(\n  {body},\n  caption: [],\n) <fig:{label}>")
+        }
+    };
+    let edit = make_edit(source, path, start..end, text, encoding)?;
+    let mut action =
+        CodeAction::rewrite("Wrap in figure".to_string(), vec![edit]);
+    action.snippet = snippet;
+    Some(action)
+}
+
+/// Return text of an expression if the whole source is a single embedded
+/// expression which is usable as an argument (e.g. `#image("cat.png")`).
+fn embedded_expr(source: &Source) -> Option<&str> {
+    let root = source.root();
+    if root.erroneous() {
+        return None;
+    }
+    let children = root
+        .children()
+        .filter(|node| !matches!(node.kind(), SyntaxKind::Space))
+        .collect::<Vec<_>>();
+    match children.as_slice() {
+        [hash, expr]
+            if hash.kind() == SyntaxKind::Hash
+                && matches!(
+                    expr.kind(),
+                    SyntaxKind::FuncCall
+                        | SyntaxKind::Ident
+                        | SyntaxKind::FieldAccess
+                        | SyntaxKind::ContentBlock
+                ) =>
+        {
+            source.text().trim().strip_prefix('#')
+        }
+        _ => None,
+    }
+}
+
+/// Find the first string literal and use stem of a path in it as a name.
+fn first_file_stem(source: &Source) -> Option<String> {
+    fn find(node: &LinkedNode) -> Option<String> {
+        if node.kind() == SyntaxKind::Str {
+            return Some(node.text().trim_matches('"').to_string());
+        }
+        node.children().find_map(|child| find(&child))
+    }
+    let literal = find(&LinkedNode::new(source.root()))?;
+    let stem = Path::new(&literal).file_stem()?.to_str()?;
+    label_name(stem)
+}
+
+/// Make a name of a label from a file stem: runs of characters which are
+/// not allowed in labels become single dashes. It is `None` if nothing is
+/// left.
+fn label_name(stem: &str) -> Option<String> {
+    let mut name = String::with_capacity(stem.len());
+    for ch in stem.chars() {
+        if is_id_continue(ch) && ch != '-' {
+            name.push(ch.to_ascii_lowercase());
+        } else if !name.is_empty() && !name.ends_with('-') {
+            name.push('-');
+        }
+    }
+    let name = name.trim_end_matches('-');
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Convert markup sugar to function call and vice versa: `*bold*` and
+/// `#strong[bold]`, `_emph_` and `#emph[emph]`, `= Title` and
+/// `#heading(level: 1)[Title]`. Function form is useful when a construct needs
+/// extra arguments.
+fn convert_markup(
+    source: &Source,
+    path: &Path,
+    range: Range<usize>,
+    encoding: PositionEncoding,
+) -> Option<CodeAction> {
+    let leaf = LinkedNode::new(source.root()).leaf_at(range.start + 1)?;
+    let mut node = Some(leaf);
+    while let Some(current) = node {
+        if let Some((title, range, text)) =
+            to_function(&current).or_else(|| to_markup(source, &current))
+        {
+            let edit = make_edit(source, path, range, text, encoding)?;
+            return Some(CodeAction::rewrite(title, vec![edit]));
+        }
+        node = current.parent().cloned();
+    }
+    None
+}
+
+/// Text of markup node.
+fn markup_text(markup: ast::Markup) -> String {
+    markup.to_untyped().clone().into_text().to_string()
+}
+
+fn to_function(node: &LinkedNode) -> Option<(String, Range<usize>, String)> {
+    let (name, text) = if let Some(strong) = node.cast::<ast::Strong>() {
+        ("strong", format!("#strong[{}]", markup_text(strong.body())))
+    } else if let Some(emph) = node.cast::<ast::Emph>() {
+        ("emph", format!("#emph[{}]", markup_text(emph.body())))
+    } else if let Some(heading) = node.cast::<ast::Heading>() {
+        let body = markup_text(heading.body());
+        let text = format!("#heading(level: {})[{body}]", heading.depth());
+        ("heading", text)
+    } else {
+        return None;
+    };
+    let title = format!("Convert to `#{name}` call");
+    Some((title, node.range(), text))
+}
+
+fn to_markup(
+    source: &Source,
+    node: &LinkedNode,
+) -> Option<(String, Range<usize>, String)> {
+    let call = node.cast::<ast::FuncCall>()?;
+    let ast::Expr::Ident(callee) = call.callee() else {
+        return None;
+    };
+
+    // Only calls with trailing content block (and heading level) have markup
+    // counterparts.
+    let mut body = None;
+    let mut level = 1;
+    for arg in call.args().items() {
+        match arg {
+            ast::Arg::Pos(ast::Expr::Content(block)) if body.is_none() => {
+                body = Some(markup_text(block.body()));
+            }
+            ast::Arg::Named(named)
+                if callee.get() == "heading"
+                    && named.name().get() == "level" =>
+            {
+                let ast::Expr::Int(int) = named.expr() else {
+                    return None;
+                };
+                level = usize::try_from(int.get()).ok().filter(|it| *it > 0)?;
+            }
+            _ => return None,
+        }
+    }
+    let body = body?;
+
+    // Markup in function form is preceded by a hash.
+    let hash = node.prev_sibling()?;
+    if hash.kind() != SyntaxKind::Hash || hash.range().end != node.offset() {
+        return None;
+    }
+    let range = hash.offset()..node.range().end;
+
+    let text = match callee.get().as_str() {
+        "strong" if !body.contains('*') => format!("*{body}*"),
+        "emph" if !body.contains('_') => format!("_{body}_"),
+        "heading" => {
+            // Heading marker is recognized at the beginning of a line only.
+            let line = source.byte_to_line(range.start)?;
+            let line_start = source.line_to_byte(line)?;
+            let prefix = source.get(line_start..range.start)?;
+            if !prefix.trim().is_empty() || body.contains('\n') {
+                return None;
+            }
+            format!("{} {}", "=".repeat(level), body.trim())
+        }
+        _ => return None,
+    };
+    let title = "Convert to markup".to_string();
+    Some((title, range, text))
+}
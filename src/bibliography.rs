@@ -0,0 +1,167 @@
+//! Bibliography keys.
+//!
+//! Files passed to `#bibliography` (BibLaTeX `.bib` or Hayagriva `.yml`) are
+//! parsed with Hayagriva without compilation. Their keys are suggested in
+//! `@key` references and hovering a reference or a `#cite(<key>)` label shows
+//! the whole entry. Entries are located in files textually since parsers do
+//! not keep positions.
+
+use std::fmt::Write;
+use std::ops::Range;
+
+use hayagriva::{Entry, Library};
+use typst::syntax::{ast, LinkedNode, Source, SyntaxKind, SyntaxNode};
+
+/// Find paths of all bibliography files of a source.
+pub fn bibliography_paths(source: &Source) -> Vec<String> {
+    fn visit(node: &SyntaxNode, paths: &mut Vec<String>) {
+        if let Some(call) = node.cast::<ast::FuncCall>() {
+            if let ast::Expr::Ident(callee) = call.callee() {
+                if callee.get() == "bibliography" {
+                    extend_paths(call, paths);
+                }
+            }
+        }
+        for child in node.children() {
+            visit(child, paths);
+        }
+    }
+    let mut paths = Vec::new();
+    visit(source.root(), &mut paths);
+    paths
+}
+
+/// The first positional argument of `bibliography` is a path or an array of
+/// paths.
+fn extend_paths(call: ast::FuncCall, paths: &mut Vec<String>) {
+    let first = call.args().items().find_map(|arg| match arg {
+        ast::Arg::Pos(expr) => Some(expr),
+        _ => None,
+    });
+    match first {
+        Some(ast::Expr::Str(path)) => paths.push(path.get().to_string()),
+        Some(ast::Expr::Array(array)) => {
+            paths.extend(array.items().filter_map(|item| match item {
+                ast::ArrayItem::Pos(ast::Expr::Str(path)) => {
+                    Some(path.get().to_string())
+                }
+                _ => None,
+            }))
+        }
+        _ => {}
+    }
+}
+
+/// Parse a bibliography file. Format is determined by extension.
+pub fn parse(path: &str, bytes: &[u8]) -> Result<Library, String> {
+    let text = std::str::from_utf8(bytes)
+        .map_err(|err| format!("failed to decode {path}: {err}"))?;
+    match path.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()) {
+        Some(ext) if ext == "bib" => hayagriva::io::from_biblatex_str(text)
+            .map_err(|errs| match errs.first() {
+                Some(err) => format!("failed to parse {path}: {err:?}"),
+                None => format!("failed to parse {path}"),
+            }),
+        Some(ext) if ext == "yml" || ext == "yaml" => {
+            hayagriva::io::from_yaml_str(text)
+                .map_err(|err| format!("failed to parse {path}: {err}"))
+        }
+        _ => Err(format!("unknown bibliography format: {path}")),
+    }
+}
+
+/// Find a byte range of a key of an entry in a bibliography file: `@type{key,`
+/// in BibLaTeX and a top-level `key:` in Hayagriva.
+pub fn find_entry(path: &str, text: &str, key: &str) -> Option<Range<usize>> {
+    if path.to_lowercase().ends_with(".bib") {
+        return text.match_indices('@').find_map(|(index, _)| {
+            let rest = &text[index + 1..];
+            let rest = rest.trim_start_matches(|it: char| it.is_alphanumeric());
+            let rest = rest.strip_prefix(['{', '('])?.trim_start();
+            let tail = rest.strip_prefix(key)?;
+            if !tail.starts_with(|it: char| it == ',' || it.is_whitespace()) {
+                return None;
+            }
+            let start = text.len() - rest.len();
+            Some(start..start + key.len())
+        });
+    }
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        // Keys are possibly quoted.
+        let quote = line.strip_prefix(['"', '\'']).map(|_| &line[..1]);
+        let tail = line
+            .strip_prefix(quote.unwrap_or_default())
+            .and_then(|it| it.strip_prefix(key))
+            .and_then(|it| it.strip_prefix(quote.unwrap_or_default()));
+        if tail.is_some_and(|it| it.trim_start().starts_with(':')) {
+            let start = offset + quote.map_or(0, str::len);
+            return Some(start..start + key.len());
+        }
+        offset += line.len();
+    }
+    None
+}
+
+/// Find a key under cursor: a target of a reference (`@key`) or a label in a
+/// call of `cite` (`#cite(<key>)`).
+pub fn key_at(source: &Source, cursor: usize) -> Option<String> {
+    let root = LinkedNode::new(source.root());
+    let leaf = [cursor + 1, cursor]
+        .into_iter()
+        .filter_map(|it| root.leaf_at(it))
+        .find(|it| {
+            matches!(it.kind(), SyntaxKind::RefMarker | SyntaxKind::Label)
+        })?;
+    if leaf.kind() == SyntaxKind::RefMarker {
+        let target = leaf.parent()?.cast::<ast::Ref>()?.target();
+        return Some(target.to_string());
+    }
+    let label = leaf.cast::<ast::Label>()?;
+    let call = leaf.parent()?.parent()?.cast::<ast::FuncCall>()?;
+    match call.callee() {
+        ast::Expr::Ident(callee) if callee.get() == "cite" => {
+            Some(label.get().to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Summarize an entry in a line: authors, year, and title.
+pub fn summary(entry: &Entry) -> String {
+    let mut summary = String::new();
+    if let Some(authors) = entry.authors() {
+        let names = authors
+            .iter()
+            .map(|it| it.name.as_str())
+            .collect::<Vec<_>>();
+        match names.as_slice() {
+            [] => {}
+            [name] => summary.push_str(name),
+            [first, second] => write!(summary, "{first} and {second}").unwrap(),
+            [first, ..] => write!(summary, "{first} et al.").unwrap(),
+        }
+    }
+    if let Some(date) = entry.date_any() {
+        write!(summary, " ({})", date.year).unwrap();
+    }
+    if let Some(title) = entry.title() {
+        if !summary.is_empty() {
+            summary.push_str(". ");
+        }
+        write!(summary, "{title}").unwrap();
+    }
+    match summary.trim() {
+        "" => entry.key().to_string(),
+        summary => summary.to_string(),
+    }
+}
+
+/// Describe an entry in Markdown: a summary and all fields in Hayagriva
+/// format.
+pub fn describe(entry: &Entry) -> String {
+    let mut library = Library::new();
+    library.push(entry);
+    let fields = hayagriva::io::to_yaml_str(&library).unwrap_or_default();
+    format!("{}\n\n```yaml\n{}\n```", summary(entry), fields.trim_end())
+}
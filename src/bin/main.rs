@@ -1,470 +1,4775 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::error::Error;
+use std::future::Future;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::result;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
 use std::sync::{Mutex, RwLock};
-use std::time::Instant;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, UNIX_EPOCH};
 
-use clap::Parser;
-use tower_lsp::jsonrpc::Result;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use clap::{Parser, Subcommand, ValueEnum};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+use tokio_tungstenite::WebSocketStream;
+use tower::Service;
+use tower_lsp::jsonrpc::{self, Result};
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer, LspService, Server};
 use tracing::instrument;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::{fmt, util::SubscriberInitExt, EnvFilter};
+use typst::foundations::{Bytes, Dict, IntoValue};
+use typst::syntax::package::PackageSpec;
+use typst::syntax::Source;
 use typst_ide::CompletionKind;
 
+use typstd::capabilities::ClientFeatures;
+use typstd::config::{self, Config, ExportFormat, ExportPdf, ProjectConfig};
+use typstd::count::DocumentMetrics;
+use typstd::deps::Graph;
+use typstd::diagnostics::{self, Severity};
+use typstd::hints::HintKind;
+use typstd::introspect::Introspection;
+use typstd::notebook::{
+    DidChangeNotebookDocumentParams, DidCloseNotebookDocumentParams,
+    DidOpenNotebookDocumentParams, DidSaveNotebookDocumentParams, Notebook,
+};
+use typstd::preview::{self, Preview};
+use typstd::session::{self, Recorder};
+use typstd::syntax::PositionEncoding;
+use typstd::update::PackageUpdate;
 use typstd::workspace::{search_targets, search_workspace, Target};
-use typstd::LanguageServiceWorld;
+use typstd::{
+    completion, date, doctor, metrics, on_type, package, path, snippet, syntax,
+    uri, workspace,
+};
+use typstd::{FontProvider, LanguageServiceWorld};
 
-#[derive(Debug)]
-struct TypstLanguageService {
-    /// Language Server Protocol (LSP) client for backward communication with
-    /// service clients. Primarly, it is used for publishing diagnostics
-    /// information.
+/// Interval during which successive diagnostic publications for the same
+/// document are coalesced into the last one.
+const DIAGNOSTICS_COALESCE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval between checks of files which are changed outside of editor.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before accepting connections once again after a failure (e.g. file
+/// descriptors are exhausted) so that a daemon does not spin.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Glob patterns of files which client is asked to watch in addition to
+/// project settings: sources, bibliographies, data files, and images.
+const WATCHED_FILES: &str =
+    "**/*.{typ,bib,yml,yaml,json,csv,toml,xml,txt,png,jpg,jpeg,gif,svg}";
+
+#[derive(Debug, Default)]
+struct PublishedDiagnostics {
+    /// Sequential number of the latest publication request.
+    generation: u64,
+    /// Diagnostics which were actually sent to client.
+    diagnostics: Option<Vec<Diagnostic>>,
+}
+
+/// Diagnostics of documents reported by worlds indexed by URI of main file.
+type Reported = BTreeMap<Url, HashMap<Url, Vec<Diagnostic>>>;
+
+/// DiagnosticsPublisher throttles diagnostics publication. Publication
+/// requests for a document are delayed for a short period and superseded by
+/// newer ones. Also, diagnostics identical to the published ones are not sent
+/// again. This reduces editor flicker and JSON-RPC traffic on fast typing.
+#[derive(Clone, Debug)]
+struct DiagnosticsPublisher {
     client: Client,
-    /// Actual execution contexts for language analysis. It would be better to
-    /// use URI as keys instead of paths if we want non-local environment such
-    /// as browsers.
-    worlds: RwLock<HashMap<PathBuf, Arc<Mutex<LanguageServiceWorld>>>>,
+    state: Arc<Mutex<HashMap<Url, PublishedDiagnostics>>>,
+    /// Diagnostics of documents reported by the last compilation of a world
+    /// indexed by URI of its main file. A document is published with the
+    /// union of diagnostics of all worlds which report it (worlds are ordered
+    /// so that the union is stable).
+    reported: Arc<Mutex<Reported>>,
+    /// Client supports refreshing of code lenses which show summary of the
+    /// last compilation.
+    code_lens_refresh: Arc<AtomicBool>,
 }
 
-impl TypstLanguageService {
-    /// Compile document and update user with compilation status.
-    fn compile(&self, uri: &Url) -> result::Result<(), String> {
-        log::info!("try to compile document");
-        let Some((_, world)) = self.find_world(uri) else {
-            return Err("missing compilation context".to_string());
-        };
-        let started_at = Instant::now();
-        let result = world.lock().unwrap().compile();
-        let elapsed = started_at.elapsed();
-        match result {
-            Ok(_) => {
-                log::info!("compilation finished in {:?}", elapsed);
-                Ok(())
-            }
-            Err(err) => {
-                log::error!("compilation failed in {:?}: {}", elapsed, err);
-                Err(err)
-            }
+impl DiagnosticsPublisher {
+    fn new(client: Client) -> Self {
+        Self {
+            client,
+            state: Default::default(),
+            reported: Default::default(),
+            code_lens_refresh: Default::default(),
         }
     }
 
-    /// Find the closest parent URI for the specified one.
-    fn find_world(
-        &self,
-        uri: &Url,
-    ) -> Option<(PathBuf, Arc<Mutex<LanguageServiceWorld>>)> {
-        let mut path = Path::new(uri.path());
-        let worlds = self.worlds.read().unwrap();
-        // Is it better to use trie or something like that?
-        while let Some(parent) = path.parent() {
-            match worlds.get(parent) {
-                Some(world) => {
-                    return Some((parent.to_path_buf(), world.clone()))
-                }
-                None => {
-                    path = parent;
+    fn set_code_lens_refresh(&self, refresh: bool) {
+        self.code_lens_refresh.store(refresh, Ordering::Relaxed);
+    }
+
+    /// Schedule publication of diagnostics of all documents affected by
+    /// compilation of a world. Diagnostics of documents which are not
+    /// affected anymore (e.g. errors have been fixed) are cleared unless
+    /// other worlds report them.
+    fn publish_report(&self, world: Url, report: Report) {
+        let unions = {
+            let mut reported = self.reported.lock().unwrap();
+            update_reported(&mut reported, world, report)
+        };
+        for (uri, diagnostics) in unions {
+            self.publish(uri, diagnostics);
+        }
+        // Code lenses of main file show status of the last compilation.
+        if self.code_lens_refresh.load(Ordering::Relaxed) {
+            let client = self.client.clone();
+            tokio::spawn(async move {
+                if let Err(err) = client.code_lens_refresh().await {
+                    log::warn!("failed to refresh code lenses: {err}");
                 }
-            };
+            });
         }
-        None
     }
 
-    fn new_world_from_str(
-        &self,
-        uri: &Url,
-        text: String,
-    ) -> Option<(PathBuf, Arc<Mutex<LanguageServiceWorld>>)> {
-        log::info!("initialize world from main file with text");
-        let path = Path::new(uri.path());
-        self.new_world_from_path(path, Some(text))
+    /// Forget diagnostics reported by a world (e.g. all its documents have
+    /// been closed) and clear them unless other worlds report them.
+    fn clear_world(&self, world: &Url) {
+        let unions = {
+            let mut reported = self.reported.lock().unwrap();
+            let previous = reported.remove(world).unwrap_or_default();
+            union_reported(&reported, previous.into_keys().collect())
+        };
+        for (uri, diagnostics) in unions {
+            self.publish(uri, diagnostics);
+        }
     }
 
-    fn new_world_from_uri(
-        &self,
-        uri: &Url,
-    ) -> Option<(PathBuf, Arc<Mutex<LanguageServiceWorld>>)> {
-        let path = Path::new(uri.path());
-        let Some(root_dir) = path.parent() else {
-            log::error!("there is no root directory for {:?}", path);
-            return None;
+    /// Schedule publication of diagnostics for a document.
+    fn publish(&self, uri: Url, diagnostics: Vec<Diagnostic>) {
+        let generation = {
+            let mut state = self.state.lock().unwrap();
+            let entry = state.entry(uri.clone()).or_default();
+            entry.generation += 1;
+            entry.generation
         };
-
-        // Search for workspace root (i.e. search for `typst.toml`) from the
-        // parent directory of the file to the filesystem hierarchy. If we
-        // found nothing then fallback to base directory of the file.
-        let root_dir = search_workspace(root_dir).unwrap_or(root_dir);
-
-        // Create a new world and insert it to world index. If there are no valid targets then
-        // create file-specific world; otherwise; search once again.
-        let targets = search_targets(vec![root_dir]);
-        log::info!("found {} target(s)", targets.len());
-        match self.new_worlds(targets) {
-            0 => self.new_world_from_path(path, None),
-            _ => self
-                .find_world(uri)
-                .or_else(|| self.new_world_from_path(path, None)),
-        }
+        let publisher = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(DIAGNOSTICS_COALESCE_INTERVAL).await;
+            publisher.flush(uri, generation, diagnostics).await;
+        });
     }
 
-    fn new_world_from_path(
+    async fn flush(
         &self,
-        main_file: &Path,
-        main_text: Option<String>,
-    ) -> Option<(PathBuf, Arc<Mutex<LanguageServiceWorld>>)> {
-        log::info!("initialize world from main file: path={:?}", main_file);
-        let root_dir = main_file.parent()?;
-        match LanguageServiceWorld::new(root_dir, main_file, main_text) {
-            Some(world) => {
-                log::info!(
-                    "initialize world for {:?} at {:?}",
-                    main_file,
-                    root_dir,
-                );
-                let world = Arc::new(Mutex::new(world));
-                self.worlds
-                    .write()
-                    .unwrap()
-                    .insert(root_dir.to_path_buf(), world.clone());
-                Some((root_dir.to_path_buf(), world))
+        uri: Url,
+        generation: u64,
+        diagnostics: Vec<Diagnostic>,
+    ) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let Some(entry) = state.get_mut(&uri) else {
+                return;
+            };
+            if entry.generation != generation {
+                log::debug!("diagnostics for {} are superseded", uri);
+                return;
             }
-            None => {
-                log::error!(
-                    "failed to initialize world for {:?} at {:?}",
-                    main_file,
-                    root_dir,
-                );
-                None
+            if entry.diagnostics.as_ref() == Some(&diagnostics) {
+                log::debug!("diagnostics for {} are unchanged", uri);
+                return;
             }
+            entry.diagnostics = Some(diagnostics.clone());
         }
+        self.client
+            .publish_diagnostics(uri, diagnostics, None)
+            .await;
     }
+}
 
-    fn new_worlds(&self, targets: Vec<Target>) -> u32 {
-        let mut counter: u32 = 0;
-        for (index, target) in targets.iter().enumerate() {
-            let Some(relpath) =
-                target.main_file.strip_prefix(&target.root_dir).ok()
-            else {
-                log::warn!(
-                    "[{}] main file {:?} is not descendant of {:?}: skip it",
-                    index,
-                    target.root_dir,
-                    target.main_file
-                );
-                continue;
-            };
-            match LanguageServiceWorld::new(
-                &target.root_dir,
-                &target.main_file,
-                None,
-            ) {
-                Some(world) => {
-                    log::info!(
-                        "[{}] initialize world for {:?} at {:?}",
-                        index,
-                        relpath,
-                        target.root_dir,
-                    );
-                    let world = Mutex::new(world);
-                    self.worlds
-                        .write()
-                        .unwrap()
-                        .insert(target.root_dir.clone(), world.into());
-                    counter += 1;
+/// Replace a report of a world and make diagnostics of documents which the
+/// world reports now or has reported before.
+fn update_reported(
+    reported: &mut Reported,
+    world: Url,
+    report: Report,
+) -> Report {
+    let mut uris = report
+        .iter()
+        .map(|(uri, _)| uri.clone())
+        .collect::<HashSet<_>>();
+    let previous = reported.insert(world, report.into_iter().collect());
+    uris.extend(previous.unwrap_or_default().into_keys());
+    union_reported(reported, uris)
+}
+
+/// Make diagnostics of documents as union of diagnostics of all worlds which
+/// report them. Documents which no world reports get no diagnostics so that
+/// they are cleared.
+fn union_reported(reported: &Reported, uris: HashSet<Url>) -> Report {
+    uris.into_iter()
+        .map(|uri| {
+            let mut union = Vec::<Diagnostic>::new();
+            for diagnostic in
+                reported.values().filter_map(|it| it.get(&uri)).flatten()
+            {
+                if !union.contains(diagnostic) {
+                    union.push(diagnostic.clone());
                 }
-                None => log::error!(
-                    "[{}] failed to initialize world for {:?} at {:?}",
-                    index,
-                    relpath,
-                    target.root_dir,
-                ),
-            };
-        }
-        counter
+            }
+            (uri, union)
+        })
+        .collect()
+}
+
+/// CancelOnDrop raises cancellation flag when dropped. Request handler futures
+/// are dropped by `tower-lsp` on `$/cancelRequest` so the guard should be
+/// held by handler future.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
     }
 }
 
-#[tower_lsp::async_trait]
-impl LanguageServer for TypstLanguageService {
-    #[instrument(
-        skip_all,
-        fields(process_id = params.process_id),
-    )]
-    async fn initialize(
-        &self,
-        params: InitializeParams,
-    ) -> Result<InitializeResult> {
-        // It is safe to unwrap since all keys and values are JSON
-        // serialiable.
-        let params_json = serde_json::to_string_pretty(&params).unwrap();
-        log::info!("initialize language server params={}", params_json);
+/// Run an expensive job against a world on blocking thread pool. If request
+/// is cancelled while the job waits for world lock then the job is skipped
+/// and the lock is released immediately. It returns `None` if the job has
+/// been cancelled. A job which has started is not interrupted; see
+/// `run_interruptible` for jobs which check cancellation themselves.
+async fn run_cancellable<T, F>(
+    world: Arc<RwLock<LanguageServiceWorld>>,
+    job: F,
+) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&LanguageServiceWorld) -> T + Send + 'static,
+{
+    run_interruptible(world, move |world, _| job(world)).await
+}
 
-        let mut root_uris = Vec::<Url>::new();
-        if let Some(folders) = params.workspace_folders {
-            log::info!("use workspace folders for targets discovery");
-            root_uris.extend(folders.iter().map(|folder| folder.uri.clone()));
-        } else if let Some(root_uri) = params.root_uri {
-            log::info!("use obsolete root uri for targets discovery");
-            root_uris.push(root_uri);
+/// Run an expensive job like `run_cancellable` but pass cancellation flag to
+/// the job so that it stops between its phases and releases world lock
+/// early (e.g. completion and hover).
+async fn run_interruptible<T, F>(
+    world: Arc<RwLock<LanguageServiceWorld>>,
+    job: F,
+) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&LanguageServiceWorld, &AtomicBool) -> T + Send + 'static,
+{
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _guard = CancelOnDrop(cancelled.clone());
+    let handle = tokio::task::spawn_blocking(move || {
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
         }
+        let world = world.read().unwrap();
+        if cancelled.load(Ordering::Relaxed) {
+            log::info!("request is cancelled: skip it");
+            return None;
+        }
+        Some(job(&world, &cancelled))
+    });
+    handle.await.ok().flatten()
+}
 
-        log::info!("try to load workspace configurations");
-        let root_dirs = if !root_uris.is_empty() {
-            root_uris
-                .iter()
-                .map(|uri| Path::new(uri.path()).to_path_buf())
-                .collect()
-        } else {
-            log::warn!("no root uris: fallback to current work directory");
-            env::current_dir().ok().map_or(vec![], |cwd| vec![cwd])
+/// Convert text edits of a library to LSP workspace edit.
+fn to_workspace_edit(edits: Vec<typstd::actions::TextEdit>) -> WorkspaceEdit {
+    let mut changes = HashMap::<Url, Vec<TextEdit>>::new();
+    for edit in edits {
+        let Some(uri) = uri::from_path(&edit.path) else {
+            continue;
+        };
+        let position = |(line, column): (usize, usize)| Position {
+            line: line as u32,
+            character: column as u32,
         };
-        let root_dirs = root_dirs.iter().map(PathBuf::as_path).collect();
-        let targets = search_targets(root_dirs);
+        changes.entry(uri).or_default().push(TextEdit {
+            range: Range {
+                start: position(edit.start),
+                end: position(edit.end),
+            },
+            new_text: edit.text,
+        });
+    }
+    WorkspaceEdit {
+        changes: Some(changes),
+        ..Default::default()
+    }
+}
 
-        log::info!("found {} target(s)", targets.len());
-        self.new_worlds(targets);
+/// Convert text edits of a library to LSP text edits of a single document.
+fn to_text_edits(edits: Vec<typstd::actions::TextEdit>) -> Vec<TextEdit> {
+    let position = |(line, column): (usize, usize)| {
+        Position::new(line as u32, column as u32)
+    };
+    edits
+        .into_iter()
+        .map(|edit| TextEdit {
+            range: Range::new(position(edit.start), position(edit.end)),
+            new_text: edit.text,
+        })
+        .collect()
+}
 
-        Ok(InitializeResult {
-            capabilities: ServerCapabilities {
-                text_document_sync: Some(TextDocumentSyncCapability::Options(
-                    TextDocumentSyncOptions {
-                        open_close: Some(true),
-                        change: Some(TextDocumentSyncKind::INCREMENTAL),
-                        save: Some(TextDocumentSyncSaveOptions::Supported(
-                            true,
-                        )),
-                        ..Default::default()
+/// Annotation of text edits of code actions which are snippets. Annotated
+/// edits are marked as snippets in responses by [`SnippetEdits`].
+static SNIPPET_ANNOTATION: &str = "typstd.snippet";
+
+/// Convert text edits of a library which are Typst snippets to workspace edit
+/// with annotated edits in LSP snippet syntax.
+fn to_snippet_edit(edits: Vec<typstd::actions::TextEdit>) -> WorkspaceEdit {
+    let changes = to_workspace_edit(edits).changes.unwrap_or_default();
+    let changes = changes.into_iter().map(|(uri, edits)| TextDocumentEdit {
+        text_document: OptionalVersionedTextDocumentIdentifier {
+            uri,
+            version: None,
+        },
+        edits: edits
+            .into_iter()
+            .map(|edit| {
+                OneOf::Right(AnnotatedTextEdit {
+                    text_edit: TextEdit {
+                        new_text: snippet::to_lsp_snippet(&edit.new_text),
+                        ..edit
                     },
-                )),
-                completion_provider: Some(CompletionOptions {
-                    trigger_characters: Some(vec![
-                        "#".to_string(),
-                        ".".to_string(),
-                        "@".to_string(),
-                    ]),
-                    ..Default::default()
-                }),
-                hover_provider: Some(HoverProviderCapability::Simple(true)),
-                workspace: Some(WorkspaceServerCapabilities {
-                    workspace_folders: Some(
-                        WorkspaceFoldersServerCapabilities {
-                            supported: Some(true),
-                            change_notifications: Some(OneOf::Left(true)),
-                        },
-                    ),
-                    file_operations: None,
+                    annotation_id: SNIPPET_ANNOTATION.to_string(),
+                })
+            })
+            .collect(),
+    });
+    let annotation = ChangeAnnotation {
+        label: "Snippet".to_string(),
+        needs_confirmation: None,
+        description: None,
+    };
+    WorkspaceEdit {
+        document_changes: Some(DocumentChanges::Edits(changes.collect())),
+        change_annotations: Some(HashMap::from([(
+            SNIPPET_ANNOTATION.to_string(),
+            annotation,
+        )])),
+        ..Default::default()
+    }
+}
+
+/// Convert file creations and text edits to workspace edit with document
+/// changes. Files are created before edits are applied and existing files are
+/// left intact.
+fn to_document_changes(
+    create: Vec<PathBuf>,
+    edits: Vec<typstd::actions::TextEdit>,
+) -> WorkspaceEdit {
+    let creations = create.iter().filter_map(|path| {
+        let uri = uri::from_path(path)?;
+        Some(DocumentChangeOperation::Op(ResourceOp::Create(
+            CreateFile {
+                uri,
+                options: Some(CreateFileOptions {
+                    overwrite: Some(false),
+                    ignore_if_exists: Some(true),
                 }),
-                ..Default::default()
+                annotation_id: None,
             },
-            ..Default::default()
+        )))
+    });
+    let changes = to_workspace_edit(edits).changes.unwrap_or_default();
+    let changes = changes.into_iter().map(|(uri, edits)| {
+        DocumentChangeOperation::Edit(TextDocumentEdit {
+            text_document: OptionalVersionedTextDocumentIdentifier {
+                uri,
+                version: None,
+            },
+            edits: edits.into_iter().map(OneOf::Left).collect(),
         })
+    });
+    WorkspaceEdit {
+        document_changes: Some(DocumentChanges::Operations(
+            creations.chain(changes).collect(),
+        )),
+        ..Default::default()
     }
+}
 
-    #[instrument(skip_all)]
-    async fn initialized(&self, _params: InitializedParams) {
-        log::info!("language server client is initialized");
-    }
+/// Command which updates packages of a workspace. Arguments are URI of a text
+/// document and a flag whether to rewrite import statements.
+static UPDATE_PACKAGES_COMMAND: &str = "typstd.packages.update";
 
-    #[instrument(skip_all)]
-    async fn shutdown(&self) -> Result<()> {
-        log::info!("shutdown language server");
-        Ok(())
+/// Command which downloads packages imported by a workspace. Argument is an
+/// optional URI of a text document; all worlds are used if it is missing.
+static PREFETCH_PACKAGES_COMMAND: &str = "typstd.packages.prefetch";
+
+/// Commands which manage package cache. They list cached packages, remove all
+/// of them, download a package (argument is a spec like
+/// `@preview/cetz:0.2.2`), and remove packages which have not been used for a
+/// number of days (argument is the number of days).
+static LIST_PACKAGES_COMMAND: &str = "typstd.packages.list";
+static CLEAR_PACKAGES_COMMAND: &str = "typstd.packages.clear";
+static FETCH_PACKAGE_COMMAND: &str = "typstd.packages.fetch";
+static COLLECT_PACKAGES_COMMAND: &str = "typstd.packages.gc";
+
+/// Command which resolves the latest compatible version of a package.
+/// Argument is a spec with an optional version (e.g. `@preview/cetz`).
+static RESOLVE_PACKAGE_COMMAND: &str = "typstd.packages.resolve";
+
+/// Command which asks user which target a text document belongs to. Argument
+/// is URI of a text document.
+static SELECT_TARGET_COMMAND: &str = "typstd.target.select";
+
+/// Command which compiles a text document as a part of a main file instead
+/// of a discovered target. Arguments are URI of a text document and URI of a
+/// main file; the document returns to discovered targets if the latter is
+/// missing.
+static PIN_MAIN_FILE_COMMAND: &str = "typstd.pinMainFile";
+
+/// Commands which compile a document and export it to PDF, SVG, or PNG.
+/// Arguments are URI of a text document and optional output path (relative to
+/// root directory). Output is placed next to configured output otherwise.
+/// Result is path to output.
+static EXPORT_PDF_COMMAND: &str = "typstd.exportPdf";
+static EXPORT_SVG_COMMAND: &str = "typstd.exportSvg";
+static EXPORT_PNG_COMMAND: &str = "typstd.exportPng";
+
+/// Command which shows a document in live preview. Argument is URI of a text
+/// document. Result is URL of preview page.
+static START_PREVIEW_COMMAND: &str = "typst.startPreview";
+
+/// Make a diagnostic which is not bound to a specific range.
+fn make_diagnostic(
+    severity: DiagnosticSeverity,
+    message: String,
+) -> Diagnostic {
+    let pos = Position {
+        line: 0,
+        character: 0,
+    };
+    Diagnostic {
+        range: Range {
+            start: pos,
+            end: pos,
+        },
+        severity: Some(severity),
+        source: Some("typst".to_string()),
+        message,
+        ..Default::default()
     }
+}
 
-    #[instrument(
-        skip_all,
-        fields(uri = %params.text_document.uri.path_segments()
-            .map(|it| it.last().unwrap_or("/"))
-            .unwrap_or("/")
-        )
-    )]
-    async fn did_close(&self, params: DidCloseTextDocumentParams) {
-        log::info!("close {}", params.text_document.uri);
+/// Diagnostics of documents affected by compilation of a target.
+type Report = Vec<(Url, Vec<Diagnostic>)>;
+
+/// Convert diagnostic of a world to LSP diagnostic.
+fn to_lsp_diagnostic(diagnostic: diagnostics::Diagnostic) -> Diagnostic {
+    let to_position = |(line, column): diagnostics::Position| Position {
+        line: line as u32,
+        character: column as u32,
+    };
+    let severity = match diagnostic.severity {
+        Severity::Error => DiagnosticSeverity::ERROR,
+        Severity::Warning => DiagnosticSeverity::WARNING,
+        Severity::Hint => DiagnosticSeverity::HINT,
+    };
+    Diagnostic {
+        range: Range {
+            start: to_position(diagnostic.start),
+            end: to_position(diagnostic.end),
+        },
+        severity: Some(severity),
+        source: Some("typst".to_string()),
+        message: diagnostic.message,
+        ..Default::default()
     }
+}
 
-    #[instrument(
-        skip_all,
-        fields(uri = %params.text_document.uri.path_segments()
-            .map(|it| it.last().unwrap_or("/"))
-            .unwrap_or("/")
-        )
-    )]
-    async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        log::info!("apply {} changes", params.content_changes.len());
-        // TODO: (1) find a context by URI; (2) trigger an update of that
-        // source within Context(?).
-        let uri = params.text_document.uri;
-        for change in params.content_changes.iter() {
-            let Some(range) = change.range else {
-                continue;
-            };
-            let begin = range.start;
-            let end = range.end;
-            let Some((_, world)) = self.find_world(&uri) else {
-                return;
-            };
-            world.lock().unwrap().update_file(
-                Path::new(uri.path()),
-                change.text.as_str(),
-                (begin.line as usize, begin.character as usize),
-                (end.line as usize, end.character as usize),
-            );
+/// Notification `typst/compileStatus` which is sent on completion of each
+/// compilation so that editors could show build state.
+enum CompileStatus {}
+
+impl notification::Notification for CompileStatus {
+    type Params = CompileStatusParams;
+    const METHOD: &'static str = "typst/compileStatus";
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CompileStatusParams {
+    /// URI of main file of a compiled world.
+    uri: Url,
+    success: bool,
+    draft: bool,
+    /// Duration of compilation in milliseconds.
+    duration: f64,
+    /// Number of pages of a compiled document (zero on failure).
+    pages: usize,
+    /// Path to exported document if a document has been exported.
+    output: Option<PathBuf>,
+}
+
+/// Reporter of compilation status to client. Work done progress is shown
+/// while a world is compiled (if client supports it) and notification
+/// `typst/compileStatus` is sent on completion. Compile workers run outside
+/// of runtime so that messages are sent from tasks of runtime which has
+/// created reporter.
+#[derive(Clone, Debug)]
+struct StatusReporter {
+    client: Client,
+    runtime: Option<tokio::runtime::Handle>,
+    /// Client supports server-initiated work done progress.
+    progress: Arc<AtomicBool>,
+    tokens: Arc<AtomicUsize>,
+}
+
+impl StatusReporter {
+    fn new(client: Client) -> Self {
+        Self {
+            client,
+            runtime: tokio::runtime::Handle::try_current().ok(),
+            progress: Default::default(),
+            tokens: Default::default(),
         }
     }
 
-    #[instrument(
-        skip_all,
-        fields(uri = %params.text_document.uri.path_segments()
-            .map(|it| it.last().unwrap_or("/"))
-            .unwrap_or("/")
+    fn set_progress(&self, progress: bool) {
+        self.progress.store(progress, Ordering::Relaxed);
+    }
+
+    /// Show progress of compilation of a world until a message is sent to
+    /// returned channel (or it is dropped).
+    fn begin(&self, main_path: &Path) -> Option<oneshot::Sender<String>> {
+        let runtime = self.runtime.as_ref()?;
+        if !self.progress.load(Ordering::Relaxed) {
+            return None;
+        }
+        let index = self.tokens.fetch_add(1, Ordering::Relaxed);
+        let token = NumberOrString::String(format!("typstd/compile/{index}"));
+        let name = main_path.file_name().unwrap_or_default();
+        let message = name.to_string_lossy().to_string();
+        let (sender, receiver) = oneshot::channel::<String>();
+        let client = self.client.clone();
+        runtime.spawn(async move {
+            let params = WorkDoneProgressCreateParams {
+                token: token.clone(),
+            };
+            let created = client
+                .send_request::<request::WorkDoneProgressCreate>(params)
+                .await;
+            if let Err(err) = created {
+                log::debug!("failed to create progress: {err}");
+                return;
+            }
+            let begin = WorkDoneProgress::Begin(WorkDoneProgressBegin {
+                title: "Compiling".to_string(),
+                message: Some(message),
+                ..Default::default()
+            });
+            client
+                .send_notification::<notification::Progress>(ProgressParams {
+                    token: token.clone(),
+                    value: ProgressParamsValue::WorkDone(begin),
+                })
+                .await;
+            let end = WorkDoneProgress::End(WorkDoneProgressEnd {
+                message: receiver.await.ok(),
+            });
+            client
+                .send_notification::<notification::Progress>(ProgressParams {
+                    token,
+                    value: ProgressParamsValue::WorkDone(end),
+                })
+                .await;
+        });
+        Some(sender)
+    }
+
+    /// Finish progress of compilation and notify client about its status.
+    fn finish(
+        &self,
+        progress: Option<oneshot::Sender<String>>,
+        status: CompileStatusParams,
+    ) {
+        let Some(runtime) = self.runtime.as_ref() else {
+            return;
+        };
+        if let Some(progress) = progress {
+            let message = match status.success {
+                true => format!("{} page(s)", status.pages),
+                false => "failed".to_string(),
+            };
+            let _ = progress.send(message);
+        }
+        let client = self.client.clone();
+        runtime.spawn(async move {
+            client.send_notification::<CompileStatus>(status).await;
+        });
+    }
+}
+
+/// Compile a world and report compilation status as diagnostics of documents
+/// they belong to. Diagnostics which are not bound to files are reported to
+/// a target document (it is always in report). Draft mode is for fast
+/// feedback (see [`LanguageServiceWorld::compile_draft`]).
+fn compile_world(
+    world: &RwLock<LanguageServiceWorld>,
+    draft: bool,
+    target: &Url,
+    superseded: &dyn Fn() -> bool,
+) -> (Report, CompileStatusParams) {
+    let world = world.read().unwrap();
+    let started_at = Instant::now();
+    let result = match draft {
+        true => world.compile_draft(),
+        // Export is skipped if a newer compilation has been queued meanwhile.
+        false => world.compile().and_then(|doc| match superseded() {
+            true => Ok(doc),
+            false => world.export_output(&doc).map(|_| doc),
+        }),
+    };
+    let elapsed = started_at.elapsed();
+    metrics::record(if draft { "compileDraft" } else { "compile" }, elapsed);
+
+    let diagnostics = world.diagnostics();
+    match &result {
+        Ok(_) => {
+            let stats = world.stats();
+            log::info!(
+                "compilation finished in {:?}: reused {}/{} pages ({:.0}%) \
+                 and {}/{} sources",
+                elapsed,
+                stats.reused_pages,
+                stats.pages,
+                100.0 * stats.reuse_ratio(),
+                stats.reused_sources,
+                stats.sources,
+            )
+        }
+        Err(err) => log::error!("compilation failed in {:?}: {}", elapsed, err),
+    }
+    let main_uri = uri::from_path(world.main_path());
+    let status = CompileStatusParams {
+        uri: main_uri.unwrap_or_else(|| target.clone()),
+        success: result.is_ok(),
+        draft,
+        duration: elapsed.as_secs_f64() * 1e3,
+        pages: result.as_ref().map_or(0, |doc| doc.pages.len()),
+        output: (result.is_ok() && !draft && world.exports())
+            .then(|| world.output_path().to_path_buf()),
+    };
+
+    (make_report(target, result.err(), diagnostics), status)
+}
+
+/// Group diagnostics of a world by documents they belong to. Diagnostics
+/// which are not bound to a file and a compilation failure which is not
+/// reported by Typst itself go to a target. A target is always reported so
+/// that its stale diagnostics are cleared.
+fn make_report(
+    target: &Url,
+    failure: Option<String>,
+    diagnostics: Vec<diagnostics::Diagnostic>,
+) -> Report {
+    let mut report = HashMap::<Url, Vec<Diagnostic>>::new();
+    report.insert(target.clone(), Vec::new());
+    if let Some(err) = failure {
+        if diagnostics.iter().all(|it| it.severity != Severity::Error) {
+            let diagnostic = make_diagnostic(DiagnosticSeverity::ERROR, err);
+            report.entry(target.clone()).or_default().push(diagnostic);
+        }
+    }
+    for diagnostic in diagnostics {
+        let uri = diagnostic.path.as_deref().and_then(uri::from_path);
+        let uri = uri.unwrap_or_else(|| target.clone());
+        let diagnostic = to_lsp_diagnostic(diagnostic);
+        report.entry(uri).or_default().push(diagnostic);
+    }
+    report.into_iter().collect()
+}
+
+/// Compilation which is queued to a compile worker of a world.
+#[derive(Debug)]
+struct CompileJob {
+    target: Url,
+    draft: bool,
+    /// Sequential number of a job among jobs of a worker.
+    generation: u64,
+    reply: oneshot::Sender<(Url, Report)>,
+}
+
+/// Generations of the latest jobs submitted to a compile worker indexed by
+/// targets: of any job and of a full compilation. A job is superseded by a
+/// newer job of the same target unless it is a full compilation and newer
+/// jobs are drafts.
+#[derive(Debug, Default)]
+struct Generations {
+    counter: u64,
+    latest: HashMap<Url, (u64, u64)>,
+}
+
+impl Generations {
+    /// Assign a generation to a new job of a target.
+    fn next(&mut self, target: &Url, draft: bool) -> u64 {
+        self.counter += 1;
+        let latest = self.latest.entry(target.clone()).or_default();
+        latest.0 = self.counter;
+        if !draft {
+            latest.1 = self.counter;
+        }
+        self.counter
+    }
+
+    fn is_superseded(&self, job: &CompileJob) -> bool {
+        let Some(&(any, full)) = self.latest.get(&job.target) else {
+            return false;
+        };
+        match job.draft {
+            true => any > job.generation,
+            false => full > job.generation,
+        }
+    }
+}
+
+/// Compile worker of a world. Jobs are run one by one on a dedicated thread
+/// so that request handlers never wait for compilation. Queued jobs of a
+/// target are superseded by newer ones and a queued full compilation
+/// supersedes draft ones. A running job is checked against newer jobs
+/// before and after compilation (and before export) so that superseded jobs
+/// stop early. Replies of superseded jobs are dropped. Reports are replied
+/// together with URI of main file of a world.
+#[derive(Debug)]
+struct CompileWorker {
+    world: WeakWorld,
+    jobs: std::sync::mpsc::Sender<CompileJob>,
+    generations: Arc<Mutex<Generations>>,
+}
+
+impl CompileWorker {
+    fn start(
+        world: &Arc<RwLock<LanguageServiceWorld>>,
+        includes: Includes,
+        status: StatusReporter,
+    ) -> Self {
+        let (jobs, receiver) = std::sync::mpsc::channel();
+        let weak = Arc::downgrade(world);
+        let generations = Arc::<Mutex<Generations>>::default();
+        let worker = Self {
+            world: weak.clone(),
+            jobs,
+            generations: generations.clone(),
+        };
+        let result = std::thread::Builder::new()
+            .name("compile".to_string())
+            .spawn(move || {
+                run_compile_jobs(weak, receiver, generations, includes, status)
+            });
+        if let Err(err) = result {
+            log::error!("failed to start compile worker: {err}");
+        }
+        worker
+    }
+}
+
+/// Run compilation jobs until a world is dropped or its worker is stopped.
+/// Files which a world includes are indexed after each compilation.
+fn run_compile_jobs(
+    world: WeakWorld,
+    receiver: std::sync::mpsc::Receiver<CompileJob>,
+    generations: Arc<Mutex<Generations>>,
+    includes: Includes,
+    status: StatusReporter,
+) {
+    let superseded =
+        |job: &CompileJob| generations.lock().unwrap().is_superseded(job);
+    while let Ok(job) = receiver.recv() {
+        let mut queue = Vec::<CompileJob>::new();
+        for job in std::iter::once(job).chain(receiver.try_iter()) {
+            match queue.iter_mut().find(|it| it.target == job.target) {
+                Some(queued) if !queued.draft && job.draft => {
+                    log::debug!("draft compilation is superseded");
+                }
+                Some(queued) => {
+                    log::debug!("queued compilation is superseded");
+                    *queued = job;
+                }
+                None => queue.push(job),
+            }
+        }
+        for job in queue {
+            if superseded(&job) {
+                log::debug!("queued compilation is superseded");
+                continue;
+            }
+            let Some(world) = world.upgrade() else {
+                return;
+            };
+            let main_path = world.read().unwrap().main_path().to_path_buf();
+            let progress = status.begin(&main_path);
+            let (report, compiled) =
+                compile_world(&world, job.draft, &job.target, &|| {
+                    superseded(&job)
+                });
+            status.finish(progress, compiled);
+            let files = world.read().unwrap().dependencies().files;
+            includes.update(&world, files);
+            if superseded(&job) {
+                log::info!("drop results of superseded compilation");
+                continue;
+            }
+            let key = uri::from_path(&main_path).unwrap_or(job.target);
+            let _ = job.reply.send((key, report));
+        }
+    }
+}
+
+/// Reference to a world which does not keep it alive.
+type WeakWorld = Weak<RwLock<LanguageServiceWorld>>;
+
+/// Reverse dependencies: worlds which have read a file during their last
+/// compilation indexed by normalized paths of files.
+#[derive(Clone, Debug, Default)]
+struct Includes(Arc<RwLock<HashMap<PathBuf, Vec<WeakWorld>>>>);
+
+impl Includes {
+    /// Replace files which a world includes.
+    fn update(
+        &self,
+        world: &Arc<RwLock<LanguageServiceWorld>>,
+        files: impl IntoIterator<Item = PathBuf>,
+    ) {
+        let weak = Arc::downgrade(world);
+        let mut includes = self.0.write().unwrap();
+        includes.retain(|_, worlds| {
+            worlds.retain(|it| it.strong_count() > 0 && !it.ptr_eq(&weak));
+            !worlds.is_empty()
+        });
+        for path in files {
+            let worlds = includes.entry(path::normalize(&path)).or_default();
+            worlds.push(weak.clone());
+        }
+    }
+
+    /// Find worlds which include a file.
+    fn find(&self, path: &Path) -> Vec<Arc<RwLock<LanguageServiceWorld>>> {
+        let includes = self.0.read().unwrap();
+        let worlds = includes.get(&path::normalize(path));
+        worlds
+            .into_iter()
+            .flatten()
+            .filter_map(Weak::upgrade)
+            .collect()
+    }
+}
+
+/// Compile workers of worlds. A worker is started on the first compilation
+/// of a world and it is stopped as soon as its world is dropped.
+#[derive(Clone, Debug)]
+struct Compilers {
+    workers: Arc<Mutex<Vec<CompileWorker>>>,
+    includes: Includes,
+    status: StatusReporter,
+}
+
+impl Compilers {
+    fn new(client: Client) -> Self {
+        Self {
+            workers: Default::default(),
+            includes: Default::default(),
+            status: StatusReporter::new(client),
+        }
+    }
+
+    /// Queue compilation of a world. Diagnostics are reported as ones of a
+    /// target document (see [`compile_world`]). Returned receiver fails if
+    /// compilation is superseded.
+    fn submit(
+        &self,
+        world: &Arc<RwLock<LanguageServiceWorld>>,
+        target: Url,
+        draft: bool,
+    ) -> oneshot::Receiver<(Url, Report)> {
+        let (reply, receiver) = oneshot::channel();
+        let mut workers = self.workers.lock().unwrap();
+        workers.retain(|it| it.world.strong_count() > 0);
+        let index = workers
+            .iter()
+            .position(|it| std::ptr::eq(it.world.as_ptr(), Arc::as_ptr(world)))
+            .unwrap_or_else(|| {
+                let includes = self.includes.clone();
+                let status = self.status.clone();
+                workers.push(CompileWorker::start(world, includes, status));
+                workers.len() - 1
+            });
+        let worker = &workers[index];
+        let generation =
+            worker.generations.lock().unwrap().next(&target, draft);
+        let job = CompileJob {
+            target,
+            draft,
+            generation,
+            reply,
+        };
+        if let Err(err) = worker.jobs.send(job) {
+            log::error!("compile worker is stopped: {err}");
+        }
+        receiver
+    }
+}
+
+/// Worlds of documents indexed by normalized paths.
+type Worlds = RwLock<HashMap<PathBuf, Arc<RwLock<LanguageServiceWorld>>>>;
+
+/// Worlds of targets indexed by normalized directories (which documents are
+/// looked up by) and normalized main files. Several targets could share a
+/// directory: they are adjacent in the index.
+type Targets = RwLock<TargetIndex>;
+
+type TargetIndex =
+    BTreeMap<(PathBuf, PathBuf), Arc<RwLock<LanguageServiceWorld>>>;
+
+/// Iterate over worlds of targets which are indexed by a directory.
+fn targets_at<'a>(
+    worlds: &'a TargetIndex,
+    dir: &'a Path,
+) -> impl Iterator<Item = (&'a PathBuf, &'a Arc<RwLock<LanguageServiceWorld>>)>
+{
+    worlds
+        .range((dir.to_path_buf(), PathBuf::new())..)
+        .take_while(move |((it, _), _)| it == dir)
+        .map(|((_, main), world)| (main, world))
+}
+
+/// Poll files which worlds have read from file system and recompile worlds
+/// (in draft mode) which depend on files changed outside of editor or which
+/// have waited for packages downloaded in background. Polling stops as soon
+/// as a session ends.
+async fn watch_files(
+    worlds: Weak<Targets>,
+    chapters: Weak<Worlds>,
+    compilers: Compilers,
+    publisher: DiagnosticsPublisher,
+) {
+    let mut interval = tokio::time::interval(WATCH_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let (Some(worlds), Some(chapters)) =
+            (worlds.upgrade(), chapters.upgrade())
+        else {
+            return;
+        };
+        let mut all = Vec::<Arc<RwLock<LanguageServiceWorld>>>::new();
+        for world in worlds.read().unwrap().values() {
+            if !all.iter().any(|it| Arc::ptr_eq(it, world)) {
+                all.push(world.clone());
+            }
+        }
+        all.extend(chapters.read().unwrap().values().cloned());
+        drop((worlds, chapters));
+
+        let changed = tokio::task::spawn_blocking(move || {
+            all.into_iter()
+                .filter_map(|world| {
+                    let (changed, downloaded, main_path) = {
+                        let world = world.read().unwrap();
+                        let changed = world.refresh();
+                        let downloaded = world.downloaded();
+                        (changed, downloaded, world.main_path().to_path_buf())
+                    };
+                    if downloaded {
+                        log::info!("packages of {main_path:?} are downloaded");
+                    } else if changed.is_empty() {
+                        return None;
+                    } else {
+                        log::info!(
+                            "files changed outside of editor: {changed:?}"
+                        );
+                    }
+                    Some((uri::from_path(&main_path)?, world))
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_default();
+        let pending = changed
+            .into_iter()
+            .map(|(uri, world)| compilers.submit(&world, uri, true))
+            .collect::<Vec<_>>();
+        for report in pending {
+            if let Ok((world, report)) = report.await {
+                publisher.publish_report(world, report);
+            }
+        }
+    }
+}
+
+/// Show source positions which clicks in preview point to in editor.
+async fn follow_clicks(
+    mut clicks: tokio::sync::mpsc::UnboundedReceiver<preview::Position>,
+    preview: Arc<Mutex<Option<ActivePreview>>>,
+    client: Client,
+) {
+    while let Some(click) = clicks.recv().await {
+        let Some((_, world)) = preview.lock().unwrap().clone() else {
+            continue;
+        };
+        let found =
+            run_cancellable(world, move |it| it.jump_from_preview(&click));
+        let Some((path, (line, column))) = found.await.flatten() else {
+            log::debug!("no source position for click at {:?}", click);
+            continue;
+        };
+        let Some(uri) = uri::from_path(&path) else {
+            continue;
+        };
+        let position = Position::new(line as u32, column as u32);
+        let params = ShowDocumentParams {
+            uri,
+            external: Some(false),
+            take_focus: Some(true),
+            selection: Some(Range::new(position, position)),
+        };
+        if let Err(err) = client.show_document(params).await {
+            log::warn!("failed to show source of click: {err}");
+        }
+    }
+}
+
+/// Check whether a newer draft compilation of a document has been scheduled.
+fn is_superseded(
+    drafts: &Mutex<HashMap<Url, u64>>,
+    uri: &Url,
+    generation: u64,
+) -> bool {
+    drafts.lock().unwrap().get(uri) != Some(&generation)
+}
+
+/// Copy text of a document to worlds which have not got it from editor yet
+/// (e.g. a document is routed to a world which has included it after the
+/// document has been opened) so that changes apply to the same text.
+fn share_text(path: &Path, worlds: &[Arc<RwLock<LanguageServiceWorld>>]) {
+    let Some(text) = worlds.iter().find_map(|world| {
+        let world = world.read().unwrap();
+        world
+            .owns_file(path)
+            .then(|| world.file_text(path))
+            .flatten()
+    }) else {
+        return;
+    };
+    for world in worlds {
+        if !world.read().unwrap().owns_file(path) {
+            world.read().unwrap().open_file(path, text.clone());
+        }
+    }
+}
+
+/// Merge a failure of a blocking task into a result of the task.
+fn flatten<T, E: std::fmt::Display>(
+    result: result::Result<result::Result<T, E>, tokio::task::JoinError>,
+) -> result::Result<T, String> {
+    match result {
+        Ok(result) => result.map_err(|err| err.to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Ask client to request inlay hints again since values could be changed by
+/// compilation.
+async fn refresh_inlay_hints(client: Option<Client>) {
+    let Some(client) = client else {
+        return;
+    };
+    if let Err(err) = client.inlay_hint_refresh().await {
+        log::warn!("failed to refresh inlay hints: {err}");
+    }
+}
+
+/// Format a number of items with a noun in singular or plural form.
+fn plural(count: usize, noun: &str) -> String {
+    match count {
+        1 => format!("{count} {noun}"),
+        _ => format!("{count} {noun}s"),
+    }
+}
+
+/// World which a document belongs to and a key of the world in index.
+type FoundWorld = (PathBuf, Arc<RwLock<LanguageServiceWorld>>);
+
+/// Open notebook and a world of its virtual source.
+type OpenNotebook = (Notebook, Arc<RwLock<LanguageServiceWorld>>);
+
+/// Preview server and a world which it shows.
+type ActivePreview = (Arc<Preview>, Arc<RwLock<LanguageServiceWorld>>);
+
+#[derive(Debug)]
+struct TypstLanguageService {
+    /// Language Server Protocol (LSP) client for backward communication with
+    /// service clients. Primarly, it is used for publishing diagnostics
+    /// information.
+    client: Client,
+    /// Throttled publisher of diagnostics to client.
+    diagnostics: DiagnosticsPublisher,
+    /// Actual execution contexts for language analysis. It would be better to
+    /// use URI as keys instead of paths if we want non-local environment such
+    /// as browsers. Paths are normalized with [`path::normalize`].
+    worlds: Arc<Targets>,
+    /// Configuration provided by client on initialization.
+    config: RwLock<Config>,
+    /// Features supported by client.
+    features: RwLock<ClientFeatures>,
+    /// Normalized paths of open documents which exceed size limits. Changes
+    /// of such documents are not tracked and they are read from file system
+    /// on save.
+    large_files: RwLock<HashSet<PathBuf>>,
+    /// Versions of open documents indexed by normalized paths. Changes of
+    /// older versions are stale and they are dropped.
+    versions: RwLock<HashMap<PathBuf, i32>>,
+    /// URIs of open documents as client has sent them indexed by normalized
+    /// paths. URIs made of normalized paths could differ (e.g. in case of
+    /// letters on Windows) so that client would not recognize them.
+    documents: RwLock<HashMap<PathBuf, Url>>,
+    /// Worlds of individual chapters of books indexed by normalized paths of
+    /// chapters. Chapters also belong to worlds of their books.
+    chapters: Arc<Worlds>,
+    /// Generations of changes of documents which are used to skip superseded
+    /// draft compilations.
+    drafts: Arc<Mutex<HashMap<Url, u64>>>,
+    /// Compile workers of worlds.
+    compilers: Compilers,
+    /// Open notebooks and worlds of their virtual sources indexed by URIs of
+    /// notebooks.
+    notebooks: RwLock<HashMap<Url, OpenNotebook>>,
+    /// Project settings indexed by normalized paths of project directories.
+    projects: RwLock<HashMap<PathBuf, ProjectConfig>>,
+    /// Targets chosen by user for documents which belong to several targets
+    /// indexed by normalized paths of documents.
+    associations: Arc<RwLock<HashMap<PathBuf, FoundWorld>>>,
+    /// Single-file worlds of documents which are not reachable from any
+    /// target. They are retired as soon as a target includes the documents.
+    orphans: RwLock<Vec<Arc<RwLock<LanguageServiceWorld>>>>,
+    /// Preview server and a world which it shows.
+    preview: Arc<Mutex<Option<ActivePreview>>>,
+    /// Contents of files which client provides in memory indexed by
+    /// normalized paths.
+    bytes: RwLock<HashMap<PathBuf, Bytes>>,
+}
+
+impl TypstLanguageService {
+    /// Apply client and project settings to a world. Output file is placed
+    /// next to main file unless project or client sets output directory.
+    /// Output directory of a project is relative to project directory and
+    /// the one of client is relative to root directory of a world.
+    fn configure_world(&self, world: &mut LanguageServiceWorld) {
+        let main_path = world.main_path().to_path_buf();
+        let project = self.find_project(&main_path);
+        let config = self.config.read().unwrap();
+        world
+            .set_fonts(FontProvider::shared(&config.font_paths, &config.fonts));
+        world.set_background_downloads(true);
+        world.set_lock_packages(config.packages.lock);
+        let export_pdf = project
+            .as_ref()
+            .and_then(|(_, project)| project.export_pdf)
+            .unwrap_or(config.export_pdf);
+        // Untitled documents have nowhere to export to.
+        let untitled = uri::is_untitled(&main_path);
+        world.set_export(export_pdf != ExportPdf::Never && !untitled);
+        world.set_diagnostics_level(config.diagnostics.level);
+        world.set_update_hints(config.diagnostics.package_updates);
+        world.set_timestamp(
+            config.creation_timestamp.or_else(date::source_date_epoch),
+        );
+        let mut inputs = config.inputs.clone();
+        if let Some((_, project)) = &project {
+            inputs.extend(project.inputs.clone());
+        }
+        world.set_inputs(to_inputs(inputs));
+        let encoding = self.features.read().unwrap().position_encoding;
+        world.set_position_encoding(encoding);
+        world.set_pdf_options(config.pdf.clone());
+        let format = config.export.format();
+        world.set_export_format(format);
+        let output_path = main_path.with_extension(format.extension());
+        let output_dir = match project {
+            Some((
+                dir,
+                ProjectConfig {
+                    output_dir: Some(output_dir),
+                    ..
+                },
+            )) => Some(dir.join(output_dir)),
+            _ => config
+                .output_dir
+                .as_ref()
+                .map(|it| world.root_dir().join(it)),
+        };
+        let output_path = match (output_dir, output_path.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => output_path,
+        };
+        world.set_output_path(&output_path);
+    }
+
+    /// Open documents of editor and files in memory under root directory of
+    /// a new world so that they shadow files on disk from the start rather
+    /// than once the documents are routed to the world.
+    fn open_buffers(&self, world: &mut LanguageServiceWorld) {
+        let root_dir = path::normalize(world.root_dir());
+        for (path, bytes) in self.bytes.read().unwrap().iter() {
+            if path.starts_with(&root_dir) {
+                world.add_bytes(path, bytes.clone());
+            }
+        }
+        let paths = self
+            .versions
+            .read()
+            .unwrap()
+            .keys()
+            .filter(|it| it.starts_with(&root_dir))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in paths {
+            let Some(uri) = uri::from_path(&path) else {
+                continue;
+            };
+            let text = self.find_worlds(&uri).iter().find_map(|it| {
+                let it = it.read().unwrap();
+                it.owns_file(&path).then(|| it.file_text(&path)).flatten()
+            });
+            if let Some(text) = text {
+                world.open_file(&path, text);
+            }
+        }
+    }
+
+    /// Load settings of a project (if any) located at a directory.
+    fn load_project(&self, dir: &Path) {
+        let key = path::normalize(dir);
+        match ProjectConfig::load(dir) {
+            Ok(Some(project)) => {
+                log::info!("load project settings at {:?}", dir);
+                self.projects.write().unwrap().insert(key, project);
+            }
+            Ok(None) => {
+                self.projects.write().unwrap().remove(&key);
+            }
+            Err(err) => log::warn!("{}", err),
+        }
+    }
+
+    /// Find settings of the closest project which a file belongs to.
+    fn find_project(&self, path: &Path) -> Option<(PathBuf, ProjectConfig)> {
+        let projects = self.projects.read().unwrap();
+        path::normalize(path).ancestors().find_map(|dir| {
+            let project = projects.get(dir)?;
+            Some((dir.to_path_buf(), project.clone()))
+        })
+    }
+
+    /// Determine when to export a document: project settings override client
+    /// ones.
+    fn export_pdf(&self, uri: &Url) -> ExportPdf {
+        let project = uri::to_path(uri).and_then(|it| self.find_project(&it));
+        match project.and_then(|(_, project)| project.export_pdf) {
+            Some(export_pdf) => export_pdf,
+            None => self.config.read().unwrap().export_pdf,
+        }
+    }
+
+    /// Apply settings to all worlds once again (e.g. on changes of project
+    /// settings).
+    fn reconfigure_worlds(&self) {
+        let mut worlds = Vec::new();
+        worlds.extend(self.worlds.read().unwrap().values().cloned());
+        worlds.extend(self.chapters.read().unwrap().values().cloned());
+        worlds.extend(
+            self.notebooks
+                .read()
+                .unwrap()
+                .values()
+                .map(|(_, world)| world.clone()),
+        );
+        for world in worlds {
+            self.configure_world(&mut world.write().unwrap());
+        }
+    }
+
+    /// Discover targets of a workspace once again (e.g. its `typst.toml` has
+    /// been changed).
+    fn rescan_workspace(&self, dir: &Path) {
+        log::info!("rescan targets of workspace at {:?}", dir);
+        self.reload_workspace(dir, search_targets(vec![dir]));
+    }
+
+    /// Replace worlds under a directory with worlds of targets and move open
+    /// documents and preview to them. Open documents which belong to none of
+    /// targets get worlds as on opening. Diagnostics of targets which are
+    /// gone are cleared.
+    fn reload_workspace(&self, dir: &Path, targets: Vec<Target>) {
+        let key = path::normalize(dir);
+        let open = self
+            .documents
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(it, _)| it.starts_with(&key))
+            .map(|(path, uri)| (uri.clone(), path.clone()))
+            .collect::<Vec<_>>();
+        let texts = open
+            .iter()
+            .map(|(uri, path)| {
+                let (_, world) = self.find_world(uri)?;
+                let world = world.read().unwrap();
+                world.file_text(path)
+            })
+            .collect::<Vec<_>>();
+
+        let mut removed = Vec::<Arc<RwLock<LanguageServiceWorld>>>::new();
+        let mut keep = |it: &Path, world: &Arc<RwLock<_>>| {
+            let keep = !it.starts_with(&key);
+            if !keep && !removed.iter().any(|it| Arc::ptr_eq(it, world)) {
+                removed.push(world.clone());
+            }
+            keep
+        };
+        self.worlds
+            .write()
+            .unwrap()
+            .retain(|(dir, _), world| keep(dir, world));
+        self.chapters
+            .write()
+            .unwrap()
+            .retain(|path, world| keep(path, world));
+        self.associations
+            .write()
+            .unwrap()
+            .retain(|it, _| !it.starts_with(&key));
+        log::info!("found {} target(s)", targets.len());
+        self.new_worlds(targets);
+
+        for ((uri, path), text) in open.into_iter().zip(texts) {
+            if self.find_world(&uri).is_none() {
+                self.new_world_from_uri(&uri);
+            }
+            if let Some(text) = text {
+                for world in self.find_worlds(&uri) {
+                    world.read().unwrap().open_file(&path, text.clone());
+                }
+            }
+            self.compile_draft(&uri);
+        }
+
+        for world in removed {
+            let main_path = world.read().unwrap().main_path().to_path_buf();
+            let Some(uri) = uri::from_path(&main_path) else {
+                continue;
+            };
+            let replacement = self
+                .find_worlds(&uri)
+                .into_iter()
+                .find(|it| it.read().unwrap().main_path() == main_path);
+            let mut preview = self.preview.lock().unwrap();
+            if let Some((server, previewed)) = preview.as_mut() {
+                if Arc::ptr_eq(previewed, &world) {
+                    if let Some(replacement) = replacement.as_ref() {
+                        let server = Some(server.clone());
+                        replacement.write().unwrap().set_preview(server);
+                        *previewed = replacement.clone();
+                    }
+                }
+            }
+            if replacement.is_none() {
+                log::info!("target {} is gone", uri);
+                self.diagnostics.clear_world(&uri);
+            }
+        }
+    }
+
+    /// Retire single-file worlds of documents which targets have included
+    /// since the worlds were created so that the documents are compiled (and
+    /// reported) once. A world is kept while any of its open documents is
+    /// not included elsewhere.
+    fn adopt_orphans(&self) {
+        // Orphans could have been replaced on rescans of workspaces.
+        let orphans = {
+            let worlds = self.worlds.read().unwrap();
+            let mut orphans = self.orphans.write().unwrap();
+            orphans.retain(|it| worlds.values().any(|w| Arc::ptr_eq(w, it)));
+            orphans.clone()
+        };
+        if orphans.is_empty() {
+            return;
+        }
+        let open = self
+            .versions
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        for orphan in orphans {
+            let main_path = orphan.read().unwrap().main_path().to_path_buf();
+            let includers = |path: &Path| {
+                self.find_includers(path)
+                    .into_iter()
+                    .filter(|it| !Arc::ptr_eq(it, &orphan))
+                    .collect::<Vec<_>>()
+            };
+            let owned = open
+                .iter()
+                .filter(|it| orphan.read().unwrap().owns_file(it))
+                .collect::<Vec<_>>();
+            let Some(adopter) = includers(&main_path).into_iter().next() else {
+                continue;
+            };
+            if owned.iter().any(|it| includers(it).is_empty()) {
+                continue;
+            }
+            for path in owned {
+                let mut worlds = includers(path);
+                worlds.insert(0, orphan.clone());
+                share_text(path, &worlds);
+            }
+            self.retire_world(&orphan);
+            let Some(uri) = uri::from_path(&main_path) else {
+                continue;
+            };
+            log::info!("world of {} is adopted by a target", uri);
+            let mut preview = self.preview.lock().unwrap();
+            if let Some((server, previewed)) = preview.as_mut() {
+                if Arc::ptr_eq(previewed, &orphan) {
+                    orphan.write().unwrap().set_preview(None);
+                    adopter.write().unwrap().set_preview(Some(server.clone()));
+                    *previewed = adopter;
+                }
+            }
+            self.diagnostics.clear_world(&uri);
+        }
+    }
+
+    /// Remove a world from indices.
+    fn retire_world(&self, world: &Arc<RwLock<LanguageServiceWorld>>) {
+        self.orphans
+            .write()
+            .unwrap()
+            .retain(|it| !Arc::ptr_eq(it, world));
+        self.associations
+            .write()
+            .unwrap()
+            .retain(|_, (_, it)| !Arc::ptr_eq(it, world));
+        self.worlds
+            .write()
+            .unwrap()
+            .retain(|_, it| !Arc::ptr_eq(it, world));
+    }
+
+    /// Drop cached sources of files which have been changed outside of
+    /// editor and recompile (in draft mode) worlds which have read them.
+    fn invalidate_files(&self, paths: &[PathBuf]) {
+        let open = self.versions.read().unwrap();
+        let paths = paths
+            .iter()
+            .filter(|it| !open.contains_key(&path::normalize(it)))
+            .collect::<Vec<_>>();
+        drop(open);
+        let mut worlds = Vec::<Arc<RwLock<LanguageServiceWorld>>>::new();
+        for world in self
+            .worlds
+            .read()
+            .unwrap()
+            .values()
+            .chain(self.chapters.read().unwrap().values())
+        {
+            if !worlds.iter().any(|it| Arc::ptr_eq(it, world)) {
+                worlds.push(world.clone());
+            }
+        }
+        worlds.extend(
+            self.notebooks
+                .read()
+                .unwrap()
+                .values()
+                .map(|(_, world)| world.clone()),
+        );
+
+        let mut pending = Vec::new();
+        for world in worlds {
+            let (affected, main_path) = {
+                let world = world.read().unwrap();
+                let mut affected = false;
+                for path in paths.iter() {
+                    affected |= world.invalidate(path);
+                }
+                (affected, world.main_path().to_path_buf())
+            };
+            let Some(uri) = uri::from_path(&main_path) else {
+                continue;
+            };
+            if affected {
+                log::info!("recompile {} on changes of watched files", uri);
+                pending.push(self.compilers.submit(&world, uri, true));
+            }
+        }
+        let publisher = self.diagnostics.clone();
+        tokio::spawn(async move {
+            for report in pending {
+                if let Ok((world, report)) = report.await {
+                    publisher.publish_report(world, report);
+                }
+            }
+        });
+    }
+
+    /// Root directory of a world of a document outside of workspaces. It is
+    /// either configured by client, or set with `TYPST_ROOT`, or a parent
+    /// directory of the document.
+    fn standalone_root_dir(&self, main_file: &Path) -> Option<PathBuf> {
+        let root_path = self.config.read().unwrap().root_path.clone();
+        if let Some(root_dir) = root_path {
+            if main_file.starts_with(&root_dir) {
+                return Some(root_dir);
+            }
+        }
+        workspace::env_root_dir(main_file)
+            .or_else(|| main_file.parent().map(Path::to_path_buf))
+    }
+
+    /// Find worlds to compile on changes of a document and documents which
+    /// diagnostics of the worlds belong to. A chapter of a book is compiled
+    /// on its own and as a part of the book.
+    fn compile_targets(
+        &self,
+        uri: &Url,
+    ) -> Vec<(Url, Arc<RwLock<LanguageServiceWorld>>)> {
+        self.adopt_orphans();
+        let mut targets = Vec::new();
+        if let Some(world) = self.find_chapter_world(uri) {
+            targets.push((uri.clone(), world));
+        }
+        let Some((_, world)) = self.find_world(uri) else {
+            return targets;
+        };
+        if targets.is_empty() {
+            targets.push((uri.clone(), world));
+        } else {
+            let main_path = world.read().unwrap().main_path().to_path_buf();
+            if let Some(main_uri) = uri::from_path(&main_path) {
+                targets.push((main_uri, world));
+            }
+        }
+        targets
+    }
+
+    /// Queue compilation of document and return pending diagnostics of
+    /// affected documents per compiled world.
+    fn compile(&self, uri: &Url) -> Vec<oneshot::Receiver<(Url, Report)>> {
+        log::info!("try to compile document");
+        // Hints on package updates appear on the next compilation after
+        // package index is refreshed.
+        let package_updates =
+            self.config.read().unwrap().diagnostics.package_updates;
+        if package_updates && package::is_index_stale() {
+            tokio::task::spawn_blocking(|| {
+                if let Err(err) = package::refresh_index() {
+                    log::warn!("failed to refresh package index: {err}");
+                }
+            });
+        }
+        let targets = self.compile_targets(uri);
+        if targets.is_empty() {
+            let error = "missing compilation context".to_string();
+            let diagnostic = make_diagnostic(DiagnosticSeverity::ERROR, error);
+            let (reply, report) = oneshot::channel();
+            let _ = reply
+                .send((uri.clone(), vec![(uri.clone(), vec![diagnostic])]));
+            return vec![report];
+        }
+        targets
+            .into_iter()
+            .map(|(uri, world)| self.compilers.submit(&world, uri, false))
+            .collect()
+    }
+
+    /// Schedule compilation in draft mode after a document has been changed.
+    /// Compilation is postponed for a while and it is skipped if there are
+    /// newer changes.
+    fn compile_draft(&self, uri: &Url) {
+        let targets = self.compile_targets(uri);
+        // Client could ask to export document on every change.
+        let draft = self.export_pdf(uri) != ExportPdf::OnType;
+        if draft && !self.config.read().unwrap().diagnostics.on_change {
+            return;
+        }
+        let generation = {
+            let mut drafts = self.drafts.lock().unwrap();
+            let generation = drafts.entry(uri.clone()).or_default();
+            *generation += 1;
+            *generation
+        };
+        let drafts = self.drafts.clone();
+        let compilers = self.compilers.clone();
+        let publisher = self.diagnostics.clone();
+        let refresher = self.inlay_hint_refresher();
+        let delay = self.draft_delay();
+        let uri = uri.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            if is_superseded(&drafts, &uri, generation) {
+                log::debug!("draft compilation of {} is superseded", uri);
+                return;
+            }
+            let pending = targets
+                .into_iter()
+                .map(|(uri, world)| compilers.submit(&world, uri, draft))
+                .collect::<Vec<_>>();
+            let mut reports = Vec::new();
+            for report in pending {
+                if let Ok(report) = report.await {
+                    reports.push(report);
+                }
+            }
+            // Diagnostics of stale compilation are going to be replaced with
+            // ones of newer compilation.
+            if is_superseded(&drafts, &uri, generation) {
+                log::debug!("draft diagnostics of {} are stale", uri);
+                return;
+            }
+            for (world, report) in reports {
+                publisher.publish_report(world, report);
+            }
+            refresh_inlay_hints(refresher).await;
+        });
+    }
+
+    /// Show a world which a document belongs to in preview and return URL of
+    /// preview page. Preview server is started on the first call and it is
+    /// reused afterwards.
+    fn start_preview(&self, uri: &Url) -> result::Result<String, String> {
+        let Some((_, world)) = self.find_world(uri) else {
+            return Err(format!("missing compilation context of {uri}"));
+        };
+        let mut preview = self.preview.lock().unwrap();
+        let server = match preview.take() {
+            Some((server, previewed)) => {
+                if !Arc::ptr_eq(&previewed, &world) {
+                    previewed.write().unwrap().set_preview(None);
+                }
+                server
+            }
+            None => {
+                let config = self.config.read().unwrap().preview.clone();
+                let (server, clicks) =
+                    Preview::start(&config.address, config.format())?;
+                if self.features.read().unwrap().show_document {
+                    let client = self.client.clone();
+                    tokio::spawn(follow_clicks(
+                        clicks,
+                        self.preview.clone(),
+                        client,
+                    ));
+                }
+                server
+            }
+        };
+        world.write().unwrap().set_preview(Some(server.clone()));
+        let url = server.url();
+        *preview = Some((server, world));
+        Ok(url)
+    }
+
+    /// Ask client to open preview page in a browser or tell its URL.
+    async fn show_preview(&self, url: &str) {
+        let show_document = self.features.read().unwrap().show_document;
+        let Ok(uri) = Url::parse(url) else {
+            return;
+        };
+        if show_document {
+            let params = ShowDocumentParams {
+                uri,
+                external: Some(true),
+                take_focus: None,
+                selection: None,
+            };
+            if let Err(err) = self.client.show_document(params).await {
+                log::warn!("failed to open preview: {err}");
+            }
+            return;
+        }
+        let message = format!("preview is served at {url}");
+        self.client.show_message(MessageType::INFO, message).await;
+    }
+
+    /// Replace settings with ones of client. Settings from command line
+    /// (network settings, preview, and fonts) are kept unless client
+    /// overrides them.
+    fn update_config(&self, mut config: Config) {
+        let mut current = self.config.write().unwrap();
+        let packages = &mut config.packages;
+        if packages.ca_bundle.is_none() && !packages.insecure {
+            packages.ca_bundle = current.packages.ca_bundle.clone();
+            packages.insecure = current.packages.insecure;
+        }
+        if packages.proxy.is_none() {
+            packages.proxy = current.packages.proxy.clone();
+        }
+        if packages.mirror.is_none() {
+            packages.mirror = current.packages.mirror.clone();
+        }
+        if !config.preview.auto && current.preview.auto {
+            config.preview = current.preview.clone();
+        }
+        if config.font_paths.is_empty() {
+            config.font_paths = current.font_paths.clone();
+        }
+        if config.fonts == config::Fonts::default() {
+            config.fonts = current.fonts.clone();
+        }
+        *current = config;
+    }
+
+    /// Targets of main file from settings in workspace folders which do not
+    /// have targets of `typst.toml` yet.
+    fn main_file_targets(
+        &self,
+        root_dirs: &[&Path],
+        targets: &[Target],
+    ) -> Vec<Target> {
+        let config = self.config.read().unwrap();
+        let Some(main_file) = config.main_file.as_ref() else {
+            return vec![];
+        };
+        root_dirs
+            .iter()
+            .filter(|dir| !targets.iter().any(|it| it.root_dir == **dir))
+            .filter_map(|dir| {
+                let main_file = dir.join(main_file);
+                if !main_file.is_file() {
+                    return None;
+                }
+                // Workspace folder is a root directory unless main file is
+                // outside of it.
+                let root_dir = match &config.root_path {
+                    Some(root_dir) if main_file.starts_with(root_dir) => {
+                        root_dir.clone()
+                    }
+                    _ if main_file.starts_with(dir) => dir.to_path_buf(),
+                    _ => main_file.parent()?.to_path_buf(),
+                };
+                Some(Target {
+                    root_dir,
+                    main_file,
+                    chapters: vec![],
+                })
+            })
+            .collect()
+    }
+
+    /// Pause after the last change of a document before draft compilation.
+    fn draft_delay(&self) -> Duration {
+        let debounce = self.config.read().unwrap().diagnostics.debounce;
+        Duration::from_millis(debounce)
+    }
+
+    /// Format a text document or a range of it. Indentation is taken from
+    /// formatting options of a client.
+    async fn format(
+        &self,
+        uri: &Url,
+        options: &FormattingOptions,
+        range: Option<((usize, usize), (usize, usize))>,
+    ) -> Option<Vec<TextEdit>> {
+        let path = uri::to_path(uri)?;
+        if self.is_large_file(&path) {
+            log::info!("document exceeds size limits: skip formatting");
+            return None;
+        }
+        let Some((_, world)) = self.find_world(uri) else {
+            log::error!("unable to find a world for formatting");
+            return None;
+        };
+        let indent = match options.insert_spaces {
+            true => " ".repeat(options.tab_size as usize),
+            false => "\t".to_string(),
+        };
+        let format = self.config.read().unwrap().format.clone();
+        let edits = run_cancellable(world, move |world| {
+            world.format(&path, &indent, &format, range)
+        })
+        .await?;
+        Some(to_text_edits(edits))
+    }
+
+    /// Client to ask for refreshing inlay hints after compilation if hints
+    /// are enabled and client supports refreshing.
+    fn inlay_hint_refresher(&self) -> Option<Client> {
+        let enabled = self.config.read().unwrap().inlay_hints.let_values;
+        let supported = self.features.read().unwrap().inlay_hint_refresh;
+        (enabled && supported).then(|| self.client.clone())
+    }
+
+    /// Find world of a chapter of a book.
+    fn find_chapter_world(
+        &self,
+        uri: &Url,
+    ) -> Option<Arc<RwLock<LanguageServiceWorld>>> {
+        let path = path::normalize(&uri::to_path(uri)?);
+        self.chapters.read().unwrap().get(&path).cloned()
+    }
+
+    /// Find all worlds which a document belongs to: the closest one and the
+    /// world of a chapter if the document is a chapter of a book.
+    fn find_worlds(&self, uri: &Url) -> Vec<Arc<RwLock<LanguageServiceWorld>>> {
+        let mut worlds = Vec::new();
+        worlds.extend(self.find_world(uri).map(|(_, world)| world));
+        worlds.extend(self.find_chapter_world(uri));
+        for world in self.find_candidates(uri) {
+            if !worlds.iter().any(|it| Arc::ptr_eq(it, &world)) {
+                worlds.push(world);
+            }
+        }
+        worlds
+    }
+
+    /// Find a world of a target which main file is a document among targets
+    /// which share a root directory. Main file of a target is always compiled
+    /// by its own world.
+    fn find_own_target(&self, path: &Path) -> Option<FoundWorld> {
+        let worlds = self.worlds.read().unwrap();
+        path.ancestors().skip(1).find_map(|dir| {
+            let key = (dir.to_path_buf(), path.to_path_buf());
+            Some((key.0.clone(), worlds.get(&key)?.clone()))
+        })
+    }
+
+    /// Find worlds of all targets which a document could belong to. It is
+    /// empty if there is no ambiguity.
+    fn find_candidates(
+        &self,
+        uri: &Url,
+    ) -> Vec<Arc<RwLock<LanguageServiceWorld>>> {
+        let Some(path) = uri::to_path(uri) else {
+            return vec![];
+        };
+        let path = path::normalize(&path);
+        let worlds = self.worlds.read().unwrap();
+        let candidates = path
+            .ancestors()
+            .skip(1)
+            .map(|dir| targets_at(&worlds, dir).collect::<Vec<_>>())
+            .find(|it| !it.is_empty())
+            .unwrap_or_default();
+        match candidates.len() {
+            0 | 1 => vec![],
+            _ => candidates.into_iter().map(|(_, it)| it.clone()).collect(),
+        }
+    }
+
+    /// Ask user which target a document should belong to if there are several
+    /// of them and remember the choice. If `force` is not set then user is
+    /// not asked once again.
+    fn select_target(&self, uri: &Url, force: bool) {
+        let Some(path) = uri::to_path(uri) else {
+            return;
+        };
+        let key = path::normalize(&path);
+        if !force && self.associations.read().unwrap().contains_key(&key) {
+            return;
+        }
+        let candidates = self.find_candidates(uri);
+        if candidates.len() < 2 || self.find_own_target(&key).is_some() {
+            return;
+        }
+        let Some((root_dir, _)) = self.find_world(uri) else {
+            return;
+        };
+        let client = self.client.clone();
+        let associations = self.associations.clone();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let message = format!(
+            "{name} belongs to several targets. Which one should be used?"
+        );
+        tokio::spawn(async move {
+            let titles = candidates
+                .iter()
+                .map(|world| {
+                    let world = world.read().unwrap();
+                    let main_path = world.main_path();
+                    main_path
+                        .strip_prefix(&root_dir)
+                        .unwrap_or(main_path)
+                        .display()
+                        .to_string()
+                })
+                .collect::<Vec<_>>();
+            let actions = titles
+                .iter()
+                .map(|title| MessageActionItem {
+                    title: title.clone(),
+                    properties: Default::default(),
+                })
+                .collect();
+            let response = client
+                .show_message_request(MessageType::INFO, message, Some(actions))
+                .await;
+            let Ok(Some(action)) = response else {
+                return;
+            };
+            let Some(index) = titles.iter().position(|it| *it == action.title)
+            else {
+                return;
+            };
+            log::info!("associate {:?} with target {}", key, action.title);
+            associations
+                .write()
+                .unwrap()
+                .insert(key, (root_dir, candidates[index].clone()));
+        });
+    }
+
+    /// Compile a document as a part of a main file regardless of discovered
+    /// targets. A world of a main file is reused if it is a target already.
+    /// Otherwise, a new world is created and it becomes a target of the
+    /// directory of a main file (among others if there are any).
+    fn pin_main_file(
+        &self,
+        uri: &Url,
+        main_uri: Option<&Url>,
+    ) -> result::Result<(), String> {
+        let path = uri::to_path(uri)
+            .ok_or_else(|| format!("unsupported uri {uri}"))?;
+        let key = path::normalize(&path);
+        let Some(main_uri) = main_uri else {
+            log::info!("unpin main file of {:?}", key);
+            self.associations.write().unwrap().remove(&key);
+            self.compile_draft(uri);
+            return Ok(());
+        };
+        let main_path = uri::to_path(main_uri)
+            .filter(|it| it.is_file())
+            .ok_or_else(|| format!("main file {main_uri} does not exist"))?;
+        let main_path = path::normalize(&main_path);
+        let found = match self.find_target(&main_path) {
+            Some(found) => found,
+            None => self.new_pinned_world(&main_path)?,
+        };
+        log::info!("pin {:?} to main file {:?}", key, main_path);
+        let mut worlds = self.find_worlds(uri);
+        worlds.push(found.1.clone());
+        self.associations.write().unwrap().insert(key, found);
+        share_text(&path, &worlds);
+        self.compile_draft(uri);
+        Ok(())
+    }
+
+    /// Find a world of a target by its main file.
+    fn find_target(&self, main_path: &Path) -> Option<FoundWorld> {
+        self.worlds
+            .read()
+            .unwrap()
+            .iter()
+            .find(|((_, main), _)| main == main_path)
+            .map(|((dir, _), world)| (dir.clone(), world.clone()))
+    }
+
+    fn new_pinned_world(
+        &self,
+        main_path: &Path,
+    ) -> result::Result<FoundWorld, String> {
+        let key = main_path
+            .parent()
+            .ok_or_else(|| format!("no parent directory of {main_path:?}"))?
+            .to_path_buf();
+        let root_dir = search_workspace(&key)
+            .map(Path::to_path_buf)
+            .or_else(|| self.standalone_root_dir(main_path))
+            .unwrap_or_else(|| key.clone());
+        let mut world = LanguageServiceWorld::new(&root_dir, main_path, None)
+            .ok_or_else(|| {
+            format!("failed to initialize world for {main_path:?}")
+        })?;
+        self.configure_world(&mut world);
+        self.open_buffers(&mut world);
+        log::info!("initialize world for {:?} at {:?}", main_path, root_dir);
+        let world = Arc::new(RwLock::new(world));
+        self.worlds
+            .write()
+            .unwrap()
+            .insert((key.clone(), main_path.to_path_buf()), world.clone());
+        Ok((key, world))
+    }
+
+    /// Create worlds for files which include or import a document outside of
+    /// workspaces and make them candidate targets of the document.
+    fn new_candidate_worlds(&self, path: &Path, key: &Path) {
+        for main_file in workspace::find_main_candidates(path) {
+            let Some(root_dir) = self.standalone_root_dir(&main_file) else {
+                continue;
+            };
+            let Some(mut world) =
+                LanguageServiceWorld::new(&root_dir, &main_file, None)
+            else {
+                continue;
+            };
+            self.configure_world(&mut world);
+            self.open_buffers(&mut world);
+            log::info!("initialize candidate world for {:?}", main_file);
+            let key = (path::normalize(key), path::normalize(&main_file));
+            self.worlds
+                .write()
+                .unwrap()
+                .insert(key, Arc::new(RwLock::new(world)));
+        }
+    }
+
+    /// Check whether an open document exceeds size limits.
+    fn is_large_file(&self, path: &Path) -> bool {
+        self.large_files
+            .read()
+            .unwrap()
+            .contains(&path::normalize(path))
+    }
+
+    /// Find the closest parent URI for the specified one.
+    fn find_world(
+        &self,
+        uri: &Url,
+    ) -> Option<(PathBuf, Arc<RwLock<LanguageServiceWorld>>)> {
+        let path = path::normalize(&uri::to_path(uri)?);
+        if let Some(found) = self.associations.read().unwrap().get(&path) {
+            return Some(found.clone());
+        }
+        if let Some(found) = self.find_own_target(&path) {
+            return Some(found);
+        }
+        let found = self.find_parent_world(&path);
+
+        // A document could be included by a target outside of its directory
+        // (e.g. a chapter in a sibling directory of a book).
+        let includers = self.find_includers(&path);
+        found
+            .filter(|(_, world)| {
+                includers.is_empty()
+                    || includers.iter().any(|it| Arc::ptr_eq(it, world))
+            })
+            .or_else(|| {
+                let world = includers.first()?.clone();
+                let root_dir = world.read().unwrap().root_dir().to_path_buf();
+                Some((root_dir, world))
+            })
+    }
+
+    /// Find a world of the closest parent directory of a document. The first
+    /// target (ordered by main files) is chosen if there are several of them.
+    fn find_parent_world(&self, path: &Path) -> Option<FoundWorld> {
+        let worlds = self.worlds.read().unwrap();
+        path.ancestors().skip(1).find_map(|dir| {
+            let (_, world) = targets_at(&worlds, dir).next()?;
+            Some((dir.to_path_buf(), world.clone()))
+        })
+    }
+
+    /// Find worlds of targets (not of chapters or notebooks) which the last
+    /// compilations have included a document into.
+    fn find_includers(
+        &self,
+        path: &Path,
+    ) -> Vec<Arc<RwLock<LanguageServiceWorld>>> {
+        let worlds = self.worlds.read().unwrap();
+        self.compilers
+            .includes
+            .find(path)
+            .into_iter()
+            .filter(|world| worlds.values().any(|it| Arc::ptr_eq(it, world)))
+            .collect()
+    }
+
+    fn new_world_from_str(
+        &self,
+        uri: &Url,
+        text: String,
+    ) -> Option<(PathBuf, Arc<RwLock<LanguageServiceWorld>>)> {
+        log::info!("initialize world from main file with text");
+        let path = uri::to_path(uri)?;
+        self.new_world_from_path(&path, Some(text))
+    }
+
+    fn new_world_from_uri(
+        &self,
+        uri: &Url,
+    ) -> Option<(PathBuf, Arc<RwLock<LanguageServiceWorld>>)> {
+        // Untitled documents do not exist on disk: they get in-memory worlds
+        // from their texts only.
+        let path = uri::to_path(uri)?;
+        if uri::is_untitled(&path) {
+            return None;
+        }
+        let path = path.as_path();
+        let Some(root_dir) = path.parent() else {
+            log::error!("there is no root directory for {:?}", path);
+            return None;
+        };
+
+        // Search for workspace root (i.e. search for `typst.toml`) from the
+        // parent directory of the file to the filesystem hierarchy. If we
+        // found nothing then fallback to base directory of the file.
+        let root_dir = search_workspace(root_dir).unwrap_or(root_dir);
+
+        // Create a new world and insert it to world index. If there are no valid targets then
+        // create file-specific world; otherwise; search once again.
+        let targets = search_targets(vec![root_dir]);
+        log::info!("found {} target(s)", targets.len());
+        match self.new_worlds(targets) {
+            0 => {
+                let (key, world) = self.new_world_from_path(path, None)?;
+                self.new_candidate_worlds(path, &key);
+                Some((key, world))
+            }
+            _ => self
+                .find_world(uri)
+                .or_else(|| self.new_world_from_path(path, None)),
+        }
+    }
+
+    fn new_world_from_path(
+        &self,
+        main_file: &Path,
+        main_text: Option<String>,
+    ) -> Option<(PathBuf, Arc<RwLock<LanguageServiceWorld>>)> {
+        log::info!("initialize world from main file: path={:?}", main_file);
+        // World is indexed by parent directory of a main file but its root
+        // directory could be overridden with environment variable.
+        let parent_dir = main_file.parent()?;
+        let root_dir = self.standalone_root_dir(main_file)?;
+        match LanguageServiceWorld::new(&root_dir, main_file, main_text) {
+            Some(mut world) => {
+                self.configure_world(&mut world);
+                self.open_buffers(&mut world);
+                log::info!(
+                    "initialize world for {:?} at {:?}",
+                    main_file,
+                    root_dir,
+                );
+                let world = Arc::new(RwLock::new(world));
+                let key =
+                    (path::normalize(parent_dir), path::normalize(main_file));
+                self.worlds.write().unwrap().insert(key, world.clone());
+                self.orphans.write().unwrap().push(world.clone());
+                Some((parent_dir.to_path_buf(), world))
+            }
+            None => {
+                log::error!(
+                    "failed to initialize world for {:?} at {:?}",
+                    main_file,
+                    root_dir,
+                );
+                None
+            }
+        }
+    }
+
+    /// Initialize worlds for individual chapters of a book.
+    fn new_chapter_worlds(&self, target: &Target) {
+        for chapter in target.chapters.iter() {
+            let Some(mut world) =
+                LanguageServiceWorld::new(&target.root_dir, chapter, None)
+            else {
+                log::error!("failed to initialize world for {:?}", chapter);
+                continue;
+            };
+            self.configure_world(&mut world);
+            self.open_buffers(&mut world);
+            log::info!("initialize world for chapter {:?}", chapter);
+            self.chapters
+                .write()
+                .unwrap()
+                .insert(path::normalize(chapter), Arc::new(RwLock::new(world)));
+        }
+    }
+
+    fn new_worlds(&self, targets: Vec<Target>) -> u32 {
+        let mut counter: u32 = 0;
+        for (index, target) in targets.iter().enumerate() {
+            let Some(relpath) =
+                target.main_file.strip_prefix(&target.root_dir).ok()
+            else {
+                log::warn!(
+                    "[{}] main file {:?} is not descendant of {:?}: skip it",
+                    index,
+                    target.root_dir,
+                    target.main_file
+                );
+                continue;
+            };
+            if let Some(dir) =
+                target.main_file.parent().and_then(search_workspace)
+            {
+                self.load_project(dir);
+            }
+            match LanguageServiceWorld::new(
+                &target.root_dir,
+                &target.main_file,
+                None,
+            ) {
+                Some(mut world) => {
+                    self.configure_world(&mut world);
+                    self.open_buffers(&mut world);
+                    log::info!(
+                        "[{}] initialize world for {:?} at {:?}",
+                        index,
+                        relpath,
+                        target.root_dir,
+                    );
+                    let key = (
+                        path::normalize(&target.root_dir),
+                        path::normalize(&target.main_file),
+                    );
+                    self.worlds
+                        .write()
+                        .unwrap()
+                        .insert(key, Arc::new(RwLock::new(world)));
+                    self.new_chapter_worlds(target);
+                    counter += 1;
+                }
+                None => log::error!(
+                    "[{}] failed to initialize world for {:?} at {:?}",
+                    index,
+                    relpath,
+                    target.root_dir,
+                ),
+            };
+        }
+        counter
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Status {
+    /// Number of worlds (i.e. compilation targets).
+    worlds: usize,
+    /// Number of worlds of individual chapters of books.
+    chapters: usize,
+    /// Latencies of request handlers in milliseconds.
+    latencies: Vec<metrics::Latency>,
+}
+
+/// Data of a completion item which is needed to resolve its documentation.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CompletionData {
+    uri: Url,
+    kind: CompletionKind,
+    detail: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DependencyGraphParams {
+    text_document: TextDocumentIdentifier,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IntrospectParams {
+    text_document: TextDocumentIdentifier,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentMetricsParams {
+    text_document: TextDocumentIdentifier,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SetBytesParams {
+    uri: Url,
+    /// Content of a file encoded with base64. A file is read from file
+    /// system again if it is missing.
+    data: Option<String>,
+}
+
+/// Custom requests.
+impl TypstLanguageService {
+    /// Find a world of a notebook which a text document is a cell of and
+    /// translate position in the cell to position in virtual source.
+    fn find_notebook_cell(
+        &self,
+        uri: &Url,
+        position: Position,
+    ) -> Option<(Arc<RwLock<LanguageServiceWorld>>, PathBuf, Position)> {
+        let notebooks = self.notebooks.read().unwrap();
+        let (notebook, world) =
+            notebooks.values().find(|(it, _)| it.contains(uri))?;
+        let position = notebook.to_source(uri, position)?;
+        Some((world.clone(), notebook.path()?, position))
+    }
+
+    /// Compile virtual source of a notebook and publish diagnostics to its
+    /// cells. Draft compilation is debounced like one of text documents.
+    fn compile_notebook(&self, uri: &Url, draft: bool) {
+        let Some((notebook, world)) =
+            self.notebooks.read().unwrap().get(uri).cloned()
+        else {
+            return;
+        };
+        let generation = {
+            let mut drafts = self.drafts.lock().unwrap();
+            let generation = drafts.entry(uri.clone()).or_default();
+            *generation += 1;
+            *generation
+        };
+        let drafts = self.drafts.clone();
+        let compilers = self.compilers.clone();
+        let publisher = self.diagnostics.clone();
+        let delay = self.draft_delay();
+        let uri = uri.clone();
+        tokio::spawn(async move {
+            if draft {
+                tokio::time::sleep(delay).await;
+                if is_superseded(&drafts, &uri, generation) {
+                    log::debug!("draft compilation of {} is superseded", uri);
+                    return;
+                }
+            }
+            // Diagnostics of virtual source are reported to cells while
+            // diagnostics of other files are reported as is.
+            let Some(source_uri) =
+                notebook.path().and_then(|it| uri::from_path(&it))
+            else {
+                return;
+            };
+            let target = source_uri.clone();
+            let Ok((_, report)) = compilers.submit(&world, target, draft).await
+            else {
+                log::debug!("compilation of {} is superseded", uri);
+                return;
+            };
+            if draft && is_superseded(&drafts, &uri, generation) {
+                log::debug!("draft diagnostics of {} are stale", uri);
+                return;
+            }
+            let mut reports = notebook
+                .cell_uris()
+                .map(|uri| (uri.clone(), Vec::new()))
+                .collect::<HashMap<_, _>>();
+            let mut diagnostics = Vec::new();
+            for (uri, mut file_diagnostics) in report {
+                match uri == source_uri {
+                    true => diagnostics.append(&mut file_diagnostics),
+                    false => {
+                        reports.insert(uri, file_diagnostics);
+                    }
+                }
+            }
+            for mut diagnostic in diagnostics {
+                let Some((uri, start)) =
+                    notebook.to_cell(diagnostic.range.start)
+                else {
+                    continue;
+                };
+                let end = match notebook.to_cell(diagnostic.range.end) {
+                    Some((end_uri, end)) if end_uri == uri => end,
+                    _ => start,
+                };
+                diagnostic.range = Range { start, end };
+                reports.entry(uri).or_default().push(diagnostic);
+            }
+            publisher.publish_report(source_uri, reports.into_iter().collect());
+        });
+    }
+
+    /// Handle `notebookDocument/didOpen` notification: Typst cells of a
+    /// notebook are stitched into virtual source of a new world.
+    #[instrument(skip_all, fields(uri = %params.notebook_document.uri))]
+    async fn did_open_notebook(&self, params: DidOpenNotebookDocumentParams) {
+        let notebook = Notebook::new(params);
+        let uri = notebook.uri().clone();
+        let Some(main_path) = notebook.path() else {
+            log::warn!("unsupported notebook uri {}: skip it", uri);
+            return;
+        };
+        let Some(root_dir) = self.standalone_root_dir(&main_path) else {
+            return;
+        };
+        let Some(mut world) = LanguageServiceWorld::new(
+            &root_dir,
+            &main_path,
+            Some(notebook.text()),
+        ) else {
+            log::error!("failed to initialize world for notebook {}", uri);
+            return;
+        };
+        self.configure_world(&mut world);
+        log::info!("open notebook {} as {:?}", uri, main_path);
+        self.notebooks
+            .write()
+            .unwrap()
+            .insert(uri.clone(), (notebook, Arc::new(RwLock::new(world))));
+        if self.config.read().unwrap().diagnostics.on_change {
+            self.compile_notebook(&uri, true);
+        }
+    }
+
+    /// Handle `notebookDocument/didChange` notification.
+    #[instrument(skip_all, fields(uri = %params.notebook_document.uri))]
+    async fn did_change_notebook(
+        &self,
+        params: DidChangeNotebookDocumentParams,
+    ) {
+        let uri = params.notebook_document.uri;
+        {
+            let mut notebooks = self.notebooks.write().unwrap();
+            let Some((notebook, world)) = notebooks.get_mut(&uri) else {
+                log::warn!("notebook {} is not open: skip changes", uri);
+                return;
+            };
+            let encoding = self.features.read().unwrap().position_encoding;
+            notebook.apply(params.change, encoding);
+            if let Some(path) = notebook.path() {
+                world.read().unwrap().open_file(&path, notebook.text());
+            }
+        }
+        self.compile_notebook(&uri, true);
+    }
+
+    /// Handle `notebookDocument/didSave` notification.
+    #[instrument(skip_all, fields(uri = %params.notebook_document.uri))]
+    async fn did_save_notebook(&self, params: DidSaveNotebookDocumentParams) {
+        self.compile_notebook(&params.notebook_document.uri, false);
+    }
+
+    /// Handle `notebookDocument/didClose` notification.
+    #[instrument(skip_all, fields(uri = %params.notebook_document.uri))]
+    async fn did_close_notebook(&self, params: DidCloseNotebookDocumentParams) {
+        let uri = params.notebook_document.uri;
+        let Some((notebook, _)) = self.notebooks.write().unwrap().remove(&uri)
+        else {
+            return;
+        };
+        for uri in notebook.cell_uris() {
+            self.diagnostics.publish(uri.clone(), Vec::new());
+        }
+        if let Some(source_uri) =
+            notebook.path().and_then(|it| uri::from_path(&it))
+        {
+            self.diagnostics.clear_world(&source_uri);
+        }
+    }
+
+    /// Handle `typstd/dependencyGraph` request which returns import and
+    /// include graph of a world which the text document belongs to.
+    #[instrument(skip_all)]
+    async fn dependency_graph(
+        &self,
+        params: DependencyGraphParams,
+    ) -> Result<Option<Graph>> {
+        let uri = params.text_document.uri;
+        log::info!("build dependency graph for {}", uri);
+        let Some((_, world)) = self.find_world(&uri) else {
+            log::error!("unable to find a world for dependency graph");
+            return Ok(None);
+        };
+        Ok(run_cancellable(world, |world| world.dependency_graph()).await)
+    }
+
+    /// Handle `typstd/setBytes` notification which provides content of a
+    /// file (e.g. of a data file or an image) in memory instead of on disk
+    /// and recompiles (in draft mode) worlds which have read the file.
+    #[instrument(skip_all, fields(uri = %params.uri))]
+    async fn set_bytes(&self, params: SetBytesParams) {
+        let Some(path) = uri::to_path(&params.uri) else {
+            log::warn!("unsupported uri {}: skip file", params.uri);
+            return;
+        };
+        let key = path::normalize(&path);
+        let bytes = match params.data.map(|it| BASE64_STANDARD.decode(it)) {
+            Some(Ok(data)) => Some(Bytes::from(data)),
+            Some(Err(err)) => {
+                log::error!("failed to decode content of {key:?}: {err}");
+                return;
+            }
+            None => None,
+        };
+        let mut files = self.bytes.write().unwrap();
+        match &bytes {
+            Some(bytes) => {
+                log::info!("set {} bytes of {key:?} in memory", bytes.len());
+                files.insert(key.clone(), bytes.clone());
+            }
+            None => {
+                log::info!("drop content of {key:?} in memory");
+                files.remove(&key);
+            }
+        }
+        drop(files);
+
+        let mut worlds = Vec::<Arc<RwLock<LanguageServiceWorld>>>::new();
+        for world in self
+            .worlds
+            .read()
+            .unwrap()
+            .values()
+            .chain(self.chapters.read().unwrap().values())
+            .chain(self.orphans.read().unwrap().iter())
+        {
+            if !worlds.iter().any(|it| Arc::ptr_eq(it, world)) {
+                worlds.push(world.clone());
+            }
+        }
+        let mut pending = Vec::new();
+        for world in worlds {
+            let (affected, main_path) = {
+                let world = world.read().unwrap();
+                if !key.starts_with(path::normalize(world.root_dir())) {
+                    continue;
+                }
+                let affected = match &bytes {
+                    Some(bytes) => world.add_bytes(&key, bytes.clone()),
+                    None => world.remove_bytes(&key),
+                };
+                (affected, world.main_path().to_path_buf())
+            };
+            let Some(uri) = uri::from_path(&main_path) else {
+                continue;
+            };
+            if affected {
+                log::info!("recompile {} on changes of files in memory", uri);
+                pending.push(self.compilers.submit(&world, uri, true));
+            }
+        }
+        let publisher = self.diagnostics.clone();
+        tokio::spawn(async move {
+            for report in pending {
+                if let Ok((world, report)) = report.await {
+                    publisher.publish_report(world, report);
+                }
+            }
+        });
+    }
+
+    /// Handle `typstd/status` request which reports state of language server
+    /// and latencies of request handlers.
+    #[instrument(skip_all)]
+    async fn status(&self) -> Result<Status> {
+        Ok(Status {
+            worlds: self.worlds.read().unwrap().len(),
+            chapters: self.chapters.read().unwrap().len(),
+            latencies: metrics::latencies(),
+        })
+    }
+
+    /// Handle `typstd/introspect` request which returns headings, figures,
+    /// labels, and metadata of the last compiled document of a world which
+    /// the text document belongs to.
+    #[instrument(skip_all)]
+    async fn introspect(
+        &self,
+        params: IntrospectParams,
+    ) -> Result<Option<Introspection>> {
+        let uri = params.text_document.uri;
+        log::info!("introspect document for {}", uri);
+        let Some((_, world)) = self.find_world(&uri) else {
+            log::error!("unable to find a world for introspection");
+            return Ok(None);
+        };
+        Ok(run_cancellable(world, |world| world.introspect()).await)
+    }
+
+    /// Handle `typst/documentMetrics` request which returns word, character,
+    /// page, and heading counts of the last compiled document of a world which
+    /// the text document belongs to.
+    #[instrument(skip_all)]
+    async fn document_metrics(
+        &self,
+        params: DocumentMetricsParams,
+    ) -> Result<Option<DocumentMetrics>> {
+        let uri = params.text_document.uri;
+        log::info!("document metrics for {}", uri);
+        let Some((_, world)) = self.find_world(&uri) else {
+            log::error!("unable to find a world for document metrics");
+            return Ok(None);
+        };
+        Ok(run_cancellable(world, |world| world.document_metrics()).await)
+    }
+
+    /// Find a position in preview which a cursor corresponds to and scroll
+    /// preview to it. A world shown in preview is used if any.
+    #[instrument(skip_all)]
+    async fn jump_to_preview(
+        &self,
+        params: TextDocumentPositionParams,
+    ) -> Result<Option<preview::Position>> {
+        let uri = params.text_document.uri;
+        let Some(path) = uri::to_path(&uri) else {
+            return Ok(None);
+        };
+        let active = self.preview.lock().unwrap().clone();
+        let world = match &active {
+            Some((_, world)) => world.clone(),
+            None => match self.find_world(&uri) {
+                Some((_, world)) => world,
+                None => return Ok(None),
+            },
+        };
+        let (line, column) = (
+            params.position.line as usize,
+            params.position.character as usize,
+        );
+        let found = run_cancellable(world, move |world| {
+            world.jump_to_preview(&path, line, column)
+        })
+        .await
+        .flatten();
+        if let (Some(position), Some((server, _))) = (found, active) {
+            server.jump(position);
+        }
+        Ok(found)
+    }
+
+    /// Find a source position which a point on a page of preview corresponds
+    /// to.
+    #[instrument(skip_all)]
+    async fn jump_from_preview(
+        &self,
+        params: preview::Position,
+    ) -> Result<Option<Location>> {
+        let Some((_, world)) = self.preview.lock().unwrap().clone() else {
+            return Ok(None);
+        };
+        let found =
+            run_cancellable(world, move |it| it.jump_from_preview(&params));
+        let Some((path, (line, column))) = found.await.flatten() else {
+            return Ok(None);
+        };
+        let position = Position::new(line as u32, column as u32);
+        Ok(uri::from_path(&path).map(|uri| Location {
+            uri,
+            range: Range::new(position, position),
+        }))
+    }
+
+    /// Download packages imported by a world which the text document belongs
+    /// to or by all worlds in background. Task yields packages which failed
+    /// to download.
+    fn prefetch_packages(&self, uri: Option<Url>) -> JoinHandle<Vec<String>> {
+        let worlds: Vec<_> = match uri {
+            Some(uri) => self.find_world(&uri).into_iter().collect(),
+            None => self
+                .worlds
+                .read()
+                .unwrap()
+                .iter()
+                .map(|((dir, _), world)| (dir.clone(), world.clone()))
+                .collect(),
+        };
+        let client = self.client.clone();
+        tokio::spawn(async move {
+            let failures = tokio::task::spawn_blocking(move || {
+                // Do not hold world locks while downloading.
+                let specs = worlds
+                    .iter()
+                    .flat_map(|(_, it)| it.read().unwrap().imported_packages())
+                    .collect();
+                package::prefetch(specs)
+            })
+            .await
+            .unwrap_or_default();
+            for (spec, err) in failures.iter() {
+                log::error!("failed to prefetch package {}: {}", spec, err);
+            }
+            if !failures.is_empty() {
+                let message =
+                    format!("failed to download {} package(s)", failures.len());
+                client.show_message(MessageType::ERROR, message).await;
+            }
+            failures
+                .into_iter()
+                .map(|(spec, _)| spec.to_string())
+                .collect()
+        })
+    }
+
+    /// Run a command of package cache management on a blocking thread.
+    async fn manage_packages(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> Result<Option<serde_json::Value>> {
+        let describe = |packages: Vec<package::CachedPackage>| {
+            let packages = packages.iter().map(|it| {
+                let last_used = it
+                    .last_used
+                    .and_then(|it| it.duration_since(UNIX_EPOCH).ok())
+                    .map(|it| it.as_secs());
+                serde_json::json!({
+                    "package": it.spec.to_string(),
+                    "path": it.path,
+                    "size": it.size,
+                    "lastUsed": last_used,
+                })
+            });
+            serde_json::Value::Array(packages.collect())
+        };
+        let arg = params.arguments.into_iter().next();
+        let command = params.command;
+        let result = if command == LIST_PACKAGES_COMMAND {
+            let packages =
+                tokio::task::spawn_blocking(package::cached_packages);
+            Ok(describe(packages.await.unwrap_or_default()))
+        } else if command == CLEAR_PACKAGES_COMMAND {
+            let packages = tokio::task::spawn_blocking(package::clear_cache);
+            flatten(packages.await).map(describe)
+        } else if command == COLLECT_PACKAGES_COMMAND {
+            let Some(days) = arg.as_ref().and_then(|it| it.as_u64()) else {
+                return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                    "expected number of days as the first argument",
+                ));
+            };
+            let max_age = Duration::from_secs(days * 24 * 60 * 60);
+            let packages = tokio::task::spawn_blocking(move || {
+                package::collect_garbage(max_age)
+            });
+            flatten(packages.await).map(describe)
+        } else {
+            let spec = arg.as_ref().and_then(|it| it.as_str());
+            let Some(Ok(spec)) = spec.map(str::parse::<PackageSpec>) else {
+                return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                    "expected package spec as the first argument",
+                ));
+            };
+            let path = tokio::task::spawn_blocking(move || {
+                package::fetch_package(&spec)
+            });
+            flatten(path.await)
+                .map(|it| serde_json::Value::from(it.to_string_lossy()))
+        };
+        match result {
+            Ok(value) => Ok(Some(value)),
+            Err(err) => {
+                log::error!("failed to manage packages: {}", err);
+                self.client.show_message(MessageType::ERROR, &err).await;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Compile a world which the text document belongs to with full fidelity
+    /// and export it regardless of export settings. Relative output paths are
+    /// relative to root directory of the world.
+    async fn export(
+        &self,
+        uri: &Url,
+        format: ExportFormat,
+        output: Option<PathBuf>,
+    ) -> result::Result<PathBuf, String> {
+        let Some((_, world)) = self.find_world(uri) else {
+            return Err(format!("unable to find a world for {uri}"));
+        };
+        run_cancellable(world, move |world| {
+            let path = match output {
+                Some(path) => world.root_dir().join(path),
+                None => world.output_path().with_extension(format.extension()),
+            };
+            let doc = world.compile()?;
+            world.export(&doc, format, &path)?;
+            Ok(path)
+        })
+        .await
+        .ok_or("export is cancelled")?
+    }
+
+    /// Update packages of a world which the text document belongs to, and
+    /// optionally rewrite versions in import statements.
+    async fn update_packages(
+        &self,
+        uri: Url,
+        rewrite: bool,
+    ) -> result::Result<Vec<PackageUpdate>, String> {
+        let Some((_, world)) = self.find_world(&uri) else {
+            return Err(format!("unable to find a world for {uri}"));
+        };
+        let index = tokio::task::spawn_blocking(package::fetch_index)
+            .await
+            .map_err(|err| err.to_string())?
+            .map_err(|err| err.to_string())?;
+        let updates =
+            run_cancellable(world, move |world| world.update_packages(&index))
+                .await
+                .ok_or("package update is cancelled")??;
+
+        if rewrite && !updates.is_empty() {
+            let edits = updates.iter().flat_map(|it| it.edits.clone());
+            let edit = to_workspace_edit(edits.collect());
+            if let Err(err) = self.client.apply_edit(edit).await {
+                log::error!("failed to rewrite package imports: {}", err);
+            }
+        }
+        Ok(updates)
+    }
+}
+
+#[tower_lsp::async_trait]
+impl LanguageServer for TypstLanguageService {
+    #[instrument(
+        skip_all,
+        fields(process_id = params.process_id),
+    )]
+    async fn initialize(
+        &self,
+        params: InitializeParams,
+    ) -> Result<InitializeResult> {
+        // It is safe to unwrap since all keys and values are JSON
+        // serialiable.
+        let params_json = serde_json::to_string_pretty(&params).unwrap();
+        log::info!("initialize language server params={}", params_json);
+
+        let features = ClientFeatures::from(&params.capabilities);
+        log::info!("client features: {:?}", features);
+        *self.features.write().unwrap() = features.clone();
+        self.compilers
+            .status
+            .set_progress(features.work_done_progress);
+        self.diagnostics
+            .set_code_lens_refresh(features.code_lens_refresh);
+
+        if let Some(options) = params.initialization_options {
+            match Config::from_value(options) {
+                Ok(config) => self.update_config(config),
+                Err(err) => log::warn!("{}", err),
+            }
+        }
+        let packages = self.config.read().unwrap().packages.clone();
+        if let Err(err) = package::configure(&packages) {
+            log::error!("{}", err);
+            self.client.show_message(MessageType::ERROR, err).await;
+        } else if packages.insecure {
+            let message = "TLS certificate verification of package \
+                           downloads is disabled: connections are insecure";
+            self.client
+                .show_message(MessageType::WARNING, message)
+                .await;
+        }
+
+        let mut root_uris = Vec::<Url>::new();
+        if let Some(folders) = params.workspace_folders {
+            log::info!("use workspace folders for targets discovery");
+            root_uris.extend(folders.iter().map(|folder| folder.uri.clone()));
+        } else if let Some(root_uri) = params.root_uri {
+            log::info!("use obsolete root uri for targets discovery");
+            root_uris.push(root_uri);
+        }
+
+        log::info!("try to load workspace configurations");
+        let root_dirs = if !root_uris.is_empty() {
+            root_uris.iter().filter_map(uri::to_path).collect()
+        } else {
+            log::warn!("no root uris: fallback to current work directory");
+            env::current_dir().ok().map_or(vec![], |cwd| vec![cwd])
+        };
+        let root_dirs: Vec<_> =
+            root_dirs.iter().map(PathBuf::as_path).collect();
+        let mut targets = search_targets(root_dirs.clone());
+        targets.extend(self.main_file_targets(&root_dirs, &targets));
+
+        log::info!("found {} target(s)", targets.len());
+        self.new_worlds(targets);
+
+        Ok(InitializeResult {
+            capabilities: ServerCapabilities {
+                position_encoding: Some(PositionEncodingKind::new(
+                    features.position_encoding.as_str(),
+                )),
+                text_document_sync: Some(TextDocumentSyncCapability::Options(
+                    TextDocumentSyncOptions {
+                        open_close: Some(true),
+                        change: Some(TextDocumentSyncKind::INCREMENTAL),
+                        save: Some(TextDocumentSyncSaveOptions::Supported(
+                            true,
+                        )),
+                        ..Default::default()
+                    },
+                )),
+                completion_provider: features.completion.then(|| {
+                    CompletionOptions {
+                        trigger_characters: Some(vec![
+                            "#".to_string(),
+                            ".".to_string(),
+                            "@".to_string(),
+                            "\"".to_string(),
+                            "/".to_string(),
+                            ":".to_string(),
+                        ]),
+                        resolve_provider: Some(features.completion_resolve),
+                        ..Default::default()
+                    }
+                }),
+                hover_provider: features
+                    .hover
+                    .then_some(HoverProviderCapability::Simple(true)),
+                signature_help_provider: features.signature_help.then(|| {
+                    SignatureHelpOptions {
+                        trigger_characters: Some(vec![
+                            "(".to_string(),
+                            ",".to_string(),
+                        ]),
+                        ..Default::default()
+                    }
+                }),
+                document_formatting_provider: features
+                    .formatting
+                    .then_some(OneOf::Left(true)),
+                document_range_formatting_provider: features
+                    .range_formatting
+                    .then_some(OneOf::Left(true)),
+                document_on_type_formatting_provider: features
+                    .on_type_formatting
+                    .then(|| DocumentOnTypeFormattingOptions {
+                        first_trigger_character: "\n".to_string(),
+                        more_trigger_character: Some(
+                            on_type::TRIGGER_CHARACTERS
+                                .iter()
+                                .map(|it| it.to_string())
+                                .collect(),
+                        ),
+                    }),
+                linked_editing_range_provider: features
+                    .linked_editing_range
+                    .then_some(LinkedEditingRangeServerCapabilities::Simple(
+                        true,
+                    )),
+                definition_provider: features
+                    .definition
+                    .then_some(OneOf::Left(true)),
+                inlay_hint_provider: (features.inlay_hint
+                    && self.config.read().unwrap().inlay_hints.enabled())
+                .then_some(OneOf::Left(true)),
+                code_lens_provider: features.code_lens.then_some(
+                    CodeLensOptions {
+                        resolve_provider: Some(false),
+                    },
+                ),
+                code_action_provider: features.code_action.then(|| {
+                    CodeActionProviderCapability::Options(CodeActionOptions {
+                        code_action_kinds: Some(vec![
+                            CodeActionKind::QUICKFIX,
+                            CodeActionKind::REFACTOR_REWRITE,
+                        ]),
+                        ..Default::default()
+                    })
+                }),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        UPDATE_PACKAGES_COMMAND.to_string(),
+                        PREFETCH_PACKAGES_COMMAND.to_string(),
+                        LIST_PACKAGES_COMMAND.to_string(),
+                        CLEAR_PACKAGES_COMMAND.to_string(),
+                        FETCH_PACKAGE_COMMAND.to_string(),
+                        COLLECT_PACKAGES_COMMAND.to_string(),
+                        RESOLVE_PACKAGE_COMMAND.to_string(),
+                        SELECT_TARGET_COMMAND.to_string(),
+                        PIN_MAIN_FILE_COMMAND.to_string(),
+                        START_PREVIEW_COMMAND.to_string(),
+                        EXPORT_PDF_COMMAND.to_string(),
+                        EXPORT_SVG_COMMAND.to_string(),
+                        EXPORT_PNG_COMMAND.to_string(),
+                    ],
+                    ..Default::default()
+                }),
+                workspace: features.workspace_folders.then_some(
+                    WorkspaceServerCapabilities {
+                        workspace_folders: Some(
+                            WorkspaceFoldersServerCapabilities {
+                                supported: Some(true),
+                                change_notifications: Some(OneOf::Left(true)),
+                            },
+                        ),
+                        file_operations: None,
+                    },
+                ),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    #[instrument(skip_all)]
+    async fn initialized(&self, _params: InitializedParams) {
+        log::info!("language server client is initialized");
+        tokio::spawn(watch_files(
+            Arc::downgrade(&self.worlds),
+            Arc::downgrade(&self.chapters),
+            self.compilers.clone(),
+            self.diagnostics.clone(),
+        ));
+        // There is no notebook capability in server capabilities of LSP
+        // types so that notebook synchronization is registered dynamically.
+        if self.features.read().unwrap().notebook_sync {
+            let registration = Registration {
+                id: "typstd.notebook.sync".to_string(),
+                method: "notebookDocument/sync".to_string(),
+                register_options: Some(serde_json::json!({
+                    "notebookSelector": [{"cells": [{"language": "typst"}]}],
+                    "save": true,
+                })),
+            };
+            if let Err(err) =
+                self.client.register_capability(vec![registration]).await
+            {
+                log::warn!("failed to register notebook sync: {}", err);
+            }
+        }
+        if self.features.read().unwrap().dynamic_registration {
+            let watchers = [config::PROJECT_FILENAME, workspace::FILENAME]
+                .iter()
+                .map(|name| format!("**/{name}"))
+                .chain(Some(WATCHED_FILES.to_string()))
+                .map(|pattern| FileSystemWatcher {
+                    glob_pattern: GlobPattern::String(pattern),
+                    kind: None,
+                })
+                .collect();
+            let options = DidChangeWatchedFilesRegistrationOptions { watchers };
+            let registration = Registration {
+                id: "typstd.projects.watch".to_string(),
+                method: "workspace/didChangeWatchedFiles".to_string(),
+                register_options: serde_json::to_value(options).ok(),
+            };
+            if let Err(err) =
+                self.client.register_capability(vec![registration]).await
+            {
+                log::warn!("failed to watch files: {}", err);
+            }
+        }
+        if self.config.read().unwrap().packages.prefetch {
+            log::info!("prefetch packages of all worlds");
+            self.prefetch_packages(None);
+        }
+    }
+
+    /// Reload project settings on changes and apply them to worlds, rescan
+    /// targets of workspaces which `typst.toml` has been changed, and
+    /// recompile worlds which depend on changed files.
+    #[instrument(skip_all)]
+    async fn did_change_watched_files(
+        &self,
+        params: DidChangeWatchedFilesParams,
+    ) {
+        let mut changed = false;
+        let mut workspaces = Vec::new();
+        let mut files = Vec::new();
+        for event in params.changes {
+            let Some(path) = uri::to_path(&event.uri) else {
+                continue;
+            };
+            let Some(name) = path.file_name().and_then(|it| it.to_str()) else {
+                continue;
+            };
+            if name != config::PROJECT_FILENAME && name != workspace::FILENAME {
+                files.push(path);
+                continue;
+            }
+            if let Some(dir) = path.parent() {
+                log::info!("project settings at {:?} changed", dir);
+                self.load_project(dir);
+                changed = true;
+                if name == workspace::FILENAME {
+                    workspaces.push(dir.to_path_buf());
+                }
+            }
+        }
+        for dir in workspaces.iter() {
+            self.rescan_workspace(dir);
+        }
+        if changed {
+            self.reconfigure_worlds();
+        }
+        if !files.is_empty() {
+            self.invalidate_files(&files);
+        }
+    }
+
+    /// Discover targets of added workspace folders and drop worlds of removed
+    /// ones.
+    #[instrument(skip_all)]
+    async fn did_change_workspace_folders(
+        &self,
+        params: DidChangeWorkspaceFoldersParams,
+    ) {
+        let DidChangeWorkspaceFoldersParams {
+            event: WorkspaceFoldersChangeEvent { added, removed },
+        } = params;
+        for folder in removed {
+            let Some(dir) = uri::to_path(&folder.uri) else {
+                continue;
+            };
+            log::info!("remove workspace folder {:?}", dir);
+            self.reload_workspace(&dir, Vec::new());
+        }
+        for folder in added {
+            let Some(dir) = uri::to_path(&folder.uri) else {
+                continue;
+            };
+            log::info!("add workspace folder {:?}", dir);
+            let mut targets = search_targets(vec![&dir]);
+            targets.extend(self.main_file_targets(&[&dir], &targets));
+            self.reload_workspace(&dir, targets);
+        }
+    }
+
+    /// Replace settings and apply them to worlds. Root directory and main file
+    /// take effect on worlds which are created afterwards.
+    #[instrument(skip_all)]
+    async fn did_change_configuration(
+        &self,
+        params: DidChangeConfigurationParams,
+    ) {
+        if params.settings.is_null() {
+            return;
+        }
+        match Config::from_value(params.settings) {
+            Ok(config) => self.update_config(config),
+            Err(err) => {
+                log::warn!("{}", err);
+                return;
+            }
+        }
+        log::info!("settings are changed");
+        let packages = self.config.read().unwrap().packages.clone();
+        if let Err(err) = package::configure(&packages) {
+            log::error!("{}", err);
+            self.client.show_message(MessageType::ERROR, err).await;
+        }
+        self.reconfigure_worlds();
+    }
+
+    #[instrument(skip_all)]
+    async fn shutdown(&self) -> Result<()> {
+        log::info!("shutdown language server");
+        Ok(())
+    }
+
+    #[instrument(
+        skip_all,
+        fields(uri = %params.text_document.uri.path_segments()
+            .map(|mut it| it.next_back().unwrap_or("/"))
+            .unwrap_or("/")
+        )
+    )]
+    async fn did_close(&self, params: DidCloseTextDocumentParams) {
+        let uri = params.text_document.uri;
+        log::info!("close {}", uri);
+        let Some(path) = uri::to_path(&uri) else {
+            return;
+        };
+        let key = path::normalize(&path);
+        self.large_files.write().unwrap().remove(&key);
+        self.versions.write().unwrap().remove(&key);
+        self.documents.write().unwrap().remove(&key);
+
+        // Files on disk replace texts of editor in all worlds. Unsaved
+        // changes are discarded, so worlds are compiled once again.
+        let mut worlds = Vec::<Arc<RwLock<LanguageServiceWorld>>>::new();
+        for world in self
+            .worlds
+            .read()
+            .unwrap()
+            .values()
+            .chain(self.chapters.read().unwrap().values())
+            .chain(self.orphans.read().unwrap().iter())
+        {
+            if !worlds.iter().any(|it| Arc::ptr_eq(it, world)) {
+                worlds.push(world.clone());
+            }
+        }
+        let reverted = worlds
+            .into_iter()
+            .filter(|world| world.read().unwrap().close_file(&path))
+            .collect::<Vec<_>>();
+
+        // Diagnostics of a world are not relevant anymore as soon as none of
+        // its documents is open.
+        let open = self
+            .documents
+            .read()
+            .unwrap()
+            .values()
+            .flat_map(|uri| self.find_worlds(uri))
+            .collect::<Vec<_>>();
+        for world in self.find_worlds(&uri) {
+            if open.iter().any(|it| Arc::ptr_eq(it, &world)) {
+                continue;
+            }
+            let main_path = world.read().unwrap().main_path().to_path_buf();
+            if let Some(main_uri) = uri::from_path(&main_path) {
+                self.diagnostics.clear_world(&main_uri);
+            }
+        }
+
+        let mut pending = Vec::new();
+        for world in reverted {
+            if !open.iter().any(|it| Arc::ptr_eq(it, &world)) {
+                continue;
+            }
+            let main_path = world.read().unwrap().main_path().to_path_buf();
+            if let Some(main_uri) = uri::from_path(&main_path) {
+                log::info!("recompile {} on discarded changes", main_uri);
+                pending.push(self.compilers.submit(&world, main_uri, true));
+            }
+        }
+        let publisher = self.diagnostics.clone();
+        tokio::spawn(async move {
+            for report in pending {
+                if let Ok((world, report)) = report.await {
+                    publisher.publish_report(world, report);
+                }
+            }
+        });
+    }
+
+    #[instrument(
+        skip_all,
+        fields(uri = %params.text_document.uri.path_segments()
+            .map(|mut it| it.next_back().unwrap_or("/"))
+            .unwrap_or("/")
+        )
+    )]
+    async fn did_change(&self, params: DidChangeTextDocumentParams) {
+        log::info!("apply {} changes", params.content_changes.len());
+        let uri = params.text_document.uri;
+        let Some(path) = uri::to_path(&uri) else {
+            log::warn!("unsupported uri {}: skip changes", uri);
+            return;
+        };
+        if self.is_large_file(&path) {
+            log::debug!("document exceeds size limits: skip changes");
+            return;
+        }
+        if package::is_cached(&path) {
+            log::debug!("document belongs to downloaded package: skip changes");
+            return;
+        }
+
+        // Versions of a document increase monotonically so that changes of
+        // an older version have been already applied or superseded.
+        let version = params.text_document.version;
+        {
+            let mut versions = self.versions.write().unwrap();
+            let key = path::normalize(&path);
+            if let Some(&current) = versions.get(&key) {
+                if version <= current {
+                    log::warn!(
+                        "skip stale changes of {} (version {} <= {})",
+                        uri,
+                        version,
+                        current,
+                    );
+                    return;
+                }
+            }
+            versions.insert(key, version);
+        }
+
+        // Changes are applied one after another: ranges of a change refer to
+        // the text which the previous change results in. A change without
+        // range replaces the whole text.
+        let timer = metrics::timer("didChange");
+        let worlds = self.find_worlds(&uri);
+        share_text(&path, &worlds);
+        for change in params.content_changes.iter() {
+            for world in worlds.iter() {
+                let world = world.read().unwrap();
+                let Some(range) = change.range else {
+                    if world.replace_file(&path, &change.text).is_none() {
+                        world.open_file(&path, change.text.clone());
+                    }
+                    continue;
+                };
+                let begin = range.start;
+                let end = range.end;
+                world.update_file(
+                    &path,
+                    change.text.as_str(),
+                    (begin.line as usize, begin.character as usize),
+                    (end.line as usize, end.character as usize),
+                );
+            }
+        }
+        drop(timer);
+        self.compile_draft(&uri);
+    }
+
+    #[instrument(
+        skip_all,
+        fields(uri = %params.text_document.uri.path_segments()
+            .map(|mut it| it.next_back().unwrap_or("/"))
+            .unwrap_or("/")
+        )
+    )]
+    async fn did_open(&self, params: DidOpenTextDocumentParams) {
+        let lang_id = params.text_document.language_id;
+        let uri = params.text_document.uri;
+        log::info!("open {} text document {}", lang_id, uri);
+
+        // It seems that there is a data race in sense that we are trying to
+        // create a new world non-atomically. This means that a concurrent
+        // call can create a new world faster.
+        let Some(path) = uri::to_path(&uri) else {
+            log::error!("unsupported uri {}: only file scheme is allowed", uri);
+            return;
+        };
+        let key = path::normalize(&path);
+        self.versions
+            .write()
+            .unwrap()
+            .insert(key.clone(), params.text_document.version);
+        self.documents.write().unwrap().insert(key, uri.clone());
+        let text = params.text_document.text;
+
+        // Files of downloaded packages (e.g. after go to definition) are
+        // served by worlds which import them and they are never edited.
+        if package::is_cached(&path) {
+            match self.find_world(&uri) {
+                Some(_) => log::info!("open read-only package file {}", uri),
+                None => log::info!("package file {} is not imported", uri),
+            }
+            return;
+        }
+        let Some((root_dir, world)) = self
+            .find_world(&uri)
+            .or_else(|| self.new_world_from_uri(&uri))
+            .or_else(|| self.new_world_from_str(&uri, text.clone()))
+        else {
+            log::error!("failed to find or initialize new world");
+            return;
+        };
+
+        log::info!("found world rooted at {:?}", root_dir);
+        let mut worlds = vec![world];
+        for world in self.find_worlds(&uri) {
+            if !worlds.iter().any(|it| Arc::ptr_eq(it, &world)) {
+                worlds.push(world);
+            }
+        }
+        let limits = self.config.read().unwrap().limits.clone();
+        match limits.check(&text) {
+            Ok(()) => {
+                for world in worlds.iter() {
+                    world.read().unwrap().open_file(&path, text.clone());
+                }
+            }
+            Err(reason) => {
+                log::warn!("document {} is too large: {}", uri, reason);
+                self.large_files
+                    .write()
+                    .unwrap()
+                    .insert(path::normalize(&path));
+                for world in worlds.iter() {
+                    world.read().unwrap().remove_file(&path);
+                }
+                let message = format!(
+                    "{}: {}. Changes are not tracked until the document is \
+                     saved and completion is disabled.",
+                    uri, reason,
+                );
+                self.client
+                    .show_message(MessageType::WARNING, message)
+                    .await;
+            }
+        }
+        self.select_target(&uri, false);
+        if self.config.read().unwrap().preview.auto {
+            if let Err(err) = self.start_preview(&uri) {
+                log::error!("failed to start preview: {}", err);
+            }
+        }
+        let _ = self.compile(&uri);
+    }
+
+    #[instrument(
+        skip_all,
+        fields(uri = %params.text_document.uri.path_segments()
+            .map(|mut it| it.next_back().unwrap_or("/"))
+            .unwrap_or("/")
+        )
+    )]
+    async fn did_save(&self, params: DidSaveTextDocumentParams) {
+        let uri = params.text_document.uri;
+        log::info!("save text document located at {}", uri);
+        if let Some(path) = uri::to_path(&uri) {
+            // Large documents are not tracked so we make world to reread
+            // them from file system.
+            if self.is_large_file(&path) {
+                for world in self.find_worlds(&uri) {
+                    world.read().unwrap().remove_file(&path);
+                }
+            }
+        }
+        let pending = self.compile(&uri);
+        let publisher = self.diagnostics.clone();
+        let refresher = self.inlay_hint_refresher();
+        tokio::spawn(async move {
+            for report in pending {
+                if let Ok((world, report)) = report.await {
+                    publisher.publish_report(world, report);
+                }
+            }
+            refresh_inlay_hints(refresher).await;
+        });
+    }
+
+    #[instrument(
+        skip_all,
+        fields(uri = %params.text_document_position_params.text_document.uri
+            .path_segments()
+            .map(|mut it| it.next_back().unwrap_or("/"))
+            .unwrap_or("/")
+        )
+    )]
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let _timer = metrics::timer("hover");
+        log::info!(
+            "hover at {}:{} in {}",
+            params.text_document_position_params.position.line,
+            params.text_document_position_params.position.character,
+            params.text_document_position_params.text_document.uri,
+        );
+
+        let position = params.text_document_position_params.position;
+        let uri = params.text_document_position_params.text_document.uri;
+        let (world, path, position) =
+            match self.find_notebook_cell(&uri, position) {
+                Some(found) => found,
+                None => {
+                    let Some(path) = uri::to_path(&uri) else {
+                        return Ok(None);
+                    };
+                    if self.is_large_file(&path) {
+                        log::info!("document exceeds size limits: skip hover");
+                        return Ok(None);
+                    }
+                    let Some((_, world)) = self.find_world(&uri) else {
+                        log::error!("unable to find a world for hover");
+                        return Ok(None);
+                    };
+                    (world, path, position)
+                }
+            };
+
+        let (line, column) =
+            (position.line as usize, position.character as usize);
+        let Some(docs) = run_interruptible(world, move |world, cancelled| {
+            world.hover(&path, line, column, cancelled)
+        })
+        .await
+        .flatten() else {
+            return Ok(None);
+        };
+        let kind = match self.features.read().unwrap().markdown_hover {
+            true => MarkupKind::Markdown,
+            false => MarkupKind::PlainText,
+        };
+        Ok(Some(Hover {
+            contents: HoverContents::Markup(docs.render(kind)),
+            range: None,
+        }))
+    }
+
+    #[instrument(
+        skip_all,
+        fields(uri = %params.text_document_position_params.text_document.uri
+            .path_segments()
+            .map(|mut it| it.next_back().unwrap_or("/"))
+            .unwrap_or("/")
+        )
+    )]
+    async fn signature_help(
+        &self,
+        params: SignatureHelpParams,
+    ) -> Result<Option<SignatureHelp>> {
+        let _timer = metrics::timer("signatureHelp");
+        let position = params.text_document_position_params.position;
+        let uri = params.text_document_position_params.text_document.uri;
+        let Some(path) = uri::to_path(&uri) else {
+            return Ok(None);
+        };
+        let Some((_, world)) = self.find_world(&uri) else {
+            log::error!("unable to find a world for signature help");
+            return Ok(None);
+        };
+
+        let (line, column) =
+            (position.line as usize, position.character as usize);
+        let Some(signature) = run_cancellable(world, move |world| {
+            world.signature_help(&path, line, column)
+        })
+        .await
+        .flatten() else {
+            return Ok(None);
+        };
+        let parameters = signature
+            .params
+            .into_iter()
+            .map(|param| ParameterInformation {
+                label: ParameterLabel::LabelOffsets([
+                    param.label.0 as u32,
+                    param.label.1 as u32,
+                ]),
+                documentation: param.docs.map(Documentation::String),
+            })
+            .collect();
+        let active = signature.active.map(|it| it as u32);
+        Ok(Some(SignatureHelp {
+            signatures: vec![SignatureInformation {
+                label: signature.label,
+                documentation: signature.docs.map(Documentation::String),
+                parameters: Some(parameters),
+                active_parameter: active,
+            }],
+            active_signature: Some(0),
+            active_parameter: active,
+        }))
+    }
+
+    #[instrument(
+        skip_all,
+        fields(uri = %params.text_document_position_params.text_document.uri
+            .path_segments()
+            .map(|mut it| it.next_back().unwrap_or("/"))
+            .unwrap_or("/")
+        )
+    )]
+    async fn goto_definition(
+        &self,
+        params: GotoDefinitionParams,
+    ) -> Result<Option<GotoDefinitionResponse>> {
+        let _timer = metrics::timer("definition");
+        let position = params.text_document_position_params.position;
+        let uri = params.text_document_position_params.text_document.uri;
+        log::info!(
+            "definition at {}:{} in {}",
+            position.line,
+            position.character,
+            uri,
+        );
+        let Some(path) = uri::to_path(&uri) else {
+            return Ok(None);
+        };
+        let Some((_, world)) = self.find_world(&uri) else {
+            log::error!("unable to find a world for definition");
+            return Ok(None);
+        };
+
+        let (line, column) =
+            (position.line as usize, position.character as usize);
+        let Some((path, start, end)) = run_cancellable(world, move |world| {
+            world.definition(&path, line, column)
+        })
+        .await
+        .flatten() else {
+            return Ok(None);
+        };
+        let Some(uri) = uri::from_path(&path) else {
+            return Ok(None);
+        };
+        let range = Range::new(
+            Position::new(start.0 as u32, start.1 as u32),
+            Position::new(end.0 as u32, end.1 as u32),
+        );
+        Ok(Some(GotoDefinitionResponse::Scalar(Location::new(
+            uri, range,
+        ))))
+    }
+
+    #[instrument(
+        skip_all,
+        fields(uri = %params.text_document.uri.path_segments()
+            .map(|mut it| it.next_back().unwrap_or("/"))
+            .unwrap_or("/")
+        )
+    )]
+    async fn inlay_hint(
+        &self,
+        params: InlayHintParams,
+    ) -> Result<Option<Vec<InlayHint>>> {
+        let uri = params.text_document.uri;
+        let range = params.range;
+        let options = self.config.read().unwrap().inlay_hints.clone();
+        if !options.enabled() {
+            return Ok(None);
+        }
+        let Some(path) = uri::to_path(&uri) else {
+            return Ok(None);
+        };
+        let Some((_, world)) = self.find_world(&uri) else {
+            log::error!("unable to find a world for inlay hints");
+            return Ok(None);
+        };
+        let Some(hints) = run_cancellable(world, move |world| {
+            world.inlay_hints(&path, &options)
+        })
+        .await
+        else {
+            return Ok(None);
+        };
+        let hints = hints
+            .into_iter()
+            .map(|((line, column), label, kind)| {
+                let parameter = kind == HintKind::Parameter;
+                InlayHint {
+                    position: Position::new(line as u32, column as u32),
+                    label: InlayHintLabel::String(label),
+                    kind: parameter.then_some(InlayHintKind::PARAMETER),
+                    text_edits: None,
+                    tooltip: None,
+                    padding_left: Some(!parameter),
+                    padding_right: Some(parameter),
+                    data: None,
+                }
+            })
+            .filter(|it| {
+                range.start.line <= it.position.line
+                    && it.position.line <= range.end.line
+            })
+            .collect();
+        Ok(Some(hints))
+    }
+
+    async fn formatting(
+        &self,
+        params: DocumentFormattingParams,
+    ) -> Result<Option<Vec<TextEdit>>> {
+        let uri = params.text_document.uri;
+        log::info!("format {}", uri);
+        Ok(self.format(&uri, &params.options, None).await)
+    }
+
+    async fn range_formatting(
+        &self,
+        params: DocumentRangeFormattingParams,
+    ) -> Result<Option<Vec<TextEdit>>> {
+        let uri = params.text_document.uri;
+        let range = params.range;
+        log::info!(
+            "format {}:{}-{}:{} in {}",
+            range.start.line,
+            range.start.character,
+            range.end.line,
+            range.end.character,
+            uri,
+        );
+        let begin = (range.start.line as usize, range.start.character as usize);
+        let end = (range.end.line as usize, range.end.character as usize);
+        Ok(self.format(&uri, &params.options, Some((begin, end))).await)
+    }
+
+    async fn on_type_formatting(
+        &self,
+        params: DocumentOnTypeFormattingParams,
+    ) -> Result<Option<Vec<TextEdit>>> {
+        let uri = params.text_document_position.text_document.uri;
+        let position = params.text_document_position.position;
+        log::info!(
+            "format on type {:?} at {}:{} in {}",
+            params.ch,
+            position.line,
+            position.character,
+            uri,
+        );
+        let Some(path) = uri::to_path(&uri) else {
+            return Ok(None);
+        };
+        if self.is_large_file(&path) {
+            log::info!("document exceeds size limits: skip formatting");
+            return Ok(None);
+        }
+        let Some((_, world)) = self.find_world(&uri) else {
+            log::error!("unable to find a world for formatting");
+            return Ok(None);
+        };
+        let position = (position.line as usize, position.character as usize);
+        let edits = run_cancellable(world, move |world| {
+            world.on_type_format(&path, position, &params.ch)
+        })
+        .await;
+        Ok(edits.map(to_text_edits))
+    }
+
+    async fn linked_editing_range(
+        &self,
+        params: LinkedEditingRangeParams,
+    ) -> Result<Option<LinkedEditingRanges>> {
+        let position = params.text_document_position_params.position;
+        let uri = params.text_document_position_params.text_document.uri;
+        log::info!(
+            "linked editing ranges at {}:{} in {}",
+            position.line,
+            position.character,
+            uri,
+        );
+        let Some(path) = uri::to_path(&uri) else {
+            return Ok(None);
+        };
+        let Some((_, world)) = self.find_world(&uri) else {
+            log::error!("unable to find a world for linked editing ranges");
+            return Ok(None);
+        };
+
+        let (line, column) =
+            (position.line as usize, position.character as usize);
+        let ranges = run_cancellable(world, move |world| {
+            world.linked_ranges(&path, line, column)
+        })
+        .await
+        .unwrap_or_default();
+        if ranges.is_empty() {
+            return Ok(None);
+        }
+        let ranges = ranges
+            .into_iter()
+            .map(|(start, end)| {
+                Range::new(
+                    Position::new(start.0 as u32, start.1 as u32),
+                    Position::new(end.0 as u32, end.1 as u32),
+                )
+            })
+            .collect();
+        Ok(Some(LinkedEditingRanges {
+            ranges,
+            word_pattern: None,
+        }))
+    }
+
+    async fn code_action(
+        &self,
+        params: CodeActionParams,
+    ) -> Result<Option<CodeActionResponse>> {
+        let uri = params.text_document.uri;
+        let range = params.range;
+        log::info!(
+            "code actions for {}:{}-{}:{}",
+            range.start.line,
+            range.start.character,
+            range.end.line,
+            range.end.character,
+        );
+        let Some(path) = uri::to_path(&uri) else {
+            return Ok(None);
+        };
+        let Some((_, world)) = self.find_world(&uri) else {
+            log::error!("unable to find a world for code actions");
+            return Ok(None);
+        };
+
+        let begin = (range.start.line as usize, range.start.character as usize);
+        let end = (range.end.line as usize, range.end.character as usize);
+        let reported = params.context.diagnostics;
+        let diagnostics = reported
+            .iter()
+            .map(|it| {
+                let start = it.range.start;
+                let end = it.range.end;
+                (
+                    (start.line as usize, start.character as usize),
+                    (end.line as usize, end.character as usize),
+                    it.message.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        let Some(actions) = run_cancellable(world, move |world| {
+            world.code_actions(&path, begin, end, &diagnostics)
+        })
+        .await
+        else {
+            return Ok(None);
+        };
+
+        let snippets = self.features.read().unwrap().snippet_text_edit;
+        let actions = actions
+            .into_iter()
+            .map(|mut action| {
+                let diagnostics = action
+                    .diagnostic
+                    .and_then(|index| reported.get(index).cloned())
+                    .map(|it| vec![it]);
+                if action.snippet && !snippets {
+                    for edit in action.edits.iter_mut() {
+                        edit.text = snippet::to_plain_text(&edit.text);
+                    }
+                }
+                let edit = match action.create.is_empty() {
+                    true if action.snippet && snippets => {
+                        to_snippet_edit(action.edits)
+                    }
+                    true => to_workspace_edit(action.edits),
+                    false => to_document_changes(action.create, action.edits),
+                };
+                CodeActionOrCommand::CodeAction(CodeAction {
+                    title: action.title,
+                    kind: Some(action.kind),
+                    diagnostics,
+                    edit: Some(edit),
+                    ..Default::default()
+                })
+            })
+            .collect::<Vec<_>>();
+        Ok(Some(actions))
+    }
+
+    #[instrument(
+        skip_all,
+        fields(uri = %params.text_document.uri.path_segments()
+            .map(|mut it| it.next_back().unwrap_or("/"))
+            .unwrap_or("/")
         )
     )]
-    async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        let lang_id = params.text_document.language_id;
+    async fn code_lens(
+        &self,
+        params: CodeLensParams,
+    ) -> Result<Option<Vec<CodeLens>>> {
         let uri = params.text_document.uri;
-        log::info!("open {} text document {}", lang_id, uri);
+        log::info!("code lenses for {}", uri);
+        let Some(path) = uri::to_path(&uri) else {
+            return Ok(None);
+        };
+        let Some((_, world)) = self.find_world(&uri) else {
+            log::error!("unable to find a world for code lenses");
+            return Ok(None);
+        };
 
-        // It seems that there is a data race in sense that we are trying to
-        // create a new world non-atomically. This means that a concurrent
-        // call can create a new world faster.
-        let path = Path::new(uri.path());
-        let text = params.text_document.text;
-        let Some((root_dir, world)) = self
-            .find_world(&uri)
-            .or_else(|| self.new_world_from_uri(&uri))
-            .or_else(|| self.new_world_from_str(&uri, text.clone()))
+        // Lenses are shown on main file only since they summarize the whole
+        // document.
+        let summary = run_cancellable(world, move |world| {
+            if path::normalize(world.main_path()) != path::normalize(&path) {
+                return None;
+            }
+            let diagnostics = world.diagnostics();
+            let count = |severity| {
+                diagnostics
+                    .iter()
+                    .filter(|it| it.severity == severity)
+                    .count()
+            };
+            let errors = count(Severity::Error);
+            let warnings = count(Severity::Warning);
+            Some((errors, warnings, world.stats().pages, world.count()))
+        })
+        .await
+        .flatten();
+        let Some((errors, warnings, pages, count)) = summary else {
+            return Ok(None);
+        };
+
+        let status = match (errors, warnings) {
+            (0, 0) => "compiled".to_string(),
+            (0, _) => format!("compiled with {}", plural(warnings, "warning")),
+            (_, _) => format!("failed with {}", plural(errors, "error")),
+        };
+        let lens = |title: String, command: &str, arguments| CodeLens {
+            range: Range::default(),
+            command: Some(tower_lsp::lsp_types::Command {
+                title,
+                command: command.to_string(),
+                arguments,
+            }),
+            data: None,
+        };
+        let export = serde_json::to_value(&uri).ok().map(|it| vec![it]);
+        Ok(Some(vec![
+            lens(status, "", None),
+            lens(plural(pages, "page"), "", None),
+            lens(format!("~{}", plural(count.words, "word")), "", None),
+            lens("Export PDF".to_string(), EXPORT_PDF_COMMAND, export),
+        ]))
+    }
+
+    #[instrument(skip_all, fields(command = params.command))]
+    async fn execute_command(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> Result<Option<serde_json::Value>> {
+        if params.command == PREFETCH_PACKAGES_COMMAND {
+            let uri = params.arguments.into_iter().next();
+            let uri = uri.and_then(|it| serde_json::from_value(it).ok());
+            let failures = self.prefetch_packages(uri).await;
+            return Ok(Some(serde_json::Value::from(failures.ok())));
+        }
+        if [
+            LIST_PACKAGES_COMMAND,
+            CLEAR_PACKAGES_COMMAND,
+            FETCH_PACKAGE_COMMAND,
+            COLLECT_PACKAGES_COMMAND,
+        ]
+        .contains(&params.command.as_str())
+        {
+            return self.manage_packages(params).await;
+        }
+        if params.command == RESOLVE_PACKAGE_COMMAND {
+            let spec = params.arguments.into_iter().next();
+            let Some(serde_json::Value::String(spec)) = spec else {
+                return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                    "expected package spec as the first argument",
+                ));
+            };
+            let resolved = tokio::task::spawn_blocking(move || {
+                package::resolve_spec(&spec)
+            });
+            return match flatten(resolved.await) {
+                Ok(spec) => Ok(Some(serde_json::Value::from(spec.to_string()))),
+                Err(err) => {
+                    log::warn!("failed to resolve package: {}", err);
+                    Ok(None)
+                }
+            };
+        }
+        if params.command == SELECT_TARGET_COMMAND {
+            let uri = params.arguments.into_iter().next();
+            let Some(Ok(uri)) = uri.map(serde_json::from_value::<Url>) else {
+                return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                    "expected text document uri as the first argument",
+                ));
+            };
+            self.select_target(&uri, true);
+            return Ok(None);
+        }
+        if params.command == PIN_MAIN_FILE_COMMAND {
+            let mut args = params.arguments.into_iter();
+            let Some(Ok(uri)) = args.next().map(serde_json::from_value::<Url>)
+            else {
+                return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                    "expected text document uri as the first argument",
+                ));
+            };
+            let main_uri =
+                args.next().and_then(|it| serde_json::from_value(it).ok());
+            if let Err(err) = self.pin_main_file(&uri, main_uri.as_ref()) {
+                log::error!("failed to pin main file: {}", err);
+                self.client.show_message(MessageType::ERROR, &err).await;
+            }
+            return Ok(None);
+        }
+        let dpi = self.config.read().unwrap().export.dpi;
+        let format = match params.command.as_str() {
+            command if command == EXPORT_PDF_COMMAND => Some(ExportFormat::Pdf),
+            command if command == EXPORT_SVG_COMMAND => Some(ExportFormat::Svg),
+            command if command == EXPORT_PNG_COMMAND => {
+                Some(ExportFormat::Png { dpi })
+            }
+            _ => None,
+        };
+        if let Some(format) = format {
+            let mut args = params.arguments.into_iter();
+            let Some(Ok(uri)) = args.next().map(serde_json::from_value::<Url>)
+            else {
+                return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                    "expected text document uri as the first argument",
+                ));
+            };
+            let output = args.next().and_then(|it| match it {
+                serde_json::Value::String(path) => Some(PathBuf::from(path)),
+                _ => None,
+            });
+            return match self.export(&uri, format, output).await {
+                Ok(path) => {
+                    let message = format!("document is exported to {path:?}");
+                    self.client.show_message(MessageType::INFO, message).await;
+                    Ok(Some(serde_json::Value::from(path.to_string_lossy())))
+                }
+                Err(err) => {
+                    log::error!("failed to export document: {}", err);
+                    self.client.show_message(MessageType::ERROR, &err).await;
+                    Ok(None)
+                }
+            };
+        }
+        if params.command == START_PREVIEW_COMMAND {
+            let uri = params.arguments.into_iter().next();
+            let Some(Ok(uri)) = uri.map(serde_json::from_value::<Url>) else {
+                return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                    "expected text document uri as the first argument",
+                ));
+            };
+            return match self.start_preview(&uri) {
+                Ok(url) => {
+                    self.show_preview(&url).await;
+                    Ok(Some(serde_json::Value::from(url)))
+                }
+                Err(err) => {
+                    log::error!("failed to start preview: {}", err);
+                    self.client.show_message(MessageType::ERROR, &err).await;
+                    Ok(None)
+                }
+            };
+        }
+        if params.command != UPDATE_PACKAGES_COMMAND {
+            return Err(tower_lsp::jsonrpc::Error::method_not_found());
+        }
+        let mut args = params.arguments.into_iter();
+        let Some(Ok(uri)) = args.next().map(serde_json::from_value::<Url>)
         else {
-            log::error!("failed to find or initialize new world");
-            return;
+            return Err(tower_lsp::jsonrpc::Error::invalid_params(
+                "expected text document uri as the first argument",
+            ));
         };
+        let rewrite = args.next().and_then(|it| it.as_bool()).unwrap_or(false);
+        match self.update_packages(uri, rewrite).await {
+            Ok(updates) => {
+                let message = match updates.len() {
+                    0 => "all packages are up to date".to_string(),
+                    n => format!("{n} package(s) updated"),
+                };
+                self.client.show_message(MessageType::INFO, message).await;
+                let updates = updates
+                    .iter()
+                    .map(|it| {
+                        serde_json::json!({
+                            "package": it.spec.to_string(),
+                            "version": it.version.to_string(),
+                        })
+                    })
+                    .collect();
+                Ok(Some(serde_json::Value::Array(updates)))
+            }
+            Err(err) => {
+                log::error!("failed to update packages: {}", err);
+                self.client.show_message(MessageType::ERROR, &err).await;
+                Ok(None)
+            }
+        }
+    }
 
-        log::info!("found world rooted at {:?}", root_dir);
-        world.lock().unwrap().add_file(path, text);
-        let _ = self.compile(&uri);
+    #[instrument(
+        skip_all,
+        fields(uri = %params.text_document_position.text_document.uri),
+    )]
+    async fn completion(
+        &self,
+        params: CompletionParams,
+    ) -> Result<Option<CompletionResponse>> {
+        let _timer = metrics::timer("completion");
+        let position = params.text_document_position.position;
+        log::info!("complete at {}:{}", position.line, position.character);
+
+        let uri = params.text_document_position.text_document.uri;
+        let origin = position;
+        let (world, path, position) = match self
+            .find_notebook_cell(&uri, position)
+        {
+            Some(found) => found,
+            None => {
+                let Some(path) = uri::to_path(&uri) else {
+                    return Ok(None);
+                };
+                if self.is_large_file(&path) {
+                    log::info!("document exceeds size limits: skip completion");
+                    return Ok(None);
+                }
+                let Some((_, world)) = self.find_world(&uri) else {
+                    log::error!("unable to find a world for completion");
+                    return Ok(None);
+                };
+                (world, path, position)
+            }
+        };
+
+        let (line, column) =
+            (position.line as usize, position.character as usize);
+        let options = self.config.read().unwrap().completion.clone();
+        let max_items = options.max_items;
+        // Package index is refreshed in background so it is likely to be
+        // available for the next completions.
+        if options.package_index && package::is_index_stale() {
+            tokio::task::spawn_blocking(|| {
+                if let Err(err) = package::refresh_index() {
+                    log::warn!("failed to refresh package index: {err}");
+                }
+            });
+        }
+        let lazy = self.features.read().unwrap().completion_resolve;
+        let Some(mut labels) =
+            run_interruptible(world, move |world, cancelled| {
+                world.complete(&path, line, column, &options, lazy, cancelled)
+            })
+            .await
+        else {
+            return Ok(None);
+        };
+        if labels.is_empty() {
+            return Ok(None);
+        }
+
+        // Truncated list is marked as incomplete so client requests it again
+        // as user keeps typing.
+        let is_incomplete = max_items > 0 && labels.len() > max_items;
+        if is_incomplete {
+            labels.truncate(max_items);
+        }
+        // Clients without snippet support are likely to render snippet kind
+        // in a special way so we fallback to keyword kind for them.
+        let syntax_kind = match self.features.read().unwrap().snippets {
+            true => CompletionItemKind::SNIPPET,
+            false => CompletionItemKind::KEYWORD,
+        };
+        let docs_kind = match self.features.read().unwrap().markdown_completion
+        {
+            true => MarkupKind::Markdown,
+            false => MarkupKind::PlainText,
+        };
+        let snippets = self.features.read().unwrap().snippets;
+        let items = labels
+            .iter()
+            .map(|el| {
+                let insert_text =
+                    el.apply.as_deref().map(|apply| match snippets {
+                        true => snippet::to_lsp_snippet(apply),
+                        false => snippet::to_plain_text(apply),
+                    });
+                CompletionItem {
+                    label: el.label.clone(),
+                    insert_text: insert_text.clone(),
+                    insert_text_format: el.apply.as_ref().map(
+                        |_| match snippets {
+                            true => InsertTextFormat::SNIPPET,
+                            false => InsertTextFormat::PLAIN_TEXT,
+                        },
+                    ),
+                    // Detail is shown next to label so only its first line is
+                    // kept. It is resolved with documentation in lazy mode.
+                    detail: el
+                        .detail
+                        .as_deref()
+                        .filter(|_| {
+                            !lazy
+                                || !matches!(el.kind, completion::Kind::Ide(_))
+                        })
+                        .and_then(|it| it.lines().next())
+                        .map(str::to_string),
+                    documentation: el.docs.as_ref().map(|docs| {
+                        Documentation::MarkupContent(
+                            docs.render(docs_kind.clone()),
+                        )
+                    }),
+                    // Ranges are on the line of cursor so they are shifted to
+                    // lines of a notebook cell as a whole.
+                    text_edit: el.range.map(|(start, end)| {
+                        let to_position = |(line, column): (usize, usize)| {
+                            let line =
+                                line as u32 + origin.line - position.line;
+                            Position::new(line, column as u32)
+                        };
+                        CompletionTextEdit::Edit(TextEdit {
+                            range: Range::new(
+                                to_position(start),
+                                to_position(end),
+                            ),
+                            new_text: insert_text
+                                .clone()
+                                .unwrap_or_else(|| el.label.clone()),
+                        })
+                    }),
+                    data: match &el.kind {
+                        completion::Kind::Ide(kind) if lazy => {
+                            serde_json::to_value(CompletionData {
+                                uri: uri.clone(),
+                                kind: kind.clone(),
+                                detail: el.detail.clone(),
+                            })
+                            .ok()
+                        }
+                        _ => None,
+                    },
+                    kind: Some(match &el.kind {
+                        completion::Kind::File => CompletionItemKind::FILE,
+                        completion::Kind::Folder => CompletionItemKind::FOLDER,
+                        completion::Kind::Package => CompletionItemKind::MODULE,
+                        completion::Kind::Version => CompletionItemKind::VALUE,
+                        completion::Kind::Reference => {
+                            CompletionItemKind::REFERENCE
+                        }
+                        completion::Kind::Ide(kind) => match kind {
+                            CompletionKind::Func => {
+                                CompletionItemKind::FUNCTION
+                            }
+                            CompletionKind::Syntax => syntax_kind,
+                            CompletionKind::Type => CompletionItemKind::CLASS,
+                            CompletionKind::Param => CompletionItemKind::VALUE,
+                            CompletionKind::Constant => {
+                                CompletionItemKind::CONSTANT
+                            }
+                            // There is no suitable category for symbols (like
+                            // dot.circle) in language server protocol. So we decided
+                            // to map `Symbol` to `EnumMember` since set of all
+                            // symbols are is bounded and we can say that all symbols
+                            // constitutes some big enumeration. ¯\_(ツ)_/¯
+                            CompletionKind::Symbol(_) => {
+                                CompletionItemKind::ENUM_MEMBER
+                            }
+                        },
+                    }),
+                    ..Default::default()
+                }
+            })
+            .collect();
+        Ok(Some(CompletionResponse::List(CompletionList {
+            is_incomplete,
+            items,
+        })))
+    }
+
+    #[instrument(skip_all, fields(label = %item.label))]
+    async fn completion_resolve(
+        &self,
+        mut item: CompletionItem,
+    ) -> Result<CompletionItem> {
+        let _timer = metrics::timer("completionResolve");
+        let Some(data) = item
+            .data
+            .take()
+            .and_then(|it| serde_json::from_value::<CompletionData>(it).ok())
+        else {
+            return Ok(item);
+        };
+
+        // Documentation comes from standard library so any world of a
+        // document will do.
+        let world =
+            match self.find_notebook_cell(&data.uri, Position::default()) {
+                Some((world, _, _)) => Some(world),
+                None => self.find_world(&data.uri).map(|(_, world)| world),
+            };
+        let Some(world) = world else {
+            log::error!("unable to find a world for completion resolve");
+            return Ok(item);
+        };
+
+        item.detail = data
+            .detail
+            .as_deref()
+            .and_then(|it| it.lines().next())
+            .map(str::to_string);
+        let label = item.label.clone();
+        let detail = data.detail.clone();
+        let Some(docs) = run_cancellable(world, move |world| {
+            world.completion_docs(&label, &data.kind, detail.as_deref())
+        })
+        .await
+        .flatten() else {
+            return Ok(item);
+        };
+        let docs_kind = match self.features.read().unwrap().markdown_completion
+        {
+            true => MarkupKind::Markdown,
+            false => MarkupKind::PlainText,
+        };
+        item.documentation =
+            Some(Documentation::MarkupContent(docs.render(docs_kind)));
+        Ok(item)
     }
+}
+
+#[derive(Parser, Debug)]
+#[clap(name = "typstd", version, author, about = "Typst language server.")]
+struct Args {
+    /// Path to log file.
+    #[arg(long)]
+    log_output: Option<String>,
+
+    /// Run as a shared daemon on TCP address (e.g. `127.0.0.1:9257`), on Unix
+    /// domain socket (e.g. `unix:/tmp/typstd.sock`), or on WebSocket address
+    /// (e.g. `ws://127.0.0.1:9258`) for browser-based editors.
+    #[arg(short, long, conflicts_with = "record")]
+    listen: Option<String>,
+
+    /// Exit daemon after the last editor disconnects and no one connects
+    /// within timeout in seconds.
+    #[arg(long, value_name = "SECS", requires = "listen")]
+    idle_timeout: Option<u64>,
+
+    /// Path to PEM file with additional CA certificates for package
+    /// downloads.
+    #[arg(long, global = true)]
+    ca_bundle: Option<PathBuf>,
+
+    /// Disable TLS certificate verification for package downloads (insecure).
+    #[arg(long, global = true)]
+    insecure: bool,
+
+    /// Proxy for package downloads (e.g. `http://proxy.local:3128`). It takes
+    /// precedence over HTTPS_PROXY and other environment variables.
+    #[arg(long, value_name = "URL", global = true)]
+    proxy: Option<String>,
+
+    /// URL of a mirror of package registry which replaces
+    /// https://packages.typst.org.
+    #[arg(long, value_name = "URL", global = true)]
+    package_mirror: Option<String>,
+
+    /// Additional directory with fonts (could be repeated).
+    #[arg(long = "font-path", value_name = "DIR", global = true)]
+    font_paths: Vec<PathBuf>,
+
+    /// Do not discover fonts installed in system.
+    #[arg(long, global = true)]
+    ignore_system_fonts: bool,
+
+    /// Do not use fonts embedded into language server.
+    #[arg(long, global = true)]
+    ignore_embedded_fonts: bool,
+
+    /// Serve live preview of opened documents on address (defaults to a
+    /// random port on localhost).
+    #[arg(
+        long,
+        value_name = "ADDR",
+        num_args = 0..=1,
+        default_missing_value = "127.0.0.1:0"
+    )]
+    preview: Option<String>,
+
+    /// Record all incoming messages to a session file.
+    #[arg(long, value_name = "FILE")]
+    record: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compile a document to PDF, SVG, or PNG and exit.
+    Compile(CompileArgs),
+    /// Print elements of a document which match a selector.
+    Query(QueryArgs),
+    /// Manage packages imported by a document.
+    #[command(subcommand)]
+    Packages(PackagesCommand),
+    /// Feed a recorded session to language server and print its output.
+    Replay(ReplayArgs),
+    /// Check environment and report problems with setup.
+    Doctor(DoctorArgs),
+}
+
+#[derive(Subcommand, Debug)]
+enum PackagesCommand {
+    /// Update packages to the latest compatible versions and record them to
+    /// lockfile.
+    Update(UpdateArgs),
+    /// Download all imported packages ahead of compilation.
+    Prefetch(PrefetchArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct DoctorArgs {
+    /// Workspace directory to search manifests in (defaults to current
+    /// directory).
+    dir: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ReplayArgs {
+    /// Path to session file recorded with `--record`.
+    session: PathBuf,
+
+    /// Keep recorded delays between messages.
+    #[arg(long)]
+    realtime: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct PrefetchArgs {
+    /// Path to main source file.
+    input: PathBuf,
+
+    /// Root directory of a project (defaults to `TYPST_ROOT` or parent of
+    /// input).
+    #[arg(long)]
+    root: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct UpdateArgs {
+    /// Path to main source file.
+    input: PathBuf,
+
+    /// Root directory of a project (defaults to `TYPST_ROOT` or parent of
+    /// input).
+    #[arg(long)]
+    root: Option<PathBuf>,
+
+    /// Rewrite version numbers in import statements.
+    #[arg(long)]
+    rewrite: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct CompileArgs {
+    /// Path to main source file.
+    input: PathBuf,
+
+    /// Path to output file (defaults to input with extension of format).
+    output: Option<PathBuf>,
+
+    /// Path to output file (same as positional output).
+    #[arg(
+        short = 'o',
+        long = "output",
+        id = "output_flag",
+        value_name = "OUTPUT",
+        conflicts_with = "output"
+    )]
+    output_flag: Option<PathBuf>,
+
+    /// Output format (defaults to extension of output or PDF).
+    #[arg(short, long, value_enum)]
+    format: Option<config::OutputFormat>,
+
+    /// Resolution of PNG images in pixels per inch.
+    #[arg(long, default_value_t = 144.0)]
+    dpi: f32,
+
+    /// Root directory of a project (defaults to `TYPST_ROOT` or parent of
+    /// input).
+    #[arg(long)]
+    root: Option<PathBuf>,
+
+    /// Write files and packages the compilation depended on.
+    #[arg(long, value_enum)]
+    deps: Option<DepsFormat>,
+
+    /// Path to dependency file (defaults to stdout).
+    #[arg(long, requires = "deps")]
+    deps_output: Option<PathBuf>,
+
+    /// Compile N times after a warm-up and report timings.
+    #[arg(long, value_name = "N")]
+    bench: Option<usize>,
+
+    /// Embed complete fonts or subsets of used glyphs.
+    #[arg(long, value_enum, default_value_t)]
+    font_embedding: config::FontEmbedding,
+
+    /// What to do with fonts which licenses restrict embedding.
+    #[arg(long, value_enum, default_value_t)]
+    restricted_fonts: config::RestrictedFonts,
+
+    /// Value of `sys.inputs` as `key=value` pair (could be repeated).
+    #[arg(
+        long = "input",
+        value_name = "KEY=VALUE",
+        value_parser = parse_input
+    )]
+    inputs: Vec<(String, String)>,
+}
+
+#[derive(clap::Args, Debug)]
+struct QueryArgs {
+    /// Path to main source file.
+    input: PathBuf,
+
+    /// Selector in Typst code (e.g. `heading`, `<intro>`, or
+    /// `metadata.where(label: <info>)`).
+    selector: String,
+
+    /// Print values of a field of elements instead of whole elements.
+    #[arg(long)]
+    field: Option<String>,
+
+    /// Expect exactly one element and print it alone.
+    #[arg(long)]
+    one: bool,
+
+    /// Output format.
+    #[arg(long, value_enum, default_value = "json")]
+    format: QueryFormat,
+
+    /// Pretty-print JSON.
+    #[arg(long)]
+    pretty: bool,
+
+    /// Root directory of a project (defaults to `TYPST_ROOT` or parent of
+    /// input).
+    #[arg(long)]
+    root: Option<PathBuf>,
 
-    #[instrument(
-        skip_all,
-        fields(uri = %params.text_document.uri.path_segments()
-            .map(|it| it.last().unwrap_or("/"))
-            .unwrap_or("/")
-        )
+    /// Value of `sys.inputs` as `key=value` pair (could be repeated).
+    #[arg(
+        long = "input",
+        value_name = "KEY=VALUE",
+        value_parser = parse_input
     )]
-    async fn did_save(&self, params: DidSaveTextDocumentParams) {
-        let uri = params.text_document.uri;
-        log::info!("save text document located at {}", uri);
-        let Err(msg) = self.compile(&uri) else {
-            self.client.publish_diagnostics(uri, vec![], None).await;
-            return;
-        };
+    inputs: Vec<(String, String)>,
+}
 
-        // Handle compilation errors in a primitive way.
-        let pos = Position {
-            line: 0,
-            character: 0,
-        };
-        let diagnostic = Diagnostic {
-            range: Range {
-                start: pos,
-                end: pos,
-            },
-            severity: Some(DiagnosticSeverity::ERROR),
-            source: Some("typst".to_string()),
-            message: msg,
-            ..Default::default()
-        };
-        self.client
-            .publish_diagnostics(uri, vec![diagnostic], None)
-            .await;
-    }
+fn parse_input(arg: &str) -> result::Result<(String, String), String> {
+    config::parse_input(arg)
+        .ok_or_else(|| format!("expected `key=value` but got `{arg}`"))
+}
 
-    #[instrument(
-        skip_all,
-        fields(uri = %params.text_document_position_params.text_document.uri
-            .path_segments()
-            .map(|it| it.last().unwrap_or("/"))
-            .unwrap_or("/")
-        )
-    )]
-    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
-        log::info!(
-            "hover at {}:{} in {}",
-            params.text_document_position_params.position.line,
-            params.text_document_position_params.position.character,
-            params.text_document_position_params.text_document.uri,
+/// Convert `key=value` pairs to values of `sys.inputs`.
+fn to_inputs(inputs: impl IntoIterator<Item = (String, String)>) -> Dict {
+    inputs
+        .into_iter()
+        .map(|(key, value)| (key.into(), value.into_value()))
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum QueryFormat {
+    Json,
+    Yaml,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DepsFormat {
+    /// Makefile rule with output as target.
+    Make,
+    /// JSON object with lists of files and packages.
+    Json,
+}
+
+/// Run environment checks and print findings. It fails if any check fails.
+fn doctor(
+    args: DoctorArgs,
+    packages: &typstd::config::Packages,
+) -> result::Result<(), Box<dyn Error>> {
+    let dir = args.dir.unwrap_or(env::current_dir()?);
+    let findings = doctor::run(&dir, packages);
+    for finding in findings.iter() {
+        println!(
+            "{:<7} {}: {}",
+            finding.status, finding.check, finding.message
         );
-        Ok(None)
+        if let Some(hint) = &finding.hint {
+            println!("        hint: {hint}");
+        }
     }
+    let failed = findings
+        .iter()
+        .filter(|it| it.status == doctor::Status::Error)
+        .count();
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{failed} check(s) failed").into()),
+    }
+}
 
-    #[instrument(
-        skip_all,
-        fields(uri = %params.text_document_position.text_document.uri),
-    )]
-    async fn completion(
-        &self,
-        params: CompletionParams,
-    ) -> Result<Option<CompletionResponse>> {
-        let position = params.text_document_position.position;
-        log::info!("complete at {}:{}", position.line, position.character);
+/// Create a world for a main file given on command line.
+fn load_world(
+    input: &Path,
+    root: Option<&Path>,
+) -> result::Result<LanguageServiceWorld, Box<dyn Error>> {
+    let input = env::current_dir()?.join(input);
+    let root_dir = match root {
+        Some(root_dir) => env::current_dir()?.join(root_dir),
+        None => match workspace::env_root_dir(&input) {
+            Some(root_dir) => root_dir,
+            None => input.parent().ok_or("no parent directory")?.to_path_buf(),
+        },
+    };
+    let world = LanguageServiceWorld::new(&root_dir, &input, None)
+        .ok_or_else(|| format!("failed to read {input:?}"))?;
+    Ok(world)
+}
 
-        let uri = params.text_document_position.text_document.uri;
-        let path = Path::new(uri.path());
-        let world = match self.find_world(&uri) {
-            Some((_, world)) => world,
-            None => {
-                log::error!("unable to find a world for completion");
-                return Ok(None);
+fn compile(
+    args: CompileArgs,
+    defaults: &Config,
+) -> result::Result<(), Box<dyn Error>> {
+    let mut world = load_world(&args.input, args.root.as_deref())?;
+    world
+        .set_fonts(FontProvider::shared(&defaults.font_paths, &defaults.fonts));
+    world.set_inputs(to_inputs(args.inputs));
+    let output = args.output.or(args.output_flag);
+    let export = config::Export {
+        format: args
+            .format
+            .or_else(|| {
+                output.as_deref().and_then(config::OutputFormat::from_path)
+            })
+            .unwrap_or_default(),
+        dpi: args.dpi,
+    };
+    let format = export.format();
+    let output =
+        output.unwrap_or_else(|| args.input.with_extension(format.extension()));
+    world.set_pdf_options(config::Pdf {
+        font_embedding: args.font_embedding,
+        restricted_fonts: args.restricted_fonts,
+    });
+    let result = world
+        .compile()
+        .and_then(|doc| world.export(&doc, format, &output));
+    // Paths are relative to current directory like in typst-cli.
+    let current_dir = env::current_dir()?;
+    let mut errors = 0;
+    for mut diagnostic in world.diagnostics() {
+        if let Some(path) = diagnostic.path.as_mut() {
+            if let Ok(relative) = path.strip_prefix(&current_dir) {
+                *path = relative.to_path_buf();
             }
-        };
+        }
+        if diagnostic.severity == Severity::Error {
+            errors += 1;
+        }
+        eprintln!("{diagnostic}");
+    }
+    if let (Err(_), 1..) = (&result, errors) {
+        return Err(format!("compilation failed with {errors} error(s)").into());
+    }
+    result?;
 
-        let labels = world.lock().unwrap().complete(
-            path,
-            position.line as usize,
-            position.character as usize,
-        );
-        if labels.is_empty() {
-            return Ok(None);
+    if let Some(iterations) = args.bench {
+        bench(&world, iterations)?;
+    }
+
+    let Some(format) = args.deps else {
+        return Ok(());
+    };
+    let deps = world.dependencies();
+    let content = match format {
+        DepsFormat::Make => deps.to_make(&output),
+        DepsFormat::Json => serde_json::to_string_pretty(&deps)? + "\n",
+    };
+    match args.deps_output {
+        Some(path) => std::fs::write(path, content)?,
+        None => print!("{content}"),
+    }
+    Ok(())
+}
+
+/// Compile a document and print elements which match a selector like
+/// `typst query` does.
+fn query(
+    args: QueryArgs,
+    defaults: &Config,
+) -> result::Result<(), Box<dyn Error>> {
+    let mut world = load_world(&args.input, args.root.as_deref())?;
+    world
+        .set_fonts(FontProvider::shared(&defaults.font_paths, &defaults.fonts));
+    world.set_inputs(to_inputs(args.inputs));
+    let result = world.compile();
+    for diagnostic in world.diagnostics() {
+        eprintln!("{diagnostic}");
+    }
+    result?;
+
+    let mut values = world.query(&args.selector, args.field.as_deref())?;
+    let output = match args.one {
+        true if values.len() == 1 => values.pop().unwrap_or_default(),
+        true => {
+            let count = values.len();
+            return Err(
+                format!("expected one element but found {count}").into()
+            );
         }
-        let items = labels
-            .iter()
-            .map(|el| CompletionItem {
-                label: el.label.clone(),
-                kind: Some(match el.kind {
-                    CompletionKind::Func => CompletionItemKind::FUNCTION,
-                    CompletionKind::Syntax => CompletionItemKind::SNIPPET,
-                    CompletionKind::Type => CompletionItemKind::CLASS,
-                    CompletionKind::Param => CompletionItemKind::VALUE,
-                    CompletionKind::Constant => CompletionItemKind::CONSTANT,
-                    // There is no suitable category for symbols (like
-                    // dot.circle) in language server protocol. So we decided
-                    // to map `Symbol` to `EnumMember` since set of all
-                    // symbols are is bounded and we can say that all symbols
-                    // constitutes some big enumeration. ¯\_(ツ)_/¯
-                    CompletionKind::Symbol(_) => {
-                        CompletionItemKind::ENUM_MEMBER
-                    }
-                }),
-                ..Default::default()
-            })
-            .collect();
-        Ok(Some(CompletionResponse::Array(items)))
+        false => typst::foundations::Value::Array(values.into_iter().collect()),
+    };
+    let text = match args.format {
+        QueryFormat::Json if args.pretty => {
+            serde_json::to_string_pretty(&output)? + "\n"
+        }
+        QueryFormat::Json => serde_json::to_string(&output)? + "\n",
+        QueryFormat::Yaml => serde_yaml::to_string(&output)?,
+    };
+    print!("{text}");
+    Ok(())
+}
+
+/// Compile a world several times (the first compilation is a warm-up) and
+/// report wall times and reuse of previous results.
+fn bench(
+    world: &LanguageServiceWorld,
+    iterations: usize,
+) -> result::Result<(), Box<dyn Error>> {
+    let mut timings = Vec::<Duration>::with_capacity(iterations);
+    let mut reused_pages = 0;
+    let mut reused_sources = 0;
+    let mut pages = 0;
+    let mut sources = 0;
+    for _ in 0..iterations {
+        let started_at = Instant::now();
+        world.compile()?;
+        timings.push(started_at.elapsed());
+        let stats = world.stats();
+        reused_pages += stats.reused_pages;
+        reused_sources += stats.reused_sources;
+        pages += stats.pages;
+        sources += stats.sources;
+    }
+    if timings.is_empty() {
+        return Ok(());
     }
+    timings.sort();
+    let percentile = |p: usize| timings[(timings.len() - 1) * p / 100];
+    println!("iterations: {}", timings.len());
+    println!("min:        {:?}", timings[0]);
+    println!("median:     {:?}", percentile(50));
+    println!("p95:        {:?}", percentile(95));
+    println!("max:        {:?}", timings[timings.len() - 1]);
+    println!("pages:      {reused_pages}/{pages} reused");
+    println!("sources:    {reused_sources}/{sources} reused");
+    Ok(())
 }
 
-#[derive(Parser, Debug)]
-#[clap(name = "typstd", version, author, about = "Typst language server.")]
-struct Args {
-    /// Path to log file.
-    #[arg(long)]
-    log_output: Option<String>,
+fn update_packages(args: UpdateArgs) -> result::Result<(), Box<dyn Error>> {
+    let world = load_world(&args.input, args.root.as_deref())?;
+    let index = package::fetch_index()?;
+    let updates = world.update_packages(&index)?;
+    if updates.is_empty() {
+        println!("all packages are up to date");
+    }
+    for update in updates.iter() {
+        println!("update {} to {}", update.spec, update.version);
+    }
+    if args.rewrite {
+        let edits = updates.iter().flat_map(|it| it.edits.iter());
+        apply_edits(edits.cloned().collect())?;
+    }
+    Ok(())
+}
 
-    /// Listen TCP address
-    #[arg(short, long)]
-    listen: Option<String>,
+fn prefetch_packages(args: PrefetchArgs) -> result::Result<(), Box<dyn Error>> {
+    let world = load_world(&args.input, args.root.as_deref())?;
+    let failures = package::prefetch(world.imported_packages());
+    for (spec, err) in failures.iter() {
+        eprintln!("error: failed to prefetch {spec}: {err}");
+    }
+    match failures.len() {
+        0 => Ok(()),
+        n => Err(format!("failed to download {n} package(s)").into()),
+    }
+}
+
+/// Apply text edits to files on file system.
+fn apply_edits(
+    edits: Vec<typstd::actions::TextEdit>,
+) -> result::Result<(), Box<dyn Error>> {
+    let mut files = HashMap::<PathBuf, Vec<typstd::actions::TextEdit>>::new();
+    for edit in edits {
+        files.entry(edit.path.clone()).or_default().push(edit);
+    }
+    for (path, mut edits) in files {
+        let source = Source::detached(std::fs::read_to_string(&path)?);
+        let mut text = source.text().to_string();
+        // Apply edits from the end of file in order to keep offsets valid.
+        edits.sort_by_key(|it| std::cmp::Reverse(it.start));
+        for edit in edits {
+            let offset = |(line, column)| {
+                let encoding = PositionEncoding::default();
+                syntax::position_to_byte(&source, line, column, encoding)
+            };
+            text.replace_range(
+                offset(edit.start)..offset(edit.end),
+                &edit.text,
+            );
+        }
+        std::fs::write(&path, text)?;
+    }
+    Ok(())
 }
 
 #[cfg(not(feature = "telemetry"))]
@@ -493,7 +4798,9 @@ fn init_logging(
 }
 
 #[cfg(feature = "telemetry")]
-fn init_logging() -> result::Result<(), Box<dyn Error>> {
+fn init_logging(
+    log_output: Option<String>,
+) -> result::Result<(), Box<dyn Error>> {
     let tracer = opentelemetry_otlp::new_pipeline()
         .tracing()
         .with_exporter(opentelemetry_otlp::new_exporter().tonic())
@@ -503,6 +4810,14 @@ fn init_logging() -> result::Result<(), Box<dyn Error>> {
     // Create a tracing layer with the configured tracer
     let opentelemetry = tracing_opentelemetry::layer().with_tracer(tracer);
 
+    // Export events with `histogram.` prefix (e.g. handler latencies) as
+    // metrics.
+    let meter_provider = opentelemetry_otlp::new_pipeline()
+        .metrics(opentelemetry::runtime::Tokio)
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .build()?;
+    let metrics = tracing_opentelemetry::MetricsLayer::new(meter_provider);
+
     // Parse an `EnvFilter` configuration from the `RUST_LOG`
     // environment variable.
     let filter = EnvFilter::from_env("TYPSTD_LOG")
@@ -512,6 +4827,7 @@ fn init_logging() -> result::Result<(), Box<dyn Error>> {
     // that impls `LookupSpan`
     let registry = tracing_subscriber::registry()
         .with(opentelemetry)
+        .with(metrics)
         .with(filter);
 
     match log_output {
@@ -533,17 +4849,655 @@ fn init_logging() -> result::Result<(), Box<dyn Error>> {
 #[tokio::main]
 pub async fn main() {
     let args = Args::parse();
-    if args.listen.is_some() {
-        unimplemented!("serve over listen TCP/UDP sockets and WebSocket");
+
+    // Network settings of package downloads from command line are defaults
+    // which could be overridden by client on initialization.
+    let packages = typstd::config::Packages {
+        ca_bundle: args.ca_bundle,
+        insecure: args.insecure,
+        proxy: args.proxy,
+        mirror: args.package_mirror,
+        ..Default::default()
+    };
+    if packages.insecure {
+        eprintln!("warning: TLS certificate verification is disabled");
+    }
+    if let Err(err) = package::configure(&packages) {
+        eprintln!("error: {err}");
+        std::process::exit(1);
     }
+    // Preview from command line follows opened documents unless client
+    // configures preview on its own.
+    let preview = match args.preview {
+        Some(address) => config::Preview {
+            auto: true,
+            address,
+            ..Default::default()
+        },
+        None => Default::default(),
+    };
+    let defaults = Config {
+        packages: packages.clone(),
+        preview,
+        font_paths: args.font_paths,
+        fonts: config::Fonts {
+            system: !args.ignore_system_fonts,
+            embedded: !args.ignore_embedded_fonts,
+        },
+        ..Default::default()
+    };
 
     let _ = init_logging(args.log_output);
 
+    if let Some(Command::Replay(args)) = args.command {
+        if let Err(err) = replay(args, defaults).await {
+            eprintln!("error: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(command) = args.command {
+        let result = match command {
+            Command::Compile(args) => compile(args, &defaults),
+            Command::Query(args) => query(args, &defaults),
+            Command::Packages(PackagesCommand::Update(args)) => {
+                update_packages(args)
+            }
+            Command::Packages(PackagesCommand::Prefetch(args)) => {
+                prefetch_packages(args)
+            }
+            Command::Doctor(args) => doctor(args, &packages),
+            Command::Replay(_) => unreachable!(),
+        };
+        if let Err(err) = result {
+            eprintln!("error: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(addr) = args.listen {
+        let sessions =
+            Sessions::new(args.idle_timeout.map(Duration::from_secs));
+        if let Err(err) = listen(&addr, defaults, sessions).await {
+            eprintln!("error: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
-    let (service, socket) = LspService::new(|client| TypstLanguageService {
-        client: client,
+    match args.record {
+        Some(path) => match Recorder::new(stdin, &path) {
+            Ok(stdin) => serve(stdin, stdout, defaults).await,
+            Err(err) => {
+                eprintln!("error: {err}");
+                std::process::exit(1);
+            }
+        },
+        None => serve(stdin, stdout, defaults).await,
+    }
+}
+
+/// Active sessions of a daemon. Daemon exits if there is no active session
+/// for idle timeout.
+struct Sessions {
+    active: AtomicUsize,
+    changed: tokio::sync::Notify,
+    idle_timeout: Option<Duration>,
+}
+
+impl Sessions {
+    fn new(idle_timeout: Option<Duration>) -> Arc<Self> {
+        Arc::new(Self {
+            active: AtomicUsize::new(0),
+            changed: tokio::sync::Notify::new(),
+            idle_timeout,
+        })
+    }
+
+    /// Serve a connection in its own session. All state of a session is torn
+    /// down as soon as a client disconnects.
+    fn spawn<I, O>(
+        self: &Arc<Self>,
+        peer: String,
+        input: I,
+        output: O,
+        defaults: Config,
+    ) where
+        I: AsyncRead + Unpin + Send + 'static,
+        O: AsyncWrite + Send + 'static,
+    {
+        tracing::info!("accept connection from {peer}");
+        self.active.fetch_add(1, Ordering::SeqCst);
+        self.changed.notify_one();
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            serve(input, output, defaults).await;
+            let active = sessions.active.fetch_sub(1, Ordering::SeqCst) - 1;
+            sessions.changed.notify_one();
+            tracing::info!("{peer} disconnected ({active} session(s) left)");
+        });
+    }
+
+    /// Wait until there is no active session for idle timeout. It never
+    /// completes if there is no idle timeout.
+    async fn idle(&self) {
+        let Some(timeout) = self.idle_timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            if self.active.load(Ordering::SeqCst) > 0 {
+                self.changed.notified().await;
+                continue;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(timeout) => return,
+                _ = self.changed.notified() => continue,
+            }
+        }
+    }
+
+    /// Persist reusable caches so that the next launch is fast. Downloaded
+    /// packages are already on disk.
+    fn shutdown(&self) {
+        tracing::info!("no session for {:?}: exit", self.idle_timeout);
+        if let Err(err) = FontProvider::persist_shared() {
+            tracing::warn!("failed to persist fonts: {err}");
+        }
+    }
+}
+
+/// Accept connections of editors and serve each of them in its own session.
+/// Sessions are isolated from each other but share fonts, package cache, and
+/// memoized compilation results since they live in the same process.
+async fn listen(
+    addr: &str,
+    defaults: Config,
+    sessions: Arc<Sessions>,
+) -> result::Result<(), Box<dyn Error>> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return listen_unix(Path::new(path), defaults, sessions).await;
+    }
+    if let Some(addr) = addr.strip_prefix("ws://") {
+        return listen_websocket(addr, defaults, sessions).await;
+    }
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("listen on {}", listener.local_addr()?);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        tracing::warn!("failed to accept connection: {err}");
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let (input, output) = stream.into_split();
+                sessions.spawn(peer.to_string(), input, output, defaults.clone());
+            }
+            _ = sessions.idle() => break,
+        }
+    }
+    sessions.shutdown();
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn listen_unix(
+    path: &Path,
+    defaults: Config,
+    sessions: Arc<Sessions>,
+) -> result::Result<(), Box<dyn Error>> {
+    // Socket file of a previous daemon prevents binding.
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    tracing::info!("listen on {path:?}");
+    let mut counter = 0;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        tracing::warn!("failed to accept connection: {err}");
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let (input, output) = stream.into_split();
+                counter += 1;
+                let peer = format!("{}#{counter}", path.display());
+                sessions.spawn(peer, input, output, defaults.clone());
+            }
+            _ = sessions.idle() => break,
+        }
+    }
+    sessions.shutdown();
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn listen_unix(
+    _path: &Path,
+    _defaults: Config,
+    _sessions: Arc<Sessions>,
+) -> result::Result<(), Box<dyn Error>> {
+    Err("unix domain sockets are not supported on this platform".into())
+}
+
+/// Accept WebSocket connections of browser-based editors. Each message of a
+/// socket carries a single JSON-RPC message without headers.
+async fn listen_websocket(
+    addr: &str,
+    defaults: Config,
+    sessions: Arc<Sessions>,
+) -> result::Result<(), Box<dyn Error>> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!("listen on ws://{}", listener.local_addr()?);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        tracing::warn!("failed to accept connection: {err}");
+                        tokio::time::sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let sessions = sessions.clone();
+                let defaults = defaults.clone();
+                // Handshake of a slow client should not block others and a
+                // failed handshake ends its connection only.
+                tokio::spawn(async move {
+                    match tokio_tungstenite::accept_async(stream).await {
+                        Ok(socket) => {
+                            let (input, output) = websocket_pipe(socket);
+                            let peer = format!("ws://{peer}");
+                            sessions.spawn(peer, input, output, defaults);
+                        }
+                        Err(err) => {
+                            tracing::warn!("failed to accept {peer}: {err}");
+                        }
+                    }
+                });
+            }
+            _ = sessions.idle() => break,
+        }
+    }
+    sessions.shutdown();
+    Ok(())
+}
+
+/// Convert messages of a WebSocket to a stream of framed messages (with
+/// `Content-Length` header) which language server reads and vice versa. A
+/// session ends as soon as a client closes the socket.
+fn websocket_pipe(
+    socket: WebSocketStream<tokio::net::TcpStream>,
+) -> (tokio::io::DuplexStream, tokio::io::DuplexStream) {
+    let (mut sink, mut stream) = socket.split();
+    let (mut writer, input) = tokio::io::duplex(64 << 10);
+    let (output, mut reader) = tokio::io::duplex(64 << 10);
+    tokio::spawn(async move {
+        while let Some(Ok(message)) = stream.next().await {
+            let body = match message {
+                WsMessage::Text(text) => text.into_bytes(),
+                WsMessage::Binary(bytes) => bytes,
+                WsMessage::Close(_) => break,
+                _ => continue,
+            };
+            let head = format!("Content-Length: {}\r\n\r\n", body.len());
+            if writer.write_all(head.as_bytes()).await.is_err()
+                || writer.write_all(&body).await.is_err()
+            {
+                break;
+            }
+        }
+    });
+    tokio::spawn(async move {
+        let mut chunk = vec![0; 8 << 10];
+        let mut buffer = Vec::new();
+        while let Ok(size @ 1..) = reader.read(&mut chunk).await {
+            buffer.extend_from_slice(&chunk[..size]);
+            while let Some(body) = session::next_message(&mut buffer) {
+                let text = String::from_utf8_lossy(&body).into_owned();
+                if sink.send(WsMessage::Text(text)).await.is_err() {
+                    return;
+                }
+            }
+        }
+        let _ = sink.close().await;
+    });
+    (input, output)
+}
+
+/// Feed recorded messages to language server in the recorded order and print
+/// its output. A request is fed only after the previous one has been answered
+/// (or server has sent a request which a recorded response is waiting for) so
+/// that messages are handled in the same order on each replay. Delays between
+/// messages are kept only in realtime mode.
+async fn replay(
+    args: ReplayArgs,
+    defaults: Config,
+) -> result::Result<(), Box<dyn Error>> {
+    let records = session::load(&args.session)?;
+    let (mut writer, input) = tokio::io::duplex(64 << 10);
+    let (output, mut reader) = tokio::io::duplex(64 << 10);
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+
+    // Forward server output to stdout and report which requests are answered.
+    let printer = tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        let mut chunk = vec![0; 8 << 10];
+        let mut buffer = Vec::new();
+        while let Ok(size @ 1..) = reader.read(&mut chunk).await {
+            let _ = stdout.write_all(&chunk[..size]).await;
+            let _ = stdout.flush().await;
+            buffer.extend_from_slice(&chunk[..size]);
+            while let Some(body) = session::next_message(&mut buffer) {
+                if let Ok(message) = serde_json::from_slice(&body) {
+                    let _ = tx.send(message);
+                }
+            }
+        }
+    });
+
+    let feeder = tokio::spawn(async move {
+        let started = Instant::now();
+        for record in records {
+            if args.realtime {
+                let at = started + Duration::from_millis(record.time);
+                tokio::time::sleep_until(at.into()).await;
+            }
+            if writer.write_all(&record.to_frame()).await.is_err() {
+                break;
+            }
+            if !session::is_request(&record.message) {
+                continue;
+            }
+            while let Some(message) = rx.recv().await {
+                let answered = !session::is_request(&message)
+                    && message.get("id") == record.message.get("id");
+                if answered || session::is_request(&message) {
+                    break;
+                }
+            }
+        }
+    });
+
+    serve(input, output, defaults).await;
+    feeder.await?;
+    printer.await?;
+    Ok(())
+}
+
+/// Serve a client. Defaults are settings from command line which client could
+/// override on initialization.
+async fn serve<I, O>(input: I, output: O, defaults: Config)
+where
+    I: AsyncRead + Unpin,
+    O: AsyncWrite,
+{
+    let (service, socket) = LspService::build(|client| TypstLanguageService {
+        diagnostics: DiagnosticsPublisher::new(client.clone()),
+        compilers: Compilers::new(client.clone()),
+        client,
         worlds: Default::default(),
+        config: RwLock::new(defaults),
+        features: Default::default(),
+        large_files: Default::default(),
+        versions: Default::default(),
+        documents: Default::default(),
+        chapters: Default::default(),
+        drafts: Default::default(),
+        notebooks: Default::default(),
+        projects: Default::default(),
+        associations: Default::default(),
+        orphans: Default::default(),
+        preview: Default::default(),
+        bytes: Default::default(),
+    })
+    .custom_method(
+        "typstd/dependencyGraph",
+        TypstLanguageService::dependency_graph,
+    )
+    .custom_method("typstd/introspect", TypstLanguageService::introspect)
+    .custom_method("typstd/status", TypstLanguageService::status)
+    .custom_method("typstd/setBytes", TypstLanguageService::set_bytes)
+    .custom_method(
+        "typst/documentMetrics",
+        TypstLanguageService::document_metrics,
+    )
+    .custom_method("typst/jumpToPreview", TypstLanguageService::jump_to_preview)
+    .custom_method(
+        "typst/jumpFromPreview",
+        TypstLanguageService::jump_from_preview,
+    )
+    .custom_method(
+        "notebookDocument/didOpen",
+        TypstLanguageService::did_open_notebook,
+    )
+    .custom_method(
+        "notebookDocument/didChange",
+        TypstLanguageService::did_change_notebook,
+    )
+    .custom_method(
+        "notebookDocument/didSave",
+        TypstLanguageService::did_save_notebook,
+    )
+    .custom_method(
+        "notebookDocument/didClose",
+        TypstLanguageService::did_close_notebook,
+    )
+    .finish();
+    Server::new(input, output, socket)
+        .serve(Extensions(service))
+        .await;
+}
+
+/// Extensions fill gaps of LSP types on raw messages. It marks annotated text
+/// edits of code actions as snippets (`insertTextFormat: 2`) for clients with
+/// experimental capability `snippetTextEdit` and it passes notebook
+/// capabilities of a client to `initialize` as experimental ones.
+struct Extensions<S>(S);
+
+impl<S> Service<jsonrpc::Request> for Extensions<S>
+where
+    S: Service<jsonrpc::Request, Response = Option<jsonrpc::Response>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<
+        Box<
+            dyn Future<Output = result::Result<Self::Response, Self::Error>>
+                + Send,
+        >,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<result::Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: jsonrpc::Request) -> Self::Future {
+        let code_action = request.method() == "textDocument/codeAction";
+        let request = match request.method() {
+            "initialize" => expose_notebook_capabilities(request),
+            _ => request,
+        };
+        let future = self.0.call(request);
+        Box::pin(async move {
+            Ok(match future.await? {
+                Some(response) if code_action => Some(mark_snippets(response)),
+                response => response,
+            })
+        })
+    }
+}
+
+/// Copy notebook capabilities of a client to experimental ones of
+/// `initialize` request since `ClientCapabilities` of LSP types drops them.
+fn expose_notebook_capabilities(request: jsonrpc::Request) -> jsonrpc::Request {
+    let (method, id, mut params) = request.into_parts();
+    let caps = params
+        .as_mut()
+        .and_then(|it| it.get_mut("capabilities"))
+        .and_then(|it| it.as_object_mut());
+    if let Some(caps) = caps {
+        if let Some(notebook) = caps.get("notebookDocument").cloned() {
+            let experimental = caps
+                .entry("experimental")
+                .or_insert_with(|| serde_json::json!({}));
+            if !experimental.is_object() {
+                *experimental = serde_json::json!({});
+            }
+            if let Some(experimental) = experimental.as_object_mut() {
+                experimental.insert("notebookDocument".to_string(), notebook);
+            }
+        }
+    }
+    let mut builder = jsonrpc::Request::build(method);
+    if let Some(id) = id {
+        builder = builder.id(id);
+    }
+    if let Some(params) = params {
+        builder = builder.params(params);
+    }
+    builder.finish()
+}
+
+/// Replace snippet annotations of text edits of code actions with snippet
+/// format of edits.
+fn mark_snippets(response: jsonrpc::Response) -> jsonrpc::Response {
+    let (id, result) = response.into_parts();
+    let result = result.map(|mut actions| {
+        for action in actions.as_array_mut().into_iter().flatten() {
+            let Some(edit) =
+                action.get_mut("edit").and_then(|it| it.as_object_mut())
+            else {
+                continue;
+            };
+            let annotated = edit
+                .get("changeAnnotations")
+                .is_some_and(|it| it.get(SNIPPET_ANNOTATION).is_some());
+            if !annotated {
+                continue;
+            }
+            edit.remove("changeAnnotations");
+            let changes = edit
+                .get_mut("documentChanges")
+                .and_then(|it| it.as_array_mut());
+            for change in changes.into_iter().flatten() {
+                let edits =
+                    change.get_mut("edits").and_then(|it| it.as_array_mut());
+                for text_edit in edits.into_iter().flatten() {
+                    let Some(text_edit) = text_edit.as_object_mut() else {
+                        continue;
+                    };
+                    if text_edit.remove("annotationId").is_some() {
+                        let format =
+                            serde_json::to_value(InsertTextFormat::SNIPPET);
+                        text_edit.insert(
+                            "insertTextFormat".to_string(),
+                            format.unwrap_or_default(),
+                        );
+                    }
+                }
+            }
+        }
+        actions
     });
-    Server::new(stdin, stdout, socket).serve(service).await;
+    jsonrpc::Response::from_parts(id, result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_in(path: &str, message: &str) -> diagnostics::Diagnostic {
+        diagnostics::Diagnostic {
+            path: Some(PathBuf::from(path)),
+            start: (1, 0),
+            end: (1, 4),
+            severity: Severity::Error,
+            message: message.to_string(),
+        }
+    }
+
+    fn find<'a>(report: &'a Report, uri: &Url) -> Option<&'a [Diagnostic]> {
+        report
+            .iter()
+            .find(|(it, _)| it == uri)
+            .map(|(_, diagnostics)| diagnostics.as_slice())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn included_file_diagnostics() {
+        let main = uri::from_path(Path::new("/thesis/main.typ")).unwrap();
+        let chapter = uri::from_path(Path::new("/thesis/intro.typ")).unwrap();
+        let mut reported = Reported::new();
+
+        // Diagnostic of an included file goes to the file and main file is
+        // reported without diagnostics.
+        let error = error_in("/thesis/intro.typ", "unknown variable: x");
+        let report = make_report(&main, None, vec![error]);
+        let published = update_reported(&mut reported, main.clone(), report);
+        let diagnostics = find(&published, &chapter).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "unknown variable: x");
+        assert_eq!(diagnostics[0].range.start.line, 1);
+        assert_eq!(find(&published, &main), Some(&[][..]));
+
+        // Once the file is fixed, its diagnostics are cleared.
+        let report = make_report(&main, None, vec![]);
+        let published = update_reported(&mut reported, main.clone(), report);
+        assert_eq!(find(&published, &chapter), Some(&[][..]));
+        assert_eq!(find(&published, &main), Some(&[][..]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shared_file_diagnostics() {
+        let book = uri::from_path(Path::new("/book/book.typ")).unwrap();
+        let paper = uri::from_path(Path::new("/book/paper.typ")).unwrap();
+        let common = uri::from_path(Path::new("/book/common.typ")).unwrap();
+        let mut reported = Reported::new();
+
+        // The same diagnostic of a file included by two worlds is published
+        // once and it is kept until both worlds stop reporting it.
+        let error = error_in("/book/common.typ", "expected expression");
+        for world in [&book, &paper] {
+            let report = make_report(world, None, vec![error.clone()]);
+            let published =
+                update_reported(&mut reported, world.clone(), report);
+            assert_eq!(find(&published, &common).map(|it| it.len()), Some(1));
+        }
+        let report = make_report(&book, None, vec![]);
+        let published = update_reported(&mut reported, book.clone(), report);
+        assert_eq!(find(&published, &common).map(|it| it.len()), Some(1));
+
+        reported.remove(&paper);
+        let published = union_reported(&reported, HashSet::from([common]));
+        assert!(published[0].1.is_empty());
+    }
+
+    #[test]
+    fn unreported_failure() {
+        let main = Url::parse("file:///main.typ").unwrap();
+        let failure = Some("failed to export document".to_string());
+        let report = make_report(&main, failure, vec![]);
+        let diagnostics = find(&report, &main).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "failed to export document");
+    }
 }
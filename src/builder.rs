@@ -0,0 +1,163 @@
+//! Construction of worlds.
+//!
+//! Language server configures worlds itself but other applications could
+//! embed typstd as a library which compiles Typst documents. Builder collects
+//! all settings of a world (root directory, fonts, inputs, current date,
+//! package cache, and files in memory) and fills the rest with the same
+//! defaults as typst-cli.
+//!
+//! ```no_run
+//! use typstd::builder::WorldBuilder;
+//!
+//! let world = WorldBuilder::new()
+//!     .main("docs/main.typ")
+//!     .root("docs")
+//!     .with_inputs([("version", "1.0")])
+//!     .ignore_system_fonts()
+//!     .build()
+//!     .unwrap();
+//! let document = world.compile().unwrap();
+//! ```
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use typst::foundations::{Bytes, Dict, IntoValue, Str};
+
+use crate::{config, workspace, FontProvider, LanguageServiceWorld};
+
+/// Builder of [`LanguageServiceWorld`].
+#[derive(Clone, Debug, Default)]
+pub struct WorldBuilder {
+    root: Option<PathBuf>,
+    main: Option<PathBuf>,
+    main_text: Option<String>,
+    inputs: Dict,
+    font_paths: Vec<PathBuf>,
+    fonts: config::Fonts,
+    timestamp: Option<i64>,
+    package_cache: Option<PathBuf>,
+    bytes: Vec<(PathBuf, Bytes)>,
+}
+
+impl WorldBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Root directory of a project. It defaults to `TYPST_ROOT` or to a
+    /// directory of main file.
+    pub fn root(mut self, dir: impl AsRef<Path>) -> Self {
+        self.root = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Path to main file. It is required.
+    pub fn main(mut self, path: impl AsRef<Path>) -> Self {
+        self.main = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Content of main file which is used instead of content on disk.
+    pub fn main_text(mut self, text: impl Into<String>) -> Self {
+        self.main_text = Some(text.into());
+        self
+    }
+
+    /// Add values of `sys.inputs`.
+    pub fn with_inputs<K, V>(
+        mut self,
+        inputs: impl IntoIterator<Item = (K, V)>,
+    ) -> Self
+    where
+        K: Into<Str>,
+        V: Into<Str>,
+    {
+        for (key, value) in inputs {
+            self.inputs.insert(key.into(), value.into().into_value());
+        }
+        self
+    }
+
+    /// Additional directories with fonts.
+    pub fn font_paths(
+        mut self,
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> Self {
+        let paths = paths.into_iter().map(|it| it.as_ref().to_path_buf());
+        self.font_paths.extend(paths);
+        self
+    }
+
+    /// Do not discover fonts installed in system.
+    pub fn ignore_system_fonts(mut self) -> Self {
+        self.fonts.system = false;
+        self
+    }
+
+    /// Do not use fonts embedded into typstd.
+    pub fn ignore_embedded_fonts(mut self) -> Self {
+        self.fonts.embedded = false;
+        self
+    }
+
+    /// Fix current date at UNIX timestamp (e.g. for reproducible builds).
+    /// It defaults to `SOURCE_DATE_EPOCH` or to the real date.
+    pub fn now(mut self, timestamp: i64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Download packages to a directory of this world only instead of shared
+    /// cache directories.
+    pub fn package_cache(mut self, dir: impl AsRef<Path>) -> Self {
+        self.package_cache = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Content of a file (e.g. of a data file or an image) which is used
+    /// instead of content on disk. The file does not have to exist.
+    pub fn with_bytes(
+        mut self,
+        path: impl AsRef<Path>,
+        bytes: impl Into<Bytes>,
+    ) -> Self {
+        self.bytes.push((path.as_ref().to_path_buf(), bytes.into()));
+        self
+    }
+
+    /// Make a world. It fails if main file is not set, if it could not be
+    /// read, or if it is outside of root directory.
+    pub fn build(self) -> Result<LanguageServiceWorld, String> {
+        let cwd = env::current_dir()
+            .map_err(|err| format!("failed to get current directory: {err}"))?;
+        let main_path = cwd.join(self.main.ok_or("main file is not set")?);
+        let root_dir = match self.root {
+            Some(dir) => cwd.join(dir),
+            None => match workspace::env_root_dir(&main_path) {
+                Some(dir) => dir,
+                None => main_path
+                    .parent()
+                    .ok_or("no parent directory")?
+                    .to_path_buf(),
+            },
+        };
+        let mut world =
+            LanguageServiceWorld::new(&root_dir, &main_path, self.main_text)
+                .ok_or_else(|| {
+                    format!("failed to read {main_path:?} within {root_dir:?}")
+                })?;
+        world.set_fonts(FontProvider::shared(&self.font_paths, &self.fonts));
+        if !self.inputs.is_empty() {
+            world.set_inputs(self.inputs);
+        }
+        if let Some(timestamp) = self.timestamp {
+            world.set_timestamp(Some(timestamp));
+        }
+        world.set_package_cache(self.package_cache);
+        for (path, bytes) in self.bytes {
+            world.add_bytes(&cwd.join(path), bytes);
+        }
+        Ok(world)
+    }
+}
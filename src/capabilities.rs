@@ -0,0 +1,181 @@
+//! Client capabilities.
+//!
+//! Clients differ a lot in supported features: minimal editors render neither
+//! snippets nor markdown. This module digests `ClientCapabilities` sent in
+//! `initialize` request into a flat set of flags which are consulted on
+//! advertising server capabilities and on building responses.
+
+use tower_lsp::lsp_types::{
+    ClientCapabilities, MarkupKind, PositionEncodingKind,
+};
+
+use crate::syntax::PositionEncoding;
+
+/// ClientFeatures is a digest of client capabilities. Default value
+/// corresponds to a minimal client which supports nothing but text document
+/// synchronization.
+#[derive(Clone, Debug, Default)]
+pub struct ClientFeatures {
+    /// Client supports `textDocument/completion` request.
+    pub completion: bool,
+    /// Client supports snippet syntax in completion items.
+    pub snippets: bool,
+    /// Client renders markdown in completion item documentation.
+    pub markdown_completion: bool,
+    /// Client resolves documentation of completion items lazily with
+    /// `completionItem/resolve` request.
+    pub completion_resolve: bool,
+    /// Client supports `textDocument/hover` request.
+    pub hover: bool,
+    /// Client renders markdown in hover content.
+    pub markdown_hover: bool,
+    /// Client supports `textDocument/signatureHelp` request.
+    pub signature_help: bool,
+    /// Client supports `textDocument/definition` request.
+    pub definition: bool,
+    /// Client supports `textDocument/formatting` request.
+    pub formatting: bool,
+    /// Client supports `textDocument/rangeFormatting` request.
+    pub range_formatting: bool,
+    /// Client supports `textDocument/onTypeFormatting` request.
+    pub on_type_formatting: bool,
+    /// Client supports `textDocument/linkedEditingRange` request.
+    pub linked_editing_range: bool,
+    /// Client supports `textDocument/codeAction` request.
+    pub code_action: bool,
+    /// Client accepts snippets in text edits of code actions (experimental
+    /// capability `snippetTextEdit`).
+    pub snippet_text_edit: bool,
+    /// Client supports `textDocument/inlayHint` request.
+    pub inlay_hint: bool,
+    /// Client supports `workspace/inlayHint/refresh` request.
+    pub inlay_hint_refresh: bool,
+    /// Client supports `textDocument/codeLens` request.
+    pub code_lens: bool,
+    /// Client supports `workspace/codeLens/refresh` request.
+    pub code_lens_refresh: bool,
+    /// Client supports server-initiated work done progress.
+    pub work_done_progress: bool,
+    /// Client opens documents (e.g. URLs in a browser) on request of server.
+    pub show_document: bool,
+    /// Client supports pull model of diagnostics.
+    pub pull_diagnostics: bool,
+    /// Client supports dynamic registration of watched files.
+    pub dynamic_registration: bool,
+    /// Client supports workspace folders.
+    pub workspace_folders: bool,
+    /// Client supports dynamic registration of notebook synchronization.
+    /// Notebook capabilities are missing in LSP types so that they are
+    /// passed as experimental capability `notebookDocument`.
+    pub notebook_sync: bool,
+    /// Units of columns of positions negotiated with client. UTF-8 is
+    /// preferred since it is native to sources; UTF-16 is mandatory.
+    pub position_encoding: PositionEncoding,
+}
+
+impl From<&ClientCapabilities> for ClientFeatures {
+    fn from(caps: &ClientCapabilities) -> Self {
+        let text_document = caps.text_document.as_ref();
+        let completion = text_document.and_then(|it| it.completion.as_ref());
+        let completion_item =
+            completion.and_then(|it| it.completion_item.as_ref());
+        let hover = text_document.and_then(|it| it.hover.as_ref());
+        let workspace = caps.workspace.as_ref();
+        Self {
+            completion: completion.is_some(),
+            snippets: completion_item
+                .and_then(|it| it.snippet_support)
+                .unwrap_or(false),
+            markdown_completion: completion_item
+                .and_then(|it| it.documentation_format.as_ref())
+                .is_some_and(|it| it.contains(&MarkupKind::Markdown)),
+            completion_resolve: completion_item
+                .and_then(|it| it.resolve_support.as_ref())
+                .is_some_and(|it| {
+                    it.properties.iter().any(|it| it == "documentation")
+                }),
+            hover: hover.is_some(),
+            markdown_hover: hover
+                .and_then(|it| it.content_format.as_ref())
+                .is_some_and(|it| it.contains(&MarkupKind::Markdown)),
+            signature_help: text_document
+                .and_then(|it| it.signature_help.as_ref())
+                .is_some(),
+            definition: text_document
+                .and_then(|it| it.definition.as_ref())
+                .is_some(),
+            formatting: text_document
+                .and_then(|it| it.formatting.as_ref())
+                .is_some(),
+            range_formatting: text_document
+                .and_then(|it| it.range_formatting.as_ref())
+                .is_some(),
+            on_type_formatting: text_document
+                .and_then(|it| it.on_type_formatting.as_ref())
+                .is_some(),
+            linked_editing_range: text_document
+                .and_then(|it| it.linked_editing_range.as_ref())
+                .is_some(),
+            code_action: text_document
+                .and_then(|it| it.code_action.as_ref())
+                .is_some(),
+            snippet_text_edit: caps
+                .experimental
+                .as_ref()
+                .and_then(|it| it.get("snippetTextEdit"))
+                .and_then(|it| it.as_bool())
+                .unwrap_or(false),
+            inlay_hint: text_document
+                .and_then(|it| it.inlay_hint.as_ref())
+                .is_some(),
+            inlay_hint_refresh: workspace
+                .and_then(|it| it.inlay_hint.as_ref())
+                .and_then(|it| it.refresh_support)
+                .unwrap_or(false),
+            code_lens: text_document
+                .and_then(|it| it.code_lens.as_ref())
+                .is_some(),
+            code_lens_refresh: workspace
+                .and_then(|it| it.code_lens.as_ref())
+                .and_then(|it| it.refresh_support)
+                .unwrap_or(false),
+            work_done_progress: caps
+                .window
+                .as_ref()
+                .and_then(|it| it.work_done_progress)
+                .unwrap_or(false),
+            show_document: caps
+                .window
+                .as_ref()
+                .and_then(|it| it.show_document.as_ref())
+                .is_some_and(|it| it.support),
+            pull_diagnostics: text_document
+                .and_then(|it| it.diagnostic.as_ref())
+                .is_some(),
+            dynamic_registration: workspace
+                .and_then(|it| it.did_change_watched_files.as_ref())
+                .and_then(|it| it.dynamic_registration)
+                .unwrap_or(false),
+            workspace_folders: workspace
+                .and_then(|it| it.workspace_folders)
+                .unwrap_or(false),
+            notebook_sync: caps
+                .experimental
+                .as_ref()
+                .and_then(|it| it.pointer("/notebookDocument/synchronization"))
+                .and_then(|it| it.get("dynamicRegistration"))
+                .and_then(|it| it.as_bool())
+                .unwrap_or(false),
+            position_encoding: caps
+                .general
+                .as_ref()
+                .and_then(|it| it.position_encodings.as_ref())
+                .map_or(PositionEncoding::Utf16, |encodings| {
+                    match encodings.contains(&PositionEncodingKind::UTF8) {
+                        true => PositionEncoding::Utf8,
+                        false => PositionEncoding::Utf16,
+                    }
+                }),
+        }
+    }
+}
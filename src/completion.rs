@@ -0,0 +1,273 @@
+//! Completion of paths and packages in string literals.
+//!
+//! Paths are completed in `#import`, `#include`, and `#image` strings. Like
+//! in Typst, relative paths are relative to a source file and absolute paths
+//! are relative to root directory. Only directories and files with suitable
+//! extensions are suggested. Paths outside of root directory are not
+//! completed.
+//!
+//! Package specs (e.g. `@preview/cetz:0.2.2`) are completed in `#import`
+//! strings part by part: namespaces, names, and versions. Packages come from
+//! local directories and from package index of the default namespace if it
+//! has been fetched.
+
+use std::cmp::Reverse;
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use typst::syntax::ast::AstNode;
+use typst::syntax::{ast, LinkedNode, Source, SyntaxKind, VirtualPath};
+use typst_ide::CompletionKind;
+
+use crate::package::{self, NAMESPACE};
+
+/// Extensions of files which are suggested for imports and includes.
+const SOURCE_EXTENSIONS: &[&str] = &["typ"];
+
+/// Extensions of files which are suggested for images.
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "svg"];
+
+/// Kind of a completion item.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    /// Items which are suggested by typst-ide.
+    Ide(CompletionKind),
+    File,
+    Folder,
+    Package,
+    Version,
+    /// Labels and keys of bibliography entries.
+    Reference,
+}
+
+/// Items which complete a part of a string literal under cursor.
+#[derive(Clone, Debug)]
+pub struct LiteralCompletion {
+    /// Byte range of a part of a literal which is replaced (e.g. the last
+    /// component of a path).
+    pub range: Range<usize>,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub label: String,
+    pub kind: Kind,
+    /// Text to insert if it differs from label.
+    pub apply: Option<String>,
+    pub detail: Option<String>,
+}
+
+impl Entry {
+    fn new(label: String, kind: Kind) -> Self {
+        Self {
+            label,
+            kind,
+            apply: None,
+            detail: None,
+        }
+    }
+}
+
+/// Complete a path or a package spec in a string literal under cursor.
+/// Package index is used for package specs if `remote` is set.
+pub fn complete_literal(
+    root: &Path,
+    source: &Source,
+    cursor: usize,
+    remote: bool,
+) -> Option<LiteralCompletion> {
+    let leaf = LinkedNode::new(source.root()).leaf_at(cursor)?;
+    if leaf.kind() != SyntaxKind::Str {
+        return None;
+    }
+    let extensions = path_extensions(&leaf)?;
+
+    // Cursor must be between quotes.
+    let text = leaf.text().as_str();
+    let start = leaf.offset() + 1;
+    let end = leaf.offset() + text.len().max(2) - 1;
+    if cursor < start || cursor > end || !text.ends_with('"') {
+        return None;
+    }
+    let typed = &source.text()[start..cursor];
+    match typed.strip_prefix('@') {
+        Some(spec) if extensions == SOURCE_EXTENSIONS => {
+            complete_package(spec, cursor, remote)
+        }
+        Some(_) => None,
+        None => complete_path(root, source, typed, cursor, extensions),
+    }
+}
+
+/// Complete a path relative to a source.
+fn complete_path(
+    root: &Path,
+    source: &Source,
+    typed: &str,
+    cursor: usize,
+    extensions: &[&str],
+) -> Option<LiteralCompletion> {
+    // Paths inside of packages are relative to a package root.
+    if source.id().package().is_some() || typed.contains('\\') {
+        return None;
+    }
+
+    // Directory part of a typed path ends with the last slash.
+    let (dir, prefix) = match typed.rfind('/') {
+        Some(index) => typed.split_at(index + 1),
+        None => ("", typed),
+    };
+    let dir = match dir.strip_prefix('/') {
+        Some(_) => VirtualPath::new(dir),
+        None => source.id().vpath().join(dir),
+    };
+    let dir = dir.resolve(root)?;
+    let current = source.id().vpath().resolve(root);
+
+    let mut entries = fs::read_dir(&dir)
+        .ok()?
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let name = entry.file_name().to_str()?.to_string();
+            if name.starts_with('.') || !name.starts_with(prefix) {
+                return None;
+            }
+            let path = entry.path();
+            if path.is_dir() {
+                return Some(Entry::new(format!("{name}/"), Kind::Folder));
+            }
+            let ext = path.extension()?.to_str()?.to_lowercase();
+            if !extensions.contains(&ext.as_str()) {
+                return None;
+            }
+            // Source file does not import itself.
+            if current.as_ref() == Some(&path) {
+                return None;
+            }
+            Some(Entry::new(name, Kind::File))
+        })
+        .collect::<Vec<_>>();
+    entries.sort_by(|lhs, rhs| {
+        let lhs_file = matches!(lhs.kind, Kind::File);
+        let rhs_file = matches!(rhs.kind, Kind::File);
+        (lhs_file, &lhs.label).cmp(&(rhs_file, &rhs.label))
+    });
+    Some(LiteralCompletion {
+        range: cursor - prefix.len()..cursor,
+        entries,
+    })
+}
+
+/// Complete a part of package spec (without leading `@`) under cursor.
+fn complete_package(
+    typed: &str,
+    cursor: usize,
+    remote: bool,
+) -> Option<LiteralCompletion> {
+    // Packages of default namespace from index have descriptions.
+    let mut packages = package::local_packages()
+        .into_iter()
+        .map(|spec| {
+            (
+                spec.namespace.to_string(),
+                spec.name.to_string(),
+                spec.version,
+                None,
+            )
+        })
+        .collect::<Vec<_>>();
+    if remote {
+        packages.extend(package::cached_index().iter().map(|it| {
+            let description = it.description.clone();
+            (
+                NAMESPACE.to_string(),
+                it.name.clone(),
+                it.version,
+                description,
+            )
+        }));
+    }
+
+    let (prefix, entries) = match typed.split_once('/') {
+        None => {
+            let mut namespaces = packages
+                .iter()
+                .map(|(namespace, ..)| namespace.as_str())
+                .chain(Some(NAMESPACE))
+                .collect::<Vec<_>>();
+            namespaces.sort();
+            namespaces.dedup();
+            let entries = namespaces
+                .into_iter()
+                .map(|it| Entry::new(format!("{it}/"), Kind::Folder))
+                .collect();
+            (typed, entries)
+        }
+        Some((namespace, rest)) => match rest.split_once(':') {
+            // The latest version is inserted together with a name.
+            None => {
+                packages.retain(|it| it.0 == namespace);
+                packages
+                    .sort_by(|lhs, rhs| (&lhs.1, rhs.2).cmp(&(&rhs.1, lhs.2)));
+                packages.dedup_by(|lhs, rhs| lhs.1 == rhs.1);
+                let entries = packages
+                    .into_iter()
+                    .map(|(_, name, version, description)| Entry {
+                        apply: Some(format!("{name}:{version}")),
+                        label: name,
+                        kind: Kind::Package,
+                        detail: description,
+                    })
+                    .collect();
+                (rest, entries)
+            }
+            Some((name, version)) => {
+                packages.retain(|it| it.0 == namespace && it.1 == name);
+                packages.sort_by_key(|it| Reverse(it.2));
+                packages.dedup_by(|lhs, rhs| lhs.2 == rhs.2);
+                let entries = packages
+                    .into_iter()
+                    .map(|(.., version, _)| {
+                        Entry::new(version.to_string(), Kind::Version)
+                    })
+                    .collect();
+                (version, entries)
+            }
+        },
+    };
+    Some(LiteralCompletion {
+        range: cursor - prefix.len()..cursor,
+        entries,
+    })
+}
+
+/// Extensions of files which a string literal could refer to.
+fn path_extensions(leaf: &LinkedNode) -> Option<&'static [&'static str]> {
+    let parent = leaf.parent()?;
+    if let Some(import) = parent.cast::<ast::ModuleImport>() {
+        return (import.source().span() == leaf.span())
+            .then_some(SOURCE_EXTENSIONS);
+    }
+    if let Some(include) = parent.cast::<ast::ModuleInclude>() {
+        return (include.source().span() == leaf.span())
+            .then_some(SOURCE_EXTENSIONS);
+    }
+
+    // The first positional argument of `image` is a path.
+    let call = parent.parent()?.cast::<ast::FuncCall>()?;
+    let ast::Expr::Ident(callee) = call.callee() else {
+        return None;
+    };
+    if callee.get() != "image" {
+        return None;
+    }
+    let path = call.args().items().find_map(|it| match it {
+        ast::Arg::Pos(expr) => Some(expr),
+        _ => None,
+    })?;
+    (path.span() == leaf.span()).then_some(IMAGE_EXTENSIONS)
+}
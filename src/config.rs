@@ -0,0 +1,555 @@
+//! Language server configuration.
+//!
+//! Configuration is provided by a client as `initializationOptions` in
+//! `initialize` request. All fields are optional and fallback to defaults.
+//! Settings which editor plugins of typst-lsp and tinymist send (`exportPdf`,
+//! `rootPath`, `fontPaths`, and `typstExtraArgs`) are understood as well.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::diagnostics::Severity;
+use crate::workspace;
+
+/// Filename of project settings.
+pub static PROJECT_FILENAME: &str = ".typstd.toml";
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Config {
+    /// Limits on text documents beyond which expensive features are off.
+    pub limits: Limits,
+    /// Settings of completion verbosity.
+    pub completion: Completion,
+    /// Settings of package downloads.
+    pub packages: Packages,
+    /// Settings of inlay hints.
+    pub inlay_hints: InlayHints,
+    /// Settings of diagnostics on changes.
+    pub diagnostics: Diagnostics,
+    /// Settings of code formatting.
+    pub format: Format,
+    /// Settings of PDF export.
+    pub pdf: Pdf,
+    /// Output format of exported documents.
+    pub export: Export,
+    /// Settings of live preview.
+    pub preview: Preview,
+    /// When to export PDF.
+    pub export_pdf: ExportPdf,
+    /// Directory of output files relative to root directory of a document
+    /// (unless absolute). Output files are placed next to main files if it is
+    /// not set.
+    pub output_dir: Option<PathBuf>,
+    /// Root directory of documents outside of workspaces.
+    pub root_path: Option<PathBuf>,
+    /// Main file of documents in workspace folders without `typst.toml`
+    /// (relative to a folder unless absolute). Documents next to it and in
+    /// its subdirectories are compiled as its parts.
+    pub main_file: Option<PathBuf>,
+    /// Additional directories with fonts.
+    pub font_paths: Vec<PathBuf>,
+    /// Sources of fonts besides additional directories.
+    pub fonts: Fonts,
+    /// UNIX timestamp which fixes current date of documents (e.g. for
+    /// reproducible builds). It defaults to `SOURCE_DATE_EPOCH`.
+    pub creation_timestamp: Option<i64>,
+    /// Values of `sys.inputs` of documents.
+    pub inputs: BTreeMap<String, String>,
+    /// Command line arguments of typst-cli. Only `--root`, `--font-path`,
+    /// and `--input` are respected.
+    pub typst_extra_args: Vec<String>,
+}
+
+impl Config {
+    /// Parse configuration from `initializationOptions` or from settings of
+    /// `workspace/didChangeConfiguration` which are possibly nested into
+    /// `typstd` section.
+    pub fn from_value(mut value: serde_json::Value) -> Result<Self, String> {
+        if let Some(section) = value.get_mut("typstd").map(|it| it.take()) {
+            value = section;
+        }
+        let mut config = serde_json::from_value::<Self>(value)
+            .map_err(|err| format!("failed to parse config: {err}"))?;
+        config.apply_extra_args();
+        Ok(config)
+    }
+
+    /// Move `--root`, `--font-path`, and `--input` options from typst-cli
+    /// arguments to settings. Explicit `rootPath` and `inputs` take
+    /// precedence.
+    fn apply_extra_args(&mut self) {
+        let mut args = self.typst_extra_args.iter();
+        while let Some(arg) = args.next() {
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.to_string())),
+                None => (arg.as_str(), None),
+            };
+            let mut value = || inline.clone().or_else(|| args.next().cloned());
+            match name {
+                "--root" => {
+                    let root_path = value().map(PathBuf::from);
+                    self.root_path = self.root_path.take().or(root_path);
+                }
+                "--font-path" => self.font_paths.extend(
+                    value().into_iter().flat_map(|paths| {
+                        std::env::split_paths(&paths).collect::<Vec<_>>()
+                    }),
+                ),
+                "--input" => {
+                    let Some((key, value)) =
+                        value().and_then(|it| parse_input(&it))
+                    else {
+                        log::warn!("ignore malformed typst argument {}", arg);
+                        continue;
+                    };
+                    self.inputs.entry(key).or_insert(value);
+                }
+                _ => log::debug!("ignore typst argument {}", arg),
+            }
+        }
+    }
+}
+
+/// Settings of font discovery. Fonts of additional directories are always
+/// used.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Fonts {
+    /// Discover fonts installed in system.
+    pub system: bool,
+    /// Use fonts embedded into language server (Linux Libertine, New Computer
+    /// Modern, and DejaVu Sans Mono) as typst-cli does.
+    pub embedded: bool,
+}
+
+impl Default for Fonts {
+    fn default() -> Self {
+        Self {
+            system: true,
+            embedded: true,
+        }
+    }
+}
+
+/// Project settings from `.typstd.toml` (or from `[tool.typstd]` section of
+/// `typst.toml`) next to `typst.toml`. They override client settings for
+/// documents of a project.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ProjectConfig {
+    /// When to export PDF.
+    pub export_pdf: Option<ExportPdf>,
+    /// Directory of output PDF files relative to project directory.
+    pub output_dir: Option<PathBuf>,
+    /// Values of `sys.inputs` of documents which extend and override client
+    /// settings.
+    pub inputs: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Manifest {
+    #[serde(default)]
+    tool: Tool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Tool {
+    typstd: Option<ProjectConfig>,
+}
+
+impl ProjectConfig {
+    /// Load project settings from a project directory. Settings from
+    /// `.typstd.toml` take precedence over `[tool.typstd]` section.
+    pub fn load(dir: &Path) -> Result<Option<Self>, String> {
+        let path = dir.join(PROJECT_FILENAME);
+        if let Some(text) = read_to_string(&path)? {
+            return toml::from_str(&text).map(Some).map_err(|err| {
+                format!("failed to parse toml at {path:?}: {err}")
+            });
+        }
+        let path = dir.join(workspace::FILENAME);
+        let Some(text) = read_to_string(&path)? else {
+            return Ok(None);
+        };
+        toml::from_str::<Manifest>(&text)
+            .map(|manifest| manifest.tool.typstd)
+            .map_err(|err| format!("failed to parse toml at {path:?}: {err}"))
+    }
+}
+
+/// Parse a value of `sys.inputs` given as `key=value` pair.
+pub fn parse_input(pair: &str) -> Option<(String, String)> {
+    let (key, value) = pair.split_once('=')?;
+    let key = key.trim();
+    match key.is_empty() {
+        true => None,
+        false => Some((key.to_string(), value.to_string())),
+    }
+}
+
+fn read_to_string(path: &Path) -> Result<Option<String>, String> {
+    match fs::read_to_string(path) {
+        Ok(text) => Ok(Some(text)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(format!("failed to read {path:?}: {err}")),
+    }
+}
+
+/// When to compile a document with PDF export. Value `onDocumentHasTitle` of
+/// tinymist is treated as `onSave`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ExportPdf {
+    Never,
+    #[default]
+    #[serde(alias = "onDocumentHasTitle")]
+    OnSave,
+    OnType,
+}
+
+/// Output format of exported documents. Pages of SVG and PNG documents are
+/// written to separate files if output path contains `{n}` placeholder (e.g.
+/// `main-{n}.png`) and they are merged into a single image otherwise.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Export {
+    pub format: OutputFormat,
+    /// Resolution of PNG images in pixels per inch.
+    pub dpi: f32,
+}
+
+impl Default for Export {
+    fn default() -> Self {
+        Self {
+            format: OutputFormat::Pdf,
+            dpi: 144.0,
+        }
+    }
+}
+
+impl Export {
+    pub fn format(&self) -> ExportFormat {
+        match self.format {
+            OutputFormat::Pdf => ExportFormat::Pdf,
+            OutputFormat::Svg => ExportFormat::Svg,
+            OutputFormat::Png => ExportFormat::Png { dpi: self.dpi },
+        }
+    }
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum OutputFormat {
+    #[default]
+    Pdf,
+    Svg,
+    Png,
+}
+
+impl OutputFormat {
+    /// Guess output format from extension of a path.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "pdf" => Some(Self::Pdf),
+            "svg" => Some(Self::Svg),
+            "png" => Some(Self::Png),
+            _ => None,
+        }
+    }
+}
+
+/// Format of exported document with its parameters.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ExportFormat {
+    #[default]
+    Pdf,
+    Svg,
+    Png {
+        dpi: f32,
+    },
+}
+
+impl ExportFormat {
+    /// Extension of output files.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Pdf => "pdf",
+            Self::Svg => "svg",
+            Self::Png { .. } => "png",
+        }
+    }
+}
+
+/// Settings of live preview. Pages are rendered as SVG unless format is PNG.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Preview {
+    /// Start preview of a document as soon as it is opened.
+    pub auto: bool,
+    /// Address of preview server (a random port by default).
+    pub address: String,
+    pub format: OutputFormat,
+    /// Resolution of PNG images in pixels per inch.
+    pub dpi: f32,
+}
+
+impl Default for Preview {
+    fn default() -> Self {
+        Self {
+            auto: false,
+            address: "127.0.0.1:0".to_string(),
+            format: OutputFormat::Svg,
+            dpi: 144.0,
+        }
+    }
+}
+
+impl Preview {
+    pub fn format(&self) -> ExportFormat {
+        match self.format {
+            OutputFormat::Pdf | OutputFormat::Svg => ExportFormat::Svg,
+            OutputFormat::Png => ExportFormat::Png { dpi: self.dpi },
+        }
+    }
+}
+
+/// Settings of fonts in exported PDF.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Pdf {
+    /// Embed complete fonts or subsets of used glyphs.
+    pub font_embedding: FontEmbedding,
+    /// What to do with fonts which licenses restrict embedding.
+    pub restricted_fonts: RestrictedFonts,
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum FontEmbedding {
+    /// Embed only glyphs used by a document.
+    #[default]
+    Subset,
+    /// Embed complete fonts (not supported by PDF exporter yet).
+    Full,
+}
+
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, clap::ValueEnum,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum RestrictedFonts {
+    /// Do not check font licenses.
+    Ignore,
+    /// Report license violations as warnings.
+    #[default]
+    Warn,
+    /// Refuse to export PDF.
+    Error,
+}
+
+/// Settings of HTTP client which downloads packages. Corporate proxies often
+/// intercept TLS connections with their own certificates which are unknown to
+/// the client.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Packages {
+    /// Path to PEM file with additional CA certificates.
+    pub ca_bundle: Option<PathBuf>,
+    /// Disable TLS certificate verification. It is insecure!
+    pub insecure: bool,
+    /// Proxy of package downloads (e.g. `http://proxy.local:3128`). It takes
+    /// precedence over `HTTPS_PROXY` and other environment variables.
+    pub proxy: Option<String>,
+    /// URL of a mirror of package registry which is used instead of
+    /// `https://packages.typst.org`. It must have the same layout.
+    pub mirror: Option<String>,
+    /// Timeout of establishing connection in seconds.
+    pub connect_timeout: u64,
+    /// Timeout of reading response in seconds.
+    pub read_timeout: u64,
+    /// Maximal size of extracted package in bytes. Zero means no limit.
+    pub max_size: u64,
+    /// Download all imported packages on startup.
+    pub prefetch: bool,
+    /// Record versions of packages used by compilations to `typst.lock`.
+    /// Otherwise, lockfile is written by command `typstd.packages.update`
+    /// only.
+    pub lock: bool,
+    /// Directories of packages under development. They are imported as
+    /// `@local/name:version` with name and version from their `typst.toml`.
+    pub paths: Vec<PathBuf>,
+    /// Cache directory where downloaded packages are stored. Packages are
+    /// looked up in cache directories of both layouts anyway.
+    pub layout: PackageLayout,
+}
+
+/// Layout of cache directory of downloaded packages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PackageLayout {
+    /// Directory `typstd/packages` in user cache directory.
+    #[default]
+    Typstd,
+    /// Directory `typst/packages` in user cache directory which typst-cli
+    /// uses, so that packages are downloaded once for both.
+    Typst,
+}
+
+impl Default for Packages {
+    fn default() -> Self {
+        Self {
+            ca_bundle: None,
+            insecure: false,
+            proxy: None,
+            mirror: None,
+            connect_timeout: 10,
+            read_timeout: 60,
+            max_size: 64 << 20,
+            prefetch: false,
+            lock: false,
+            paths: Vec::new(),
+            layout: PackageLayout::Typstd,
+        }
+    }
+}
+
+/// Settings which control amount of completion items. Different editors and
+/// users have very different tolerance for long completion lists.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Completion {
+    /// Suggest symbols (e.g. `arrow.r`) in math mode only.
+    pub symbols_in_math_only: bool,
+    /// Suggest snippet-style syntax completions (e.g. `for` loop).
+    pub syntax: bool,
+    /// Maximal number of completion items. Zero means no limit.
+    pub max_items: usize,
+    /// Suggest packages of the default namespace from remote package index.
+    pub package_index: bool,
+}
+
+impl Default for Completion {
+    fn default() -> Self {
+        Self {
+            symbols_in_math_only: false,
+            syntax: true,
+            max_items: 0,
+            package_index: true,
+        }
+    }
+}
+
+/// Settings of diagnostics which are published while typing. A document is
+/// compiled in draft mode after a pause in changes.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Diagnostics {
+    /// Pause after the last change before compilation in milliseconds.
+    pub debounce: u64,
+    /// Compile documents on changes. Otherwise, they are compiled on open and
+    /// on save only.
+    pub on_change: bool,
+    /// The least severity of published diagnostics (e.g. `warning` hides
+    /// hints).
+    pub level: Severity,
+    /// Hint at imports of packages which have newer versions. Package index
+    /// is fetched for that.
+    pub package_updates: bool,
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self {
+            debounce: 300,
+            on_change: true,
+            level: Severity::Hint,
+            package_updates: true,
+        }
+    }
+}
+
+/// Settings of code formatting. Indentation is taken from formatting requests
+/// of a client.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Format {
+    /// Lines longer than this are broken at arguments of calls.
+    pub line_width: usize,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Self { line_width: 80 }
+    }
+}
+
+/// Settings of inlay hints. They are off by default since hints are noisy.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct InlayHints {
+    /// Show evaluated values of simple `#let` bindings.
+    pub let_values: bool,
+    /// Show names of parameters at positional arguments of calls.
+    pub parameter_names: bool,
+}
+
+impl InlayHints {
+    /// Whether any kind of hints is enabled.
+    pub fn enabled(&self) -> bool {
+        self.let_values || self.parameter_names
+    }
+}
+
+/// Limits on size of text documents. Generated data files could easily be
+/// several megabytes in size and reparsing them on every keystroke freezes
+/// language server. Zero means no limit.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Limits {
+    /// Maximal size of a text document in bytes.
+    pub max_file_size: usize,
+    /// Maximal number of lines in a text document.
+    pub max_line_count: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_file_size: 1 << 20,
+            max_line_count: 20_000,
+        }
+    }
+}
+
+impl Limits {
+    /// Check text against limits and return description of the first
+    /// violated one.
+    pub fn check(&self, text: &str) -> Result<(), String> {
+        if self.max_file_size > 0 && text.len() > self.max_file_size {
+            return Err(format!(
+                "file size {} exceeds limit of {} bytes",
+                text.len(),
+                self.max_file_size
+            ));
+        }
+        if self.max_line_count > 0 {
+            let line_count = text.lines().count();
+            if line_count > self.max_line_count {
+                return Err(format!(
+                    "line count {} exceeds limit of {} lines",
+                    line_count, self.max_line_count
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//! Word and character counts.
+//!
+//! Counts are taken from text laid out in a compiled document so that
+//! markup, code, and comments are not counted while text produced by code
+//! (e.g. headings numbering or bibliography) is. Text runs are separated
+//! by whitespace so that a word which is split across lines or styles is
+//! counted more than once: counts are approximate.
+//!
+//! Text is attributed to sections by positions on pages: a section spans from
+//! its heading to the next heading of any level.
+
+use serde::Serialize;
+use typst::foundations::Value;
+use typst::layout::{Frame, FrameItem, Point};
+use typst::model::Document;
+
+use crate::introspect::plain_text;
+
+/// Number of words and characters in a text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Count {
+    pub words: usize,
+    /// Characters excluding whitespace.
+    pub characters: usize,
+}
+
+impl Count {
+    /// Count words (runs of characters separated by whitespace which contain
+    /// at least one alphanumeric character) and characters of a text.
+    pub fn of(text: &str) -> Self {
+        let words = text
+            .split_whitespace()
+            .filter(|it| it.chars().any(char::is_alphanumeric))
+            .count();
+        let characters = text.chars().filter(|it| !it.is_whitespace()).count();
+        Self { words, characters }
+    }
+}
+
+impl std::ops::AddAssign for Count {
+    fn add_assign(&mut self, other: Self) {
+        self.words += other.words;
+        self.characters += other.characters;
+    }
+}
+
+/// Length of a compiled document for writers tracking length targets.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DocumentMetrics {
+    #[serde(flatten)]
+    pub count: Count,
+    pub pages: usize,
+    pub headings: usize,
+    /// Sections in document order. Text before the first heading is not a
+    /// part of any section.
+    pub sections: Vec<SectionMetrics>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SectionMetrics {
+    pub level: usize,
+    pub heading: String,
+    /// Page (starting at 1) where a section begins.
+    pub page: usize,
+    #[serde(flatten)]
+    pub count: Count,
+}
+
+/// Count words and characters on all pages of a document.
+pub fn count_document(doc: &Document) -> Count {
+    let mut count = Count::default();
+    for page in doc.pages.iter() {
+        visit_text(&page.frame, Point::zero(), &mut |_, text| {
+            count += Count::of(text);
+        });
+    }
+    count
+}
+
+/// Count words and characters of a document and of each of its sections.
+pub fn document_metrics(doc: &Document) -> DocumentMetrics {
+    let mut starts = Vec::new();
+    let mut sections = Vec::new();
+    for content in doc.introspector.all() {
+        if content.elem().name() != "heading" {
+            continue;
+        }
+        let Some(location) = content.location() else {
+            continue;
+        };
+        let position = doc.introspector.position(location);
+        let page = position.page.get();
+        starts.push((page, position.point.y.to_pt()));
+        sections.push(SectionMetrics {
+            level: match content.get_by_name("level") {
+                Some(Value::Int(level)) => level as usize,
+                _ => 1,
+            },
+            heading: plain_text(content.get_by_name("body")),
+            page,
+            count: Count::default(),
+        });
+    }
+
+    let mut count = Count::default();
+    for (index, page) in doc.pages.iter().enumerate() {
+        visit_text(&page.frame, Point::zero(), &mut |point, text| {
+            let text = Count::of(text);
+            count += text;
+            // Text of a heading is below its position since baseline is
+            // below the top of heading.
+            let position = (index + 1, point.y.to_pt());
+            let section = starts.partition_point(|it| *it <= position);
+            if let Some(section) = section.checked_sub(1) {
+                sections[section].count += text;
+            }
+        });
+    }
+    DocumentMetrics {
+        count,
+        pages: doc.pages.len(),
+        headings: sections.len(),
+        sections,
+    }
+}
+
+/// Visit text items of a frame with their positions relative to the origin
+/// of a page. Transformations of groups are ignored.
+fn visit_text(
+    frame: &Frame,
+    origin: Point,
+    visit: &mut impl FnMut(Point, &str),
+) {
+    for (point, item) in frame.items() {
+        match item {
+            FrameItem::Group(group) => {
+                visit_text(&group.frame, origin + *point, visit)
+            }
+            FrameItem::Text(text) => visit(origin + *point, &text.text),
+            _ => {}
+        }
+    }
+}
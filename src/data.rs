@@ -0,0 +1,158 @@
+//! Summaries of data files.
+//!
+//! Hovering a path argument of `#csv`, `#json`, or `#yaml` shows a brief
+//! summary of the file (number of rows and columns, top-level keys, and the
+//! first record) so that one could check what is loaded without opening it.
+
+use typst::syntax::ast::AstNode;
+use typst::syntax::{ast, LinkedNode, Source, SyntaxKind};
+
+/// Maximal number of keys and length of a record in a summary.
+const MAX_KEYS: usize = 10;
+const MAX_RECORD_LEN: usize = 120;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    fn from_func(name: &str) -> Option<Self> {
+        match name {
+            "csv" => Some(Self::Csv),
+            "json" => Some(Self::Json),
+            "yaml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Csv => "CSV",
+            Self::Json => "JSON",
+            Self::Yaml => "YAML",
+        }
+    }
+}
+
+/// Find a path argument of a data loading function at cursor.
+pub fn data_path_at(
+    source: &Source,
+    cursor: usize,
+) -> Option<(Format, String)> {
+    let (callee, path) = path_arg_at(source, cursor)?;
+    Some((Format::from_func(&callee)?, path))
+}
+
+/// Find a path argument of a function call at cursor and return a name of a
+/// function and a path.
+pub(crate) fn path_arg_at(
+    source: &Source,
+    cursor: usize,
+) -> Option<(String, String)> {
+    let leaf = LinkedNode::new(source.root()).leaf_at(cursor)?;
+    let string = leaf.cast::<ast::Str>()?;
+    let args = leaf.parent().filter(|it| it.kind() == SyntaxKind::Args)?;
+    let call = args.parent()?.cast::<ast::FuncCall>()?;
+    let ast::Expr::Ident(callee) = call.callee() else {
+        return None;
+    };
+
+    // Only the first positional argument is a path.
+    let first = call.args().items().find_map(|arg| match arg {
+        ast::Arg::Pos(expr) => Some(expr),
+        _ => None,
+    })?;
+    if first.span() != string.span() {
+        return None;
+    }
+    Some((callee.get().to_string(), string.get().to_string()))
+}
+
+/// Summarize content of a data file in Markdown.
+pub fn summarize(
+    format: Format,
+    path: &str,
+    bytes: &[u8],
+) -> Result<String, String> {
+    let body = match format {
+        Format::Csv => summarize_csv(bytes)?,
+        Format::Json => {
+            let value = serde_json::from_slice::<serde_json::Value>(bytes)
+                .map_err(|err| format!("failed to parse JSON: {err}"))?;
+            summarize_value(&value)
+        }
+        Format::Yaml => {
+            let value = serde_yaml::from_slice::<serde_json::Value>(bytes)
+                .map_err(|err| format!("failed to parse YAML: {err}"))?;
+            summarize_value(&value)
+        }
+    };
+    Ok(format!("**{}** `{path}`: {body}", format.name()))
+}
+
+fn summarize_csv(bytes: &[u8]) -> Result<String, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(bytes);
+    let mut rows = 0;
+    let mut columns = 0;
+    let mut first = None;
+    for record in reader.records() {
+        let record =
+            record.map_err(|err| format!("failed to parse CSV: {err}"))?;
+        rows += 1;
+        columns = columns.max(record.len());
+        if first.is_none() {
+            first = Some(record.iter().collect::<Vec<_>>().join(", "));
+        }
+    }
+    let mut summary = format!("{rows} row(s) × {columns} column(s)");
+    if let Some(first) = first {
+        summary.push_str(&format!("\n\nFirst record: `{}`", truncate(first)));
+    }
+    Ok(summary)
+}
+
+fn summarize_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Object(map) => {
+            format!("dictionary with {} key(s): {}", map.len(), keys(map))
+        }
+        serde_json::Value::Array(items) => {
+            let mut summary = format!("array of {} item(s)", items.len());
+            if let Some(first) = items.first() {
+                summary.push_str(&format!(
+                    "\n\nFirst record: `{}`",
+                    truncate(first.to_string())
+                ));
+            }
+            summary
+        }
+        value => format!("`{}`", truncate(value.to_string())),
+    }
+}
+
+fn keys(map: &serde_json::Map<String, serde_json::Value>) -> String {
+    let mut keys = map
+        .keys()
+        .take(MAX_KEYS)
+        .map(|key| format!("`{key}`"))
+        .collect::<Vec<_>>();
+    if map.len() > MAX_KEYS {
+        keys.push("…".to_string());
+    }
+    keys.join(", ")
+}
+
+fn truncate(mut text: String) -> String {
+    if let Some((index, _)) = text.char_indices().nth(MAX_RECORD_LEN) {
+        text.truncate(index);
+        text.push('…');
+    }
+    // Backticks would break inline code in Markdown.
+    text.replace('`', "'")
+}
@@ -0,0 +1,80 @@
+//! Current date.
+//!
+//! Documents get current date with `datetime.today()` in local time zone or
+//! with an offset in hours from UTC. For reproducible builds, current time is
+//! fixed with a UNIX timestamp (like `SOURCE_DATE_EPOCH`) which is in UTC.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use typst::foundations::Datetime;
+
+/// Seconds in a day.
+const DAY: i64 = 86_400;
+
+/// Environment variable with a fixed UNIX timestamp of reproducible builds.
+static SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// Timestamp from `SOURCE_DATE_EPOCH` if it is set and valid.
+pub fn source_date_epoch() -> Option<i64> {
+    let value = std::env::var(SOURCE_DATE_EPOCH).ok()?;
+    match value.trim().parse() {
+        Ok(timestamp) => Some(timestamp),
+        Err(err) => {
+            log::warn!("ignore invalid {SOURCE_DATE_EPOCH}={value}: {err}");
+            None
+        }
+    }
+}
+
+/// Date at a UNIX timestamp (current time unless fixed) with an offset in
+/// hours from UTC. Local time zone is used if there is no offset and time is
+/// not fixed.
+pub fn today(fixed: Option<i64>, offset: Option<i64>) -> Option<Datetime> {
+    let (now, local) = match fixed {
+        Some(timestamp) => (timestamp, 0),
+        None => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?;
+            let now = i64::try_from(now.as_secs()).ok()?;
+            (now, local_offset(now))
+        }
+    };
+    let offset = match offset {
+        Some(hours) => hours.checked_mul(3600)?,
+        None => local,
+    };
+    let (year, month, day) =
+        civil_from_days(now.checked_add(offset)?.div_euclid(DAY));
+    Datetime::from_ymd(i32::try_from(year).ok()?, month, day)
+}
+
+/// Offset of local time zone from UTC in seconds at a UNIX timestamp.
+#[cfg(unix)]
+fn local_offset(timestamp: i64) -> i64 {
+    let time = timestamp as libc::time_t;
+    // SAFETY: `localtime_r` is reentrant and writes to the buffer only.
+    let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+    match unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+        true => 0,
+        false => tm.tm_gmtoff as i64,
+    }
+}
+
+#[cfg(not(unix))]
+fn local_offset(_timestamp: i64) -> i64 {
+    0
+}
+
+/// Convert days since 1970-01-01 to year, month, and day of proleptic
+/// Gregorian calendar (see `civil_from_days` by Howard Hinnant).
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
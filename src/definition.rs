@@ -0,0 +1,714 @@
+//! Go to definition.
+//!
+//! Definitions are resolved syntactically without evaluation. An identifier
+//! is looked up in the enclosing scopes of a source: preceding `let` bindings,
+//! parameters of closures, patterns of loops, and imported items. Imported
+//! items are followed into the modules they come from (including packages).
+//! References (`@intro`) are resolved to labels (`<intro>`) in the files
+//! reachable from main file, and paths of `#import` and `#include` are
+//! resolved to the files themselves.
+//!
+//! Names which are imported into a scope (items and wildcard imports) are
+//! resolved the same way so that hover and completion show declarations,
+//! documentation comments (`///`), and files which imported names come from.
+//! Exports of modules are memoized until the modules (or modules which they
+//! import from) change.
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::str::FromStr;
+
+use comemo::{Prehashed, Track, Tracked};
+use typst::diag::FileResult;
+use typst::foundations::{Bytes, Datetime};
+use typst::syntax::ast::AstNode;
+use typst::syntax::package::{PackageManifest, PackageSpec};
+use typst::syntax::{
+    ast, FileId, LinkedNode, Source, Span, SyntaxKind, SyntaxNode, VirtualPath,
+};
+use typst::text::{Font, FontBook};
+use typst::{Library, World};
+
+use crate::deps;
+
+/// Maximal depth of re-exports which are followed across modules.
+const MAX_DEPTH: usize = 8;
+
+/// Location of a definition: a file and a byte range in it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Definition {
+    pub id: FileId,
+    pub range: Range<usize>,
+}
+
+/// What a name is bound to.
+#[derive(Clone)]
+enum Binding {
+    /// A name is defined at a span of a file.
+    At(Span),
+    /// A name is bound to a whole module (e.g. `#import "utils.typ"`).
+    Module(FileId),
+}
+
+/// Find a definition of an item at cursor.
+pub fn definition(
+    world: &dyn World,
+    source: &Source,
+    cursor: usize,
+) -> Option<Definition> {
+    // Cursor is usually placed before a character it points to.
+    let root = LinkedNode::new(source.root());
+    let leaf = [cursor + 1, cursor]
+        .into_iter()
+        .filter_map(|it| root.leaf_at(it))
+        .find(|it| {
+            matches!(
+                it.kind(),
+                SyntaxKind::Str
+                    | SyntaxKind::RefMarker
+                    | SyntaxKind::Ident
+                    | SyntaxKind::MathIdent
+            )
+        })?;
+    let binding = match leaf.kind() {
+        SyntaxKind::Str => Binding::Module(import_path_at(source, &leaf)?),
+        SyntaxKind::RefMarker => {
+            let target = leaf.parent()?.cast::<ast::Ref>()?.target();
+            Binding::At(find_label(world, source, target)?)
+        }
+        SyntaxKind::Ident | SyntaxKind::MathIdent => {
+            resolve_ident(world, source, &leaf)?
+        }
+        _ => return None,
+    };
+    match binding {
+        Binding::At(span) => {
+            let id = span.id()?;
+            let range = world.source(id).ok()?.range(span)?;
+            Some(Definition { id, range })
+        }
+        Binding::Module(id) => {
+            let id = entrypoint(world, id)?;
+            Some(Definition { id, range: 0..0 })
+        }
+    }
+}
+
+/// Resolve a path literal of `#import` or `#include` to a file.
+fn import_path_at(source: &Source, leaf: &LinkedNode) -> Option<FileId> {
+    let string = leaf.cast::<ast::Str>()?;
+    let parent = leaf.parent()?;
+    let path = if let Some(import) = parent.cast::<ast::ModuleImport>() {
+        import.source()
+    } else {
+        parent.cast::<ast::ModuleInclude>()?.source()
+    };
+    if path.span() != string.span() {
+        return None;
+    }
+    Some(resolve_module(source.id(), string.get().as_str()))
+}
+
+/// Resolve a path of an import to a file. Packages are resolved to their
+/// manifests (see [`entrypoint`]).
+fn resolve_module(origin: FileId, path: &str) -> FileId {
+    match PackageSpec::from_str(path) {
+        Ok(spec) if path.starts_with('@') => {
+            FileId::new(Some(spec), VirtualPath::new("typst.toml"))
+        }
+        _ => origin.join(path),
+    }
+}
+
+/// Read manifest of a package and get its entrypoint. Other files are
+/// returned as is.
+fn entrypoint(world: &dyn World, id: FileId) -> Option<FileId> {
+    let Some(spec) = id.package() else {
+        return Some(id);
+    };
+    if id.vpath().as_rootless_path().to_str() != Some("typst.toml") {
+        return Some(id);
+    }
+    let bytes = world.file(id).ok()?;
+    let text = std::str::from_utf8(&bytes).ok()?;
+    let manifest = toml::from_str::<PackageManifest>(text)
+        .inspect_err(|err| log::warn!("failed to parse manifest: {err}"))
+        .ok()?;
+    let path = VirtualPath::new(manifest.package.entrypoint.as_str());
+    Some(FileId::new(Some(spec.clone()), path))
+}
+
+/// Resolve an identifier (possibly a field of a module, e.g. `utils.fn`).
+fn resolve_ident(
+    world: &dyn World,
+    source: &Source,
+    leaf: &LinkedNode,
+) -> Option<Binding> {
+    let parent = leaf.parent()?;
+
+    // Names of named arguments are not variables.
+    if let Some(named) = parent.cast::<ast::Named>() {
+        if named.name().span() == leaf.span() {
+            return None;
+        }
+    }
+
+    // Field of a module is looked up in the module.
+    if let Some(access) = parent.cast::<ast::FieldAccess>() {
+        if access.field().span() == leaf.span() {
+            let target = parent.find(access.target().span())?;
+            let Binding::Module(id) = resolve_ident(world, source, &target)?
+            else {
+                return None;
+            };
+            return find_export(
+                world,
+                entrypoint(world, id)?,
+                access.field().get(),
+                0,
+            );
+        }
+    }
+
+    let name = leaf.text().as_str();
+    let mut child = leaf.clone();
+    while let Some(parent) = child.parent().cloned() {
+        if let Some(binding) =
+            find_in_scope(world, source, &parent, &child, name)
+        {
+            return Some(binding);
+        }
+        child = parent;
+    }
+    None
+}
+
+/// Look up a name in a scope which is introduced by a parent node. Only
+/// siblings which precede a child are visible.
+fn find_in_scope(
+    world: &dyn World,
+    source: &Source,
+    parent: &LinkedNode,
+    child: &LinkedNode,
+    name: &str,
+) -> Option<Binding> {
+    // Parameters and name of a closure (for recursion).
+    if let Some(closure) = parent.cast::<ast::Closure>() {
+        let param = closure.params().children().find_map(|param| {
+            let bindings = match param {
+                ast::Param::Pos(pattern) => pattern.bindings(),
+                ast::Param::Named(named) => vec![named.name()],
+                ast::Param::Spread(spread) => {
+                    spread.sink_ident().into_iter().collect()
+                }
+            };
+            bindings.into_iter().find(|it| it.get() == name)
+        });
+        let ident = param.or(closure.name().filter(|it| it.get() == name));
+        if let Some(ident) = ident {
+            return Some(Binding::At(ident.span()));
+        }
+    }
+
+    // Patterns of loops.
+    if let Some(for_loop) = parent.cast::<ast::ForLoop>() {
+        let bindings = for_loop.pattern().bindings();
+        if let Some(ident) = bindings.into_iter().find(|it| it.get() == name) {
+            return Some(Binding::At(ident.span()));
+        }
+    }
+
+    // The last preceding binding wins.
+    let siblings = parent
+        .children()
+        .take_while(|it| it.offset() < child.offset())
+        .collect::<Vec<_>>();
+    siblings.iter().rev().find_map(|sibling| {
+        find_binding(world, source.id(), sibling.get(), name, 0)
+    })
+}
+
+/// Check whether a node binds a name with `let` or `import`.
+fn find_binding(
+    world: &dyn World,
+    origin: FileId,
+    node: &SyntaxNode,
+    name: &str,
+    depth: usize,
+) -> Option<Binding> {
+    if let Some(binding) = node.cast::<ast::LetBinding>() {
+        let bindings = binding.kind().bindings();
+        let ident = bindings.into_iter().find(|it| it.get() == name)?;
+        return Some(Binding::At(ident.span()));
+    }
+
+    let import = node.cast::<ast::ModuleImport>()?;
+    let ast::Expr::Str(path) = import.source() else {
+        return None;
+    };
+    let id = resolve_module(origin, path.get().as_str());
+    match import.imports() {
+        Some(ast::Imports::Items(items)) => {
+            let item = items.iter().find(|it| it.bound_name().get() == name)?;
+            let original = item.original_name();
+            find_export(world, entrypoint(world, id)?, original.get(), depth)
+                .or(Some(Binding::At(original.span())))
+        }
+        Some(ast::Imports::Wildcard) => {
+            find_export(world, entrypoint(world, id)?, name, depth)
+        }
+        None => {
+            let bound = match import.new_name() {
+                Some(ident) => ident.get().to_string(),
+                None => module_name(path.get().as_str())?,
+            };
+            (bound == name).then_some(Binding::Module(id))
+        }
+    }
+}
+
+/// Name which a module is bound to on import without items.
+fn module_name(path: &str) -> Option<String> {
+    match PackageSpec::from_str(path) {
+        Ok(spec) if path.starts_with('@') => Some(spec.name.to_string()),
+        _ => {
+            let stem = std::path::Path::new(path).file_stem()?;
+            Some(stem.to_string_lossy().to_string())
+        }
+    }
+}
+
+/// Find a top-level definition of a name in a module. Re-exported items are
+/// followed into the modules they come from.
+fn find_export(
+    world: &dyn World,
+    id: FileId,
+    name: &str,
+    depth: usize,
+) -> Option<Binding> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let source = world.source(id).ok()?;
+    source
+        .root()
+        .children()
+        .rev()
+        .find_map(|node| find_binding(world, id, node, name, depth + 1))
+}
+
+/// Names which are visible at cursor due to imports (items and wildcard
+/// imports) with their definitions. Inner scopes and later imports shadow
+/// outer scopes and earlier imports.
+pub fn imported_names(
+    world: &dyn World,
+    source: &Source,
+    cursor: usize,
+) -> Vec<(String, Definition)> {
+    let root = LinkedNode::new(source.root());
+    let Some(mut child) = root.leaf_at(cursor) else {
+        return vec![];
+    };
+    let mut names = HashMap::new();
+    while let Some(parent) = child.parent().cloned() {
+        let siblings = parent
+            .children()
+            .take_while(|it| it.offset() < child.offset())
+            .collect::<Vec<_>>();
+        for sibling in siblings.iter().rev() {
+            let imports = find_imports(world, source.id(), sibling.get(), 0);
+            for (name, binding) in imports {
+                names.entry(name).or_insert(binding);
+            }
+        }
+        child = parent;
+    }
+    names
+        .into_iter()
+        .filter_map(|(name, binding)| {
+            let Binding::At(span) = binding else {
+                return None;
+            };
+            let id = span.id()?;
+            let range = world.source(id).ok()?.range(span)?;
+            Some((name, Definition { id, range }))
+        })
+        .collect()
+}
+
+/// Names which are bound by an import statement. Wildcard imports bind all
+/// names which a module exports.
+fn find_imports(
+    world: &dyn World,
+    origin: FileId,
+    node: &SyntaxNode,
+    depth: usize,
+) -> Vec<(String, Binding)> {
+    let Some(import) = node.cast::<ast::ModuleImport>() else {
+        return vec![];
+    };
+    let ast::Expr::Str(path) = import.source() else {
+        return vec![];
+    };
+    let id = resolve_module(origin, path.get().as_str());
+    match import.imports() {
+        Some(ast::Imports::Items(items)) => items
+            .iter()
+            .filter_map(|item| {
+                let original = item.original_name();
+                let binding = find_export(
+                    world,
+                    entrypoint(world, id)?,
+                    original.get(),
+                    depth,
+                )
+                .unwrap_or(Binding::At(original.span()));
+                Some((item.bound_name().get().to_string(), binding))
+            })
+            .collect(),
+        Some(ast::Imports::Wildcard) => match entrypoint(world, id) {
+            Some(id) => cached_exports(world.track(), id, depth),
+            None => vec![],
+        },
+        None => vec![],
+    }
+}
+
+/// Collect top-level names which a module exports. Exports are memoized by
+/// sources which they are collected from.
+#[comemo::memoize]
+fn cached_exports(
+    world: Tracked<dyn World + '_>,
+    id: FileId,
+    depth: usize,
+) -> Vec<(String, Binding)> {
+    find_exports(&TrackedWorld(world), id, depth)
+}
+
+/// Collect top-level names which a module exports. Later bindings shadow
+/// earlier ones.
+fn find_exports(
+    world: &dyn World,
+    id: FileId,
+    depth: usize,
+) -> Vec<(String, Binding)> {
+    if depth > MAX_DEPTH {
+        return vec![];
+    }
+    let Ok(source) = world.source(id) else {
+        return vec![];
+    };
+    let mut exports = HashMap::new();
+    for node in source.root().children() {
+        if let Some(binding) = node.cast::<ast::LetBinding>() {
+            for ident in binding.kind().bindings() {
+                let name = ident.get().to_string();
+                exports.insert(name, Binding::At(ident.span()));
+            }
+        }
+        exports.extend(find_imports(world, id, node, depth + 1));
+    }
+    exports.into_iter().collect()
+}
+
+/// Describe a variable at cursor which is defined in another file (e.g.
+/// imported from a module or a package) in Markdown.
+pub fn describe_import(
+    world: &dyn World,
+    source: &Source,
+    cursor: usize,
+) -> Option<String> {
+    let found = definition(world, source, cursor)?;
+    if found.id == source.id() {
+        return None;
+    }
+    describe(world, &found)
+}
+
+/// Describe a definition of a variable in Markdown: its declaration,
+/// documentation comments which precede it, and a file it is defined in.
+pub fn describe(world: &dyn World, found: &Definition) -> Option<String> {
+    let target = world.source(found.id).ok()?;
+    let root = LinkedNode::new(target.root());
+    let mut node = root.leaf_at(found.range.start + 1)?;
+    let binding = loop {
+        node = node.parent()?.clone();
+        if let Some(binding) = node.get().cast::<ast::LetBinding>() {
+            break binding;
+        }
+    };
+
+    let mut text = format!("```typst\n{}\n```", declaration(binding));
+    let docs = doc_comments(&target, node.offset());
+    if !docs.is_empty() {
+        text.push_str("\n\n");
+        text.push_str(&docs);
+    }
+    text.push_str(&format!("\n\nDefined in `{}`", describe_file(found.id)));
+    Some(text)
+}
+
+/// Declaration of a let binding without its body (e.g. `let f(x, y: 1)`).
+/// Short values of simple bindings are kept.
+fn declaration(binding: ast::LetBinding) -> String {
+    let text = |node: &SyntaxNode| node.clone().into_text().to_string();
+    match binding.kind() {
+        ast::LetBindingKind::Closure(name) => {
+            let params = match binding.init() {
+                Some(ast::Expr::Closure(closure)) => {
+                    text(closure.params().to_untyped())
+                }
+                _ => String::new(),
+            };
+            format!("let {}{params}", name.get())
+        }
+        ast::LetBindingKind::Normal(pattern) => {
+            let pattern = text(pattern.to_untyped());
+            match binding.init().map(|it| text(it.to_untyped())) {
+                Some(init) if init.len() <= 40 && !init.contains('\n') => {
+                    format!("let {pattern} = {init}")
+                }
+                _ => format!("let {pattern}"),
+            }
+        }
+    }
+}
+
+/// Collect documentation comments (`///`) on lines right above an offset.
+fn doc_comments(source: &Source, offset: usize) -> String {
+    let Some(line) = source.byte_to_line(offset) else {
+        return String::new();
+    };
+    let mut lines = source.text().lines().take(line).collect::<Vec<_>>();
+    let mut docs = Vec::new();
+    while let Some(comment) = lines
+        .pop()
+        .and_then(|it| it.trim_start().strip_prefix("///"))
+    {
+        docs.push(comment.strip_prefix(' ').unwrap_or(comment));
+    }
+    docs.reverse();
+    docs.join("\n").trim().to_string()
+}
+
+/// Describe a file for humans: packages are prefixed with their specs (e.g.
+/// `@preview/cetz:0.2.2/src/draw.typ`) and other files are relative to root.
+pub fn describe_file(id: FileId) -> String {
+    match id.package() {
+        Some(spec) => {
+            format!("{spec}{}", id.vpath().as_rooted_path().display())
+        }
+        None => id.vpath().as_rootless_path().display().to_string(),
+    }
+}
+
+/// Find a label in files reachable from a source and main file.
+fn find_label(world: &dyn World, source: &Source, name: &str) -> Option<Span> {
+    fn visit(node: &SyntaxNode, name: &str) -> Option<Span> {
+        if let Some(label) = node.cast::<ast::Label>() {
+            if label.get() == name {
+                return Some(node.span());
+            }
+        }
+        node.children().find_map(|child| visit(child, name))
+    }
+
+    let mut visited = HashSet::new();
+    let mut queue = vec![world.main().id(), source.id()];
+    while let Some(id) = queue.pop() {
+        if !visited.insert(id) {
+            continue;
+        }
+        let Ok(source) = world.source(id) else {
+            continue;
+        };
+        if let Some(span) = visit(source.root(), name) {
+            return Some(span);
+        }
+        for (_, path, _) in deps::find_dependencies(&source) {
+            if !path.starts_with('@') {
+                queue.push(id.join(&path));
+            }
+        }
+    }
+    None
+}
+
+/// World which reads files through a tracked world so that memoized lookups
+/// could use functions which take an untracked one.
+struct TrackedWorld<'a>(Tracked<'a, dyn World + 'a>);
+
+impl World for TrackedWorld<'_> {
+    fn library(&self) -> &Prehashed<Library> {
+        self.0.library()
+    }
+
+    fn book(&self) -> &Prehashed<FontBook> {
+        self.0.book()
+    }
+
+    fn main(&self) -> Source {
+        self.0.main()
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        self.0.source(id)
+    }
+
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        self.0.file(id)
+    }
+
+    fn font(&self, index: usize) -> Option<Font> {
+        self.0.font(index)
+    }
+
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        self.0.today(offset)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use typst::diag::FileError;
+
+    /// World of in-memory files with `main.typ` as main file.
+    struct TestWorld {
+        library: Prehashed<Library>,
+        book: Prehashed<FontBook>,
+        files: HashMap<FileId, String>,
+    }
+
+    impl TestWorld {
+        fn new(files: Vec<(FileId, &str)>) -> Self {
+            Self {
+                library: Prehashed::new(Library::default()),
+                book: Prehashed::new(FontBook::new()),
+                files: files
+                    .into_iter()
+                    .map(|(id, text)| (id, text.to_string()))
+                    .collect(),
+            }
+        }
+
+        /// Find a definition of an imported name which is visible right
+        /// after the last occurrence of a name in main file.
+        fn resolve(&self, name: &str) -> Option<Definition> {
+            let source = self.main();
+            let cursor = source.text().rfind(name)? + name.len();
+            imported_names(self, &source, cursor)
+                .into_iter()
+                .find_map(|(it, found)| (it == name).then_some(found))
+        }
+    }
+
+    impl World for TestWorld {
+        fn library(&self) -> &Prehashed<Library> {
+            &self.library
+        }
+
+        fn book(&self) -> &Prehashed<FontBook> {
+            &self.book
+        }
+
+        fn main(&self) -> Source {
+            self.source(file("main.typ")).unwrap()
+        }
+
+        fn source(&self, id: FileId) -> FileResult<Source> {
+            let text = self.files.get(&id).ok_or_else(|| {
+                FileError::NotFound(id.vpath().as_rootless_path().into())
+            })?;
+            Ok(Source::new(id, text.clone()))
+        }
+
+        fn file(&self, id: FileId) -> FileResult<Bytes> {
+            let source = self.source(id)?;
+            Ok(Bytes::from(source.text().as_bytes().to_vec()))
+        }
+
+        fn font(&self, _index: usize) -> Option<Font> {
+            None
+        }
+
+        fn today(&self, _offset: Option<i64>) -> Option<Datetime> {
+            None
+        }
+    }
+
+    fn file(path: &str) -> FileId {
+        FileId::new(None, VirtualPath::new(path))
+    }
+
+    #[test]
+    fn later_imports_shadow_earlier_ones() {
+        let world = TestWorld::new(vec![
+            (file("a.typ"), "#let f(x) = x\n"),
+            (file("b.typ"), "#let f = 1\n"),
+            (
+                file("main.typ"),
+                "#import \"a.typ\": f\n#import \"b.typ\": f\n#f\n",
+            ),
+        ]);
+        let found = world.resolve("f").unwrap();
+        assert_eq!(found.id, file("b.typ"));
+        assert_eq!(found.range, 5..6);
+    }
+
+    #[test]
+    fn inner_imports_shadow_outer_ones() {
+        let world = TestWorld::new(vec![
+            (file("a.typ"), "#let f(x) = x\n"),
+            (file("b.typ"), "#let f = 1\n"),
+            (
+                file("main.typ"),
+                "#import \"b.typ\": f\n#{\n  import \"a.typ\": f\n  f\n}\n",
+            ),
+        ]);
+        assert_eq!(world.resolve("f").unwrap().id, file("a.typ"));
+    }
+
+    #[test]
+    fn renamed_imports() {
+        let world = TestWorld::new(vec![
+            (file("a.typ"), "#let f(x) = x\n"),
+            (file("main.typ"), "#import \"a.typ\": f as g\n#g\n#f\n"),
+        ]);
+        let found = world.resolve("g").unwrap();
+        assert_eq!(found.id, file("a.typ"));
+        assert_eq!(found.range, 5..6);
+        assert!(world.resolve("f").is_none());
+    }
+
+    #[test]
+    fn wildcard_imports() {
+        let world = TestWorld::new(vec![
+            (file("a.typ"), "#let f = 1\n#let f = 2\n"),
+            (file("main.typ"), "#import \"a.typ\": *\n#f\n"),
+        ]);
+        let found = world.resolve("f").unwrap();
+        assert_eq!(found.id, file("a.typ"));
+        assert_eq!(found.range, 16..17);
+    }
+
+    #[test]
+    fn package_imports() {
+        let spec = "@preview/pkg:0.1.0".parse::<PackageSpec>().unwrap();
+        let manifest = "[package]\nname = \"pkg\"\nversion = \"0.1.0\"\n\
+                        entrypoint = \"lib.typ\"\nauthors = [\"Author\"]\n\
+                        license = \"MIT\"\ndescription = \"Package\"\n";
+        let entry =
+            FileId::new(Some(spec.clone()), VirtualPath::new("lib.typ"));
+        let world = TestWorld::new(vec![
+            (
+                FileId::new(Some(spec), VirtualPath::new("typst.toml")),
+                manifest,
+            ),
+            (entry, "#let f = 1\n"),
+            (file("main.typ"), "#import \"@preview/pkg:0.1.0\": f\n#f\n"),
+        ]);
+        assert_eq!(world.resolve("f").unwrap().id, entry);
+    }
+}
@@ -0,0 +1,214 @@
+//! Dependencies of a document.
+//!
+//! There are two kinds of dependencies. The first one is the dependency graph
+//! which is built statically: sources are parsed starting from the main file
+//! and all `#import` and `#include` statements with string literal arguments
+//! are followed. Packages are leaves of the graph since their contents are not
+//! a part of a workspace. The second one is the list of files actually
+//! accessed during compilation which is used by external build systems.
+
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::Serialize;
+use tower_lsp::lsp_types::{Position, Range};
+use typst::syntax::package::PackageSpec;
+use typst::syntax::{ast, FileId, LinkedNode, Source, Span};
+use typst::World;
+
+use crate::syntax::{byte_to_position, PositionEncoding};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum NodeKind {
+    File,
+    Package,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Node {
+    /// Unique identifier of a node: a path relative to root directory (e.g.
+    /// `/chapters/intro.typ`) or a package specification (e.g.
+    /// `@preview/cetz:0.2.2`).
+    pub id: String,
+    pub kind: NodeKind,
+    /// Absolute path to a file on file system.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EdgeKind {
+    Import,
+    Include,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Edge {
+    /// Identifier of a dependent node.
+    pub source: String,
+    /// Identifier of a dependency node.
+    pub target: String,
+    pub kind: EdgeKind,
+    /// Location of import or include statement in source node.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<Range>,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Graph {
+    pub nodes: Vec<Node>,
+    pub edges: Vec<Edge>,
+}
+
+/// Build dependency graph of a main file of a world. Relative paths are
+/// resolved against `root_dir`.
+pub fn dependency_graph(
+    world: &dyn World,
+    root_dir: &Path,
+    encoding: PositionEncoding,
+) -> Graph {
+    let mut graph = Graph::default();
+    let mut visited = HashSet::<FileId>::new();
+    let mut packages = HashSet::<String>::new();
+    let mut queue = vec![world.main().id()];
+    while let Some(id) = queue.pop() {
+        if !visited.insert(id) {
+            continue;
+        }
+        graph.nodes.push(Node {
+            id: file_node_id(id),
+            kind: NodeKind::File,
+            path: match id.package() {
+                Some(_) => None,
+                None => Some(root_dir.join(id.vpath().as_rootless_path())),
+            },
+        });
+
+        let Ok(source) = world.source(id) else {
+            log::warn!("failed to read dependency source {:?}", id);
+            continue;
+        };
+        for (kind, target, span) in find_dependencies(&source) {
+            let range = span_to_range(&source, span, encoding);
+            let target = match PackageSpec::from_str(&target) {
+                Ok(spec) if target.starts_with('@') => {
+                    let spec = spec.to_string();
+                    if packages.insert(spec.clone()) {
+                        graph.nodes.push(Node {
+                            id: spec.clone(),
+                            kind: NodeKind::Package,
+                            path: None,
+                        });
+                    }
+                    spec
+                }
+                _ => {
+                    let target = id.join(&target);
+                    queue.push(target);
+                    file_node_id(target)
+                }
+            };
+            graph.edges.push(Edge {
+                source: file_node_id(id),
+                target,
+                kind,
+                range,
+            });
+        }
+    }
+    graph
+}
+
+fn file_node_id(id: FileId) -> String {
+    let path = id.vpath().as_rooted_path().to_string_lossy().to_string();
+    match id.package() {
+        Some(spec) => format!("{spec}{path}"),
+        None => path,
+    }
+}
+
+pub(crate) fn span_to_range(
+    source: &Source,
+    span: Span,
+    encoding: PositionEncoding,
+) -> Option<Range> {
+    let range = source.range(span)?;
+    let position = |offset| {
+        let (line, column) = byte_to_position(source, offset, encoding)?;
+        Some(Position::new(line as u32, column as u32))
+    };
+    Some(Range {
+        start: position(range.start)?,
+        end: position(range.end)?,
+    })
+}
+
+/// Find all imports and includes with string literal paths in a source.
+pub fn find_dependencies(source: &Source) -> Vec<(EdgeKind, String, Span)> {
+    fn visit(node: &LinkedNode, deps: &mut Vec<(EdgeKind, String, Span)>) {
+        let dep = if let Some(import) = node.cast::<ast::ModuleImport>() {
+            Some((EdgeKind::Import, import.source()))
+        } else {
+            node.cast::<ast::ModuleInclude>()
+                .map(|include| (EdgeKind::Include, include.source()))
+        };
+        if let Some((kind, ast::Expr::Str(path))) = dep {
+            deps.push((kind, path.get().to_string(), node.span()));
+        }
+        for child in node.children() {
+            visit(&child, deps);
+        }
+    }
+    let mut deps = Vec::new();
+    visit(&LinkedNode::new(source.root()), &mut deps);
+    deps
+}
+
+/// Files and packages which a compilation depended on.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Dependencies {
+    /// Absolute paths to all accessed files (including files of packages).
+    pub files: BTreeSet<PathBuf>,
+    /// Specifications of all used packages (e.g. `@preview/cetz:0.2.2`).
+    pub packages: BTreeSet<String>,
+}
+
+impl Dependencies {
+    pub fn new<'a, I>(accessed: I) -> Self
+    where
+        I: Iterator<Item = (&'a FileId, &'a PathBuf)>,
+    {
+        let mut deps = Self::default();
+        for (id, path) in accessed {
+            deps.files.insert(path.clone());
+            if let Some(spec) = id.package() {
+                deps.packages.insert(spec.to_string());
+            }
+        }
+        deps
+    }
+
+    /// Render dependencies as a Makefile rule for a target.
+    pub fn to_make(&self, target: &Path) -> String {
+        fn escape(path: &Path) -> String {
+            path.to_string_lossy()
+                .replace('$', "$$")
+                .replace(' ', "\\ ")
+                .replace('#', "\\#")
+        }
+        let mut rule = format!("{}:", escape(target));
+        for path in self.files.iter() {
+            rule.push_str(" \\\n  ");
+            rule.push_str(&escape(path));
+        }
+        rule.push('\n');
+        rule
+    }
+}
@@ -0,0 +1,69 @@
+//! Compilation diagnostics.
+//!
+//! Typst reports errors and warnings with spans of syntax nodes. Spans are
+//! mapped back to files and positions (line and column) so that diagnostics
+//! could be published for the files they belong to. Hints are reported as
+//! separate diagnostics at the same position.
+
+use std::fmt::{self, Display, Formatter};
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+/// Severity of a diagnostic. Severities are ordered from the most severe one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Hint,
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+            Self::Hint => write!(f, "hint"),
+        }
+    }
+}
+
+/// Position in a file as zero-based line and column.
+pub type Position = (usize, usize);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// File which diagnostic belongs to. It is missing if a diagnostic is not
+    /// bound to a file (e.g. lockfile warnings).
+    pub path: Option<PathBuf>,
+    pub start: Position,
+    pub end: Position,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Make a diagnostic which is not bound to a file.
+    pub fn new(severity: Severity, message: String) -> Self {
+        Self {
+            path: None,
+            start: (0, 0),
+            end: (0, 0),
+            severity,
+            message,
+        }
+    }
+}
+
+/// Format diagnostic like compilers do: `path:line:column: error: message`
+/// (line and column are one-based).
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(path) = &self.path {
+            let (line, column) = self.start;
+            write!(f, "{}:{}:{}: ", path.display(), line + 1, column + 1)?;
+        }
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+}
@@ -0,0 +1,215 @@
+//! Documentation rendering.
+//!
+//! Hover and completion documentation is rendered either as Markdown (with
+//! fenced `typst` code blocks, parameter tables, and links to the official
+//! documentation) or as plain text for clients which do not render Markdown.
+
+use std::fmt::Write;
+
+use tower_lsp::lsp_types::{MarkupContent, MarkupKind};
+use typst::foundations::{CastInfo, Category, Func, ParamInfo, Repr};
+use typst_ide::Tooltip;
+
+/// Base URL of the official reference documentation.
+pub static REFERENCE_URL: &str = "https://typst.app/docs/reference";
+
+/// Docs is a format-agnostic piece of documentation.
+#[derive(Clone, Debug)]
+pub enum Docs {
+    /// A string of text (possibly with Markdown markup).
+    Text(String),
+    /// A string of Typst code.
+    Code(String),
+    /// A function with its category (if it is known).
+    Func(Func, Option<Category>),
+    /// A rendered preview given by URL of an image (e.g. data URL) and Typst
+    /// code which is shown if images are not supported.
+    Preview(String, String),
+    /// A summary of an image (possibly with Markdown markup) and URL of its
+    /// thumbnail which is shown if images are supported.
+    Image(String, Option<String>),
+}
+
+impl From<Tooltip> for Docs {
+    fn from(tooltip: Tooltip) -> Self {
+        match tooltip {
+            Tooltip::Text(text) => Self::Text(text.to_string()),
+            Tooltip::Code(code) => Self::Code(code.to_string()),
+        }
+    }
+}
+
+impl Docs {
+    /// Render documentation in the specified format.
+    pub fn render(&self, kind: MarkupKind) -> MarkupContent {
+        let value = match (self, &kind) {
+            (Self::Text(text), MarkupKind::Markdown) => text.clone(),
+            (Self::Text(text), MarkupKind::PlainText) => strip_links(text),
+            (Self::Code(code), MarkupKind::Markdown) => fence(code),
+            (Self::Code(code), MarkupKind::PlainText) => code.clone(),
+            (Self::Func(func, category), _) => {
+                render_func(func, *category, &kind)
+            }
+            (Self::Preview(url, code), MarkupKind::Markdown) => {
+                format!("![preview]({url})\n\n{}", fence(code))
+            }
+            (Self::Preview(_, code), MarkupKind::PlainText) => code.clone(),
+            (Self::Image(text, Some(url)), MarkupKind::Markdown) => {
+                format!("{text}\n\n![thumbnail]({url})")
+            }
+            (Self::Image(text, _), MarkupKind::Markdown) => text.clone(),
+            (Self::Image(text, _), MarkupKind::PlainText) => strip_links(text),
+        };
+        MarkupContent { kind, value }
+    }
+}
+
+/// Wrap code into fenced code block.
+fn fence(code: &str) -> String {
+    format!("```typst\n{}\n```", code.trim_end())
+}
+
+/// Replace Markdown links with their text. Typst documentation refers to
+/// other items with relative links (e.g. `[heading]($heading)`) which are
+/// unresolvable outside of the official documentation.
+fn strip_links(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(begin) = rest.find('[') {
+        let Some(middle) = rest[begin..].find("](").map(|it| it + begin) else {
+            break;
+        };
+        let Some(end) = rest[middle..].find(')').map(|it| it + middle) else {
+            break;
+        };
+        result.push_str(&rest[..begin]);
+        result.push_str(&rest[begin + 1..middle]);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Extract the first paragraph of documentation as a single line. Inline code
+/// in Typst documentation is wrapped in braces (e.g. `{auto}`) which are
+/// removed as well.
+pub(crate) fn first_paragraph(docs: &str) -> String {
+    let paragraph = docs.trim().split("\n\n").next().unwrap_or_default();
+    strip_links(&paragraph.split_whitespace().collect::<Vec<_>>().join(" "))
+        .replace("`{", "`")
+        .replace("}`", "`")
+}
+
+/// Describe values accepted by a parameter (e.g. `auto | int`).
+pub(crate) fn describe_cast(info: &CastInfo) -> String {
+    let mut parts = Vec::<String>::new();
+    info.walk(|info| {
+        let part = match info {
+            CastInfo::Any => "any".to_string(),
+            CastInfo::Value(value, _) => value.repr().to_string(),
+            CastInfo::Type(ty) => ty.short_name().to_string(),
+            CastInfo::Union(_) => return,
+        };
+        if !parts.contains(&part) {
+            parts.push(part);
+        }
+    });
+    parts.join(" | ")
+}
+
+fn describe_default(param: &ParamInfo) -> Option<String> {
+    param.default.map(|default| default().repr().to_string())
+}
+
+/// Make a signature of a function (e.g. `heading(level: auto | int, ..)`).
+fn signature(func: &Func) -> String {
+    let name = func.name().unwrap_or("function");
+    let params = func
+        .params()
+        .unwrap_or_default()
+        .iter()
+        .map(|param| {
+            let prefix = if param.variadic { ".." } else { "" };
+            format!("{prefix}{}: {}", param.name, describe_cast(&param.input))
+        })
+        .collect::<Vec<_>>();
+    let returns = func.returns().map(describe_cast);
+    match returns {
+        Some(returns) => format!("{name}({}) -> {returns}", params.join(", ")),
+        None => format!("{name}({})", params.join(", ")),
+    }
+}
+
+/// Make a link to the official documentation of a function.
+fn reference_url(func: &Func, category: Option<Category>) -> Option<String> {
+    let name = func.name()?;
+    let category = category?.name();
+    Some(format!("{REFERENCE_URL}/{category}/{name}/"))
+}
+
+fn render_func(
+    func: &Func,
+    category: Option<Category>,
+    kind: &MarkupKind,
+) -> String {
+    let params = func.params().unwrap_or_default();
+    let docs = func.docs().unwrap_or_default().trim();
+    let mut output = String::new();
+    match kind {
+        MarkupKind::Markdown => {
+            output.push_str(&fence(&signature(func)));
+            if !docs.is_empty() {
+                write!(output, "\n\n{}", first_paragraph(docs)).ok();
+            }
+            if !params.is_empty() {
+                output.push_str(
+                    "\n\n| Parameter | Type | Default | Description |",
+                );
+                output.push_str("\n|---|---|---|---|");
+            }
+            for param in params {
+                let default = describe_default(param)
+                    .map(|it| format!("`{}`", it.replace('|', "\\|")))
+                    .unwrap_or_default();
+                write!(
+                    output,
+                    "\n| `{}`{} | `{}` | {} | {} |",
+                    param.name,
+                    if param.required { "" } else { "?" },
+                    describe_cast(&param.input).replace('|', "\\|"),
+                    default,
+                    first_paragraph(param.docs).replace('|', "\\|"),
+                )
+                .ok();
+            }
+            if let Some(url) = reference_url(func, category) {
+                write!(output, "\n\n[Open documentation]({url})").ok();
+            }
+        }
+        MarkupKind::PlainText => {
+            output.push_str(&signature(func));
+            if !docs.is_empty() {
+                write!(output, "\n\n{}", first_paragraph(docs)).ok();
+            }
+            if !params.is_empty() {
+                output.push_str("\n\nParameters:");
+            }
+            for param in params {
+                write!(
+                    output,
+                    "\n  {}: {}",
+                    param.name,
+                    describe_cast(&param.input)
+                )
+                .ok();
+                if let Some(default) = describe_default(param) {
+                    write!(output, " = {default}").ok();
+                }
+            }
+            if let Some(url) = reference_url(func, category) {
+                write!(output, "\n\nSee {url}").ok();
+            }
+        }
+    }
+    output
+}
@@ -0,0 +1,260 @@
+//! Diagnostics of environment.
+//!
+//! Checks mirror what language server does on startup and on compilation:
+//! package downloads, cache directories, font discovery, and loading of
+//! `typst.toml` manifests. Each check results in a finding with a hint how to
+//! fix a problem so that users could debug their setups before filing bugs.
+
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Packages;
+use crate::{package, workspace, FontProvider};
+
+/// Environment variables which configure proxies.
+static PROXY_VARS: [&str; 6] = [
+    "HTTPS_PROXY",
+    "https_proxy",
+    "HTTP_PROXY",
+    "http_proxy",
+    "ALL_PROXY",
+    "NO_PROXY",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        // Pad status so that findings are aligned.
+        f.pad(match self {
+            Self::Ok => "ok",
+            Self::Warning => "warning",
+            Self::Error => "error",
+        })
+    }
+}
+
+/// Result of a single check.
+#[derive(Clone, Debug)]
+pub struct Finding {
+    pub check: &'static str,
+    pub status: Status,
+    pub message: String,
+    /// What to do if something is wrong.
+    pub hint: Option<String>,
+}
+
+impl Finding {
+    fn ok(check: &'static str, message: String) -> Self {
+        Self {
+            check,
+            status: Status::Ok,
+            message,
+            hint: None,
+        }
+    }
+
+    fn warning(check: &'static str, message: String, hint: &str) -> Self {
+        Self {
+            check,
+            status: Status::Warning,
+            message,
+            hint: Some(hint.to_string()),
+        }
+    }
+
+    fn error(check: &'static str, message: String, hint: &str) -> Self {
+        Self {
+            check,
+            status: Status::Error,
+            message,
+            hint: Some(hint.to_string()),
+        }
+    }
+}
+
+/// Run all checks for a workspace directory.
+pub fn run(workspace_dir: &Path, packages: &Packages) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    findings.extend(check_proxy(packages));
+    findings.push(check_registry());
+    findings.push(check_cache_dir());
+    findings.extend(check_package_dir());
+    findings.push(check_fonts());
+    findings.extend(check_manifests(workspace_dir));
+    findings
+}
+
+fn check_proxy(packages: &Packages) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    let url = package::index_url();
+    let vars = PROXY_VARS
+        .iter()
+        .filter_map(|name| {
+            Some(format!("{name}={}", std::env::var(name).ok()?))
+        })
+        .collect::<Vec<_>>();
+    let message = match package::proxy_for(&url) {
+        Some(proxy) if packages.proxy.is_some() => {
+            format!("use proxy {proxy} (--proxy)")
+        }
+        Some(proxy) => format!("use proxy {proxy} ({})", vars.join(", ")),
+        None if vars.is_empty() => "no proxy is configured".to_string(),
+        None => format!("no proxy for {url} ({})", vars.join(", ")),
+    };
+    findings.push(Finding::ok("proxy", message));
+    if let Some(mirror) = &packages.mirror {
+        let message = format!("use package registry mirror {mirror}");
+        findings.push(Finding::ok("mirror", message));
+    }
+    if packages.insecure {
+        findings.push(Finding::warning(
+            "tls",
+            "certificate verification is disabled".to_string(),
+            "use --ca-bundle with certificates of your proxy instead",
+        ));
+    } else if let Some(path) = &packages.ca_bundle {
+        findings.push(match fs::metadata(path) {
+            Ok(_) => Finding::ok("tls", format!("use CA bundle {path:?}")),
+            Err(err) => Finding::error(
+                "tls",
+                format!("failed to read CA bundle {path:?}: {err}"),
+                "check path given with --ca-bundle",
+            ),
+        });
+    }
+    findings
+}
+
+fn check_registry() -> Finding {
+    let url = package::index_url();
+    match package::ping_registry() {
+        Ok(()) => Finding::ok("registry", format!("{url} is reachable")),
+        Err(err) => Finding::error(
+            "registry",
+            format!("{url} is unreachable: {err}"),
+            "check network and proxy settings (--proxy or HTTPS_PROXY) or \
+             use --ca-bundle if a proxy intercepts TLS",
+        ),
+    }
+}
+
+fn check_cache_dir() -> Finding {
+    let dir = package::package_cache_path();
+    match check_writable(&dir) {
+        Ok(()) => Finding::ok("cache", format!("{dir:?} is writable")),
+        Err(err) => Finding::error(
+            "cache",
+            err,
+            "make directory writable or set TYPST_PACKAGE_CACHE_PATH",
+        ),
+    }
+}
+
+/// Try to create a file in a directory (and the directory itself).
+fn check_writable(dir: &Path) -> Result<(), String> {
+    fs::create_dir_all(dir)
+        .map_err(|err| format!("failed to create {dir:?}: {err}"))?;
+    let path = dir.join(".typstd-doctor");
+    fs::write(&path, b"")
+        .map_err(|err| format!("{dir:?} is not writable: {err}"))?;
+    fs::remove_file(&path).ok();
+    Ok(())
+}
+
+fn check_package_dir() -> Option<Finding> {
+    let dir = package::package_path()?;
+    if !dir.exists() {
+        return None;
+    }
+    Some(match fs::read_dir(&dir) {
+        Ok(entries) => {
+            let namespaces = entries.filter_map(Result::ok).count();
+            let message = format!("{namespaces} namespace(s) in {dir:?}");
+            Finding::ok("packages", message)
+        }
+        Err(err) => Finding::warning(
+            "packages",
+            format!("failed to read {dir:?}: {err}"),
+            "fix permissions or set TYPST_PACKAGE_PATH",
+        ),
+    })
+}
+
+fn check_fonts() -> Finding {
+    let fonts = FontProvider::discover(&[], &Default::default());
+    match fonts.len() {
+        0 => Finding::error(
+            "fonts",
+            "no fonts are found".to_string(),
+            "install fonts or set TYPST_FONT_PATHS",
+        ),
+        count => Finding::ok("fonts", format!("{count} font(s) are found")),
+    }
+}
+
+fn check_manifests(workspace_dir: &Path) -> Vec<Finding> {
+    let mut paths = Vec::new();
+    find_manifests(workspace_dir, &mut paths);
+    if paths.is_empty() {
+        let message =
+            format!("no {} in {workspace_dir:?}", workspace::FILENAME);
+        return vec![Finding::ok("manifest", message)];
+    }
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let dir = path.parent().unwrap_or(workspace_dir);
+            match workspace::load_targets(dir) {
+                Ok(targets) => check_targets(&path, &targets),
+                Err(err) => Finding::error(
+                    "manifest",
+                    err,
+                    "fix syntax of manifest (see README for its format)",
+                ),
+            }
+        })
+        .collect()
+}
+
+fn check_targets(path: &Path, targets: &[workspace::Target]) -> Finding {
+    let missing = targets
+        .iter()
+        .flat_map(|it| std::iter::once(&it.main_file).chain(&it.chapters))
+        .filter(|it| !it.exists())
+        .collect::<Vec<_>>();
+    match missing.first() {
+        Some(file) => Finding::error(
+            "manifest",
+            format!("{path:?} refers to missing file {file:?}"),
+            "fix entrypoint or chapters in manifest",
+        ),
+        None => Finding::ok(
+            "manifest",
+            format!("{path:?} defines {} target(s)", targets.len()),
+        ),
+    }
+}
+
+/// Find manifests in a directory recursively. Hidden directories are skipped.
+fn find_manifests(dir: &Path, paths: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.filter_map(Result::ok) {
+        let path = entry.path();
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if path.is_dir() && !hidden {
+            find_manifests(&path, paths);
+        } else if entry.file_name() == workspace::FILENAME {
+            paths.push(path);
+        }
+    }
+}
@@ -0,0 +1,485 @@
+//! Code formatting.
+//!
+//! Formatting is conservative: markup is left as is since whitespace is
+//! significant in it (e.g. indentation of nested lists). Formatter normalizes
+//! spacing around commas, colons, and `=` in code, breaks argument lists of
+//! calls which do not fit into line width, reindents lines of code by nesting
+//! of brackets, removes trailing whitespace, collapses runs of blank lines, and
+//! ensures a single newline at the end of file. Content of raw blocks, strings,
+//! and math is never touched. Sources with syntax errors are not formatted.
+//!
+//! Every pass reparses text so that passes stay simple. Results are turned into
+//! text edits by diffing lines of original and formatted text so that range
+//! formatting could keep edits which touch a range only.
+
+use std::ops::Range;
+use std::path::Path;
+
+use typst::syntax::ast;
+use typst::syntax::{LinkedNode, Source, SyntaxKind};
+
+use crate::actions::{make_edit, TextEdit};
+use crate::syntax::PositionEncoding;
+
+/// Maximal number of passes of breaking long lines.
+const MAX_BREAKS: usize = 256;
+
+/// Maximal size of a table of line diff. Larger changes are replaced at once.
+const MAX_DIFF_SIZE: usize = 4_000_000;
+
+/// Format a text. Text is returned unchanged if it has syntax errors.
+pub fn format(text: &str, indent: &str, line_width: usize) -> String {
+    if Source::detached(text).root().erroneous() {
+        return text.to_string();
+    }
+    let formatted = normalize_spacing(text);
+    let formatted = break_long_calls(&formatted, line_width);
+    let formatted = reindent(&formatted, indent);
+    let formatted = trim_whitespace(&formatted);
+    // Formatting must not break anything.
+    if Source::detached(formatted.as_str()).root().erroneous() {
+        log::warn!("formatting produced syntax errors: skip it");
+        return text.to_string();
+    }
+    formatted
+}
+
+/// Format a source and make edits of changed lines. If a range is specified
+/// then only edits of lines which intersect the range are returned.
+pub fn format_edits(
+    source: &Source,
+    path: &Path,
+    indent: &str,
+    line_width: usize,
+    range: Option<Range<usize>>,
+    encoding: PositionEncoding,
+) -> Vec<TextEdit> {
+    let formatted = format(source.text(), indent, line_width);
+    let old = source.text().split_inclusive('\n').collect::<Vec<_>>();
+    let new = formatted.split_inclusive('\n').collect::<Vec<_>>();
+    let lines = range.map(|range| {
+        let start = source.byte_to_line(range.start).unwrap_or(0);
+        let end = source.byte_to_line(range.end).unwrap_or(old.len());
+        start..end + 1
+    });
+
+    // Byte offsets of line starts of original text.
+    let mut offsets = vec![0];
+    for line in old.iter() {
+        offsets.push(offsets.last().unwrap() + line.len());
+    }
+    diff_lines(&old, &new)
+        .into_iter()
+        .filter(|(removed, _)| match &lines {
+            // Insertions are kept if they are next to a range.
+            Some(lines) => {
+                removed.start < lines.end && lines.start <= removed.end
+            }
+            None => true,
+        })
+        .filter_map(|(removed, added)| {
+            let range = offsets[removed.start]..offsets[removed.end];
+            make_edit(source, path, range, new[added].concat(), encoding)
+        })
+        .collect()
+}
+
+/// Find hunks of changed lines. Each hunk is a range of removed lines of old
+/// text and a range of added lines of new text.
+fn diff_lines(old: &[&str], new: &[&str]) -> Vec<(Range<usize>, Range<usize>)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_end = old.len() - suffix;
+    let new_end = new.len() - suffix;
+    if prefix == old_end && prefix == new_end {
+        return vec![];
+    }
+    let (n, m) = (old_end - prefix, new_end - prefix);
+    if n * m > MAX_DIFF_SIZE {
+        return vec![(prefix..old_end, prefix..new_end)];
+    }
+
+    // Table of lengths of longest common subsequences of suffixes.
+    let mut table = vec![0u32; (n + 1) * (m + 1)];
+    let at = |i: usize, j: usize| i * (m + 1) + j;
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            table[at(i, j)] = if old[prefix + i] == new[prefix + j] {
+                table[at(i + 1, j + 1)] + 1
+            } else {
+                table[at(i + 1, j)].max(table[at(i, j + 1)])
+            };
+        }
+    }
+
+    let mut hunks = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    let mut hunk: Option<(usize, usize)> = None;
+    while i < n || j < m {
+        if i < n && j < m && old[prefix + i] == new[prefix + j] {
+            if let Some((start_old, start_new)) = hunk.take() {
+                hunks.push((
+                    prefix + start_old..prefix + i,
+                    prefix + start_new..prefix + j,
+                ));
+            }
+            i += 1;
+            j += 1;
+            continue;
+        }
+        hunk.get_or_insert((i, j));
+        if j < m && (i == n || table[at(i, j + 1)] >= table[at(i + 1, j)]) {
+            j += 1;
+        } else {
+            i += 1;
+        }
+    }
+    if let Some((start_old, start_new)) = hunk {
+        hunks.push((
+            prefix + start_old..prefix + n,
+            prefix + start_new..prefix + m,
+        ));
+    }
+    hunks
+}
+
+/// Apply replacements of byte ranges to a text. Ranges must not overlap.
+fn apply(text: &str, mut edits: Vec<(Range<usize>, String)>) -> String {
+    edits.sort_by_key(|(range, _)| (range.start, range.end));
+    let mut result = String::with_capacity(text.len());
+    let mut last = 0;
+    for (range, replacement) in edits {
+        if range.start < last {
+            continue;
+        }
+        result.push_str(&text[last..range.start]);
+        result.push_str(&replacement);
+        last = range.end;
+    }
+    result.push_str(&text[last..]);
+    result
+}
+
+fn is_closing(kind: SyntaxKind) -> bool {
+    matches!(
+        kind,
+        SyntaxKind::RightParen
+            | SyntaxKind::RightBrace
+            | SyntaxKind::RightBracket
+    )
+}
+
+fn is_inline_space(node: &LinkedNode) -> bool {
+    node.kind() == SyntaxKind::Space && !node.text().contains('\n')
+}
+
+/// Put a single space after commas and colons and around `=` in code. Spaces
+/// before commas and colons are removed.
+fn normalize_spacing(text: &str) -> String {
+    fn visit(node: &LinkedNode, edits: &mut Vec<(Range<usize>, String)>) {
+        let list = matches!(
+            node.kind(),
+            SyntaxKind::Args
+                | SyntaxKind::Array
+                | SyntaxKind::Dict
+                | SyntaxKind::Params
+                | SyntaxKind::Destructuring
+        );
+        let pair = matches!(node.kind(), SyntaxKind::Named | SyntaxKind::Keyed);
+        let binding =
+            matches!(node.kind(), SyntaxKind::LetBinding | SyntaxKind::Closure);
+        let children = node.children().collect::<Vec<_>>();
+        for (index, child) in children.iter().enumerate() {
+            let separator = match child.kind() {
+                SyntaxKind::Comma => list,
+                SyntaxKind::Colon => pair,
+                _ => false,
+            };
+            let eq = binding && child.kind() == SyntaxKind::Eq;
+            if !separator && !eq {
+                if !matches!(
+                    child.kind(),
+                    SyntaxKind::Equation | SyntaxKind::Raw
+                ) {
+                    visit(child, edits);
+                }
+                continue;
+            }
+
+            let end = child.offset() + child.len();
+            let prev = index.checked_sub(1).map(|it| &children[it]);
+            match prev {
+                Some(prev) if is_inline_space(prev) => {
+                    let space = if eq { " " } else { "" };
+                    if prev.text() != space {
+                        edits.push((prev.range(), space.to_string()));
+                    }
+                }
+                Some(prev) if eq && prev.kind() != SyntaxKind::Space => {
+                    edits.push((child.offset()..child.offset(), " ".into()));
+                }
+                _ => {}
+            }
+            match (children.get(index + 1), children.get(index + 2)) {
+                // Trailing comma is glued to a closing bracket.
+                (Some(next), Some(last))
+                    if is_inline_space(next) && is_closing(last.kind()) =>
+                {
+                    edits.push((next.range(), String::new()));
+                }
+                (Some(next), _)
+                    if is_inline_space(next) && next.text() != " " =>
+                {
+                    edits.push((next.range(), " ".into()));
+                }
+                (Some(next), _)
+                    if next.kind() != SyntaxKind::Space
+                        && !is_closing(next.kind()) =>
+                {
+                    edits.push((end..end, " ".into()));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let source = Source::detached(text);
+    let mut edits = Vec::new();
+    visit(&LinkedNode::new(source.root()), &mut edits);
+    apply(text, edits)
+}
+
+/// Break argument lists of calls which do not fit into line width. Each
+/// argument is put on its own line with a trailing comma. The outermost call
+/// on a line is broken first; inner calls are broken on later passes if they
+/// are still too long.
+fn break_long_calls(text: &str, line_width: usize) -> String {
+    fn visit(node: &LinkedNode, calls: &mut Vec<Range<usize>>) {
+        if matches!(node.kind(), SyntaxKind::Equation | SyntaxKind::Raw) {
+            return;
+        }
+        if let Some(range) = breakable(node) {
+            calls.push(range);
+        }
+        for child in node.children() {
+            visit(&child, calls);
+        }
+    }
+
+    let mut text = text.to_string();
+    for _ in 0..MAX_BREAKS {
+        let source = Source::detached(text.as_str());
+        let mut calls = Vec::new();
+        visit(&LinkedNode::new(source.root()), &mut calls);
+        let long = |range: &Range<usize>| {
+            let line = source.byte_to_line(range.start).unwrap_or(0);
+            let Some(line) = source.line_to_range(line) else {
+                return false;
+            };
+            let line = text[line].trim_end_matches(['\r', '\n']);
+            line.chars().count() > line_width
+        };
+        // Calls are visited in order so the first one is the outermost.
+        let Some(range) = calls.into_iter().find(long) else {
+            break;
+        };
+        let node = LinkedNode::new(source.root());
+        let Some(args) = node.leaf_at(range.start + 1).and_then(|it| {
+            it.parent()
+                .filter(|it| it.kind() == SyntaxKind::Args)
+                .cloned()
+        }) else {
+            break;
+        };
+        let items = args
+            .children()
+            .take_while(|it| it.kind() != SyntaxKind::RightParen)
+            .filter(|it| it.get().cast::<ast::Arg>().is_some())
+            .map(|it| it.get().clone().into_text().to_string())
+            .collect::<Vec<_>>();
+        let replacement = format!("(\n{},\n)", items.join(",\n"));
+        text = apply(&text, vec![(range, replacement)]);
+    }
+    text
+}
+
+/// Get a range of parentheses of an argument list which could be broken into
+/// several lines: it is a single line list of two or more arguments without
+/// comments.
+fn breakable(node: &LinkedNode) -> Option<Range<usize>> {
+    if node.kind() != SyntaxKind::Args {
+        return None;
+    }
+    let open = node.children().next()?;
+    if open.kind() != SyntaxKind::LeftParen {
+        return None;
+    }
+    let close = node
+        .children()
+        .find(|it| it.kind() == SyntaxKind::RightParen)?;
+    let range = open.offset()..close.offset() + close.len();
+    let mut items = 0;
+    for child in node
+        .children()
+        .take_while(|it| it.offset() < close.offset())
+    {
+        match child.kind() {
+            SyntaxKind::LeftParen | SyntaxKind::Comma => {}
+            SyntaxKind::Space if !child.text().contains('\n') => {}
+            _ if child.get().cast::<ast::Arg>().is_some() => {
+                if child.get().clone().into_text().contains('\n') {
+                    return None;
+                }
+                items += 1;
+            }
+            _ => return None,
+        }
+    }
+    (items >= 2).then_some(range)
+}
+
+/// Indent lines of code by nesting of brackets. A line is indented one level
+/// deeper than a line where its innermost enclosing bracket is opened; a line
+/// which starts with a closing bracket is aligned with the opening one. Lines
+/// of markup, math, and raw blocks are kept as is.
+fn reindent(text: &str, indent: &str) -> String {
+    let source = Source::detached(text);
+    let root = LinkedNode::new(source.root());
+    let mut indents = Vec::<String>::new();
+    let mut result = String::with_capacity(text.len());
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let content = line.trim_start_matches([' ', '\t']);
+        let leading = &line[..line.len() - content.len()];
+        let first = offset + leading.len();
+        offset += line.len();
+
+        let level = match content.trim().is_empty() {
+            true => None,
+            false => code_level(&source, &root, first),
+        };
+        let new = match level {
+            Some((opener, closing)) => {
+                let base = indents.get(opener).cloned().unwrap_or_default();
+                match closing {
+                    true => base,
+                    false => base + indent,
+                }
+            }
+            None => leading.to_string(),
+        };
+        result.push_str(&new);
+        result.push_str(content);
+        indents.push(new);
+    }
+    result
+}
+
+/// Find a line where the innermost bracket around a token is opened and
+/// whether the token closes the bracket. Tokens of markup are skipped.
+fn code_level(
+    source: &Source,
+    root: &LinkedNode,
+    first: usize,
+) -> Option<(usize, bool)> {
+    let leaf = root.leaf_at(first + 1)?;
+    // Line starts inside of a token (e.g. a string or a block comment).
+    if leaf.offset() != first {
+        return None;
+    }
+    let mut node = leaf.parent().cloned();
+    // Content blocks in code are indented like other expressions while their
+    // markup is kept as is.
+    if leaf.kind() == SyntaxKind::LeftBracket {
+        node = node.and_then(|it| it.parent().cloned());
+    }
+    while let Some(current) = node {
+        match current.kind() {
+            SyntaxKind::ContentBlock
+                if leaf.kind() == SyntaxKind::RightBracket
+                    && current.offset() < first =>
+            {
+                let line = source.byte_to_line(current.offset())?;
+                return Some((line, true));
+            }
+            SyntaxKind::Markup
+            | SyntaxKind::ContentBlock
+            | SyntaxKind::Math
+            | SyntaxKind::Equation
+            | SyntaxKind::Raw => return None,
+            SyntaxKind::CodeBlock
+            | SyntaxKind::Args
+            | SyntaxKind::Array
+            | SyntaxKind::Dict
+            | SyntaxKind::Params
+            | SyntaxKind::Parenthesized
+            | SyntaxKind::Destructuring
+                if current.offset() < first =>
+            {
+                let line = source.byte_to_line(current.offset())?;
+                let closing = is_closing(leaf.kind())
+                    && leaf
+                        .parent()
+                        .is_some_and(|it| it.span() == current.span());
+                return Some((line, closing));
+            }
+            _ => node = current.parent().cloned(),
+        }
+    }
+    None
+}
+
+/// Remove trailing whitespace, collapse runs of blank lines into one, and end
+/// text with a single newline. Lines which end inside of tokens (e.g. raw
+/// blocks or strings) are kept as is.
+fn trim_whitespace(text: &str) -> String {
+    let source = Source::detached(text);
+    let root = LinkedNode::new(source.root());
+    let protected = |newline: usize| {
+        root.leaf_at(newline + 1).is_some_and(|it| {
+            !matches!(it.kind(), SyntaxKind::Space | SyntaxKind::Parbreak)
+                && it.offset() <= newline
+        })
+    };
+
+    let mut result = String::with_capacity(text.len());
+    let mut offset = 0;
+    let mut blank = false;
+    for line in text.split_inclusive('\n') {
+        offset += line.len();
+        let (content, eol) = match line.strip_suffix('\n') {
+            Some(content) => match content.strip_suffix('\r') {
+                Some(content) => (content, "\r\n"),
+                None => (content, "\n"),
+            },
+            None => (line, ""),
+        };
+        if !eol.is_empty() && protected(offset - 1) {
+            result.push_str(line);
+            blank = false;
+            continue;
+        }
+        let content = content.trim_end_matches([' ', '\t']);
+        let is_blank = content.is_empty();
+        if !(is_blank && blank) {
+            result.push_str(content);
+            result.push_str(eol);
+        }
+        blank = is_blank;
+    }
+
+    // Exactly one newline at the end.
+    let eol = if text.contains("\r\n") { "\r\n" } else { "\n" };
+    let trimmed = result.trim_end_matches(['\n', '\r', ' ', '\t']).len();
+    if trimmed == 0 {
+        return String::new();
+    }
+    result.truncate(trimmed);
+    result.push_str(eol);
+    result
+}
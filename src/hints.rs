@@ -0,0 +1,171 @@
+//! Inlay hints.
+//!
+//! Values of simple top-level `#let` bindings (lengths, numbers, and strings)
+//! are shown at the end of definition lines. A source is evaluated as a module
+//! and values are taken from its scope. Evaluation is memoized so that it is
+//! cheap right after compilation. Names of parameters are shown before
+//! positional arguments of calls; signatures of callees are resolved like for
+//! signature help.
+
+use std::collections::HashMap;
+
+use comemo::Track;
+use typst::engine::Route;
+use typst::eval::{eval, Tracer};
+use typst::foundations::{Repr, Value};
+use typst::syntax::ast::AstNode;
+use typst::syntax::{ast, LinkedNode, Source, SyntaxKind};
+use typst::World;
+
+/// Kind of a hint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HintKind {
+    /// Value of a binding at the end of a line.
+    Value,
+    /// Name of a parameter before an argument.
+    Parameter,
+}
+
+/// Positional arguments of a call inside of parentheses.
+#[derive(Clone, Debug)]
+pub struct CallSite {
+    /// Byte offset of callee name (e.g. `pow` in `calc.pow`).
+    pub callee: usize,
+    /// Byte offsets of arguments and names of arguments which are plain
+    /// identifiers (they need no hint if they match a parameter name).
+    pub args: Vec<(usize, Option<String>)>,
+}
+
+/// Maximal length of a value in a hint.
+const MAX_LABEL_LEN: usize = 40;
+
+/// Evaluate values of simple top-level `#let` bindings of a source. It
+/// returns byte offsets of line ends of bindings and labels of hints.
+pub fn let_values(world: &dyn World, source: &Source) -> Vec<(usize, String)> {
+    // The last binding wins if a name is bound several times.
+    let mut bindings = HashMap::new();
+    for node in source.root().children() {
+        let Some(binding) = node.cast::<ast::LetBinding>() else {
+            continue;
+        };
+        let ast::LetBindingKind::Normal(ast::Pattern::Normal(
+            ast::Expr::Ident(ident),
+        )) = binding.kind()
+        else {
+            continue;
+        };
+        let Some(init) = binding.init() else {
+            continue;
+        };
+        let offset = source.range(binding.span()).map(|it| it.end);
+        if let Some(offset) = offset {
+            bindings.insert(ident.get().to_string(), (offset, init));
+        }
+    }
+    if bindings.is_empty() {
+        return Vec::new();
+    }
+
+    let mut tracer = Tracer::new();
+    let Ok(module) = eval(
+        world.track(),
+        Route::default().track(),
+        tracer.track_mut(),
+        source,
+    ) else {
+        return Vec::new();
+    };
+
+    let mut hints = bindings
+        .into_iter()
+        .filter_map(|(name, (offset, init))| {
+            let value = module.scope().get(&name)?;
+            let label = label(value)?;
+            // Literals are obvious.
+            if init.to_untyped().clone().into_text() == label.as_str() {
+                return None;
+            }
+            Some((line_end(source, offset), format!("= {label}")))
+        })
+        .collect::<Vec<_>>();
+    hints.sort();
+    hints
+}
+
+/// Render a value if it is simple enough.
+fn label(value: &Value) -> Option<String> {
+    let repr = match value {
+        Value::Int(_)
+        | Value::Float(_)
+        | Value::Length(_)
+        | Value::Angle(_)
+        | Value::Ratio(_)
+        | Value::Relative(_)
+        | Value::Fraction(_)
+        | Value::Str(_) => value.repr().to_string(),
+        _ => return None,
+    };
+    match repr.char_indices().nth(MAX_LABEL_LEN) {
+        Some((index, _)) => Some(format!("{}…", &repr[..index])),
+        None => Some(repr),
+    }
+}
+
+/// Byte offset of the end of a line which contains an offset.
+fn line_end(source: &Source, offset: usize) -> usize {
+    let text = source.text();
+    text[offset..]
+        .find(['\n', '\r'])
+        .map_or(text.len(), |index| offset + index)
+}
+
+/// Find all calls with positional arguments in a source.
+pub fn call_sites(source: &Source) -> Vec<CallSite> {
+    fn visit(node: &LinkedNode, sites: &mut Vec<CallSite>) {
+        if let Some(site) = call_site(node) {
+            sites.push(site);
+        }
+        for child in node.children() {
+            visit(&child, sites);
+        }
+    }
+    let mut sites = Vec::new();
+    visit(&LinkedNode::new(source.root()), &mut sites);
+    sites
+}
+
+fn call_site(node: &LinkedNode) -> Option<CallSite> {
+    let call = node.cast::<ast::FuncCall>()?;
+    let callee = match call.callee() {
+        ast::Expr::Ident(ident) => ident.span(),
+        ast::Expr::MathIdent(ident) => ident.span(),
+        ast::Expr::FieldAccess(access) => access.field().span(),
+        _ => return None,
+    };
+    let callee = node.find(callee)?.offset();
+
+    // Trailing content blocks are obvious.
+    let args = call.args();
+    let paren = args
+        .to_untyped()
+        .children()
+        .position(|it| it.kind() == SyntaxKind::RightParen)?;
+    let args = args
+        .to_untyped()
+        .children()
+        .take(paren)
+        .filter_map(|it| match it.cast::<ast::Arg>()? {
+            ast::Arg::Pos(expr) => Some(expr),
+            _ => None,
+        })
+        .filter_map(|expr| {
+            let offset = node.find(expr.span())?.offset();
+            let name = match expr {
+                ast::Expr::Ident(ident) => Some(ident.get().to_string()),
+                _ => None,
+            };
+            Some((offset, name))
+        })
+        .collect::<Vec<_>>();
+    (!args.is_empty()).then_some(CallSite { callee, args })
+}
@@ -0,0 +1,94 @@
+//! Summaries of images.
+//!
+//! Hovering a path argument of `#image` (e.g. in a figure) shows format,
+//! dimensions, and size of an image file. Clients which render Markdown get a
+//! thumbnail as well: raster images are downscaled and re-encoded to PNG and
+//! small SVG images are embedded as is.
+
+use std::io::Cursor;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use image::io::Reader;
+use image::ImageFormat;
+use typst::syntax::Source;
+
+use crate::data::path_arg_at;
+
+/// Maximal width and height of a thumbnail in pixels.
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// Maximal size of an SVG image which is embedded into a thumbnail.
+const MAX_SVG_SIZE: usize = 256 * 1024;
+
+/// Find a path argument of `image` function at cursor.
+pub fn image_path_at(source: &Source, cursor: usize) -> Option<String> {
+    let (callee, path) = path_arg_at(source, cursor)?;
+    (callee == "image").then_some(path)
+}
+
+/// Summarize an image in Markdown and make its thumbnail as data URL if it
+/// is possible.
+pub fn summarize(
+    path: &str,
+    bytes: &[u8],
+) -> Result<(String, Option<String>), String> {
+    let size = format_size(bytes.len());
+    if is_svg(path, bytes) {
+        let thumbnail = (bytes.len() <= MAX_SVG_SIZE).then(|| {
+            let data = BASE64_STANDARD.encode(bytes);
+            format!("data:image/svg+xml;base64,{data}")
+        });
+        return Ok((format!("**SVG** `{path}`: {size}"), thumbnail));
+    }
+
+    let reader = Reader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|err| format!("failed to read image: {err}"))?;
+    let format = match reader.format() {
+        Some(format) => format!("{format:?}").to_uppercase(),
+        None => return Err(format!("unknown format of image `{path}`")),
+    };
+    let image = reader
+        .decode()
+        .map_err(|err| format!("failed to decode image: {err}"))?;
+    let (width, height) = (image.width(), image.height());
+    let summary =
+        format!("**{format}** `{path}`: {width} × {height} px, {size}");
+
+    let image = match width.max(height) > THUMBNAIL_SIZE {
+        true => image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE),
+        false => image,
+    };
+    let mut buffer = Cursor::new(Vec::<u8>::new());
+    let thumbnail =
+        image.write_to(&mut buffer, ImageFormat::Png).ok().map(|_| {
+            let data = BASE64_STANDARD.encode(buffer.into_inner());
+            format!("data:image/png;base64,{data}")
+        });
+    Ok((summary, thumbnail))
+}
+
+/// Typst infers format of SVG images from extension but content is checked
+/// as well for images without extension.
+fn is_svg(path: &str, bytes: &[u8]) -> bool {
+    if path.to_lowercase().ends_with(".svg") {
+        return true;
+    }
+    let head = &bytes[..bytes.len().min(1024)];
+    String::from_utf8_lossy(head).contains("<svg")
+}
+
+/// Format size of a file in bytes with binary prefixes (e.g. `1.5 KiB`).
+fn format_size(size: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = size as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{size} B"),
+        _ => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
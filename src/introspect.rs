@@ -0,0 +1,229 @@
+//! Document introspection.
+//!
+//! Compiled document keeps an introspector which knows all locatable elements
+//! (headings, figures, labelled content, metadata) and their positions on
+//! pages. This module extracts them as plain data for preview panels, tables
+//! of contents, and external tooling. Arbitrary elements are selected with
+//! selectors like in `typst query`.
+
+use std::path::{Path, PathBuf};
+
+use comemo::Track;
+use serde::Serialize;
+use tower_lsp::lsp_types::Range;
+use typst::eval::{eval_string, EvalMode};
+use typst::foundations::{Content, IntoValue, LocatableSelector, Scope, Value};
+use typst::model::Document;
+use typst::syntax::Span;
+use typst::World;
+
+use crate::deps::span_to_range;
+use crate::syntax::PositionEncoding;
+
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Introspection {
+    pub headings: Vec<Heading>,
+    pub figures: Vec<Figure>,
+    pub labels: Vec<LabelTarget>,
+    pub metadata: Vec<Metadata>,
+}
+
+/// Position of an element in a document: page (starting at 1) and coordinates
+/// in points from the top left corner of a page.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Position {
+    pub page: usize,
+    pub x: f64,
+    pub y: f64,
+}
+
+/// Location of an element in a source file.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceLocation {
+    pub path: PathBuf,
+    pub range: Range,
+}
+
+/// Properties which all introspected elements share.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Element {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub position: Position,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<SourceLocation>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Heading {
+    pub level: usize,
+    pub body: String,
+    #[serde(flatten)]
+    pub element: Element,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Figure {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub caption: Option<String>,
+    #[serde(flatten)]
+    pub element: Element,
+}
+
+/// Labelled element which a reference to the label resolves to.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LabelTarget {
+    /// Name of element (e.g. `heading` or `figure`).
+    pub target: String,
+    #[serde(flatten)]
+    pub element: Element,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    pub value: serde_json::Value,
+    #[serde(flatten)]
+    pub element: Element,
+}
+
+/// Extract headings, figures, labels, and metadata from compiled document.
+/// Paths to source files are resolved against `root_dir`.
+pub fn introspect(
+    world: &dyn World,
+    root_dir: &Path,
+    document: &Document,
+    encoding: PositionEncoding,
+) -> Introspection {
+    let mut result = Introspection::default();
+    for content in document.introspector.all() {
+        let Some(element) =
+            describe(world, root_dir, document, content, encoding)
+        else {
+            continue;
+        };
+        if element.label.is_some() {
+            result.labels.push(LabelTarget {
+                target: content.elem().name().to_string(),
+                element: element.clone(),
+            });
+        }
+        match content.elem().name() {
+            "heading" => result.headings.push(Heading {
+                level: match content.get_by_name("level") {
+                    Some(Value::Int(level)) => level as usize,
+                    _ => 1,
+                },
+                body: plain_text(content.get_by_name("body")),
+                element,
+            }),
+            "figure" => result.figures.push(Figure {
+                kind: match content.get_by_name("kind") {
+                    Some(Value::Func(func)) => func.name().map(str::to_string),
+                    Some(Value::Str(kind)) => Some(kind.to_string()),
+                    _ => None,
+                },
+                caption: match content.get_by_name("caption") {
+                    Some(Value::Content(caption)) => {
+                        Some(plain_text(caption.get_by_name("body")))
+                    }
+                    _ => None,
+                },
+                element,
+            }),
+            "metadata" => result.metadata.push(Metadata {
+                value: content
+                    .get_by_name("value")
+                    .and_then(|value| serde_json::to_value(value).ok())
+                    .unwrap_or_default(),
+                element,
+            }),
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Find elements of a document which match a selector given as Typst code
+/// (e.g. `heading`, `<intro>`, or `figure.where(kind: table)`). Values of a
+/// field of elements are returned instead of elements if field is set.
+pub fn query(
+    world: &dyn World,
+    document: &Document,
+    selector: &str,
+    field: Option<&str>,
+) -> Result<Vec<Value>, String> {
+    let value = eval_string(
+        world.track(),
+        selector,
+        Span::detached(),
+        EvalMode::Code,
+        Scope::default(),
+    )
+    .map_err(|errs| match errs.first() {
+        Some(err) => format!("failed to evaluate selector: {}", err.message),
+        None => "failed to evaluate selector".to_string(),
+    })?;
+    let selector = value
+        .cast::<LocatableSelector>()
+        .map_err(|err| format!("invalid selector: {err}"))?;
+    let elements = document.introspector.query(&selector.0);
+    let values = match field {
+        Some(field) => elements
+            .iter()
+            .filter_map(|content| content.get_by_name(field))
+            .collect(),
+        None => elements.into_iter().map(IntoValue::into_value).collect(),
+    };
+    Ok(values)
+}
+
+/// Describe position of a locatable element in document and in source.
+fn describe(
+    world: &dyn World,
+    root_dir: &Path,
+    document: &Document,
+    content: &Content,
+    encoding: PositionEncoding,
+) -> Option<Element> {
+    let location = content.location()?;
+    let position = document.introspector.position(location);
+    let source = content.span().id().and_then(|id| {
+        // Elements from packages are not a part of a workspace.
+        if id.package().is_some() {
+            return None;
+        }
+        let source = world.source(id).ok()?;
+        Some(SourceLocation {
+            path: root_dir.join(id.vpath().as_rootless_path()),
+            range: span_to_range(&source, content.span(), encoding)?,
+        })
+    });
+    Some(Element {
+        label: content.label().map(|label| label.as_str().to_string()),
+        position: Position {
+            page: position.page.get(),
+            x: position.point.x.to_pt(),
+            y: position.point.y.to_pt(),
+        },
+        source,
+    })
+}
+
+/// Plain text of a field value which is content or a string.
+pub(crate) fn plain_text(value: Option<Value>) -> String {
+    match value {
+        Some(Value::Content(content)) => content.plain_text().to_string(),
+        Some(Value::Str(text)) => text.to_string(),
+        _ => String::new(),
+    }
+}
@@ -0,0 +1,91 @@
+//! Labels.
+//!
+//! Labels are collected syntactically so that references are completed
+//! without compilation. A label is attached to the preceding element in
+//! markup (e.g. `= Intro <intro>`, `#figure(..) <fig>`, or `$ x $ <eq>`).
+//! Text of the element (or a caption of a figure) is shown as a preview.
+
+use typst::syntax::ast::AstNode;
+use typst::syntax::{ast, LinkedNode, Source, SyntaxKind, SyntaxNode};
+
+/// Maximal length of a preview in characters.
+const MAX_PREVIEW_LEN: usize = 60;
+
+/// Label with a preview of the element it is attached to.
+#[derive(Clone, Debug)]
+pub struct Label {
+    pub name: String,
+    /// Kind of an element (e.g. `heading` or `figure`) and its text.
+    pub detail: String,
+}
+
+/// Find all labels of a source.
+pub fn find_labels(source: &Source) -> Vec<Label> {
+    fn visit(node: &LinkedNode, labels: &mut Vec<Label>) {
+        if let Some(label) = node.cast::<ast::Label>() {
+            // Labels in code (e.g. `#cite(<key>)`) are not definitions.
+            if node.parent_kind() == Some(SyntaxKind::Markup) {
+                labels.push(Label {
+                    name: label.get().to_string(),
+                    detail: describe(node),
+                });
+            }
+        }
+        for child in node.children() {
+            visit(&child, labels);
+        }
+    }
+    let mut labels = Vec::new();
+    visit(&LinkedNode::new(source.root()), &mut labels);
+    labels
+}
+
+/// Describe an element which a label is attached to.
+fn describe(label: &LinkedNode) -> String {
+    let Some(prev) = label.prev_sibling() else {
+        return "content".to_string();
+    };
+    let text = |node: &SyntaxNode| preview(&node.clone().into_text());
+    match prev.get().cast::<ast::Expr>() {
+        Some(ast::Expr::Heading(heading)) => {
+            format!("heading: {}", text(heading.body().to_untyped()))
+        }
+        Some(ast::Expr::Equation(_)) => {
+            format!("equation: {}", text(prev.get()))
+        }
+        Some(ast::Expr::Raw(_)) => format!("raw: {}", text(prev.get())),
+        Some(ast::Expr::FuncCall(call)) => {
+            let kind = match call.callee() {
+                ast::Expr::Ident(ident) => ident.get().clone(),
+                ast::Expr::FieldAccess(access) => access.field().get().clone(),
+                _ => "content".into(),
+            };
+            // A caption describes a figure better than its body.
+            let caption = call.args().items().find_map(|arg| match arg {
+                ast::Arg::Named(named) if named.name().get() == "caption" => {
+                    Some(named.expr())
+                }
+                _ => None,
+            });
+            match caption {
+                Some(ast::Expr::Content(block)) => {
+                    format!("{kind}: {}", text(block.body().to_untyped()))
+                }
+                Some(caption) => {
+                    format!("{kind}: {}", text(caption.to_untyped()))
+                }
+                None => format!("{kind}: {}", text(prev.get())),
+            }
+        }
+        _ => format!("content: {}", text(prev.get())),
+    }
+}
+
+/// Collapse whitespaces and truncate text.
+fn preview(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.char_indices().nth(MAX_PREVIEW_LEN) {
+        Some((index, _)) => format!("{}…", &text[..index]),
+        None => text,
+    }
+}
@@ -1,29 +1,90 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
 use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use comemo::Prehashed;
 use fontdb::Database;
-use typst::diag::{FileError, FileResult};
+use serde::{Deserialize, Serialize};
+use typst::diag::{FileError, FileResult, SourceDiagnostic};
 use typst::eval::Tracer;
-use typst::foundations::{Bytes, Datetime, Smart};
+use typst::foundations::{Bytes, Category, Datetime, Dict, Func, Smart, Value};
+use typst::layout::{Abs, Point};
 use typst::model::Document;
-use typst::syntax::{FileId, Source, VirtualPath};
+use typst::syntax::ast::{self, AstNode};
+use typst::syntax::package::{PackageSpec, PackageVersion};
+use typst::syntax::{
+    FileId, LinkedNode, Source, Span, SyntaxKind, VirtualPath,
+};
 use typst::text::{Font, FontBook, FontInfo};
+use typst::util::hash128;
+use typst::visualize::Color;
 use typst::{Library, World};
-use typst_ide::autocomplete;
 use typst_ide::CompletionKind;
+use typst_ide::{
+    autocomplete, jump_from_click, jump_from_cursor, tooltip, Jump,
+};
 
+use diagnostics::{Diagnostic, Severity};
+use docs::Docs;
+use hints::HintKind;
+use lock::{Lockfile, Resolution};
+use signature::Signature;
+use syntax::PositionEncoding;
+
+pub mod actions;
+pub mod bibliography;
+pub mod builder;
+pub mod capabilities;
+pub mod completion;
+pub mod config;
+pub mod count;
+pub mod data;
+pub mod date;
+pub mod definition;
+pub mod deps;
+pub mod diagnostics;
+pub mod docs;
+pub mod doctor;
+pub mod format;
+pub mod hints;
+pub mod images;
+pub mod introspect;
+pub mod labels;
+pub mod linked;
+pub mod lock;
+pub mod math;
+pub mod metrics;
+pub mod notebook;
+pub mod on_type;
 pub mod package;
+pub mod path;
+pub mod pdf;
+pub mod preview;
+pub mod session;
+pub mod signature;
+pub mod snippet;
+pub mod syntax;
+pub mod update;
+pub mod uri;
+pub mod watch;
 pub mod workspace;
 
 pub struct CompletionItem {
     pub label: String,
-    pub kind: CompletionKind,
+    pub kind: completion::Kind,
+    /// Text to insert in Typst snippet syntax (e.g. `heading(${})`). Label is
+    /// inserted if it is missing.
+    pub apply: Option<String>,
+    /// Short description (e.g. the first sentence of documentation).
+    pub detail: Option<String>,
+    pub docs: Option<Docs>,
+    /// Range of text which is replaced by an item. Client decides what to
+    /// replace if it is missing.
+    pub range: Option<(diagnostics::Position, diagnostics::Position)>,
 }
 
 #[derive(Debug)]
@@ -80,53 +141,75 @@ fn add_embedded_fonts(book: &mut FontBook, fonts: &mut Vec<LazyFont>) {
     add!("DejaVuSansMono-BoldOblique.ttf");
 }
 
-/// We should make an assumption that each instance of World corresponds to a
-/// specific main fail (=target).
+/// Embedded and system fonts. Font discovery takes a while so that fonts are
+/// discovered once per process (and per set of additional font directories
+/// and font settings) and shared by all worlds and by all sessions of language
+/// server. Discovered fonts could be persisted to cache in order to speed up
+/// the next launch.
 #[derive(Debug)]
-pub struct LanguageServiceWorld {
-    /// Path to a root directory. All source files are relative to it.
-    root_dir: PathBuf,
-    /// Path to main file (usually `main.typ`).
-    main_path: PathBuf,
-    /// Typst's standard library.
-    library: Prehashed<Library>,
+pub struct FontProvider {
     /// Metadata about discovered fonts.
     book: Prehashed<FontBook>,
     /// Locations of and storage for lazily loaded fonts.
     fonts: Vec<LazyFont>,
-    /// Source files.
-    sources: RefCell<HashMap<PathBuf, Source>>,
-    /// Result of compilation.
-    document: Arc<Document>,
+    /// Additional font directories.
+    font_paths: Vec<PathBuf>,
+    /// Sources of fonts besides additional directories.
+    settings: config::Fonts,
+    /// Time of discovery in seconds since Unix epoch.
+    discovered_at: u64,
 }
 
-impl LanguageServiceWorld {
-    /// Create an evaluation context with main source file `main_path` and all
-    /// files located at `root_dir`.
-    pub fn new(
-        root_dir: &Path,
-        main_path: &Path,
-        main_text: Option<String>,
-    ) -> Option<LanguageServiceWorld> {
-        // Read main file or fail.
-        let vpath = VirtualPath::within_root(main_path, root_dir)?;
-        let file_id = FileId::new(None, vpath);
-        let text = main_text.or_else(|| match fs::read(main_path) {
-            Ok(bytes) => String::from_utf8(bytes).ok(),
-            Err(_) => None,
-        })?;
-        let source = Source::new(file_id, text);
-        let sources = HashMap::<PathBuf, Source>::from([(
-            main_path.to_path_buf(),
-            source,
-        )]);
+/// Lifetime of persisted font cache. Newly installed fonts are noticed only
+/// after cache expires.
+const FONT_CACHE_TTL: u64 = 24 * 60 * 60;
+
+/// Persisted fonts discovered with the same additional font directories.
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedFonts {
+    font_paths: Vec<PathBuf>,
+    /// System fonts are discovered as well.
+    system: bool,
+    discovered_at: u64,
+    faces: Vec<CachedFace>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedFace {
+    path: PathBuf,
+    index: u32,
+    /// Modification time of font file in seconds since Unix epoch.
+    modified: u64,
+    info: FontInfo,
+}
+
+impl FontProvider {
+    /// Discover fonts in directories `font_paths` and in `TYPST_FONT_PATHS`
+    /// as well as embedded and system fonts unless they are disabled in
+    /// settings. Fonts are read from cache if it is fresh.
+    pub fn discover(font_paths: &[PathBuf], settings: &config::Fonts) -> Self {
+        let mut font_paths = font_paths.to_vec();
+        if let Some(paths) = std::env::var_os("TYPST_FONT_PATHS") {
+            font_paths.extend(std::env::split_paths(&paths));
+        }
+        if let Some(provider) = Self::load_cache(&font_paths, settings) {
+            log::info!("load {} font(s) from cache", provider.fonts.len());
+            return provider;
+        }
 
         let mut db = Database::new();
-        db.load_system_fonts();
+        for path in font_paths.iter() {
+            db.load_fonts_dir(path);
+        }
+        if settings.system {
+            db.load_system_fonts();
+        }
 
         let mut book = FontBook::new();
         let mut fonts = Vec::<LazyFont>::new();
-        add_embedded_fonts(&mut book, &mut fonts);
+        if settings.embedded {
+            add_embedded_fonts(&mut book, &mut fonts);
+        }
         for face in db.faces() {
             let path = match &face.source {
                 fontdb::Source::Binary(_) => continue,
@@ -148,34 +231,544 @@ impl LanguageServiceWorld {
             }
         }
 
+        Self {
+            book: Prehashed::new(book),
+            fonts,
+            font_paths,
+            settings: settings.clone(),
+            discovered_at: unix_time(SystemTime::now()).unwrap_or_default(),
+        }
+    }
+
+    /// Number of discovered fonts (including embedded ones).
+    pub fn len(&self) -> usize {
+        self.fonts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fonts.is_empty()
+    }
+
+    /// Get fonts shared by all worlds with the same additional font
+    /// directories and font settings.
+    pub fn shared(
+        font_paths: &[PathBuf],
+        settings: &config::Fonts,
+    ) -> Arc<Self> {
+        SHARED_FONTS
+            .lock()
+            .unwrap()
+            .entry((font_paths.to_vec(), settings.clone()))
+            .or_insert_with(|| Arc::new(Self::discover(font_paths, settings)))
+            .clone()
+    }
+
+    /// Persist all shared fonts to cache.
+    pub fn persist_shared() -> Result<(), String> {
+        let path = font_cache_path().ok_or("no cache directory")?;
+        let cache = SHARED_FONTS
+            .lock()
+            .unwrap()
+            .values()
+            .map(|provider| provider.to_cache())
+            .collect::<Vec<_>>();
+        let text = serde_json::to_string(&cache)
+            .map_err(|err| format!("failed to serialize font cache: {err}"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|err| format!("failed to create {dir:?}: {err}"))?;
+        }
+        fs::write(&path, text)
+            .map_err(|err| format!("failed to write {path:?}: {err}"))
+    }
+
+    fn to_cache(&self) -> CachedFonts {
+        let faces = self
+            .fonts
+            .iter()
+            .enumerate()
+            // Embedded fonts have no path.
+            .filter(|(_, font)| !font.path.as_os_str().is_empty())
+            .filter_map(|(index, font)| {
+                Some(CachedFace {
+                    path: font.path.clone(),
+                    index: font.index,
+                    modified: modified(&font.path)?,
+                    info: self.book.info(index)?.clone(),
+                })
+            })
+            .collect();
+        CachedFonts {
+            font_paths: self.font_paths.clone(),
+            system: self.settings.system,
+            discovered_at: self.discovered_at,
+            faces,
+        }
+    }
+
+    /// Load fonts from cache if cache is fresh and no font file has been
+    /// changed since then.
+    fn load_cache(
+        font_paths: &[PathBuf],
+        settings: &config::Fonts,
+    ) -> Option<Self> {
+        let text = fs::read_to_string(font_cache_path()?).ok()?;
+        let caches = serde_json::from_str::<Vec<CachedFonts>>(&text).ok()?;
+        let cache = caches.into_iter().find(|it| {
+            it.font_paths == font_paths && it.system == settings.system
+        })?;
+        let now = unix_time(SystemTime::now())?;
+        if now > cache.discovered_at + FONT_CACHE_TTL {
+            return None;
+        }
+        let mut book = FontBook::new();
+        let mut fonts = Vec::<LazyFont>::new();
+        if settings.embedded {
+            add_embedded_fonts(&mut book, &mut fonts);
+        }
+        for face in cache.faces {
+            if modified(&face.path) != Some(face.modified) {
+                log::info!("font {:?} has been changed", face.path);
+                return None;
+            }
+            book.push(face.info);
+            fonts.push(LazyFont {
+                path: face.path,
+                index: face.index,
+                font: Default::default(),
+            });
+        }
+        Some(Self {
+            book: Prehashed::new(book),
+            fonts,
+            font_paths: cache.font_paths,
+            settings: settings.clone(),
+            discovered_at: cache.discovered_at,
+        })
+    }
+}
+
+/// Shared fonts indexed by additional font directories and font settings.
+type SharedFonts = BTreeMap<(Vec<PathBuf>, config::Fonts), Arc<FontProvider>>;
+
+static SHARED_FONTS: Mutex<SharedFonts> = Mutex::new(BTreeMap::new());
+
+fn font_cache_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("typstd/fonts.json"))
+}
+
+fn unix_time(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|it| it.as_secs())
+}
+
+/// Modification time of a file in seconds since Unix epoch.
+fn modified(path: &Path) -> Option<u64> {
+    unix_time(fs::metadata(path).ok()?.modified().ok()?)
+}
+
+/// Make an image which substitutes an image file in draft mode. Typst infers
+/// image format from file extension so that placeholder must be encoded in
+/// the same format.
+fn placeholder_image(path: &Path) -> Option<Bytes> {
+    static SVG: &[u8] = br##"<svg xmlns="http://www.w3.org/2000/svg" width="64" height="48"><rect width="64" height="48" fill="#ccc"/></svg>"##;
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    let format = match ext.as_str() {
+        "svg" => return Some(Bytes::from_static(SVG)),
+        "png" => image::ImageFormat::Png,
+        "jpg" | "jpeg" => image::ImageFormat::Jpeg,
+        "gif" => image::ImageFormat::Gif,
+        _ => return None,
+    };
+    let pixels = image::RgbImage::from_pixel(64, 48, image::Rgb([204; 3]));
+    let mut buffer = std::io::Cursor::new(Vec::<u8>::new());
+    pixels.write_to(&mut buffer, format).ok()?;
+    Some(Bytes::from(buffer.into_inner()))
+}
+
+/// Load lockfile from a root directory. An empty lockfile is created for
+/// projects (i.e. directories with `typst.toml`) without it.
+fn load_lockfile(root_dir: &Path) -> Option<Lockfile> {
+    match Lockfile::load(root_dir) {
+        Ok(Some(lockfile)) => Some(lockfile),
+        Ok(None) if root_dir.join(workspace::FILENAME).exists() => {
+            Some(Lockfile::default())
+        }
+        Ok(None) => None,
+        Err(err) => {
+            log::warn!("failed to load lockfile: {}", err);
+            None
+        }
+    }
+}
+
+/// We should make an assumption that each instance of World corresponds to a
+/// specific main fail (=target).
+///
+/// # Locking
+///
+/// Language server shares a world between handlers behind a `RwLock`. State
+/// of a world is split into three parts.
+///
+/// 1. Settings (paths, library, fonts, export options, and so on) are
+///    changed through `&mut self` only, i.e. under the write lock of a world.
+///    It is taken on (re)configuration only and it is never held for long.
+/// 2. Files (`sources`, `overlay`, `bytes`, and `watcher`) are read-mostly
+///    snapshot of a project. Edits of editor and changes on disk are applied
+///    through `&self` under the read lock of a world. Their own locks are held
+///    for a single lookup or update, so edits and queries (e.g. completions)
+///    never wait for compilation.
+/// 3. Compilation state (`history`, `document`, `dependencies`,
+///    `diagnostics`, `downloads`, and `stats`) belongs to compilation.
+///    `history` is held for the whole compilation so that compilations of a
+///    world do not interleave. Queries read the last results only.
+///
+/// Locks of a world are taken in the order of the list above (a world lock,
+/// then `history`, then locks of files, then the rest of compilation state)
+/// and a lock is never taken while a lock of a later part is held.
+#[derive(Debug)]
+pub struct LanguageServiceWorld {
+    /// Path to a root directory. All source files are relative to it.
+    root_dir: PathBuf,
+    /// Path to main file (usually `main.typ`).
+    main_path: PathBuf,
+    /// Typst's standard library.
+    library: Prehashed<Library>,
+    /// Cache directory of packages of this world only. Shared cache
+    /// directories are used if it is missing.
+    package_cache: Option<PathBuf>,
+    /// Embedded and system fonts shared by all worlds. Default fonts are
+    /// discovered on the first access unless fonts are set before.
+    fonts: OnceLock<Arc<FontProvider>>,
+    /// Source files indexed by normalized paths (see [`path::normalize`]).
+    sources: RwLock<HashMap<PathBuf, Source>>,
+    /// Normalized paths of files opened in editor. Their texts in `sources`
+    /// shadow files on disk until they are closed.
+    overlay: RwLock<HashSet<PathBuf>>,
+    /// Contents of files provided in memory (e.g. data files or images of a
+    /// remote client) indexed by normalized paths. They shadow files on disk.
+    bytes: RwLock<HashMap<PathBuf, Bytes>>,
+    /// Result of compilation.
+    document: RwLock<Arc<Document>>,
+    /// Path to output PDF file.
+    output_path: PathBuf,
+    /// Export PDF on full compilation.
+    export: bool,
+    /// Settings of fonts in exported PDF.
+    pdf: config::Pdf,
+    /// Format of exported document.
+    format: config::ExportFormat,
+    /// Files accessed during the last compilation.
+    dependencies: Mutex<HashMap<FileId, PathBuf>>,
+    /// Files of a project read from file system which could be changed
+    /// outside of editor.
+    watcher: Mutex<watch::Watcher>,
+    /// Package lockfile of a project if any. It is loaded from disk once
+    /// again on each compilation.
+    lockfile: Mutex<Option<Lockfile>>,
+    /// Record packages used by compilations to lockfile.
+    lock_packages: bool,
+    /// Download missing packages in background instead of waiting for them.
+    background_downloads: bool,
+    /// Packages which are being downloaded in background for the last
+    /// compilation.
+    downloads: Mutex<HashSet<PackageSpec>>,
+    /// Errors, warnings, and hints issued during the last compilation.
+    diagnostics: Mutex<Vec<Diagnostic>>,
+    /// Compilation in draft mode is in progress.
+    draft: AtomicBool,
+    /// The least severity of reported diagnostics.
+    diagnostics_level: Severity,
+    /// Report imports of packages which have newer versions in package index.
+    update_hints: bool,
+    /// Preview which follows successful compilations.
+    preview: Option<Arc<preview::Preview>>,
+    /// Units of columns of positions which are exchanged with a client.
+    position_encoding: PositionEncoding,
+    /// Fixed UNIX timestamp of current date for reproducible builds.
+    timestamp: Option<i64>,
+    /// Statistics of the last compilation.
+    stats: Mutex<CompileStats>,
+    /// Hashes of the last compilation. The lock is held for the whole
+    /// compilation so that compilations of a world do not interleave (they
+    /// share dependencies and diagnostics) while queries (e.g. completions)
+    /// read sources concurrently.
+    history: Mutex<History>,
+}
+
+/// Hashes of pages of the last compiled document and of sources used by the
+/// last compilation.
+#[derive(Debug, Default)]
+struct History {
+    pages: Vec<u128>,
+    sources: HashMap<FileId, u128>,
+}
+
+/// Statistics of compilation which shows how much of the previous document
+/// and sources are reused. Typst memoizes layout so unchanged pages of
+/// previous document are expected to be reproduced as is.
+#[derive(Clone, Debug, Default)]
+pub struct CompileStats {
+    pub pages: usize,
+    pub reused_pages: usize,
+    pub sources: usize,
+    pub reused_sources: usize,
+}
+
+impl CompileStats {
+    /// Fraction of pages which are the same as in previous document.
+    pub fn reuse_ratio(&self) -> f64 {
+        match self.pages {
+            0 => 0.0,
+            pages => self.reused_pages as f64 / pages as f64,
+        }
+    }
+}
+
+impl LanguageServiceWorld {
+    /// Create an evaluation context with main source file `main_path` and all
+    /// files located at `root_dir`.
+    pub fn new(
+        root_dir: &Path,
+        main_path: &Path,
+        main_text: Option<String>,
+    ) -> Option<LanguageServiceWorld> {
+        // Read main file or fail.
+        let vpath = VirtualPath::within_root(main_path, root_dir)?;
+        let file_id = FileId::new(None, vpath);
+        let text = main_text.or_else(|| match fs::read(main_path) {
+            Ok(bytes) => String::from_utf8(bytes).ok(),
+            Err(_) => None,
+        })?;
+        let source = Source::new(file_id, text);
+        let sources = HashMap::<PathBuf, Source>::from([(
+            path::normalize(main_path),
+            source,
+        )]);
+
         Some(Self {
             root_dir: root_dir.to_path_buf(),
             main_path: main_path.to_path_buf(),
             library: Prehashed::new(Library::default()),
-            book: Prehashed::new(book),
-            fonts: fonts,
+            package_cache: None,
+            fonts: OnceLock::new(),
             sources: sources.into(),
+            overlay: Default::default(),
+            bytes: Default::default(),
             document: Default::default(),
+            output_path: main_path.with_extension("pdf"),
+            export: true,
+            dependencies: Default::default(),
+            watcher: Default::default(),
+            lockfile: load_lockfile(root_dir).into(),
+            lock_packages: false,
+            background_downloads: false,
+            downloads: Default::default(),
+            diagnostics: Default::default(),
+            pdf: Default::default(),
+            format: Default::default(),
+            draft: Default::default(),
+            diagnostics_level: Severity::Hint,
+            update_hints: false,
+            preview: None,
+            position_encoding: Default::default(),
+            timestamp: date::source_date_epoch(),
+            stats: Default::default(),
+            history: Default::default(),
         })
     }
 
-    pub fn add_file(&mut self, path: &Path, text: String) {
-        // Make FileID (an internal identifier for a file in Typst).
-        let root_dir = path.parent().unwrap();
-        let vpath = VirtualPath::within_root(path, root_dir).unwrap();
-        let id = FileId::new(None, vpath);
+    /// Open a file in editor: its text shadows the file on disk for both
+    /// sources and raw bytes until [`LanguageServiceWorld::close_file`].
+    /// Text of an already open file is replaced.
+    pub fn open_file(&self, path: &Path, text: String) {
+        // Content of a file is managed by editor from now on.
+        let key = self.key(path);
+        self.watcher.lock().unwrap().untrack(&key);
+        self.overlay.write().unwrap().insert(key.clone());
+
+        // Update existing source in place so that unchanged parts of syntax
+        // tree (and memoized results which depend on them) are reused.
+        if let Some(source) = self.sources.write().unwrap().get_mut(&key) {
+            if source.text() != text {
+                source.replace(&text);
+            }
+            return;
+        }
+
+        // Make FileID (an internal identifier for a file in Typst). It should
+        // be relative to root directory in order to match identifiers which
+        // Typst requests.
+        let id = self.file_id(path).unwrap_or_else(|| {
+            let vpath = VirtualPath::within_root(path, path.parent().unwrap());
+            FileId::new(None, vpath.unwrap())
+        });
 
         // // Read file content, decode and return it as a source.
         // let body = fs::read(path).unwrap();
         // let text = String::from_utf8(body).unwrap();
         let source = Source::new(id, text);
 
-        self.sources.borrow_mut().insert(path.to_path_buf(), source);
+        self.sources.write().unwrap().insert(key, source);
+    }
+
+    /// Close a file in editor so that it is read from file system on the
+    /// next access. Unsaved changes are discarded. It returns `true` if text
+    /// of editor differs from the file on disk so that the world should be
+    /// compiled once again.
+    pub fn close_file(&self, path: &Path) -> bool {
+        let key = self.key(path);
+        if !self.overlay.write().unwrap().remove(&key) {
+            return false;
+        }
+        let Some(source) = self.sources.write().unwrap().remove(&key) else {
+            return false;
+        };
+        match fs::read(path) {
+            Ok(bytes) => bytes != source.text().as_bytes(),
+            Err(_) => true,
+        }
+    }
+
+    /// Drop a source from cache so that it will be read from file system on
+    /// the next access. Editor does not own the file anymore.
+    pub fn remove_file(&self, path: &Path) {
+        let key = self.key(path);
+        self.overlay.write().unwrap().remove(&key);
+        self.sources.write().unwrap().remove(&key);
+    }
+
+    /// Provide content of a file (e.g. of a data file or an image) in memory
+    /// instead of on disk. Only files which Typst reads as raw bytes are
+    /// affected (not sources). It returns `true` if the last compilation has
+    /// read the file so that the world should be compiled once again.
+    pub fn add_bytes(&self, path: &Path, bytes: Bytes) -> bool {
+        let key = self.key(path);
+        self.watcher.lock().unwrap().untrack(&key);
+        self.bytes.write().unwrap().insert(key.clone(), bytes);
+        self.depends_on(&key)
+    }
+
+    /// Drop content of a file provided in memory so that it is read from file
+    /// system on the next access. It returns `true` if the world should be
+    /// compiled once again.
+    pub fn remove_bytes(&self, path: &Path) -> bool {
+        let key = self.key(path);
+        let removed = self.bytes.write().unwrap().remove(&key).is_some();
+        removed && self.depends_on(&key)
+    }
+
+    /// Check whether the last compilation has accessed a file.
+    fn depends_on(&self, key: &Path) -> bool {
+        let dependencies = self.dependencies.lock().unwrap();
+        dependencies.values().any(|it| path::normalize(it) == key)
+    }
+
+    /// Watch a file of a project (not of a package) which is read from file
+    /// system for changes outside of editor.
+    fn watch(&self, path: &Path, id: FileId) {
+        if id.package().is_none() {
+            self.watcher.lock().unwrap().track(&path::normalize(path));
+        }
+    }
+
+    /// Drop cached sources of files which have been changed outside of editor
+    /// since they were read so that they are read again on the next
+    /// compilation. It returns paths to changed files.
+    pub fn refresh(&self) -> Vec<PathBuf> {
+        let mut changed = self.watcher.lock().unwrap().changed();
+        changed.retain(|path| !self.overlay.read().unwrap().contains(path));
+        let mut sources = self.sources.write().unwrap();
+        for path in changed.iter() {
+            sources.remove(path);
+        }
+        changed
+    }
+
+    /// Check whether background downloads of packages which the last
+    /// compilation has failed on are over so that the world should be
+    /// compiled once again.
+    pub fn downloaded(&self) -> bool {
+        let mut downloads = self.downloads.lock().unwrap();
+        let count = downloads.len();
+        downloads.retain(package::is_downloading);
+        downloads.len() < count
+    }
+
+    /// Drop a cached source of a file which has been changed outside of
+    /// editor (e.g. client reports changes of watched files). Files which
+    /// editor owns are kept. It returns `true` if the world has read the file
+    /// so that it should be compiled once again.
+    pub fn invalidate(&self, path: &Path) -> bool {
+        let key = self.key(path);
+        if self.overlay.read().unwrap().contains(&key) {
+            return false;
+        }
+        if !self.watcher.lock().unwrap().update(&key) {
+            return false;
+        }
+        self.sources.write().unwrap().remove(&key);
+        true
+    }
+
+    /// Check whether text of a file is managed by editor.
+    pub fn owns_file(&self, path: &Path) -> bool {
+        let key = self.key(path);
+        self.overlay.read().unwrap().contains(&key)
+    }
+
+    /// Key of a file in indices of sources and files. A file is keyed by a
+    /// path which Typst resolves its FileID to, so that a file which editor
+    /// refers to through a symbolic link hits the same entries as the file
+    /// which compilation reads (see [`path::normalize`]).
+    fn key(&self, path: &Path) -> PathBuf {
+        let key = path::normalize(path);
+        if key.starts_with(path::normalize(&self.root_dir)) {
+            return key;
+        }
+        match self.file_id(path) {
+            Some(id) => path::normalize(
+                &self.root_dir.join(id.vpath().as_rootless_path()),
+            ),
+            None => key,
+        }
+    }
+
+    /// Make FileID of a file of a project. Paths which are outside of root
+    /// directory lexically are resolved through symbolic links.
+    fn file_id(&self, path: &Path) -> Option<FileId> {
+        if let Some(vpath) = VirtualPath::within_root(path, &self.root_dir) {
+            return Some(FileId::new(None, vpath));
+        }
+        let path = fs::canonicalize(path).ok()?;
+        let root_dir = fs::canonicalize(&self.root_dir).ok()?;
+        let vpath = VirtualPath::within_root(&path, &root_dir)?;
+        Some(FileId::new(None, vpath))
+    }
+
+    /// Find a source of a file of a project: a cached one or the one read
+    /// from file system (e.g. a file has not been compiled yet).
+    fn lookup_source(&self, path: &Path) -> Option<Source> {
+        let key = self.key(path);
+        if let Some(source) = self.sources.read().unwrap().get(&key) {
+            return Some(source.clone());
+        }
+        let id = self.file_id(path)?;
+        let path = self.root_dir.join(id.vpath().as_rootless_path());
+        self.read_source(&path, id).ok()
+    }
+
+    /// Text of a cached source (e.g. of a document which editor owns).
+    pub fn file_text(&self, path: &Path) -> Option<String> {
+        let key = self.key(path);
+        let sources = self.sources.read().unwrap();
+        let source = sources.get(&key)?;
+        Some(source.text().to_string())
     }
 
     fn read_source(&self, path: &Path, id: FileId) -> FileResult<Source> {
         // If source is missing then read it from file system.
         log::info!("source(): read source from fs with id={:?}", id);
+        self.watch(path, id);
         match fs::read(path) {
             Ok(bytes) => String::from_utf8(bytes).map_or(
                 Err(FileError::InvalidUtf8),
@@ -186,8 +779,9 @@ impl LanguageServiceWorld {
                     );
                     let source = Source::new(id, text);
                     self.sources
-                        .borrow_mut()
-                        .insert(path.to_path_buf(), source.clone());
+                        .write()
+                        .unwrap()
+                        .insert(path::normalize(path), source.clone());
                     Ok(source)
                 },
             ),
@@ -196,78 +790,1325 @@ impl LanguageServiceWorld {
     }
 
     pub fn update_file(
-        &mut self,
+        &self,
         path: &Path,
         text: &str,
         begin: (usize, usize),
         end: (usize, usize),
     ) -> Option<Range<usize>> {
-        let mut binding = self.sources.borrow_mut();
-        let source = binding.get_mut(path)?;
-        let begin = source.line_column_to_byte(begin.0, begin.1)?;
-        let end = source.line_column_to_byte(end.0, end.1)?;
-        let range = Range {
-            start: begin,
-            end: end,
-        };
+        let key = self.key(path);
+        let mut binding = self.sources.write().unwrap();
+        let source = binding.get_mut(&key)?;
+        let range = self.to_byte(source, begin)..self.to_byte(source, end);
         Some(source.edit(range, text))
     }
 
-    pub fn compile(&mut self) -> Result<(), String> {
+    /// Replace the whole text of a source (e.g. on full document sync) and
+    /// return a byte range of a changed part of the new text. Unchanged parts
+    /// of syntax tree are reused.
+    pub fn replace_file(
+        &self,
+        path: &Path,
+        text: &str,
+    ) -> Option<Range<usize>> {
+        let key = self.key(path);
+        let mut binding = self.sources.write().unwrap();
+        let source = binding.get_mut(&key)?;
+        Some(source.replace(text))
+    }
+
+    /// Path to main file of the world.
+    pub fn main_path(&self) -> &Path {
+        &self.main_path
+    }
+
+    /// Root directory of the world.
+    pub fn root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+
+    /// Path to output file of full compilation.
+    pub fn output_path(&self) -> &Path {
+        &self.output_path
+    }
+
+    /// Set path to output PDF file.
+    pub fn set_output_path(&mut self, path: &Path) {
+        self.output_path = path.to_path_buf();
+    }
+
+    /// Whether document is exported on full compilation.
+    pub fn exports(&self) -> bool {
+        self.export
+    }
+
+    /// Download missing packages in background: compilation fails at once
+    /// and [`LanguageServiceWorld::downloaded`] tells when to compile again.
+    /// Otherwise, compilation waits for downloads.
+    pub fn set_background_downloads(&mut self, background: bool) {
+        self.background_downloads = background;
+    }
+
+    /// Record versions of packages used by full compilations to lockfile.
+    /// Otherwise, lockfile is written by
+    /// [`LanguageServiceWorld::update_packages`] only.
+    pub fn set_lock_packages(&mut self, enabled: bool) {
+        self.lock_packages = enabled;
+    }
+
+    /// Enable or disable PDF export on full compilation.
+    pub fn set_export(&mut self, export: bool) {
+        self.export = export;
+    }
+
+    /// Attach a preview to the world or detach it. Attached preview shows
+    /// the last compiled document at once.
+    pub fn set_preview(&mut self, preview: Option<Arc<preview::Preview>>) {
+        if let Some(preview) = &preview {
+            preview.update(&self.document());
+        }
+        self.preview = preview;
+    }
+
+    /// Set settings of fonts in exported PDF.
+    pub fn set_pdf_options(&mut self, options: config::Pdf) {
+        self.pdf = options;
+    }
+
+    /// Set format of exported document.
+    pub fn set_export_format(&mut self, format: config::ExportFormat) {
+        self.format = format;
+    }
+
+    /// Replace fonts of the world (e.g. with ones from additional font
+    /// directories).
+    pub fn set_fonts(&mut self, fonts: Arc<FontProvider>) {
+        self.fonts = OnceLock::from(fonts);
+    }
+
+    /// The last compiled document.
+    fn document(&self) -> Arc<Document> {
+        self.document.read().unwrap().clone()
+    }
+
+    fn fonts(&self) -> &FontProvider {
+        self.fonts.get_or_init(|| {
+            FontProvider::shared(&[], &config::Fonts::default())
+        })
+    }
+
+    /// Diagnostics of the last compilation: errors and warnings of Typst as
+    /// well as warnings of language server (e.g. package versions outside of
+    /// lockfile).
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.diagnostics
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|it| it.severity <= self.diagnostics_level)
+            .cloned()
+            .collect()
+    }
+
+    /// Set the least severity of diagnostics which are reported.
+    pub fn set_diagnostics_level(&mut self, level: Severity) {
+        self.diagnostics_level = level;
+    }
+
+    /// Set values of `sys.inputs` which documents could read.
+    pub fn set_inputs(&mut self, inputs: Dict) {
+        let library = Library::builder().with_inputs(inputs).build();
+        self.library = Prehashed::new(library);
+    }
+
+    /// Use a cache directory of packages of this world only instead of shared
+    /// ones (see [`package::prepare_package_in`]).
+    pub fn set_package_cache(&mut self, dir: Option<PathBuf>) {
+        self.package_cache = dir;
+    }
+
+    /// Enable hints on imports of packages which have newer versions in
+    /// package index (see [`package::cached_index`]).
+    pub fn set_update_hints(&mut self, enabled: bool) {
+        self.update_hints = enabled;
+    }
+
+    /// Fix current date at a UNIX timestamp (e.g. for reproducible builds)
+    /// or use the real one.
+    pub fn set_timestamp(&mut self, timestamp: Option<i64>) {
+        self.timestamp = timestamp;
+    }
+
+    /// Set units of columns of positions which are exchanged with a client.
+    pub fn set_position_encoding(&mut self, encoding: PositionEncoding) {
+        self.position_encoding = encoding;
+    }
+
+    /// Convert a position (line and column) of a client to byte offset in a
+    /// source.
+    fn to_byte(
+        &self,
+        source: &Source,
+        (line, column): (usize, usize),
+    ) -> usize {
+        syntax::position_to_byte(source, line, column, self.position_encoding)
+    }
+
+    /// Convert byte offset in a source to a position (line and column) of a
+    /// client.
+    fn to_position(
+        &self,
+        source: &Source,
+        offset: usize,
+    ) -> Option<(usize, usize)> {
+        syntax::byte_to_position(source, offset, self.position_encoding)
+    }
+
+    /// Add a diagnostic which is not bound to a file.
+    fn report(&self, severity: Severity, message: String) {
+        let diagnostic = Diagnostic::new(severity, message);
+        let mut diagnostics = self.diagnostics.lock().unwrap();
+        if !diagnostics.contains(&diagnostic) {
+            diagnostics.push(diagnostic);
+        }
+    }
+
+    /// Convert Typst diagnostic to diagnostics bound to files. If span of
+    /// diagnostic is detached then the closest call site is used.
+    fn convert_diagnostic(&self, diag: &SourceDiagnostic) -> Vec<Diagnostic> {
+        let location = self.locate(diag.span).or_else(|| {
+            diag.trace.iter().find_map(|point| self.locate(point.span))
+        });
+        let (path, start, end) = match location {
+            Some((path, start, end)) => (Some(path), start, end),
+            None => (None, (0, 0), (0, 0)),
+        };
+        let severity = match diag.severity {
+            typst::diag::Severity::Error => Severity::Error,
+            typst::diag::Severity::Warning => Severity::Warning,
+        };
+        let messages = std::iter::once((severity, &diag.message))
+            .chain(diag.hints.iter().map(|hint| (Severity::Hint, hint)));
+        let mut diagnostics = messages
+            .map(|(severity, message)| Diagnostic {
+                path: path.clone(),
+                start,
+                end,
+                severity,
+                message: message.to_string(),
+            })
+            .collect::<Vec<_>>();
+
+        // Failures inside packages are reported at the closest call site in
+        // the project as well since files of packages are rarely open.
+        let Some(spec) = diag.span.id().and_then(|id| id.package().cloned())
+        else {
+            return diagnostics;
+        };
+        let call_site = diag
+            .trace
+            .iter()
+            .filter(|point| {
+                point.span.id().is_some_and(|it| it.package().is_none())
+            })
+            .find_map(|point| self.locate(point.span));
+        if let Some((path, start, end)) = call_site {
+            diagnostics.push(Diagnostic {
+                path: Some(path),
+                start,
+                end,
+                severity,
+                message: format!("{} (in package {spec})", diag.message),
+            });
+        }
+        diagnostics
+    }
+
+    /// Find file and range (lines and columns) of a span.
+    fn locate(
+        &self,
+        span: Span,
+    ) -> Option<(PathBuf, diagnostics::Position, diagnostics::Position)> {
+        let id = span.id()?;
+        let source = self.source(id).ok()?;
+        let range = source.range(span)?;
+        let start = self.to_position(&source, range.start)?;
+        let end = self.to_position(&source, range.end)?;
+        Some((self.resolve_path(id).ok()?, start, end))
+    }
+
+    /// Hints on imports of packages which have newer versions in package
+    /// index. Only sources of a project are checked.
+    fn outdated_imports(&self) -> Vec<Diagnostic> {
+        let index = package::cached_index();
+        if index.is_empty() {
+            return Vec::new();
+        }
+        let sources = self
+            .dependencies
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| id.package().is_none())
+            .filter(|(_, path)| path.extension().is_some_and(|it| it == "typ"))
+            .map(|(id, path)| (*id, path.clone()))
+            .collect::<Vec<_>>();
+        let mut diagnostics = Vec::new();
+        for (id, path) in sources {
+            let Ok(source) = self.source(id) else {
+                continue;
+            };
+            for import in update::find_outdated(&source, &index) {
+                let start = self.to_position(&source, import.range.start);
+                let end = self.to_position(&source, import.range.end);
+                let (Some(start), Some(end)) = (start, end) else {
+                    continue;
+                };
+                diagnostics.push(Diagnostic {
+                    path: Some(path.clone()),
+                    start,
+                    end,
+                    severity: Severity::Hint,
+                    message: import.message(),
+                });
+            }
+        }
+        diagnostics
+    }
+
+    /// Add packages used by the last compilation to lockfile and save it.
+    fn update_lockfile(&self) {
+        let mut binding = self.lockfile.lock().unwrap();
+        let Some(lockfile) = binding.as_mut() else {
+            return;
+        };
+        let mut changed = false;
+        for id in self.dependencies.lock().unwrap().keys() {
+            if let Some(spec) = id.package() {
+                changed |= lockfile.insert(spec);
+            }
+        }
+        if changed {
+            if let Err(err) = lockfile.save(&self.root_dir) {
+                log::error!("failed to save lockfile: {}", err);
+            }
+        }
+    }
+
+    /// Files and packages which the last compilation depended on.
+    pub fn dependencies(&self) -> deps::Dependencies {
+        deps::Dependencies::new(self.dependencies.lock().unwrap().iter())
+    }
+
+    /// Compile document with full fidelity. Nothing is exported (see
+    /// [`LanguageServiceWorld::export`]) so that compilation is cheap enough
+    /// for diagnostics only.
+    pub fn compile(&self) -> Result<Arc<Document>, String> {
+        let doc = self.compile_document(false)?;
+        if self.lock_packages {
+            self.update_lockfile();
+        }
+        Ok(doc)
+    }
+
+    /// Compile document and export it to output path in configured format
+    /// unless export is disabled.
+    pub fn compile_and_export(&self) -> Result<Arc<Document>, String> {
+        let doc = self.compile()?;
+        self.export_output(&doc)?;
+        Ok(doc)
+    }
+
+    /// Export a compiled document to output path in configured format unless
+    /// export is disabled.
+    pub fn export_output(&self, doc: &Document) -> Result<(), String> {
+        if !self.export {
+            return Ok(());
+        }
+        self.export(doc, self.format, &self.output_path)
+            .inspect_err(|err| log::error!("failed to export document ({err})"))
+    }
+
+    /// Export a document to a PDF file.
+    pub fn export_pdf(
+        &self,
+        doc: &Document,
+        path: &Path,
+    ) -> Result<(), String> {
+        self.export(doc, config::ExportFormat::Pdf, path)
+    }
+
+    /// Export a document to a file. Pages of SVG and PNG documents are
+    /// written to separate files if a path contains `{n}` placeholder and they
+    /// are merged otherwise.
+    pub fn export(
+        &self,
+        doc: &Document,
+        format: config::ExportFormat,
+        path: &Path,
+    ) -> Result<(), String> {
+        // Output directory (e.g. `build`) could be missing in a fresh clone.
+        if let Some(dir) = path.parent().filter(|it| !it.as_os_str().is_empty())
+        {
+            fs::create_dir_all(dir)
+                .map_err(|err| format!("failed to create {dir:?}: {err}"))?;
+        }
+        if let config::ExportFormat::Pdf = format {
+            let warnings = pdf::check_fonts(doc, &self.pdf)
+                .inspect_err(|err| self.report(Severity::Error, err.clone()))?;
+            for warning in warnings {
+                self.report(Severity::Warning, warning);
+            }
+            let buffer = typst_pdf::pdf(doc, Smart::Auto, None);
+            return fs::write(path, buffer)
+                .map_err(|err| format!("failed to write {path:?}: {err}"));
+        }
+
+        let template = path.to_string_lossy();
+        if !template.contains("{n}") {
+            let buffer = match format {
+                config::ExportFormat::Png { dpi } => {
+                    typst_render::render_merged(
+                        doc,
+                        dpi / 72.0,
+                        Color::WHITE,
+                        Abs::zero(),
+                        Color::WHITE,
+                    )
+                    .encode_png()
+                    .map_err(|err| err.to_string())?
+                }
+                _ => typst_svg::svg_merged(doc, Abs::zero()).into_bytes(),
+            };
+            return fs::write(path, buffer)
+                .map_err(|err| format!("failed to write {path:?}: {err}"));
+        }
+        for (index, page) in doc.pages.iter().enumerate() {
+            let path = template.replace("{n}", &(index + 1).to_string());
+            let buffer = match format {
+                config::ExportFormat::Png { dpi } => {
+                    typst_render::render(&page.frame, dpi / 72.0, Color::WHITE)
+                        .encode_png()
+                        .map_err(|err| err.to_string())?
+                }
+                _ => typst_svg::svg(&page.frame).into_bytes(),
+            };
+            fs::write(&path, buffer)
+                .map_err(|err| format!("failed to write {path:?}: {err}"))?;
+        }
+        Ok(())
+    }
+
+    /// Compile document in draft mode for fast feedback: images are replaced
+    /// with placeholders and nothing is exported.
+    pub fn compile_draft(&self) -> Result<Arc<Document>, String> {
+        self.compile_document(true)
+    }
+
+    /// Statistics of the last compilation.
+    pub fn stats(&self) -> CompileStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Count words and characters of the last compiled document.
+    pub fn count(&self) -> count::Count {
+        count::count_document(&self.document())
+    }
+
+    /// Count words and characters of the last compiled document and of its
+    /// sections.
+    pub fn document_metrics(&self) -> count::DocumentMetrics {
+        count::document_metrics(&self.document())
+    }
+
+    /// Compare document and sources with the ones of previous compilation in
+    /// order to estimate how much of previous work has been reused.
+    fn update_stats(&self, history: &mut History, doc: &Document) {
+        let pages = doc.pages.iter().map(|page| hash128(&page.frame));
+        let pages = pages.collect::<Vec<_>>();
+        let reused_pages = pages
+            .iter()
+            .zip(history.pages.iter())
+            .filter(|(new, old)| new == old)
+            .count();
+
+        let cache = self.sources.read().unwrap();
+        let sources = self
+            .dependencies
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(id, path)| {
+                let source = cache.get(&path::normalize(path))?;
+                Some((*id, hash128(source)))
+            })
+            .collect::<HashMap<_, _>>();
+        drop(cache);
+        let reused_sources = sources
+            .iter()
+            .filter(|(id, hash)| history.sources.get(id) == Some(hash))
+            .count();
+
+        *self.stats.lock().unwrap() = CompileStats {
+            pages: pages.len(),
+            reused_pages,
+            sources: sources.len(),
+            reused_sources,
+        };
+        history.pages = pages;
+        history.sources = sources;
+    }
+
+    fn compile_document(&self, draft: bool) -> Result<Arc<Document>, String> {
+        let mut history = self.history.lock().unwrap();
+        self.draft.store(draft, Ordering::Relaxed);
+        // Tracer is cheap and it is not a part of memoization keys so that
+        // a new one is used for each compilation.
         let mut tracer = Tracer::new();
-        let result = match typst::compile(self, &mut tracer) {
+        let main_id = self.main().id();
+        // Lockfile could be changed outside (e.g. on checkout) and packages
+        // outside of it are reported against the one on disk.
+        *self.lockfile.lock().unwrap() = load_lockfile(&self.root_dir);
+        self.diagnostics.lock().unwrap().clear();
+        let mut dependencies = self.dependencies.lock().unwrap();
+        dependencies.clear();
+        dependencies.insert(main_id, self.main_path.clone());
+        drop(dependencies);
+        let result = typst::compile(self, &mut tracer);
+        let warnings = tracer.warnings();
+        let mut diagnostics = warnings
+            .iter()
+            .chain(result.as_ref().err().into_iter().flatten())
+            .flat_map(|diag| self.convert_diagnostic(diag))
+            .collect::<Vec<_>>();
+        if self.update_hints {
+            diagnostics.extend(self.outdated_imports());
+        }
+        self.diagnostics.lock().unwrap().append(&mut diagnostics);
+        let result = match result {
             Ok(doc) => {
-                log::info!("compiled successfully");
-                let buffer = typst_pdf::pdf(&doc, Smart::Auto, None);
-                let _ = fs::write("main.pdf", buffer).map_err(|err| {
-                    log::error!("failed to write PDF file ({err})")
-                });
-                // Save compiled document in execution context.
-                self.document = Arc::new(doc);
-                Ok(())
+                log::info!("compiled successfully (draft={draft})");
+                self.update_stats(&mut history, &doc);
+                // Save compiled document in execution context. Previous
+                // document is kept on failures.
+                let doc = Arc::new(doc);
+                *self.document.write().unwrap() = doc.clone();
+                if let Some(preview) = &self.preview {
+                    preview.update(&doc);
+                }
+                Ok(doc)
             }
             Err(diag) => {
                 let fst = diag.first().unwrap();
                 log::warn!("failed to compile: {}", fst.message);
-                Err("compilation failed".to_string())
+                Err(format!("compilation failed: {}", fst.message))
             }
         };
         // Do some garbage collection sweeping out objectes older than N
         // cycles (see typst-cli for details).
         comemo::evict(10);
+        self.draft.store(false, Ordering::Relaxed);
         result
     }
 
+    /// Complete an item at cursor. Completion is checked for cancellation
+    /// between phases and it returns nothing if it has been cancelled.
     pub fn complete(
-        &mut self,
+        &self,
         path: &Path,
         line: usize,
         column: usize,
+        options: &config::Completion,
+        lazy: bool,
+        cancelled: &AtomicBool,
     ) -> Vec<CompletionItem> {
-        let Some(source) = self.sources.borrow().get(path).cloned() else {
+        let cancelled = || cancelled.load(Ordering::Relaxed);
+        let Some(source) = self.lookup_source(path) else {
             return vec![];
         };
 
-        let Some(pos) = source.line_column_to_byte(line, column) else {
+        let pos = self.to_byte(&source, (line, column));
+        if let Some(literal) = completion::complete_literal(
+            &self.root_dir,
+            &source,
+            pos,
+            options.package_index,
+        ) {
+            let to_position = |offset| self.to_position(&source, offset);
+            let range = to_position(literal.range.start)
+                .zip(to_position(literal.range.end));
+            return literal
+                .entries
+                .into_iter()
+                .map(|el| CompletionItem {
+                    label: el.label,
+                    kind: el.kind,
+                    apply: el.apply,
+                    detail: el.detail,
+                    docs: None,
+                    range,
+                })
+                .collect();
+        }
+
+        if cancelled() {
             return vec![];
-        };
+        }
         let result = autocomplete(
             self,
-            Some(self.document.as_ref()),
+            Some(self.document().as_ref()),
             &source,
             pos,
             false,
         );
-        match result {
+        if cancelled() {
+            return vec![];
+        }
+        let math = options.symbols_in_math_only
+            && syntax::mode_at(&source, pos) == syntax::Mode::Math;
+        let mut items = match result {
             Some((_, items)) => items
                 .iter()
+                .filter(|el| match el.kind {
+                    CompletionKind::Symbol(_) => {
+                        !options.symbols_in_math_only || math
+                    }
+                    CompletionKind::Syntax => options.syntax,
+                    _ => true,
+                })
                 .map(|el| CompletionItem {
                     label: el.label.to_string(),
-                    kind: el.kind.clone(),
+                    kind: completion::Kind::Ide(el.kind.clone()),
+                    apply: el.apply.as_ref().map(|it| it.to_string()),
+                    detail: el.detail.as_ref().map(|it| it.to_string()),
+                    // Documentation is resolved on demand in lazy mode.
+                    docs: match lazy {
+                        true => None,
+                        false => self.completion_docs(
+                            &el.label,
+                            &el.kind,
+                            el.detail.as_deref(),
+                        ),
+                    },
+                    range: None,
                 })
                 .collect(),
             None => vec![],
+        };
+
+        // Imported names are annotated with files they come from.
+        if cancelled() {
+            return vec![];
+        }
+        let imported = definition::imported_names(self, &source, pos);
+        for item in items.iter_mut() {
+            let Some((_, found)) =
+                imported.iter().find(|(name, _)| *name == item.label)
+            else {
+                continue;
+            };
+            let file = definition::describe_file(found.id);
+            item.detail = Some(format!("imported from `{file}`"));
+            if !lazy {
+                let docs = definition::describe(self, found);
+                item.docs = docs.map(Docs::Text).or(item.docs.take());
+            }
+        }
+
+        // Labels and keys of bibliographies are known without compilation.
+        if cancelled() {
+            return vec![];
+        }
+        if let Some(range) = syntax::ref_prefix_at(&source, pos) {
+            let to_position = |offset| self.to_position(&source, offset);
+            let range = to_position(range.start).zip(to_position(range.end));
+            let sources = self.reachable_sources(&source);
+            let labels =
+                sources.iter().flat_map(labels::find_labels).map(|label| {
+                    CompletionItem {
+                        label: label.name,
+                        kind: completion::Kind::Reference,
+                        apply: None,
+                        detail: Some(label.detail),
+                        docs: None,
+                        range,
+                    }
+                });
+            let entries = self.bibliography_entries(&sources);
+            let entries = entries.iter().map(|entry| CompletionItem {
+                label: entry.key().to_string(),
+                kind: completion::Kind::Reference,
+                apply: None,
+                detail: Some(bibliography::summary(entry)),
+                docs: Some(Docs::Text(bibliography::describe(entry))),
+                range,
+            });
+            let mut refs = labels.chain(entries).collect::<Vec<_>>();
+            refs.sort_by(|lhs, rhs| lhs.label.cmp(&rhs.label));
+            refs.dedup_by(|lhs, rhs| lhs.label == rhs.label);
+            items.retain(|it| refs.iter().all(|el| el.label != it.label));
+            items.extend(refs);
+        }
+        items
+    }
+
+    /// Sources which are reachable with imports and includes from main file
+    /// and a source.
+    fn reachable_sources(&self, source: &Source) -> Vec<Source> {
+        let mut sources = Vec::new();
+        let mut visited = HashSet::new();
+        let mut queue = vec![self.main().id(), source.id()];
+        while let Some(id) = queue.pop() {
+            if !visited.insert(id) {
+                continue;
+            }
+            let Ok(source) = self.source(id) else {
+                continue;
+            };
+            for (_, path, _) in deps::find_dependencies(&source) {
+                if !path.starts_with('@') {
+                    queue.push(id.join(&path));
+                }
+            }
+            sources.push(source);
+        }
+        sources
+    }
+
+    /// Entries of bibliographies of sources. Unreadable bibliographies are
+    /// skipped.
+    fn bibliography_entries(
+        &self,
+        sources: &[Source],
+    ) -> Vec<hayagriva::Entry> {
+        let mut entries = Vec::new();
+        for source in sources {
+            for path in bibliography::bibliography_paths(source) {
+                let library = self
+                    .file(source.id().join(&path))
+                    .map_err(|err| err.to_string())
+                    .and_then(|bytes| bibliography::parse(&path, &bytes));
+                match library {
+                    Ok(library) => entries.extend(library),
+                    Err(err) => log::warn!("failed to load {path}: {err}"),
+                }
+            }
+        }
+        entries
+    }
+
+    /// Documentation of a completion item: functions of standard library are
+    /// documented with their signatures and other items with their details.
+    pub fn completion_docs(
+        &self,
+        label: &str,
+        kind: &CompletionKind,
+        detail: Option<&str>,
+    ) -> Option<Docs> {
+        match kind {
+            CompletionKind::Func => self.lookup_docs(label),
+            _ => None,
+        }
+        .or_else(|| detail.map(|it| Docs::Text(it.to_string())))
+    }
+
+    /// Describe an item under cursor. Hover is checked for cancellation
+    /// before expensive phases (e.g. rendering of images and equations) and it
+    /// returns nothing if it has been cancelled.
+    pub fn hover(
+        &self,
+        path: &Path,
+        line: usize,
+        column: usize,
+        cancelled: &AtomicBool,
+    ) -> Option<Docs> {
+        let cancelled = || cancelled.load(Ordering::Relaxed);
+        let source = self.lookup_source(path)?;
+        let pos = self.to_byte(&source, (line, column));
+        if let Some((format, path)) = data::data_path_at(&source, pos) {
+            let id = source.id().join(&path);
+            let summary = match self.file(id) {
+                Ok(bytes) => data::summarize(format, &path, &bytes),
+                Err(err) => Err(err.to_string()),
+            };
+            return Some(Docs::Text(summary.unwrap_or_else(|err| err)));
+        }
+        if let Some(path) = images::image_path_at(&source, pos) {
+            if cancelled() {
+                return None;
+            }
+            let id = source.id().join(&path);
+            let summary = match self.file(id) {
+                Ok(bytes) => images::summarize(&path, &bytes),
+                Err(err) => Err(err.to_string()),
+            };
+            return Some(match summary {
+                Ok((text, thumbnail)) => Docs::Image(text, thumbnail),
+                Err(err) => Docs::Text(err),
+            });
+        }
+        if let Some(docs) = self.func_docs(&source, pos) {
+            return Some(docs);
+        }
+        if cancelled() {
+            return None;
+        }
+        if let Some(key) = bibliography::key_at(&source, pos) {
+            let sources = self.reachable_sources(&source);
+            let entries = self.bibliography_entries(&sources);
+            if let Some(entry) = entries.iter().find(|it| it.key() == key) {
+                return Some(Docs::Text(bibliography::describe(entry)));
+            }
+        }
+        if cancelled() {
+            return None;
+        }
+        if let Some(text) = definition::describe_import(self, &source, pos) {
+            return Some(Docs::Text(text));
+        }
+        if cancelled() {
+            return None;
+        }
+        let docs = tooltip(self, Some(self.document().as_ref()), &source, pos);
+        if docs.is_some() {
+            return docs.map(Docs::from);
+        }
+        if cancelled() {
+            return None;
+        }
+
+        // Equation is previewed only if there is nothing specific to an item
+        // under cursor.
+        let range = math::equation_at(&source, pos)?;
+        let code = source.get(range.clone()).unwrap_or_default();
+        match math::render_equation(self, &source, range.clone()) {
+            Ok(url) => Some(Docs::Preview(url, code.to_string())),
+            Err(err) => {
+                log::debug!("{err}");
+                None
+            }
+        }
+    }
+
+    /// Find a definition of an item under cursor. It returns a path to a file
+    /// and a range (lines and columns) of the definition in it.
+    pub fn definition(
+        &self,
+        path: &Path,
+        line: usize,
+        column: usize,
+    ) -> Option<(PathBuf, diagnostics::Position, diagnostics::Position)> {
+        let source = self.lookup_source(path)?;
+        let pos = self.to_byte(&source, (line, column));
+        let (found, target) = match definition::definition(self, &source, pos) {
+            Some(found) => {
+                let target = self.source(found.id).ok()?;
+                (found, target)
+            }
+            None => self.bibliography_definition(&source, pos)?,
+        };
+        let start = self.to_position(&target, found.range.start)?;
+        let end = self.to_position(&target, found.range.end)?;
+        let path = self.resolve_path(found.id).ok()?;
+        Some((path, start, end))
+    }
+
+    /// Find a position in the last compiled document which a cursor in a
+    /// source corresponds to (forward search). Only cursors in text are
+    /// mapped.
+    pub fn jump_to_preview(
+        &self,
+        path: &Path,
+        line: usize,
+        column: usize,
+    ) -> Option<preview::Position> {
+        let source = self.lookup_source(path)?;
+        let cursor = self.to_byte(&source, (line, column));
+        let position = jump_from_cursor(&self.document(), &source, cursor)?;
+        Some(preview::Position {
+            page: position.page.get(),
+            x: position.point.x.to_pt(),
+            y: position.point.y.to_pt(),
+        })
+    }
+
+    /// Find a position in a source which a point on a page of the last
+    /// compiled document corresponds to (inverse search). Links are not
+    /// followed.
+    pub fn jump_from_preview(
+        &self,
+        position: &preview::Position,
+    ) -> Option<(PathBuf, diagnostics::Position)> {
+        let doc = self.document();
+        let page = doc.pages.get(position.page.checked_sub(1)?)?;
+        let click = Point::new(Abs::pt(position.x), Abs::pt(position.y));
+        let Jump::Source(id, offset) =
+            jump_from_click(self, &doc, &page.frame, click)?
+        else {
+            return None;
+        };
+        let source = self.source(id).ok()?;
+        let start = self.to_position(&source, offset)?;
+        Some((self.resolve_path(id).ok()?, start))
+    }
+
+    /// Find an entry of a bibliography file which a reference or a citation
+    /// at cursor refers to. Bibliography files are not Typst sources so they
+    /// are returned as detached sources.
+    fn bibliography_definition(
+        &self,
+        source: &Source,
+        cursor: usize,
+    ) -> Option<(definition::Definition, Source)> {
+        let key = bibliography::key_at(source, cursor)?;
+        for source in self.reachable_sources(source) {
+            for path in bibliography::bibliography_paths(&source) {
+                let id = source.id().join(&path);
+                let Ok(bytes) = self.file(id) else {
+                    continue;
+                };
+                let Ok(text) = std::str::from_utf8(&bytes) else {
+                    continue;
+                };
+                if let Some(range) = bibliography::find_entry(&path, text, &key)
+                {
+                    let target = Source::new(id, text.to_string());
+                    return Some((
+                        definition::Definition { id, range },
+                        target,
+                    ));
+                }
+            }
+        }
+        None
+    }
+
+    /// Describe a signature of a function which is called at cursor.
+    /// Functions defined in sources take precedence over standard library.
+    pub fn signature_help(
+        &self,
+        path: &Path,
+        line: usize,
+        column: usize,
+    ) -> Option<Signature> {
+        let source = self.lookup_source(path)?;
+        let pos = self.to_byte(&source, (line, column));
+        let call = signature::call_at(&source, pos)?;
+        let mut signature = self.callee_signature(&source, call.callee)?;
+        signature.activate(&call);
+        Some(signature)
+    }
+
+    /// Make a signature of a function which is called by name at byte offset.
+    fn callee_signature(
+        &self,
+        source: &Source,
+        callee: usize,
+    ) -> Option<Signature> {
+        self.closure_signature(source, callee).or_else(|| {
+            let (func, _) = self.std_func(source, callee + 1)?;
+            Some(Signature::from_func(&func))
+        })
+    }
+
+    /// Make a signature of a closure which is bound to a name at cursor
+    /// (e.g. `#let f(x) = ..` or `#let f = (x) => ..`).
+    fn closure_signature(
+        &self,
+        source: &Source,
+        cursor: usize,
+    ) -> Option<Signature> {
+        let found = definition::definition(self, source, cursor)?;
+        let target = self.source(found.id).ok()?;
+        let root = LinkedNode::new(target.root());
+        let leaf = root.leaf_at(found.range.start + 1)?;
+        let parent = leaf.parent()?;
+        let closure = match parent.cast::<ast::LetBinding>() {
+            Some(binding) => match binding.init()? {
+                ast::Expr::Closure(closure) => closure,
+                _ => return None,
+            },
+            None => parent.cast::<ast::Closure>()?,
+        };
+        Some(Signature::from_closure(closure))
+    }
+
+    /// Collect inlay hints which are enabled in options: values of simple
+    /// `#let` bindings at line ends and names of parameters before positional
+    /// arguments. It returns positions (line and column), labels, and kinds
+    /// of hints.
+    pub fn inlay_hints(
+        &self,
+        path: &Path,
+        options: &config::InlayHints,
+    ) -> Vec<((usize, usize), String, HintKind)> {
+        let Some(source) = self.lookup_source(path) else {
+            return vec![];
+        };
+        let mut hints = Vec::new();
+        if options.let_values {
+            hints.extend(
+                hints::let_values(self, &source)
+                    .into_iter()
+                    .map(|(offset, label)| (offset, label, HintKind::Value)),
+            );
         }
+        if options.parameter_names {
+            for site in hints::call_sites(&source) {
+                let Some(signature) =
+                    self.callee_signature(&source, site.callee)
+                else {
+                    continue;
+                };
+                let params = signature
+                    .params
+                    .iter()
+                    .filter(|it| it.positional && !it.variadic);
+                for ((offset, arg), param) in site.args.iter().zip(params) {
+                    if arg.as_ref() != Some(&param.name) {
+                        let label = format!("{}:", param.name);
+                        hints.push((*offset, label, HintKind::Parameter));
+                    }
+                }
+            }
+        }
+        hints
+            .into_iter()
+            .filter_map(|(offset, label, kind)| {
+                Some((self.to_position(&source, offset)?, label, kind))
+            })
+            .collect()
+    }
+
+    /// Format a document or lines of a range of it (line and column of start
+    /// and end) and return edits.
+    pub fn format(
+        &self,
+        path: &Path,
+        indent: &str,
+        options: &config::Format,
+        range: Option<((usize, usize), (usize, usize))>,
+    ) -> Vec<actions::TextEdit> {
+        let Some(source) = self.lookup_source(path) else {
+            return vec![];
+        };
+        let range = range.map(|(begin, end)| {
+            self.to_byte(&source, begin)..self.to_byte(&source, end)
+        });
+        format::format_edits(
+            &source,
+            path,
+            indent,
+            options.line_width,
+            range,
+            self.position_encoding,
+        )
+    }
+
+    /// Make edits after a character `ch` has been typed at a position (line
+    /// and column).
+    pub fn on_type_format(
+        &self,
+        path: &Path,
+        position: (usize, usize),
+        ch: &str,
+    ) -> Vec<actions::TextEdit> {
+        let Some(source) = self.lookup_source(path) else {
+            return vec![];
+        };
+        let cursor = self.to_byte(&source, position);
+        on_type::on_type_edits(
+            &source,
+            path,
+            cursor,
+            ch,
+            self.position_encoding,
+        )
+    }
+
+    /// Find ranges (start and end positions) of a delimiter under cursor and
+    /// its counterpart which are edited together.
+    pub fn linked_ranges(
+        &self,
+        path: &Path,
+        line: usize,
+        column: usize,
+    ) -> Vec<(diagnostics::Position, diagnostics::Position)> {
+        let Some(source) = self.lookup_source(path) else {
+            return vec![];
+        };
+        let cursor = self.to_byte(&source, (line, column));
+        linked::linked_ranges(&source, cursor)
+            .into_iter()
+            .filter_map(|range| {
+                let start = self.to_position(&source, range.start)?;
+                let end = self.to_position(&source, range.end)?;
+                Some((start, end))
+            })
+            .collect()
+    }
+
+    /// Collect code actions applicable to a selection. Diagnostics are ranges
+    /// (start and end positions) and messages of diagnostics which a client
+    /// reports for a selection.
+    pub fn code_actions(
+        &self,
+        path: &Path,
+        begin: (usize, usize),
+        end: (usize, usize),
+        diagnostics: &[(
+            diagnostics::Position,
+            diagnostics::Position,
+            String,
+        )],
+    ) -> Vec<actions::CodeAction> {
+        let Some(source) = self.lookup_source(path) else {
+            return vec![];
+        };
+        let to_byte = |position| self.to_byte(&source, position);
+        let diagnostics = diagnostics
+            .iter()
+            .map(|(begin, end, message)| {
+                (to_byte(*begin)..to_byte(*end), message.clone())
+            })
+            .collect::<Vec<_>>();
+        let range = to_byte(begin)..to_byte(end);
+        let encoding = self.position_encoding;
+        actions::code_actions(&source, path, range, &diagnostics, encoding)
+    }
+
+    /// Get a real path from FileID (an internal identifier for a file in
+    /// Typst). Packages are downloaded on demand.
+    fn resolve_path(&self, id: FileId) -> FileResult<PathBuf> {
+        match id.package() {
+            Some(pkg) => {
+                // Prefer a compatible version from lockfile if any.
+                let spec = PackageSpec {
+                    version: self.resolve_locked(pkg).unwrap_or(pkg.version),
+                    ..pkg.clone()
+                };
+                let version = spec.version.to_string();
+                let failure = |err: package::Error| {
+                    FileError::Other(Some(
+                        format!("package failure: {err}").into(),
+                    ))
+                };
+
+                // Get a root directory of the package.
+                if let Some(cache_dir) = &self.package_cache {
+                    let pkg_dir = package::prepare_package_in(cache_dir, &spec)
+                        .map_err(failure)?;
+                    return Ok(pkg_dir.join(id.vpath().as_rootless_path()));
+                }
+                let found = package::find_package(
+                    &spec.namespace,
+                    &spec.name,
+                    &version,
+                );
+                let pkg_dir = match found {
+                    Some(pkg_dir) => pkg_dir,
+                    None if self.background_downloads => {
+                        package::download_in_background(&spec)
+                            .map_err(failure)?;
+                        let message = format!("package {spec} is downloading");
+                        self.downloads.lock().unwrap().insert(spec);
+                        return Err(FileError::Other(Some(message.into())));
+                    }
+                    None => package::prepare_package(
+                        &spec.namespace,
+                        &spec.name,
+                        &version,
+                    )
+                    .map_err(failure)?,
+                };
+
+                // Make a path which is relative to a package root.
+                Ok(pkg_dir.join(id.vpath().as_rootless_path()))
+            }
+            None => Ok(self.root_dir.join(id.vpath().as_rootless_path())),
+        }
+    }
+
+    /// Resolve package against lockfile. Return locked version which should be
+    /// used instead of the requested one.
+    fn resolve_locked(&self, spec: &PackageSpec) -> Option<PackageVersion> {
+        let binding = self.lockfile.lock().unwrap();
+        let lockfile = binding.as_ref()?;
+        match lockfile.resolve(spec) {
+            Resolution::Substituted(version) => {
+                log::info!("use locked version {} of {}", version, spec);
+                Some(version)
+            }
+            Resolution::Outside => {
+                let warning = format!(
+                    "package {spec} is outside of lockfile; run `typstd \
+                     packages update` to lock it"
+                );
+                log::warn!("{}", warning);
+                self.report(Severity::Warning, warning);
+                None
+            }
+            Resolution::Locked | Resolution::Unlocked => None,
+        }
+    }
+
+    /// Packages imported by files of a world. Locked versions are preferred
+    /// like on compilation.
+    pub fn imported_packages(&self) -> Vec<PackageSpec> {
+        let graph = self.dependency_graph();
+        graph
+            .nodes
+            .iter()
+            .filter(|node| node.kind == deps::NodeKind::Package)
+            .filter_map(|node| node.id.parse::<PackageSpec>().ok())
+            .map(|mut spec| {
+                if let Some(version) = self.resolve_locked(&spec) {
+                    spec.version = version;
+                }
+                spec
+            })
+            .collect()
+    }
+
+    /// Find updates of imported packages in index and record them to
+    /// lockfile. Lockfile is created if it is missing. Only packages which
+    /// have newer versions are returned.
+    pub fn update_packages(
+        &self,
+        index: &[package::IndexEntry],
+    ) -> Result<Vec<update::PackageUpdate>, String> {
+        let updates = update::find_updates(
+            self,
+            &self.root_dir,
+            index,
+            self.position_encoding,
+        );
+        let mut binding = self.lockfile.lock().unwrap();
+        let lockfile = binding.get_or_insert_with(Default::default);
+        let mut changed = false;
+        for update in updates.iter() {
+            let mut spec = update.spec.clone();
+            spec.version = update.version;
+            changed |= lockfile.update(&spec);
+        }
+        if changed {
+            lockfile.save(&self.root_dir)?;
+        }
+        Ok(updates.into_iter().filter(|it| it.is_newer()).collect())
+    }
+
+    /// Extract headings, figures, labels, and metadata from the last compiled
+    /// document.
+    pub fn introspect(&self) -> introspect::Introspection {
+        introspect::introspect(
+            self,
+            &self.root_dir,
+            &self.document(),
+            self.position_encoding,
+        )
+    }
+
+    /// Find elements of the last compiled document which match a selector
+    /// (see [`introspect::query`]).
+    pub fn query(
+        &self,
+        selector: &str,
+        field: Option<&str>,
+    ) -> Result<Vec<Value>, String> {
+        introspect::query(self, &self.document(), selector, field)
+    }
+
+    /// Build a graph of imports and includes starting from main file.
+    pub fn dependency_graph(&self) -> deps::Graph {
+        deps::dependency_graph(self, &self.root_dir, self.position_encoding)
+    }
+
+    /// Documentation of a function of standard library (e.g. `heading` or
+    /// `calc.pow`) at cursor with its signature and parameters. Other values
+    /// are left to tooltips which show their values.
+    fn func_docs(&self, source: &Source, cursor: usize) -> Option<Docs> {
+        let (func, category) = self.std_func(source, cursor)?;
+        Some(Docs::Func(func, category))
+    }
+
+    /// Find a function of standard library at cursor (e.g. `heading` or
+    /// `calc.pow`). Names which are bound in a source are skipped.
+    fn std_func(
+        &self,
+        source: &Source,
+        cursor: usize,
+    ) -> Option<(Func, Option<Category>)> {
+        let leaf = LinkedNode::new(source.root()).leaf_at(cursor)?;
+        let math = match leaf.kind() {
+            SyntaxKind::Ident => false,
+            SyntaxKind::MathIdent => true,
+            _ => return None,
+        };
+
+        // Collect path to a function: `calc.pow` is hovered at `pow`.
+        let mut path = vec![leaf.text().to_string()];
+        let access = leaf.parent().and_then(|it| it.cast::<ast::FieldAccess>());
+        if let Some(access) = access {
+            if access.field().span() == leaf.span() {
+                let mut target = access.target();
+                loop {
+                    match target {
+                        ast::Expr::Ident(ident) => {
+                            path.insert(0, ident.get().to_string());
+                            break;
+                        }
+                        ast::Expr::MathIdent(ident) => {
+                            path.insert(0, ident.get().to_string());
+                            break;
+                        }
+                        ast::Expr::FieldAccess(inner) => {
+                            path.insert(0, inner.field().get().to_string());
+                            target = inner.target();
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+        }
+
+        // User definitions shadow standard library.
+        if syntax::is_bound(source, &path[0]) {
+            return None;
+        }
+
+        let module = if math {
+            &self.library.math
+        } else {
+            &self.library.global
+        };
+        let (name, prefix) = path.split_last()?;
+        let mut scope = module.scope();
+        for segment in prefix {
+            scope = match scope.get(segment)? {
+                Value::Module(module) => module.scope(),
+                Value::Func(func) => func.scope()?,
+                _ => return None,
+            };
+        }
+        let Value::Func(func) = scope.get(name)? else {
+            return None;
+        };
+        // Only functions of global scope have links to reference.
+        let category = prefix.is_empty().then(|| scope.get_category(name));
+        Some((func.clone(), category.flatten()))
+    }
+
+    /// Look up documentation of a function defined in standard library.
+    fn lookup_docs(&self, name: &str) -> Option<Docs> {
+        [&self.library.global, &self.library.math]
+            .iter()
+            .map(|module| module.scope())
+            .find_map(|scope| match scope.get(name) {
+                Some(Value::Func(func)) => {
+                    Some(Docs::Func(func.clone(), scope.get_category(name)))
+                }
+                _ => None,
+            })
     }
 }
 
@@ -281,34 +2122,30 @@ impl World for LanguageServiceWorld {
 
     /// Metadata about all known fonts.
     fn book(&self) -> &Prehashed<FontBook> {
-        &self.book
+        &self.fonts().book
     }
 
     /// Access the main source file.
     fn main(&self) -> Source {
         log::info!("main(): access to main file: uri={:?}", self.main_path);
-        self.sources.borrow().get(&self.main_path).unwrap().clone()
+        let key = path::normalize(&self.main_path);
+        if let Some(source) = self.sources.read().unwrap().get(&key) {
+            return source.clone();
+        }
+
+        // Main file could be evicted from cache (e.g. it is too large to
+        // track changes) so we read it from file system once again.
+        let vpath = VirtualPath::within_root(&self.main_path, &self.root_dir)
+            .expect("main file must be located within root directory");
+        let id = FileId::new(None, vpath);
+        self.read_source(&self.main_path, id)
+            .unwrap_or_else(|_| Source::new(id, String::new()))
     }
 
     /// Try to access the specified source file.
     fn source(&self, id: FileId) -> FileResult<Source> {
         log::info!("source(): request source with id={:?}", id);
-        let path = match id.package() {
-            Some(pkg) => {
-                // Get a root directory of the package.
-                let version = pkg.version.to_string();
-                let pkg_dir = package::prepare_package(&pkg.name, &version)
-                    .map_err(|err| {
-                        FileError::Other(Some(
-                            format!("package failure: {err}").into(),
-                        ))
-                    })?;
-
-                // Make a path which is relative to a package root.
-                pkg_dir.join(id.vpath().as_rootless_path())
-            }
-            None => self.root_dir.join(id.vpath().as_rootless_path()),
-        };
+        let path = self.resolve_path(id)?;
 
         // Get a real path from FileID (an internal identifier for a file
         // in Typst).
@@ -316,56 +2153,60 @@ impl World for LanguageServiceWorld {
 
         // Look up a source by its absolute path.
         {
-            let binding = self.sources.borrow();
-            let result = binding.get(&path);
-            if result.is_some() {
+            let binding = self.sources.read().unwrap();
+            if let Some(source) = binding.get(&path::normalize(&path)) {
                 log::info!("source(): found source with id={:?}", id);
-                return Ok(result.unwrap().clone());
+                self.dependencies.lock().unwrap().insert(id, path);
+                return Ok(source.clone());
             }
         };
-        self.read_source(&path, id)
+        let source = self.read_source(&path, id)?;
+        self.dependencies.lock().unwrap().insert(id, path);
+        Ok(source)
     }
 
     /// Try to access the specified file.
     fn file(&self, id: FileId) -> FileResult<Bytes> {
         log::info!("file(): request file with id={:?} ", id);
-        match id.package() {
-            Some(pkg) => {
-                // Get a root directory of the package.
-                let version = pkg.version.to_string();
-                let pkg_dir = package::prepare_package(&pkg.name, &version)
-                    .map_err(|err| {
-                        FileError::Other(Some(
-                            format!("package failure: {err}").into(),
-                        ))
-                    })?;
-
-                // Read a file which is located at package root.
-                let path = pkg_dir.join(id.vpath().as_rootless_path());
-                match fs::read(&path) {
-                    Ok(bytes) => Ok(Bytes::from(bytes)),
-                    Err(_) => Err(FileError::NotFound(path.to_path_buf())),
-                }
+        let path = self.resolve_path(id)?;
+
+        // Text of a file opened in editor (e.g. of a data file) shadows the
+        // file on disk.
+        let key = path::normalize(&path);
+        let open = self.overlay.read().unwrap().contains(&key);
+        let text = open.then(|| self.file_text(&path)).flatten();
+        let bytes = match text {
+            Some(text) => Some(Bytes::from(text.into_bytes())),
+            None => self.bytes.read().unwrap().get(&key).cloned(),
+        };
+        if let Some(bytes) = bytes {
+            self.dependencies.lock().unwrap().insert(id, path);
+            return Ok(bytes);
+        }
+        if self.draft.load(Ordering::Relaxed) && path.exists() {
+            if let Some(bytes) = placeholder_image(&path) {
+                log::debug!("file(): use placeholder for image {:?}", path);
+                return Ok(bytes);
             }
-            None => {
-                let path = self.root_dir.join(id.vpath().as_rootless_path());
-                match fs::read(&path) {
-                    Ok(bytes) => Ok(Bytes::from(bytes)),
-                    Err(_) => Err(FileError::NotFound(path.to_path_buf())),
-                }
+        }
+        self.watch(&path, id);
+        match fs::read(&path) {
+            Ok(bytes) => {
+                self.dependencies.lock().unwrap().insert(id, path);
+                Ok(Bytes::from(bytes))
             }
+            Err(_) => Err(FileError::NotFound(path)),
         }
     }
 
     /// Try to access the font with the given index in the font book.
     fn font(&self, index: usize) -> Option<Font> {
         log::debug!("font(): index={}", index);
-        self.fonts[index].get()
+        self.fonts().fonts.get(index)?.get()
     }
 
     /// Try to access the font with the given index in the font book.
-    fn today(&self, _offset: Option<i64>) -> Option<Datetime> {
-        log::info!("today()");
-        Datetime::from_ymd(1970, 1, 1)
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        date::today(self.timestamp, offset)
     }
 }
@@ -0,0 +1,47 @@
+//! Linked editing ranges.
+//!
+//! Delimiters of strong emphasis (`*`), emphasis (`_`), and equations (`$`)
+//! are linked with their counterparts so that a client edits both of them at
+//! once. Delimiters of math (e.g. `|x|` or `‖x‖`) are linked only if opening
+//! and closing ones are the same since linked ranges are edited with the same
+//! text.
+
+use std::ops::Range;
+
+use typst::syntax::{LinkedNode, Source, SyntaxKind};
+
+/// Find ranges of a delimiter at cursor and its counterpart.
+pub fn linked_ranges(source: &Source, cursor: usize) -> Vec<Range<usize>> {
+    let root = LinkedNode::new(source.root());
+    let before = root.leaf_at(cursor);
+    let after = root.leaf_at(cursor + 1);
+    before
+        .into_iter()
+        .chain(after)
+        .find_map(|leaf| counterparts(&leaf))
+        .unwrap_or_default()
+}
+
+/// Ranges of opening and closing delimiters of a node if a leaf is one of
+/// them.
+fn counterparts(leaf: &LinkedNode) -> Option<Vec<Range<usize>>> {
+    let parent = leaf.parent()?;
+    let delimited = matches!(
+        parent.kind(),
+        SyntaxKind::Strong
+            | SyntaxKind::Emph
+            | SyntaxKind::Equation
+            | SyntaxKind::MathDelimited
+    );
+    if !delimited {
+        return None;
+    }
+    let open = parent.children().next()?;
+    let close = parent.children().next_back()?;
+    let paired = open.offset() != close.offset()
+        && open.kind() == close.kind()
+        && open.text() == close.text();
+    let bound =
+        leaf.offset() == open.offset() || leaf.offset() == close.offset();
+    (paired && bound).then(|| vec![open.range(), close.range()])
+}
@@ -0,0 +1,146 @@
+//! Package lockfile.
+//!
+//! Lockfile `typst.lock` is placed next to `typst.toml` and records exact
+//! versions of packages resolved for a project. On resolution, a locked
+//! version is preferred over a requested one if they are compatible;
+//! otherwise, the requested version is used and a warning is issued.
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use typst::syntax::package::{PackageSpec, PackageVersion};
+
+/// Filename of package lockfile.
+pub static FILENAME: &str = "typst.lock";
+
+static HEADER: &str = "# This file is automatically generated by typstd.
+# It is not intended for manual editing.
+";
+
+#[derive(
+    Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize,
+)]
+pub struct LockedPackage {
+    pub namespace: String,
+    pub name: String,
+    pub version: PackageVersion,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Lockfile {
+    #[serde(default, rename = "package")]
+    pub packages: Vec<LockedPackage>,
+}
+
+/// Outcome of package resolution against lockfile.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Resolution {
+    /// Package is not locked at all.
+    Unlocked,
+    /// Requested version is locked.
+    Locked,
+    /// Requested version is not locked but compatible locked one is used.
+    Substituted(PackageVersion),
+    /// Requested version is incompatible with all locked ones.
+    Outside,
+}
+
+impl Lockfile {
+    /// Load lockfile from a root directory. Missing lockfile is not an error.
+    pub fn load(root_dir: &Path) -> Result<Option<Self>, String> {
+        let path = root_dir.join(FILENAME);
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(err) => return Err(format!("failed to read {path:?}: {err}")),
+        };
+        toml::from_str(&text)
+            .map(Some)
+            .map_err(|err| format!("failed to parse toml at {path:?}: {err}"))
+    }
+
+    /// Save lockfile to a root directory.
+    pub fn save(&self, root_dir: &Path) -> Result<(), String> {
+        let path = root_dir.join(FILENAME);
+        let text = toml::to_string(self)
+            .map_err(|err| format!("failed to serialize lockfile: {err}"))?;
+        fs::write(&path, format!("{HEADER}\n{text}"))
+            .map_err(|err| format!("failed to write {path:?}: {err}"))
+    }
+
+    /// Versions of a package in lockfile.
+    fn versions<'a>(
+        &'a self,
+        spec: &'a PackageSpec,
+    ) -> impl Iterator<Item = PackageVersion> + 'a {
+        self.packages
+            .iter()
+            .filter(|it| it.namespace == spec.namespace && it.name == spec.name)
+            .map(|it| it.version)
+    }
+
+    /// Resolve requested package against locked versions.
+    pub fn resolve(&self, spec: &PackageSpec) -> Resolution {
+        let mut versions = self.versions(spec).peekable();
+        if versions.peek().is_none() {
+            return Resolution::Unlocked;
+        }
+        let mut compatible = None;
+        for version in versions {
+            if version == spec.version {
+                return Resolution::Locked;
+            }
+            if is_compatible(spec.version, version) {
+                compatible = compatible.max(Some(version));
+            }
+        }
+        match compatible {
+            Some(version) => Resolution::Substituted(version),
+            None => Resolution::Outside,
+        }
+    }
+
+    /// Add a package to lockfile if there is no versions of it. Return true
+    /// if lockfile has been changed.
+    pub fn insert(&mut self, spec: &PackageSpec) -> bool {
+        if self.versions(spec).next().is_some() {
+            return false;
+        }
+        self.packages.push(LockedPackage {
+            namespace: spec.namespace.to_string(),
+            name: spec.name.to_string(),
+            version: spec.version,
+        });
+        self.packages.sort();
+        true
+    }
+
+    /// Replace all versions of a package with the given one. Return true if
+    /// lockfile has been changed.
+    pub fn update(&mut self, spec: &PackageSpec) -> bool {
+        let before = self.packages.len();
+        self.packages.retain(|it| {
+            it.namespace != spec.namespace
+                || it.name != spec.name
+                || it.version == spec.version
+        });
+        let removed = before != self.packages.len();
+        self.insert(spec) || removed
+    }
+}
+
+/// Check whether `candidate` version could be used instead of `requested`
+/// one according to semantic versioning: major version (or minor one for
+/// pre-1.0 versions) must match and candidate must not be older.
+pub fn is_compatible(
+    requested: PackageVersion,
+    candidate: PackageVersion,
+) -> bool {
+    if candidate < requested || candidate.major != requested.major {
+        return false;
+    }
+    requested.major > 0 || candidate.minor == requested.minor
+}
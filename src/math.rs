@@ -0,0 +1,133 @@
+//! Previews of equations.
+//!
+//! An equation under cursor is compiled alone into a page which fits it and
+//! the page is rendered to SVG. Top-level imports and let bindings which
+//! precede an equation are compiled along with it so that equations which use
+//! variables and functions of a document render as in the document. The rest
+//! of a document (e.g. templates and set rules) is not evaluated. Renders are
+//! memoized by texts of snippets so that hovering an equation once again does
+//! not compile it.
+
+use std::ops::Range;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use comemo::{Prehashed, Track, Tracked};
+use typst::diag::FileResult;
+use typst::eval::Tracer;
+use typst::foundations::{Bytes, Datetime};
+use typst::syntax::{FileId, LinkedNode, Source, SyntaxKind};
+use typst::text::{Font, FontBook};
+use typst::{Library, World};
+
+/// Page setup of a preview: page fits an equation and it is opaque so that
+/// a preview is readable with dark color themes.
+static PAGE: &str =
+    "#set page(width: auto, height: auto, margin: 4pt, fill: white)\n";
+
+/// Find a range of an equation which encloses cursor. Unclosed equations are
+/// skipped since they span up to the end of a document.
+pub fn equation_at(source: &Source, cursor: usize) -> Option<Range<usize>> {
+    let mut node = LinkedNode::new(source.root()).leaf_at(cursor);
+    while let Some(it) = node {
+        if it.kind() == SyntaxKind::Equation {
+            return (!it.erroneous()).then(|| it.range());
+        }
+        node = it.parent().cloned();
+    }
+    None
+}
+
+/// Render an equation of a source to SVG and return it as data URL.
+pub fn render_equation(
+    world: &dyn World,
+    source: &Source,
+    range: Range<usize>,
+) -> Result<String, String> {
+    let equation = source.get(range.clone()).ok_or("invalid range")?;
+    let mut text = prelude(source, range.start);
+    text.push_str(PAGE);
+    text.push_str(equation);
+    render(world.track(), source.id(), text)
+}
+
+/// Render a snippet to SVG. Snippet replaces a source so that relative paths
+/// are resolved as in the source.
+#[comemo::memoize]
+fn render(
+    world: Tracked<dyn World + '_>,
+    id: FileId,
+    text: String,
+) -> Result<String, String> {
+    let snippet = Snippet {
+        world,
+        main: Source::new(id, text),
+    };
+    let mut tracer = Tracer::new();
+    let result = typst::compile(&snippet, &mut tracer);
+    let doc = result.map_err(|errs| match errs.first() {
+        Some(err) => format!("failed to render equation: {}", err.message),
+        None => "failed to render equation".to_string(),
+    })?;
+    let page = doc.pages.first().ok_or("equation has no pages")?;
+    let svg = typst_svg::svg(&page.frame);
+    Ok(format!(
+        "data:image/svg+xml;base64,{}",
+        BASE64_STANDARD.encode(svg)
+    ))
+}
+
+/// Collect top-level imports and let bindings which precede an offset.
+fn prelude(source: &Source, offset: usize) -> String {
+    let mut prelude = String::new();
+    for child in LinkedNode::new(source.root()).children() {
+        if child.offset() >= offset {
+            break;
+        }
+        let kind = child.kind();
+        if kind == SyntaxKind::ModuleImport || kind == SyntaxKind::LetBinding {
+            prelude.push('#');
+            prelude.push_str(&source.text()[child.range()]);
+            prelude.push('\n');
+        }
+    }
+    prelude
+}
+
+/// World which compiles a snippet in place of a source of another world.
+struct Snippet<'a> {
+    world: Tracked<'a, dyn World + 'a>,
+    main: Source,
+}
+
+impl World for Snippet<'_> {
+    fn library(&self) -> &Prehashed<Library> {
+        self.world.library()
+    }
+
+    fn book(&self) -> &Prehashed<FontBook> {
+        self.world.book()
+    }
+
+    fn main(&self) -> Source {
+        self.main.clone()
+    }
+
+    fn source(&self, id: FileId) -> FileResult<Source> {
+        match id == self.main.id() {
+            true => Ok(self.main.clone()),
+            false => self.world.source(id),
+        }
+    }
+
+    fn file(&self, id: FileId) -> FileResult<Bytes> {
+        self.world.file(id)
+    }
+
+    fn font(&self, index: usize) -> Option<Font> {
+        self.world.font(index)
+    }
+
+    fn today(&self, offset: Option<i64>) -> Option<Datetime> {
+        self.world.today(offset)
+    }
+}
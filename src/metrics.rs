@@ -0,0 +1,118 @@
+//! Latency of request handlers.
+//!
+//! Latencies are accumulated in histograms with fixed buckets per handler so
+//! that memory footprint does not depend on uptime. Every measurement is also
+//! emitted as a tracing event with `histogram.` prefix which is exported as
+//! OpenTelemetry histogram if feature `telemetry` is enabled.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+/// Upper bounds of histogram buckets in milliseconds. The last bucket is
+/// unbounded.
+static BOUNDS: [f64; 14] = [
+    1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0,
+    5000.0, 10000.0, 30000.0,
+];
+
+static HISTOGRAMS: Mutex<BTreeMap<&str, Histogram>> =
+    Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Debug, Default)]
+struct Histogram {
+    buckets: [u64; BOUNDS.len() + 1],
+    count: u64,
+    sum: f64,
+    max: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let index = BOUNDS.partition_point(|&bound| bound < value);
+        self.buckets[index] += 1;
+        self.count += 1;
+        self.sum += value;
+        self.max = self.max.max(value);
+    }
+
+    /// Estimate quantile as upper bound of a bucket which contains it.
+    fn quantile(&self, q: f64) -> f64 {
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return BOUNDS
+                    .get(index)
+                    .map_or(self.max, |it| it.min(self.max));
+            }
+        }
+        self.max
+    }
+}
+
+/// Summary of latencies of a handler in milliseconds.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Latency {
+    pub handler: String,
+    pub count: u64,
+    pub mean: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+/// Record latency of a handler.
+pub fn record(handler: &'static str, elapsed: Duration) {
+    let millis = elapsed.as_secs_f64() * 1e3;
+    // Event level must pass the default filter (`typstd=info`) in order to
+    // reach metrics layer.
+    tracing::info!(histogram.typstd.latency = millis, handler);
+    HISTOGRAMS
+        .lock()
+        .unwrap()
+        .entry(handler)
+        .or_default()
+        .observe(millis);
+}
+
+/// Summarize latencies of all handlers since start.
+pub fn latencies() -> Vec<Latency> {
+    HISTOGRAMS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(handler, histogram)| Latency {
+            handler: handler.to_string(),
+            count: histogram.count,
+            mean: histogram.sum / histogram.count.max(1) as f64,
+            p50: histogram.quantile(0.5),
+            p95: histogram.quantile(0.95),
+            max: histogram.max,
+        })
+        .collect()
+}
+
+/// Timer records latency of a handler when it goes out of scope.
+pub struct Timer {
+    handler: &'static str,
+    started: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        record(self.handler, self.started.elapsed());
+    }
+}
+
+/// Start measuring latency of a handler.
+pub fn timer(handler: &'static str) -> Timer {
+    Timer {
+        handler,
+        started: Instant::now(),
+    }
+}
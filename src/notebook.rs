@@ -0,0 +1,285 @@
+//! Notebook documents.
+//!
+//! Notebook-style clients split a document into cells. Typst cells of a
+//! notebook are stitched into a single virtual source (cells are separated by
+//! line breaks) which is compiled as a main file of a world. Positions are
+//! translated between cells and the virtual source line by line.
+
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use tower_lsp::lsp_types::{
+    Position, TextDocumentContentChangeEvent, TextDocumentIdentifier,
+    TextDocumentItem, Url, VersionedTextDocumentIdentifier,
+};
+
+use crate::syntax::{column_to_byte, PositionEncoding};
+use crate::uri;
+
+/// Language identifier of cells which are stitched into virtual source.
+static LANGUAGE_ID: &str = "typst";
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookCell {
+    /// Cell kind: 1 is markup and 2 is code.
+    pub kind: u32,
+    /// URI of text document of cell.
+    pub document: Url,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookDocument {
+    pub uri: Url,
+    pub cells: Vec<NotebookCell>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookDocumentIdentifier {
+    pub uri: Url,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidOpenNotebookDocumentParams {
+    pub notebook_document: NotebookDocument,
+    pub cell_text_documents: Vec<TextDocumentItem>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidChangeNotebookDocumentParams {
+    pub notebook_document: NotebookDocumentIdentifier,
+    pub change: NotebookDocumentChangeEvent,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NotebookDocumentChangeEvent {
+    pub cells: Option<NotebookCellsChange>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NotebookCellsChange {
+    pub structure: Option<NotebookCellsStructure>,
+    pub text_content: Vec<NotebookCellTextContent>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookCellsStructure {
+    pub array: NotebookCellArrayChange,
+    #[serde(default)]
+    pub did_open: Vec<TextDocumentItem>,
+    #[serde(default)]
+    pub did_close: Vec<TextDocumentIdentifier>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookCellArrayChange {
+    pub start: usize,
+    pub delete_count: usize,
+    #[serde(default)]
+    pub cells: Vec<NotebookCell>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookCellTextContent {
+    pub document: VersionedTextDocumentIdentifier,
+    pub changes: Vec<TextDocumentContentChangeEvent>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidSaveNotebookDocumentParams {
+    pub notebook_document: NotebookDocumentIdentifier,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DidCloseNotebookDocumentParams {
+    pub notebook_document: NotebookDocumentIdentifier,
+}
+
+#[derive(Clone, Debug)]
+struct Cell {
+    uri: Url,
+    /// Cell is a part of virtual source.
+    typst: bool,
+    text: String,
+}
+
+/// Notebook with texts of its cells.
+#[derive(Clone, Debug)]
+pub struct Notebook {
+    uri: Url,
+    cells: Vec<Cell>,
+}
+
+impl Notebook {
+    pub fn new(params: DidOpenNotebookDocumentParams) -> Self {
+        let mut notebook = Self {
+            uri: params.notebook_document.uri,
+            cells: Vec::new(),
+        };
+        notebook.splice(
+            0,
+            0,
+            params.notebook_document.cells,
+            params.cell_text_documents,
+        );
+        notebook
+    }
+
+    pub fn uri(&self) -> &Url {
+        &self.uri
+    }
+
+    /// Path to virtual source file. It is placed next to notebook so that
+    /// relative imports from cells work as expected.
+    pub fn path(&self) -> Option<PathBuf> {
+        let path = uri::to_path(&self.uri)?;
+        let mut name = path.file_name()?.to_os_string();
+        name.push(".typ");
+        Some(path.with_file_name(name))
+    }
+
+    /// URIs of text documents of Typst cells.
+    pub fn cell_uris(&self) -> impl Iterator<Item = &Url> {
+        self.cells.iter().filter(|it| it.typst).map(|it| &it.uri)
+    }
+
+    /// Check whether a text document is a cell of notebook.
+    pub fn contains(&self, uri: &Url) -> bool {
+        self.cells.iter().any(|it| it.typst && &it.uri == uri)
+    }
+
+    /// Stitch Typst cells into virtual source.
+    pub fn text(&self) -> String {
+        let texts = self.cells.iter().filter(|it| it.typst);
+        texts
+            .map(|it| it.text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Apply changes of cell array and cell texts. Columns of ranges of text
+    /// changes are counted in units of encoding.
+    pub fn apply(
+        &mut self,
+        change: NotebookDocumentChangeEvent,
+        encoding: PositionEncoding,
+    ) {
+        let Some(cells) = change.cells else {
+            return;
+        };
+        if let Some(structure) = cells.structure {
+            self.splice(
+                structure.array.start,
+                structure.array.delete_count,
+                structure.array.cells,
+                structure.did_open,
+            );
+        }
+        for content in cells.text_content {
+            let uri = content.document.uri;
+            let Some(cell) = self.cells.iter_mut().find(|it| it.uri == uri)
+            else {
+                continue;
+            };
+            for change in content.changes {
+                match change.range {
+                    Some(range) => {
+                        let begin =
+                            to_offset(&cell.text, range.start, encoding);
+                        let end = to_offset(&cell.text, range.end, encoding);
+                        cell.text.replace_range(begin..end, &change.text);
+                    }
+                    None => cell.text = change.text,
+                }
+            }
+        }
+    }
+
+    /// Replace `delete` cells at `start` with new cells.
+    fn splice(
+        &mut self,
+        start: usize,
+        delete: usize,
+        cells: Vec<NotebookCell>,
+        documents: Vec<TextDocumentItem>,
+    ) {
+        let start = start.min(self.cells.len());
+        let end = (start + delete).min(self.cells.len());
+        let cells = cells.into_iter().map(|cell| {
+            let document = documents.iter().find(|it| it.uri == cell.document);
+            Cell {
+                typst: document.is_some_and(|it| {
+                    it.language_id == LANGUAGE_ID && cell.kind == 2
+                }),
+                text: document.map(|it| it.text.clone()).unwrap_or_default(),
+                uri: cell.document,
+            }
+        });
+        self.cells.splice(start..end, cells.collect::<Vec<_>>());
+    }
+
+    /// Translate position in a cell to position in virtual source.
+    pub fn to_source(&self, uri: &Url, position: Position) -> Option<Position> {
+        let mut line = 0;
+        for cell in self.cells.iter().filter(|it| it.typst) {
+            if &cell.uri == uri {
+                return Some(Position {
+                    line: line + position.line,
+                    character: position.character,
+                });
+            }
+            line += count_lines(&cell.text);
+        }
+        None
+    }
+
+    /// Translate position in virtual source to a cell and a position in it.
+    pub fn to_cell(&self, position: Position) -> Option<(Url, Position)> {
+        let mut line = 0;
+        for cell in self.cells.iter().filter(|it| it.typst) {
+            let lines = count_lines(&cell.text);
+            if position.line < line + lines {
+                let position = Position {
+                    line: position.line - line,
+                    character: position.character,
+                };
+                return Some((cell.uri.clone(), position));
+            }
+            line += lines;
+        }
+        None
+    }
+}
+
+fn count_lines(text: &str) -> u32 {
+    text.matches('\n').count() as u32 + 1
+}
+
+/// Convert position (line and column in units of encoding) to byte offset
+/// clamped to text boundaries.
+fn to_offset(
+    text: &str,
+    position: Position,
+    encoding: PositionEncoding,
+) -> usize {
+    let mut offset = 0;
+    for _ in 0..position.line {
+        match text[offset..].find('\n') {
+            Some(index) => offset += index + 1,
+            None => return text.len(),
+        }
+    }
+    let line = text[offset..].split('\n').next().unwrap_or_default();
+    offset + column_to_byte(line, position.character as usize, encoding)
+}
@@ -0,0 +1,116 @@
+//! On-type formatting.
+//!
+//! Edits are made right after a user types a trigger character. Line break
+//! after an item of a list, an enumeration, or a term list continues it with
+//! the same marker (numbers of enumerations are incremented) and line break
+//! after an empty item removes the marker. Opening `$`, `*`, and `_` in
+//! markup are closed if the closed pair is valid syntax and it does not
+//! introduce syntax errors. Escaped characters, raw blocks, and math are left
+//! as is since their syntax nodes are not delimiters.
+
+use std::path::Path;
+
+use typst::syntax::{LinkedNode, Source, SyntaxKind};
+
+use crate::actions::{make_edit, TextEdit};
+use crate::syntax::PositionEncoding;
+
+/// Characters which trigger on-type formatting besides line break.
+pub const TRIGGER_CHARACTERS: [&str; 3] = ["$", "*", "_"];
+
+/// Make edits after a character `ch` has been typed before `cursor`.
+pub fn on_type_edits(
+    source: &Source,
+    path: &Path,
+    cursor: usize,
+    ch: &str,
+    encoding: PositionEncoding,
+) -> Vec<TextEdit> {
+    let edit = match ch {
+        "\n" => continue_item(source, cursor),
+        "$" | "*" | "_" => close_delimiter(source, cursor),
+        _ => None,
+    };
+    edit.and_then(|(range, text)| {
+        make_edit(source, path, range, text, encoding)
+    })
+    .into_iter()
+    .collect()
+}
+
+/// Continue an item of a list on the previous line with the same marker or
+/// remove the marker of an empty item.
+fn continue_item(
+    source: &Source,
+    cursor: usize,
+) -> Option<(std::ops::Range<usize>, String)> {
+    let line = source.byte_to_line(cursor)?;
+    let start = source.line_to_byte(line)?;
+    let prev = source.line_to_range(line.checked_sub(1)?)?;
+    let indented = source.get(start..cursor)?;
+    if !indented.chars().all(|it| it == ' ' || it == '\t') {
+        return None;
+    }
+
+    // Marker of an item is the first leaf on the previous line.
+    let text = source.get(prev.clone())?.trim_end_matches(['\r', '\n']);
+    let indent = &text[..text.len() - text.trim_start().len()];
+    let offset = prev.start + indent.len();
+    let leaf = LinkedNode::new(source.root()).leaf_at(offset + 1)?;
+    if leaf.offset() != offset {
+        return None;
+    }
+    let marker = match leaf.kind() {
+        SyntaxKind::ListMarker | SyntaxKind::TermMarker => {
+            leaf.text().to_string()
+        }
+        SyntaxKind::EnumMarker => match leaf.text().strip_suffix('.') {
+            Some(number) => format!("{}.", number.parse::<usize>().ok()? + 1),
+            None => leaf.text().to_string(),
+        },
+        _ => return None,
+    };
+
+    // Line break after an empty item ends a list.
+    if text.trim() == leaf.text() {
+        let end = prev.start + text.len();
+        return Some((offset..end, String::new()));
+    }
+    Some((start..cursor, format!("{indent}{marker} ")))
+}
+
+/// Close a delimiter of an equation, strong, or emphasis which has just been
+/// opened in markup.
+fn close_delimiter(
+    source: &Source,
+    cursor: usize,
+) -> Option<(std::ops::Range<usize>, String)> {
+    let leaf = LinkedNode::new(source.root()).leaf_at(cursor)?;
+    let kind = match leaf.kind() {
+        SyntaxKind::Dollar => SyntaxKind::Equation,
+        SyntaxKind::Star => SyntaxKind::Strong,
+        SyntaxKind::Underscore => SyntaxKind::Emph,
+        _ => return None,
+    };
+    let parent = leaf.parent()?;
+    let opening = parent.offset() == leaf.offset();
+    let in_markup = parent.parent_kind() == Some(SyntaxKind::Markup);
+    if parent.kind() != kind || !opening || !in_markup {
+        return None;
+    }
+
+    // Closed pair must be an empty node of the same kind and it must not
+    // make more errors than there are.
+    let delimiter = leaf.text().to_string();
+    let mut text = source.text().to_string();
+    text.insert_str(cursor, &delimiter);
+    let closed = Source::detached(text);
+    let node = LinkedNode::new(closed.root()).leaf_at(cursor + 1)?;
+    let pair = node.parent()?;
+    let empty = pair.offset() + 1 == cursor && pair.len() == 2;
+    let errors = closed.root().errors().len();
+    if pair.kind() != kind || !empty || errors > source.root().errors().len() {
+        return None;
+    }
+    Some((cursor..cursor, delimiter))
+}
@@ -1,22 +1,48 @@
+use std::collections::HashSet;
+use std::env;
 use std::fmt::{Display, Formatter};
 use std::fs;
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 use std::result::Result;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use std::{error, fmt};
 
 use flate2::read::GzDecoder;
+use rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::SignatureScheme;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore};
+use serde::{Deserialize, Serialize};
 use tar::Archive;
+use typst::syntax::package::{PackageManifest, PackageSpec, PackageVersion};
+use typst::syntax::Source;
 use ureq;
 
+use crate::{config, deps, lock, path};
+
 static USER_AGENT: &str = concat!("typstd/{}", env!("CARGO_PKG_VERSION"));
 
-static NAMESPACE: &str = "preview";
+pub static NAMESPACE: &str = "preview";
 
-#[derive(Debug)]
+/// Namespace of packages which are developed locally.
+pub static LOCAL_NAMESPACE: &str = "local";
+
+#[derive(Clone, Debug)]
 pub enum Error {
     RequestError(String),
     ExtractError(String),
+    TlsError(String),
+    NotFound(String),
+    CacheError(String),
+    ProxyError(String),
 }
 
 impl error::Error for Error {}
@@ -30,55 +56,1001 @@ impl Display for Error {
             Self::ExtractError(err) => {
                 write!(f, "failed to extract archive: {err}")
             }
+            Self::TlsError(err) => {
+                write!(f, "failed to configure tls: {err}")
+            }
+            Self::NotFound(spec) => write!(f, "package {spec} not found"),
+            Self::CacheError(err) => {
+                write!(f, "failed to manage package cache: {err}")
+            }
+            Self::ProxyError(err) => {
+                write!(f, "failed to configure proxy: {err}")
+            }
         }
     }
 }
 
-/// Fetch package tarball from remote and untar it locally.
-fn fetch(url: &str, r#where: &Path) -> Result<(), Error> {
+/// Settings of HTTP client which are shared by all downloads.
+struct Settings {
+    /// Custom TLS configuration. If it is missing then default one is used.
+    tls_config: Option<Arc<ClientConfig>>,
+    /// URL of proxy which overrides proxies from environment variables.
+    proxy: Option<String>,
+    /// Base URL of package registry without trailing slash. Official registry
+    /// is used if it is missing.
+    registry: Option<String>,
+    connect_timeout: Duration,
+    read_timeout: Duration,
+    /// Maximal size of extracted package in bytes. Zero means no limit.
+    max_size: u64,
+    layout: config::PackageLayout,
+}
+
+static SETTINGS: RwLock<Settings> = RwLock::new(Settings {
+    tls_config: None,
+    proxy: None,
+    registry: None,
+    connect_timeout: Duration::from_secs(10),
+    read_timeout: Duration::from_secs(60),
+    max_size: 64 << 20,
+    layout: config::PackageLayout::Typstd,
+});
+
+/// Packages under development and their directories.
+static DEV_PACKAGES: RwLock<Vec<(PackageSpec, PathBuf)>> =
+    RwLock::new(Vec::new());
+
+/// Configure HTTP client for package downloads and register packages under
+/// development. It affects all subsequent downloads and imports.
+pub fn configure(settings: &config::Packages) -> Result<(), Error> {
+    *DEV_PACKAGES.write().unwrap() = settings
+        .paths
+        .iter()
+        .filter_map(|dir| match read_manifest(dir) {
+            Ok(spec) => {
+                log::info!("package {} is developed at {:?}", spec, dir);
+                Some((spec, dir.clone()))
+            }
+            Err(err) => {
+                log::warn!("skip package at {:?}: {}", dir, err);
+                None
+            }
+        })
+        .collect();
+    let tls_config = if settings.insecure {
+        log::warn!("TLS certificate verification is DISABLED");
+        log::warn!("package downloads are vulnerable to MITM attacks");
+        Some(insecure_tls_config())
+    } else if let Some(path) = &settings.ca_bundle {
+        log::info!("use custom CA bundle at {:?}", path);
+        Some(custom_tls_config(path)?)
+    } else {
+        None
+    };
+    if let Some(url) = &settings.proxy {
+        log::info!("use proxy {} for package downloads", url);
+        ureq::Proxy::new(url)
+            .map_err(|err| Error::ProxyError(format!("{url}: {err}")))?;
+    }
+    let registry = settings.mirror.as_ref().map(|url| {
+        log::info!("use package registry mirror {}", url);
+        url.trim_end_matches('/').to_string()
+    });
+    *SETTINGS.write().unwrap() = Settings {
+        tls_config: tls_config.map(Arc::new),
+        proxy: settings.proxy.clone(),
+        registry,
+        connect_timeout: Duration::from_secs(settings.connect_timeout),
+        read_timeout: Duration::from_secs(settings.read_timeout),
+        max_size: settings.max_size,
+        layout: settings.layout,
+    };
+    Ok(())
+}
+
+/// Read spec of a package under development from its manifest.
+fn read_manifest(dir: &Path) -> Result<PackageSpec, String> {
+    let path = dir.join("typst.toml");
+    let text = fs::read_to_string(&path)
+        .map_err(|err| format!("failed to read {path:?}: {err}"))?;
+    let manifest = toml::from_str::<PackageManifest>(&text)
+        .map_err(|err| format!("failed to parse {path:?}: {err}"))?;
+    Ok(PackageSpec {
+        namespace: LOCAL_NAMESPACE.into(),
+        name: manifest.package.name,
+        version: manifest.package.version,
+    })
+}
+
+/// Make TLS configuration which trusts default root certificates and
+/// certificates from PEM file.
+fn custom_tls_config(path: &Path) -> Result<ClientConfig, Error> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|err| Error::TlsError(format!("{path:?}: {err}")))?;
+    for cert in certs {
+        let cert =
+            cert.map_err(|err| Error::TlsError(format!("{path:?}: {err}")))?;
+        roots
+            .add(cert)
+            .map_err(|err| Error::TlsError(format!("{path:?}: {err}")))?;
+    }
+    let config = ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|err| Error::TlsError(err.to_string()))?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    Ok(config)
+}
+
+/// Make TLS configuration which accepts any server certificate.
+fn insecure_tls_config() -> ClientConfig {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .expect("default protocol versions must be supported")
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+        .with_no_client_auth()
+}
+
+/// Certificate verifier which accepts any certificate. Signatures are still
+/// checked in order to complete handshake.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.0.signature_verification_algorithms;
+        rustls::crypto::verify_tls12_signature(message, cert, dss, algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algorithms = &self.0.signature_verification_algorithms;
+        rustls::crypto::verify_tls13_signature(message, cert, dss, algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// Build HTTP agent for a URL which respects proxy and TLS settings.
+fn agent(url: &str) -> ureq::Agent {
+    let settings = SETTINGS.read().unwrap();
     let mut builder = ureq::AgentBuilder::new()
         .user_agent(USER_AGENT)
-        .timeout(Duration::from_secs(5));
+        .timeout_connect(settings.connect_timeout)
+        .timeout_read(settings.read_timeout);
 
-    // Get the network proxy config from the environment.
-    if let Some(proxy) = env_proxy::for_url_str(url)
-        .to_url()
-        .and_then(|url| ureq::Proxy::new(url).ok())
-    {
+    if let Some(tls_config) = settings.tls_config.clone() {
+        builder = builder.tls_config(tls_config);
+    }
+
+    // Configured proxy takes precedence over the network proxy config from
+    // the environment.
+    let proxy = settings.proxy.clone().or_else(|| {
+        env_proxy::for_url_str(url)
+            .to_url()
+            .map(|it| it.to_string())
+    });
+    if let Some(proxy) = proxy.and_then(|url| ureq::Proxy::new(url).ok()) {
         builder = builder.proxy(proxy);
     }
 
-    let agent = builder.build();
-    let reader = agent
-        .get(url)
+    builder.build()
+}
+
+/// Reader which fails if more than a limited number of bytes is read. It
+/// protects from decompression bombs.
+struct LimitedReader<R> {
+    inner: R,
+    remaining: u64,
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        match self.remaining.checked_sub(size as u64) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Ok(size)
+            }
+            None => Err(io::Error::other("package exceeds size limit")),
+        }
+    }
+}
+
+/// Number of attempts to download a package.
+const FETCH_ATTEMPTS: u32 = 3;
+
+/// Pause before the second attempt. It doubles after each failed attempt.
+const FETCH_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Counter of temporary directories of downloads.
+static FETCH_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Fetch package tarball from remote and untar it locally. Failed requests
+/// (except for missing packages) and broken archives are retried with
+/// exponential backoff. A package is extracted to a temporary
+/// directory next to destination and it is moved to destination as soon as
+/// it is completely extracted so that interrupted downloads never look like
+/// cached packages.
+fn fetch(url: &str, r#where: &Path) -> Result<(), Error> {
+    let mut backoff = FETCH_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match fetch_once(url, r#where) {
+            Err(err @ Error::NotFound(_)) => return Err(err),
+            Err(err) if attempt < FETCH_ATTEMPTS => {
+                log::warn!("attempt {attempt} to fetch {url} failed: {err}");
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn fetch_once(url: &str, r#where: &Path) -> Result<(), Error> {
+    let reader = match agent(url).get(url).call() {
+        Ok(response) => response.into_reader(),
+        Err(ureq::Error::Status(404, _)) => {
+            return Err(Error::NotFound(url.to_string()));
+        }
+        Err(err) => return Err(Error::RequestError(err.to_string())),
+    };
+
+    let max_size = match SETTINGS.read().unwrap().max_size {
+        0 => u64::MAX,
+        max_size => max_size,
+    };
+    let inflated = LimitedReader {
+        inner: GzDecoder::new(reader),
+        remaining: max_size,
+    };
+    let parent = r#where.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)
+        .map_err(|err| Error::ExtractError(err.to_string()))?;
+    let name = r#where.file_name().unwrap_or_default().to_string_lossy();
+    let index = FETCH_COUNTER.fetch_add(1, Ordering::Relaxed);
+    let temp_dir =
+        parent.join(format!(".{name}.{}.{index}.tmp", std::process::id()));
+    let result = extract(inflated, &temp_dir).and_then(|()| {
+        match fs::rename(&temp_dir, r#where) {
+            Ok(()) => Ok(()),
+            // Another download has completed in the meantime.
+            Err(_) if is_complete(r#where) => Ok(()),
+            Err(err) => Err(Error::ExtractError(err.to_string())),
+        }
+    });
+    if temp_dir.exists() {
+        fs::remove_dir_all(&temp_dir).ok();
+    }
+    result
+}
+
+/// Unpack an archive to a directory and check that it is complete: a gzip
+/// stream is read to the end (so that its checksum is verified) and there is
+/// a manifest of a package.
+fn extract<R: Read>(reader: R, dir: &Path) -> Result<(), Error> {
+    let mut archive = Archive::new(reader);
+    archive
+        .unpack(dir)
+        .map_err(|err| Error::ExtractError(err.to_string()))?;
+    io::copy(&mut archive.into_inner(), &mut io::sink())
+        .map_err(|err| Error::ExtractError(err.to_string()))?;
+    if !is_complete(dir) {
+        let err = "there is no typst.toml in archive".to_string();
+        return Err(Error::ExtractError(err));
+    }
+    Ok(())
+}
+
+/// Check whether a directory contains an extracted package.
+fn is_complete(dir: &Path) -> bool {
+    dir.join("typst.toml").is_file()
+}
+
+/// Entry of package index of a namespace.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct IndexEntry {
+    pub name: String,
+    pub version: PackageVersion,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+/// Package index and time of its last refresh.
+type Index = (Option<Instant>, Arc<Vec<IndexEntry>>);
+
+/// Package index which is refreshed at most once per [`INDEX_TTL`] (failed
+/// attempts included). Initially, it is a copy of index from the last refresh
+/// by any process so that index is available offline.
+static INDEX: LazyLock<RwLock<Index>> =
+    LazyLock::new(|| RwLock::new((None, Arc::new(load_index()))));
+
+const INDEX_TTL: Duration = Duration::from_secs(3600);
+
+/// Base URL of package registry (or of its mirror).
+pub fn registry_url() -> String {
+    let registry = SETTINGS.read().unwrap().registry.clone();
+    registry.unwrap_or_else(|| "https://packages.typst.org".to_string())
+}
+
+/// URL of package index of the default namespace.
+pub fn index_url() -> String {
+    format!("{}/{NAMESPACE}/index.json", registry_url())
+}
+
+/// Proxy which is used for requests to a URL (if any).
+pub fn proxy_for(url: &str) -> Option<String> {
+    if let Some(proxy) = &SETTINGS.read().unwrap().proxy {
+        return Some(proxy.clone());
+    }
+    env_proxy::for_url_str(url)
+        .to_url()
+        .map(|it| it.to_string())
+}
+
+/// Check that package registry is reachable with current settings.
+pub fn ping_registry() -> Result<(), Error> {
+    let url = index_url();
+    agent(&url)
+        .head(&url)
+        .call()
+        .map(|_| ())
+        .map_err(|err| Error::RequestError(err.to_string()))
+}
+
+/// Fetch package index of the default namespace.
+pub fn fetch_index() -> Result<Vec<IndexEntry>, Error> {
+    let url = index_url();
+    log::info!("fetch package index from {}", url);
+    let body = agent(&url)
+        .get(&url)
         .call()
         .map_err(|err| Error::RequestError(err.to_string()))?
-        .into_reader();
+        .into_string()
+        .map_err(|err| Error::RequestError(err.to_string()))?;
+    serde_json::from_str(&body).map_err(|err| {
+        Error::RequestError(format!("failed to parse index: {err}"))
+    })
+}
+
+/// Package index of the default namespace from the last refresh. It is empty
+/// if index has never been fetched.
+pub fn cached_index() -> Arc<Vec<IndexEntry>> {
+    INDEX.read().unwrap().1.clone()
+}
+
+/// Path to a copy of package index in cache directory.
+fn index_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("typstd/index.json"))
+}
+
+/// Read a copy of package index. Index is empty if there is no copy.
+fn load_index() -> Vec<IndexEntry> {
+    let Some(path) = index_path() else {
+        return Vec::new();
+    };
+    let Ok(text) = fs::read_to_string(&path) else {
+        return Vec::new();
+    };
+    serde_json::from_str(&text).unwrap_or_else(|err| {
+        log::warn!("failed to parse package index at {:?}: {}", path, err);
+        Vec::new()
+    })
+}
+
+/// Write a copy of package index to cache directory.
+fn save_index(entries: &[IndexEntry]) -> Result<(), Error> {
+    let path = index_path()
+        .ok_or_else(|| Error::CacheError("no cache directory".into()))?;
+    let text = serde_json::to_string(entries)
+        .map_err(|err| Error::CacheError(err.to_string()))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|err| Error::CacheError(err.to_string()))?;
+    }
+    fs::write(&path, text).map_err(|err| {
+        Error::CacheError(format!("failed to write {path:?}: {err}"))
+    })
+}
+
+/// Check whether cached package index should be refreshed.
+pub fn is_index_stale() -> bool {
+    INDEX
+        .read()
+        .unwrap()
+        .0
+        .is_none_or(|it| it.elapsed() > INDEX_TTL)
+}
+
+/// Fetch package index of the default namespace and cache it. Index is
+/// fetched once for concurrent calls and it is not refetched on failures
+/// until it becomes stale.
+pub fn refresh_index() -> Result<(), Error> {
+    {
+        let mut index = INDEX.write().unwrap();
+        if index.0.is_some_and(|it| it.elapsed() <= INDEX_TTL) {
+            return Ok(());
+        }
+        index.0 = Some(Instant::now());
+    }
+    let entries = fetch_index()?;
+    if let Err(err) = save_index(&entries) {
+        log::warn!("failed to save package index: {err}");
+    }
+    INDEX.write().unwrap().1 = Arc::new(entries);
+    Ok(())
+}
 
-    let inflated = GzDecoder::new(reader);
-    Archive::new(inflated).unpack(r#where).map_err(|err| {
-        fs::remove_dir_all(r#where).ok();
-        Error::ExtractError(err.to_string())
+/// Resolve a package spec with an optional version (e.g. `@preview/cetz` or
+/// `@preview/cetz:0.2.0`) to the latest compatible version. Package index is
+/// refreshed if it is stale.
+pub fn resolve_spec(spec: &str) -> Result<PackageSpec, Error> {
+    let invalid = || Error::NotFound(spec.to_string());
+    let (namespace, rest) = spec
+        .strip_prefix('@')
+        .and_then(|it| it.split_once('/'))
+        .ok_or_else(invalid)?;
+    let (name, requested) = match rest.split_once(':') {
+        Some((name, version)) => {
+            let version = version.parse().map_err(|_| invalid())?;
+            (name, Some(version))
+        }
+        None => (rest, None),
+    };
+    if namespace == NAMESPACE && is_index_stale() {
+        if let Err(err) = refresh_index() {
+            log::warn!("failed to refresh package index: {err}");
+        }
+    }
+    let version =
+        latest_version(namespace, name, requested).ok_or_else(invalid)?;
+    Ok(PackageSpec {
+        namespace: namespace.into(),
+        name: name.into(),
+        version,
     })
 }
 
-pub fn prepare_package(name: &str, version: &str) -> Result<PathBuf, Error> {
-    // Search cache directory (or locally) for package. If there is a
-    // directory at the path then return it.
-    let cache_dir = match dirs::cache_dir() {
-        Some(cache_dir) => cache_dir,
-        None => PathBuf::new(),
+/// Find the latest version of a package among indexed and local ones. If a
+/// version is requested then only compatible versions (see
+/// [`lock::is_compatible`]) are considered.
+pub fn latest_version(
+    namespace: &str,
+    name: &str,
+    requested: Option<PackageVersion>,
+) -> Option<PackageVersion> {
+    let index = cached_index();
+    let indexed = index
+        .iter()
+        .filter(|_| namespace == NAMESPACE)
+        .filter(|it| it.name == name)
+        .map(|it| it.version);
+    local_versions(namespace, name)
+        .into_iter()
+        .chain(indexed)
+        .filter(|it| requested.is_none_or(|req| lock::is_compatible(req, *it)))
+        .max()
+}
+
+/// Directory with local packages. It is `TYPST_PACKAGE_PATH` or `typst/packages`
+/// in data directory like in typst-cli.
+pub fn package_path() -> Option<PathBuf> {
+    match env::var_os("TYPST_PACKAGE_PATH") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => dirs::data_dir().map(|dir| dir.join("typst/packages")),
+    }
+}
+
+/// Directory where packages are downloaded to. It is
+/// `TYPST_PACKAGE_CACHE_PATH` or a cache directory of configured layout.
+pub fn package_cache_path() -> PathBuf {
+    match env::var_os("TYPST_PACKAGE_CACHE_PATH") {
+        Some(path) => PathBuf::from(path),
+        None => layout_cache_path(SETTINGS.read().unwrap().layout),
+    }
+}
+
+/// Cache directory of packages of a layout in user cache directory.
+fn layout_cache_path(layout: config::PackageLayout) -> PathBuf {
+    let subdir = match layout {
+        config::PackageLayout::Typstd => "typstd/packages",
+        config::PackageLayout::Typst => "typst/packages",
     };
-    let r#where = format!("typstd/packages/{NAMESPACE}/{name}/{version}");
-    let r#where = cache_dir.join(r#where);
-    if r#where.exists() {
+    dirs::cache_dir().unwrap_or_default().join(subdir)
+}
+
+/// Directories with downloaded packages. The first one is where packages are
+/// downloaded to and the rest are cache directories of other layouts which
+/// are only read (e.g. packages downloaded by typst-cli).
+pub fn package_cache_paths() -> Vec<PathBuf> {
+    let mut paths = vec![package_cache_path()];
+    for layout in [config::PackageLayout::Typstd, config::PackageLayout::Typst]
+    {
+        let path = layout_cache_path(layout);
+        if !paths.contains(&path) {
+            paths.push(path);
+        }
+    }
+    paths
+}
+
+/// Check whether a file belongs to a downloaded package. Such files are
+/// read-only: they are replaced on the next download.
+pub fn is_cached(path: &Path) -> bool {
+    let path = path::normalize(path);
+    package_cache_paths()
+        .iter()
+        .any(|dir| path.starts_with(path::normalize(dir)))
+}
+
+/// Versions of a package which are available without downloading (in local
+/// packages or in cache directory) in ascending order.
+pub fn local_versions(namespace: &str, name: &str) -> Vec<PackageVersion> {
+    let subdir = format!("{namespace}/{name}");
+    let dirs = package_path()
+        .into_iter()
+        .chain(package_cache_paths())
+        .map(|dir| dir.join(&subdir));
+    let mut versions = dirs
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.filter_map(Result::ok))
+        .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+        .collect::<Vec<PackageVersion>>();
+    versions.extend(
+        DEV_PACKAGES
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(spec, _)| {
+                spec.namespace == namespace && spec.name == name
+            })
+            .map(|(spec, _)| spec.version),
+    );
+    versions.sort();
+    versions.dedup();
+    versions
+}
+
+/// Packages which are available without downloading (in local packages or in
+/// cache directory).
+pub fn local_packages() -> Vec<PackageSpec> {
+    fn read_dir(dir: &Path) -> impl Iterator<Item = (String, PathBuf)> {
+        fs::read_dir(dir)
+            .into_iter()
+            .flat_map(|entries| entries.filter_map(Result::ok))
+            .filter_map(|entry| {
+                let name = entry.file_name().to_str()?.to_string();
+                Some((name, entry.path()))
+            })
+    }
+
+    let mut specs = Vec::new();
+    let dirs = package_path().into_iter().chain(package_cache_paths());
+    for dir in dirs {
+        for (namespace, dir) in read_dir(&dir) {
+            for (name, dir) in read_dir(&dir) {
+                for (version, _) in read_dir(&dir) {
+                    let Ok(version) = version.parse() else {
+                        continue;
+                    };
+                    specs.push(PackageSpec {
+                        namespace: namespace.as_str().into(),
+                        name: name.as_str().into(),
+                        version,
+                    });
+                }
+            }
+        }
+    }
+    specs.extend(
+        DEV_PACKAGES
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(spec, _)| spec.clone()),
+    );
+    specs.sort_by(|lhs, rhs| {
+        (&lhs.namespace, &lhs.name, lhs.version).cmp(&(
+            &rhs.namespace,
+            &rhs.name,
+            rhs.version,
+        ))
+    });
+    specs.dedup();
+    specs
+}
+
+/// Packages which are being downloaded in background.
+static DOWNLOADS: Mutex<Vec<PackageSpec>> = Mutex::new(Vec::new());
+
+/// Failures of background downloads and their times. A failure is reported
+/// instead of downloading a package once again for [`RETRY_DELAY`].
+static FAILURES: Mutex<Vec<(PackageSpec, Instant, Error)>> =
+    Mutex::new(Vec::new());
+
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Start downloading a package on a thread of its own unless it is being
+/// downloaded already. It fails at once if a package could not be downloaded
+/// at all or if the recent download has failed.
+pub fn download_in_background(spec: &PackageSpec) -> Result<(), Error> {
+    if spec.namespace != NAMESPACE {
+        return Err(Error::NotFound(spec.to_string()));
+    }
+    {
+        let mut failures = FAILURES.lock().unwrap();
+        failures.retain(|(_, at, _)| at.elapsed() < RETRY_DELAY);
+        if let Some((_, _, err)) = failures.iter().find(|it| it.0 == *spec) {
+            return Err(err.clone());
+        }
+    }
+    let mut downloads = DOWNLOADS.lock().unwrap();
+    if downloads.contains(spec) {
+        return Ok(());
+    }
+    downloads.push(spec.clone());
+    let spec = spec.clone();
+    let result =
+        thread::Builder::new()
+            .name("download".to_string())
+            .spawn(move || {
+                let version = spec.version.to_string();
+                let result =
+                    prepare_package(&spec.namespace, &spec.name, &version);
+                if let Err(err) = result {
+                    log::error!("failed to download package {spec}: {err}");
+                    let failure = (spec.clone(), Instant::now(), err);
+                    FAILURES.lock().unwrap().push(failure);
+                }
+                DOWNLOADS.lock().unwrap().retain(|it| *it != spec);
+            });
+    result.map(|_| ()).map_err(|err| {
+        downloads.pop();
+        Error::RequestError(err.to_string())
+    })
+}
+
+/// Check whether a package is being downloaded in background.
+pub fn is_downloading(spec: &PackageSpec) -> bool {
+    DOWNLOADS.lock().unwrap().contains(spec)
+}
+
+/// Find a package among packages under development, local packages, and
+/// downloaded ones.
+pub fn find_package(
+    namespace: &str,
+    name: &str,
+    version: &str,
+) -> Option<PathBuf> {
+    // Packages under development take precedence over installed ones.
+    let dev_packages = DEV_PACKAGES.read().unwrap();
+    let found = dev_packages.iter().find(|(spec, _)| {
+        spec.namespace == namespace
+            && spec.name == name
+            && spec.version.to_string() == version
+    });
+    if let Some((_, dir)) = found {
+        return Some(dir.clone());
+    }
+    drop(dev_packages);
+
+    // Search local packages and then cache directory for package. If there is
+    // a directory at the path then return it.
+    let subdir = format!("{namespace}/{name}/{version}");
+    if let Some(r#where) = package_path().map(|dir| dir.join(&subdir)) {
+        if r#where.exists() {
+            log::info!("package {}:{} found at {:?}", name, version, r#where);
+            return Some(r#where);
+        }
+    }
+    let mut dirs = package_cache_paths().into_iter();
+    let r#where = dirs.next().unwrap_or_default().join(&subdir);
+    if is_complete(&r#where) {
         log::info!("package {}:{} found at {:?}", name, version, r#where);
+        touch(&r#where);
+        return Some(r#where);
+    }
+    // Leftovers of interrupted downloads of older versions of typstd.
+    if r#where.exists() {
+        log::warn!("remove incomplete package at {:?}", r#where);
+        fs::remove_dir_all(&r#where).ok();
+    }
+
+    // Caches of other layouts belong to other tools, so they are left intact.
+    dirs.map(|dir| dir.join(&subdir))
+        .find(|dir| is_complete(dir))
+        .inspect(|dir| {
+            log::info!("package {}:{} found at {:?}", name, version, dir);
+        })
+}
+
+pub fn prepare_package(
+    namespace: &str,
+    name: &str,
+    version: &str,
+) -> Result<PathBuf, Error> {
+    if let Some(dir) = find_package(namespace, name, version) {
+        return Ok(dir);
+    }
+    download_package(&package_cache_path(), namespace, name, version)
+}
+
+/// Find a package in a cache directory of its own (e.g. of an embedding
+/// application) or download it there. Neither shared cache directories nor
+/// local packages are used.
+pub fn prepare_package_in(
+    cache_dir: &Path,
+    spec: &PackageSpec,
+) -> Result<PathBuf, Error> {
+    let (namespace, name) = (spec.namespace.as_str(), spec.name.as_str());
+    let version = spec.version.to_string();
+    let r#where = cache_dir.join(format!("{namespace}/{name}/{version}"));
+    if is_complete(&r#where) {
         return Ok(r#where);
     }
+    download_package(cache_dir, namespace, name, &version)
+}
 
-    let url = format!(
-        "https://packages.typst.org/{NAMESPACE}/{name}-{version}.tar.gz",
-    );
+fn download_package(
+    cache_dir: &Path,
+    namespace: &str,
+    name: &str,
+    version: &str,
+) -> Result<PathBuf, Error> {
+    let r#where = cache_dir.join(format!("{namespace}/{name}/{version}"));
+
+    // Only packages from the official registry could be downloaded.
+    if namespace != NAMESPACE {
+        let spec = format!("@{namespace}/{name}:{version}");
+        return Err(Error::NotFound(spec));
+    }
+    let url = format!("{}/{NAMESPACE}/{name}-{version}.tar.gz", registry_url());
     log::info!("download package {}:{} to {:?}", name, version, r#where);
     fetch(&url, &r#where).map(|()| r#where)
 }
+
+/// Packages in cache directory which have been used by this process.
+static TOUCHED: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Update modification time of a directory of a cached package on its first
+/// use by this process. It is the time of the last use of a package.
+fn touch(dir: &Path) {
+    let mut touched = TOUCHED.lock().unwrap();
+    if touched.iter().any(|it| it == dir) {
+        return;
+    }
+    touched.push(dir.to_path_buf());
+    let result =
+        fs::File::open(dir).and_then(|it| it.set_modified(SystemTime::now()));
+    if let Err(err) = result {
+        log::debug!("failed to touch package at {:?}: {}", dir, err);
+    }
+}
+
+/// Package in cache directory.
+#[derive(Clone, Debug)]
+pub struct CachedPackage {
+    pub spec: PackageSpec,
+    pub path: PathBuf,
+    /// Total size of files of a package in bytes.
+    pub size: u64,
+    /// Time of the last use (or of download) of a package.
+    pub last_used: Option<SystemTime>,
+}
+
+/// List packages in cache directory.
+pub fn cached_packages() -> Vec<CachedPackage> {
+    let mut packages = Vec::new();
+    let read_dir = |dir: &Path| {
+        fs::read_dir(dir)
+            .into_iter()
+            .flat_map(|entries| entries.filter_map(Result::ok))
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_str()?.to_string();
+                Some((name, entry.path()))
+            })
+            .collect::<Vec<_>>()
+    };
+    for (namespace, dir) in read_dir(&package_cache_path()) {
+        for (name, dir) in read_dir(&dir) {
+            for (version, dir) in read_dir(&dir) {
+                let Ok(version) = version.parse() else {
+                    continue;
+                };
+                if !is_complete(&dir) {
+                    continue;
+                }
+                let spec = PackageSpec {
+                    namespace: namespace.as_str().into(),
+                    name: name.as_str().into(),
+                    version,
+                };
+                packages.push(CachedPackage {
+                    spec,
+                    size: dir_size(&dir),
+                    last_used: fs::metadata(&dir)
+                        .and_then(|it| it.modified())
+                        .ok(),
+                    path: dir,
+                });
+            }
+        }
+    }
+    packages.sort_by(|lhs, rhs| lhs.path.cmp(&rhs.path));
+    packages
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    let mut size = 0;
+    let mut queue = vec![dir.to_path_buf()];
+    while let Some(dir) = queue.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            match entry.metadata() {
+                Ok(meta) if meta.is_dir() => queue.push(entry.path()),
+                Ok(meta) => size += meta.len(),
+                Err(_) => {}
+            }
+        }
+    }
+    size
+}
+
+/// Remove a cached package and directories of its name and namespace if they
+/// become empty.
+fn remove_cached(package: &CachedPackage) -> Result<(), Error> {
+    fs::remove_dir_all(&package.path).map_err(|err| {
+        Error::CacheError(format!("failed to remove {:?}: {err}", package.path))
+    })?;
+    TOUCHED.lock().unwrap().retain(|it| *it != package.path);
+    for dir in package.path.ancestors().skip(1).take(2) {
+        // It fails if a directory is not empty.
+        if fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Remove all packages from cache directory. Packages which have been
+/// removed are returned.
+pub fn clear_cache() -> Result<Vec<CachedPackage>, Error> {
+    let packages = cached_packages();
+    for package in packages.iter() {
+        remove_cached(package)?;
+    }
+    log::info!("{} package(s) removed from cache", packages.len());
+    Ok(packages)
+}
+
+/// Remove packages from cache directory which have not been used for a
+/// while. Packages which have been removed are returned.
+pub fn collect_garbage(max_age: Duration) -> Result<Vec<CachedPackage>, Error> {
+    let now = SystemTime::now();
+    let mut removed = Vec::new();
+    for package in cached_packages() {
+        let age = package
+            .last_used
+            .and_then(|it| now.duration_since(it).ok())
+            .unwrap_or_default();
+        if age > max_age {
+            remove_cached(&package)?;
+            removed.push(package);
+        }
+    }
+    log::info!("{} unused package(s) removed from cache", removed.len());
+    Ok(removed)
+}
+
+/// Download a package unless it is available already. Path to a package is
+/// returned.
+pub fn fetch_package(spec: &PackageSpec) -> Result<PathBuf, Error> {
+    prepare_package(&spec.namespace, &spec.name, &spec.version.to_string())
+}
+
+/// Find imports of packages in all sources of a directory.
+fn scan_imports(dir: &Path) -> Vec<PackageSpec> {
+    let mut specs = Vec::<PackageSpec>::new();
+    let mut queue = vec![dir.to_path_buf()];
+    while let Some(dir) = queue.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for path in entries.filter_map(|it| it.ok()).map(|it| it.path()) {
+            if path.is_dir() {
+                queue.push(path);
+                continue;
+            }
+            if path.extension().is_some_and(|it| it == "typ") {
+                let Ok(text) = fs::read_to_string(&path) else {
+                    continue;
+                };
+                let source = Source::detached(text);
+                specs.extend(
+                    deps::find_dependencies(&source)
+                        .into_iter()
+                        .filter(|(_, target, _)| target.starts_with('@'))
+                        .filter_map(|(_, target, _)| target.parse().ok()),
+                );
+            }
+        }
+    }
+    specs
+}
+
+/// Download packages in parallel ahead of compilation. Packages imported by
+/// downloaded ones are fetched as well. It returns packages which failed to
+/// download.
+pub fn prefetch(specs: Vec<PackageSpec>) -> Vec<(PackageSpec, Error)> {
+    let mut visited = HashSet::<PackageSpec>::new();
+    let mut failures = Vec::<(PackageSpec, Error)>::new();
+    let mut pending = specs;
+    while !pending.is_empty() {
+        let batch = pending
+            .drain(..)
+            .filter(|spec| visited.insert(spec.clone()))
+            .collect::<Vec<_>>();
+        log::info!("prefetch {} package(s)", batch.len());
+        let results = thread::scope(|scope| {
+            let handles = batch
+                .into_iter()
+                .map(|spec| {
+                    scope.spawn(move || {
+                        let version = spec.version.to_string();
+                        let result = prepare_package(
+                            &spec.namespace,
+                            &spec.name,
+                            &version,
+                        );
+                        (spec, result)
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .filter_map(|handle| handle.join().ok())
+                .collect::<Vec<_>>()
+        });
+        for (spec, result) in results {
+            match result {
+                Ok(dir) => pending.extend(scan_imports(&dir)),
+                Err(err) => failures.push((spec, err)),
+            }
+        }
+    }
+    failures
+}
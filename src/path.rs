@@ -0,0 +1,43 @@
+//! Path normalization.
+//!
+//! Sources and worlds are indexed by file system paths. The same file can be
+//! referred by different paths though: `chapters/../main.typ` and `main.typ`
+//! or, on case-insensitive file systems (default ones on macOS and Windows),
+//! `Main.typ` and `main.typ`. All path-keyed indices should use normalized
+//! keys in order to avoid duplicated sources and missed cache hits.
+
+use std::path::{Component, Path, PathBuf};
+
+/// Normalize path lexically (i.e. without any file system access) and fold
+/// its case on platforms with case-insensitive file systems. Resulting path
+/// is intended for use as a key only; it may not exist on disk.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => {}
+                _ => normalized.push(component),
+            },
+            _ => normalized.push(component),
+        }
+    }
+    fold_case(normalized)
+}
+
+#[cfg(any(target_os = "macos", target_os = "windows"))]
+fn fold_case(path: PathBuf) -> PathBuf {
+    match path.to_str() {
+        Some(runes) => PathBuf::from(runes.to_lowercase()),
+        None => path,
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn fold_case(path: PathBuf) -> PathBuf {
+    path
+}
@@ -0,0 +1,78 @@
+//! Checks of fonts embedded into PDF.
+//!
+//! Font licenses restrict embedding with `fsType` flags of OS/2 table: a font
+//! could forbid embedding at all or forbid subsetting. Print shops and
+//! publishers reject documents which violate licenses so that fonts used by a
+//! document are checked before export.
+
+use ttf_parser::Permissions;
+use typst::layout::{Frame, FrameItem};
+use typst::model::Document;
+use typst::text::Font;
+
+use crate::config::{FontEmbedding, Pdf, RestrictedFonts};
+
+/// Check licenses of fonts used by a document. It returns warnings or an
+/// error if restricted fonts are forbidden.
+pub fn check_fonts(
+    doc: &Document,
+    options: &Pdf,
+) -> Result<Vec<String>, String> {
+    let mut warnings = Vec::new();
+    if options.font_embedding == FontEmbedding::Full {
+        // The only exporter we use always subsets fonts.
+        warnings.push(
+            "full font embedding is not supported by PDF exporter: fonts are \
+             subset"
+                .to_string(),
+        );
+    }
+    if options.restricted_fonts == RestrictedFonts::Ignore {
+        return Ok(warnings);
+    }
+
+    let mut fonts = Vec::new();
+    for page in doc.pages.iter() {
+        collect_fonts(&page.frame, &mut fonts);
+    }
+    let mut findings = fonts
+        .into_iter()
+        .filter_map(|font| check_font(&font))
+        .collect::<Vec<_>>();
+    findings.sort();
+    if options.restricted_fonts == RestrictedFonts::Error
+        && !findings.is_empty()
+    {
+        return Err(findings.join("; "));
+    }
+    warnings.extend(findings);
+    Ok(warnings)
+}
+
+fn collect_fonts(frame: &Frame, fonts: &mut Vec<Font>) {
+    for (_, item) in frame.items() {
+        match item {
+            FrameItem::Group(group) => collect_fonts(&group.frame, fonts),
+            FrameItem::Text(text) if !fonts.contains(&text.font) => {
+                fonts.push(text.font.clone());
+            }
+            _ => {}
+        }
+    }
+}
+
+fn check_font(font: &Font) -> Option<String> {
+    let family = &font.info().family;
+    let ttf = font.ttf();
+    if ttf.permissions() == Some(Permissions::Restricted) {
+        return Some(format!(
+            "font {family} has restricted license and must not be embedded"
+        ));
+    }
+    if !ttf.is_subsetting_allowed() {
+        return Some(format!(
+            "font {family} forbids subsetting but it is embedded as a subset"
+        ));
+    }
+    None
+}
@@ -0,0 +1,375 @@
+//! Live preview.
+//!
+//! Preview server renders pages of the last compiled document and serves them
+//! over HTTP together with a page which shows them in a browser. The page
+//! subscribes to updates over WebSocket: after each successful compilation
+//! server sends hashes of all pages so that a browser fetches only pages which
+//! have been changed. Unchanged pages are not rendered again either.
+//!
+//! The same socket is used for jumps between sources and pages: server asks
+//! a browser to scroll to a position (forward search) and a browser reports
+//! clicks on pages (inverse search) which are resolved to source positions by
+//! a world.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+use typst::layout::Frame;
+use typst::model::Document;
+use typst::util::hash128;
+use typst::visualize::Color;
+
+use crate::config::ExportFormat;
+
+/// HTML page which shows a document and follows its updates.
+static INDEX_HTML: &str = include_str!("preview.html");
+
+/// Maximal size of headers of HTTP request.
+const MAX_HEADER_LEN: usize = 16 << 10;
+
+/// Number of jumps which are kept for slow browsers.
+const JUMP_CAPACITY: usize = 16;
+
+/// Position on a page of a document: page number (starting at 1) and a point
+/// in points from top left corner of a page.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Position {
+    pub page: usize,
+    pub x: f64,
+    pub y: f64,
+}
+
+/// Messages from server to browsers.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Outgoing {
+    /// Pages of the last compiled document.
+    Pages { pages: Vec<PageInfo> },
+    /// Scroll to a position and highlight it.
+    Jump(Position),
+}
+
+#[derive(Debug, Serialize)]
+struct PageInfo {
+    hash: String,
+    width: f64,
+    height: f64,
+}
+
+/// Messages from browsers to server.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Incoming {
+    /// Click on a page.
+    Click(Position),
+}
+
+/// Rendered page of a document.
+#[derive(Clone, Debug)]
+struct Page {
+    hash: u128,
+    /// Size of a page in points.
+    width: f64,
+    height: f64,
+    image: Arc<[u8]>,
+}
+
+/// Rendered pages of the last compiled document.
+#[derive(Debug, Default)]
+struct Snapshot {
+    pages: Vec<Page>,
+}
+
+impl Snapshot {
+    fn message(&self) -> Outgoing {
+        let pages = self
+            .pages
+            .iter()
+            .map(|page| PageInfo {
+                hash: format!("{:032x}", page.hash),
+                width: page.width,
+                height: page.height,
+            })
+            .collect();
+        Outgoing::Pages { pages }
+    }
+}
+
+/// State which connections of preview server share.
+#[derive(Clone, Debug)]
+struct Shared {
+    format: ExportFormat,
+    snapshot: watch::Receiver<Arc<Snapshot>>,
+    jumps: broadcast::Sender<Position>,
+    clicks: mpsc::UnboundedSender<Position>,
+}
+
+/// Preview server of a document. Server shuts down as soon as preview is
+/// dropped.
+#[derive(Debug)]
+pub struct Preview {
+    addr: SocketAddr,
+    format: ExportFormat,
+    snapshot: watch::Sender<Arc<Snapshot>>,
+    jumps: broadcast::Sender<Position>,
+}
+
+impl Preview {
+    /// Bind preview server to an address (e.g. `127.0.0.1:0` for a random
+    /// port) and serve pages in background. Pages are rendered as SVG unless
+    /// format is PNG. Clicks on pages are sent to returned channel. It must
+    /// be called within Tokio runtime.
+    pub fn start(
+        addr: &str,
+        format: ExportFormat,
+    ) -> Result<(Arc<Self>, mpsc::UnboundedReceiver<Position>), String> {
+        let listener = std::net::TcpListener::bind(addr)
+            .and_then(|it| it.set_nonblocking(true).map(|_| it))
+            .and_then(TcpListener::from_std)
+            .map_err(|err| {
+                format!("failed to bind preview to {addr}: {err}")
+            })?;
+        let addr = listener
+            .local_addr()
+            .map_err(|err| format!("failed to bind preview: {err}"))?;
+        let format = match format {
+            ExportFormat::Pdf => ExportFormat::Svg,
+            format => format,
+        };
+        let (snapshot, receiver) = watch::channel(Default::default());
+        let (jumps, _) = broadcast::channel(JUMP_CAPACITY);
+        let (clicks, receiver_clicks) = mpsc::unbounded_channel();
+        let shared = Shared {
+            format,
+            snapshot: receiver,
+            jumps: jumps.clone(),
+            clicks,
+        };
+        tokio::spawn(accept(listener, shared));
+        log::info!("preview is served at {addr}");
+        let preview = Arc::new(Self {
+            addr,
+            format,
+            snapshot,
+            jumps,
+        });
+        Ok((preview, receiver_clicks))
+    }
+
+    /// URL of preview page.
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+
+    /// Render pages of a document and notify browsers. Pages which have not
+    /// been changed since the previous update are reused.
+    pub fn update(&self, doc: &Document) {
+        let previous = self.snapshot.borrow().clone();
+        let rendered = previous
+            .pages
+            .iter()
+            .map(|page| (page.hash, page))
+            .collect::<HashMap<_, _>>();
+        let pages = doc
+            .pages
+            .iter()
+            .map(|page| {
+                let hash = hash128(&page.frame);
+                if let Some(page) = rendered.get(&hash) {
+                    return (*page).clone();
+                }
+                Page {
+                    hash,
+                    width: page.frame.width().to_pt(),
+                    height: page.frame.height().to_pt(),
+                    image: self.render(&page.frame).into(),
+                }
+            })
+            .collect();
+        self.snapshot.send_replace(Arc::new(Snapshot { pages }));
+    }
+
+    /// Scroll browsers to a position on a page.
+    pub fn jump(&self, position: Position) {
+        // There could be no browser at all.
+        let _ = self.jumps.send(position);
+    }
+
+    fn render(&self, frame: &Frame) -> Vec<u8> {
+        let ExportFormat::Png { dpi } = self.format else {
+            return typst_svg::svg(frame).into_bytes();
+        };
+        typst_render::render(frame, dpi / 72.0, Color::WHITE)
+            .encode_png()
+            .unwrap_or_else(|err| {
+                log::error!("failed to render preview: {err}");
+                Vec::new()
+            })
+    }
+}
+
+/// Accept connections until preview is dropped.
+async fn accept(listener: TcpListener, mut shared: Shared) {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => {
+                    let shared = shared.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = handle(stream, shared).await {
+                            log::debug!("preview connection failed: {err}");
+                        }
+                    });
+                }
+                Err(err) => log::warn!("failed to accept preview: {err}"),
+            },
+            changed = shared.snapshot.changed() => if changed.is_err() {
+                break;
+            },
+        }
+    }
+    log::info!("preview server is stopped");
+}
+
+/// Serve a single HTTP request: the preview page, a page image, or an upgrade
+/// to WebSocket.
+async fn handle(stream: TcpStream, shared: Shared) -> Result<(), String> {
+    let mut reader = BufReader::new(stream);
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") && !head.ends_with("\n\n") {
+        let size = reader
+            .read_line(&mut head)
+            .await
+            .map_err(|err| err.to_string())?;
+        if size == 0 || head.len() > MAX_HEADER_LEN {
+            return Err("malformed request".to_string());
+        }
+    }
+    let mut lines = head.lines();
+    let target = lines
+        .next()
+        .and_then(|line| line.strip_prefix("GET "))
+        .and_then(|line| line.split_whitespace().next())
+        .ok_or("unsupported request")?;
+    let path = target.split('?').next().unwrap_or_default();
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim()))
+        .collect::<HashMap<_, _>>();
+
+    let mut stream = reader.into_inner();
+    if path == "/ws" {
+        let key = headers
+            .get("sec-websocket-key")
+            .ok_or("missing websocket key")?;
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            derive_accept_key(key.as_bytes()),
+        );
+        stream
+            .write_all(response.as_bytes())
+            .await
+            .map_err(|err| err.to_string())?;
+        let socket =
+            WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+        return subscribe(socket, shared).await;
+    }
+
+    let (status, content_type, body) = match path {
+        "/" => (
+            "200 OK",
+            "text/html; charset=utf-8",
+            INDEX_HTML.as_bytes().into(),
+        ),
+        _ => match path
+            .strip_prefix("/pages/")
+            .and_then(|it| it.parse::<usize>().ok())
+            .and_then(|index| {
+                shared.snapshot.borrow().pages.get(index).cloned()
+            }) {
+            Some(page) => {
+                let content_type = match shared.format {
+                    ExportFormat::Png { .. } => "image/png",
+                    _ => "image/svg+xml",
+                };
+                ("200 OK", content_type, page.image)
+            }
+            None => ("404 Not Found", "text/plain", b"not found"[..].into()),
+        },
+    };
+    let head = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len(),
+    );
+    stream
+        .write_all(head.as_bytes())
+        .await
+        .map_err(|err| err.to_string())?;
+    stream
+        .write_all(&body)
+        .await
+        .map_err(|err| err.to_string())?;
+    stream.shutdown().await.map_err(|err| err.to_string())
+}
+
+/// Send hashes and sizes of pages to a browser on each update as well as
+/// jumps and receive clicks on pages.
+async fn subscribe(
+    mut socket: WebSocketStream<TcpStream>,
+    shared: Shared,
+) -> Result<(), String> {
+    let Shared {
+        mut snapshot,
+        jumps,
+        clicks,
+        ..
+    } = shared;
+    let mut jumps = jumps.subscribe();
+    let mut message = snapshot.borrow_and_update().message();
+    loop {
+        let text = serde_json::to_string(&message)
+            .map_err(|err| format!("failed to serialize message: {err}"))?;
+        socket
+            .send(Message::Text(text))
+            .await
+            .map_err(|err| err.to_string())?;
+        message = loop {
+            tokio::select! {
+                changed = snapshot.changed() => match changed {
+                    Ok(()) => break snapshot.borrow_and_update().message(),
+                    Err(_) => return Ok(()),
+                },
+                jump = jumps.recv() => match jump {
+                    Ok(position) => break Outgoing::Jump(position),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                incoming = socket.next() => match incoming {
+                    Some(Ok(Message::Text(text))) => {
+                        match serde_json::from_str(&text) {
+                            Ok(Incoming::Click(position)) => {
+                                let _ = clicks.send(position);
+                            }
+                            Err(err) => log::debug!("bad preview message: {err}"),
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => continue,
+                    Some(Err(err)) => return Err(err.to_string()),
+                },
+            }
+        };
+    }
+}
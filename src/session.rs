@@ -0,0 +1,148 @@
+//! LSP session recording and replay.
+//!
+//! A session is recorded as JSON lines: every incoming JSON-RPC message is
+//! stored together with time in milliseconds elapsed since the start of the
+//! session. A recorded session could be fed back to language server in order
+//! to reproduce issues reported by users.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, ReadBuf};
+
+static CONTENT_LENGTH: &str = "content-length";
+
+/// A single incoming message of a recorded session.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Record {
+    /// Milliseconds since the start of a session.
+    pub time: u64,
+    pub message: serde_json::Value,
+}
+
+impl Record {
+    /// Encode message with base protocol header as it is sent by a client.
+    pub fn to_frame(&self) -> Vec<u8> {
+        let body = self.message.to_string();
+        let mut frame = format!("Content-Length: {}\r\n\r\n", body.len());
+        frame.push_str(&body);
+        frame.into_bytes()
+    }
+}
+
+/// Recorder passes through everything read from an underlying reader and
+/// appends complete messages to a session file.
+pub struct Recorder<R> {
+    inner: R,
+    file: File,
+    started: Instant,
+    buffer: Vec<u8>,
+}
+
+impl<R> Recorder<R> {
+    pub fn new(inner: R, path: &Path) -> Result<Self, String> {
+        let file = File::create(path)
+            .map_err(|err| format!("failed to create {path:?}: {err}"))?;
+        Ok(Self {
+            inner,
+            file,
+            started: Instant::now(),
+            buffer: Vec::new(),
+        })
+    }
+
+    fn record(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+        while let Some(body) = next_message(&mut self.buffer) {
+            let message = match serde_json::from_slice(&body) {
+                Ok(message) => message,
+                Err(_) => serde_json::Value::String(
+                    String::from_utf8_lossy(&body).into_owned(),
+                ),
+            };
+            let record = Record {
+                time: self.started.elapsed().as_millis() as u64,
+                message,
+            };
+            let Ok(mut line) = serde_json::to_vec(&record) else {
+                continue;
+            };
+            line.push(b'\n');
+            // Recording must never break a session itself.
+            if let Err(err) = self.file.write_all(&line) {
+                log::error!("failed to record message: {err}");
+            }
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Recorder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let offset = buf.filled().len();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = poll {
+            let bytes = buf.filled()[offset..].to_vec();
+            this.record(&bytes);
+        }
+        poll
+    }
+}
+
+/// Check whether a message is a request which expects a response.
+pub fn is_request(message: &serde_json::Value) -> bool {
+    message.get("id").is_some() && message.get("method").is_some()
+}
+
+/// Cut the next complete message body off the beginning of a buffer.
+pub fn next_message(buffer: &mut Vec<u8>) -> Option<Vec<u8>> {
+    let header_end = buffer.windows(4).position(|it| it == b"\r\n\r\n")?;
+    let header = String::from_utf8_lossy(&buffer[..header_end]);
+    let length = header.split("\r\n").find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if !name.trim().eq_ignore_ascii_case(CONTENT_LENGTH) {
+            return None;
+        }
+        value.trim().parse::<usize>().ok()
+    });
+    let body_start = header_end + 4;
+    let Some(length) = length else {
+        // Malformed header: skip it as server does not understand it either.
+        buffer.drain(..body_start);
+        return next_message(buffer);
+    };
+    if buffer.len() < body_start + length {
+        return None;
+    }
+    let body = buffer[body_start..body_start + length].to_vec();
+    buffer.drain(..body_start + length);
+    Some(body)
+}
+
+/// Load recorded session from a file.
+pub fn load(path: &Path) -> Result<Vec<Record>, String> {
+    let file = File::open(path)
+        .map_err(|err| format!("failed to open {path:?}: {err}"))?;
+    let mut records = Vec::new();
+    for (lineno, line) in BufReader::new(file).lines().enumerate() {
+        let line =
+            line.map_err(|err| format!("failed to read {path:?}: {err}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line).map_err(|err| {
+            format!("failed to parse {path:?} at line {}: {err}", lineno + 1)
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
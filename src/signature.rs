@@ -0,0 +1,243 @@
+//! Signature help.
+//!
+//! A function call which encloses cursor is found syntactically together with
+//! an argument under cursor. Signatures are made either from functions of
+//! standard library (with documentation of parameters) or from closures which
+//! are defined in sources (e.g. `#let f(x, y: 1) = ..`). Offsets of parameters
+//! in a signature label are in UTF-16 code units like columns in LSP.
+
+use typst::foundations::Func;
+use typst::syntax::ast::AstNode;
+use typst::syntax::{ast, LinkedNode, Source, SyntaxKind};
+
+use crate::docs;
+
+/// Signature of a function with its active parameter.
+#[derive(Clone, Debug, Default)]
+pub struct Signature {
+    pub label: String,
+    pub docs: Option<String>,
+    pub params: Vec<Parameter>,
+    /// Index of a parameter which corresponds to an argument under cursor.
+    pub active: Option<usize>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Parameter {
+    pub name: String,
+    /// Offsets of a parameter in a signature label.
+    pub label: (usize, usize),
+    pub docs: Option<String>,
+    pub positional: bool,
+    pub named: bool,
+    pub variadic: bool,
+}
+
+/// Function call which encloses cursor.
+#[derive(Clone, Debug)]
+pub struct Call {
+    /// Byte offset of callee name (e.g. `pow` in `calc.pow`).
+    pub callee: usize,
+    /// Number of positional arguments before an argument under cursor.
+    pub positional: usize,
+    /// Name of a named argument under cursor.
+    pub named: Option<String>,
+}
+
+/// Find a function call which encloses cursor. Cursor must be inside of
+/// parentheses of arguments.
+pub fn call_at(source: &Source, cursor: usize) -> Option<Call> {
+    let leaf = LinkedNode::new(source.root()).leaf_at(cursor)?;
+    let mut node = leaf;
+    let args = loop {
+        match node.kind() {
+            SyntaxKind::Args => break node,
+            // Calls do not leak through blocks and closures.
+            SyntaxKind::CodeBlock
+            | SyntaxKind::ContentBlock
+            | SyntaxKind::Closure => return None,
+            _ => node = node.parent()?.clone(),
+        }
+    };
+    let call = args.parent()?.cast::<ast::FuncCall>()?;
+    let callee = match call.callee() {
+        ast::Expr::Ident(ident) => ident.span(),
+        ast::Expr::MathIdent(ident) => ident.span(),
+        ast::Expr::FieldAccess(access) => access.field().span(),
+        _ => return None,
+    };
+    let callee = args.parent()?.find(callee)?.offset();
+
+    // Cursor must be between parentheses.
+    let open = args
+        .children()
+        .find(|it| it.kind() == SyntaxKind::LeftParen)?;
+    if cursor <= open.offset() {
+        return None;
+    }
+    let close = args
+        .children()
+        .find(|it| it.kind() == SyntaxKind::RightParen);
+    if close.is_some_and(|it| cursor > it.offset()) {
+        return None;
+    }
+
+    // Arguments are separated with commas. An argument under cursor is the
+    // one after the last comma before cursor.
+    let mut positional = 0;
+    let mut current = None;
+    for child in args.children().take_while(|it| it.offset() < cursor) {
+        match child.kind() {
+            SyntaxKind::Comma => {
+                if let Some(ast::Arg::Pos(_)) = current.take() {
+                    positional += 1;
+                }
+            }
+            _ => {
+                if let Some(arg) = child.get().cast::<ast::Arg>() {
+                    current = Some(arg);
+                }
+            }
+        }
+    }
+    let named = match current {
+        Some(ast::Arg::Named(named)) => Some(named.name().get().to_string()),
+        _ => None,
+    };
+    Some(Call {
+        callee,
+        positional,
+        named,
+    })
+}
+
+impl Signature {
+    /// Make a signature of a function of standard library.
+    pub fn from_func(func: &Func) -> Self {
+        let mut builder = Builder::new(func.name().unwrap_or("function"));
+        for param in func.params().unwrap_or_default() {
+            let prefix = if param.variadic { ".." } else { "" };
+            let label = format!(
+                "{prefix}{}: {}",
+                param.name,
+                docs::describe_cast(&param.input)
+            );
+            let docs = docs::first_paragraph(param.docs);
+            let label = builder.push_label(&label);
+            builder.push(Parameter {
+                name: param.name.to_string(),
+                label,
+                docs: (!docs.is_empty()).then_some(docs),
+                positional: param.positional,
+                named: param.named,
+                variadic: param.variadic,
+            });
+        }
+        let returns = func.returns().map(docs::describe_cast);
+        let docs = func.docs().map(docs::first_paragraph);
+        builder.finish(returns, docs)
+    }
+
+    /// Make a signature of a closure defined in a source.
+    pub fn from_closure(closure: ast::Closure) -> Self {
+        let name = closure.name().map_or("closure", |it| it.get().as_str());
+        let mut builder = Builder::new(name);
+        for param in closure.params().children() {
+            let label = param.to_untyped().clone().into_text().to_string();
+            let (name, positional, variadic) = match param {
+                ast::Param::Pos(pattern) => {
+                    let name = match pattern.bindings().as_slice() {
+                        [ident] => ident.get().to_string(),
+                        _ => label.clone(),
+                    };
+                    (name, true, false)
+                }
+                ast::Param::Named(named) => {
+                    (named.name().get().to_string(), false, false)
+                }
+                ast::Param::Spread(spread) => {
+                    let name = spread.sink_ident().map(|it| it.get().clone());
+                    (name.unwrap_or_default().to_string(), true, true)
+                }
+            };
+            let label = builder.push_label(&label);
+            builder.push(Parameter {
+                name,
+                label,
+                docs: None,
+                positional,
+                named: !positional,
+                variadic,
+            });
+        }
+        builder.finish(None, None)
+    }
+
+    /// Find a parameter which corresponds to an argument under cursor.
+    pub fn activate(&mut self, call: &Call) {
+        self.active = match &call.named {
+            Some(name) => self
+                .params
+                .iter()
+                .position(|it| it.named && it.name == *name),
+            None => {
+                let mut positional = self
+                    .params
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, it)| it.positional);
+                // Variadic parameter takes all remaining arguments.
+                positional
+                    .clone()
+                    .nth(call.positional)
+                    .or_else(|| positional.find(|(_, it)| it.variadic))
+                    .map(|(index, _)| index)
+            }
+        };
+    }
+}
+
+/// Builder accumulates a label of a signature and offsets of parameters.
+struct Builder {
+    label: String,
+    params: Vec<Parameter>,
+}
+
+impl Builder {
+    fn new(name: &str) -> Self {
+        Self {
+            label: format!("{name}("),
+            params: Vec::new(),
+        }
+    }
+
+    fn push_label(&mut self, label: &str) -> (usize, usize) {
+        if !self.params.is_empty() {
+            self.label.push_str(", ");
+        }
+        let begin = self.label.encode_utf16().count();
+        self.label.push_str(label);
+        (begin, begin + label.encode_utf16().count())
+    }
+
+    fn push(&mut self, param: Parameter) {
+        self.params.push(param);
+    }
+
+    fn finish(
+        mut self,
+        returns: Option<String>,
+        docs: Option<String>,
+    ) -> Signature {
+        self.label.push(')');
+        if let Some(returns) = returns {
+            self.label.push_str(&format!(" -> {returns}"));
+        }
+        Signature {
+            label: self.label,
+            docs: docs.filter(|it| !it.is_empty()),
+            params: self.params,
+            active: None,
+        }
+    }
+}
@@ -0,0 +1,64 @@
+//! Completion snippets.
+//!
+//! Typst marks placeholders of completion snippets with `${}` or `${name}`
+//! (e.g. `heading(${})`). Language server protocol uses TextMate syntax with
+//! numbered tab stops (e.g. `heading($1)`) where dollars and backslashes
+//! outside of placeholders must be escaped. Clients without snippet support
+//! get plain text with placeholders replaced by their names.
+
+/// Convert a Typst snippet to LSP snippet with numbered tab stops.
+pub fn to_lsp_snippet(apply: &str) -> String {
+    let mut result = String::with_capacity(apply.len());
+    let mut index = 0;
+    convert(apply, |part| match part {
+        Part::Text(text) => {
+            for ch in text.chars() {
+                if matches!(ch, '$' | '\\' | '}') {
+                    result.push('\\');
+                }
+                result.push(ch);
+            }
+        }
+        Part::Placeholder(name) => {
+            index += 1;
+            match name.is_empty() {
+                true => result.push_str(&format!("${index}")),
+                false => {
+                    let name = name
+                        .replace('\\', "\\\\")
+                        .replace('$', "\\$")
+                        .replace('}', "\\}");
+                    result.push_str(&format!("${{{index}:{name}}}"));
+                }
+            }
+        }
+    });
+    result
+}
+
+/// Replace placeholders of a Typst snippet with their names.
+pub fn to_plain_text(apply: &str) -> String {
+    let mut result = String::with_capacity(apply.len());
+    convert(apply, |part| match part {
+        Part::Text(text) | Part::Placeholder(text) => result.push_str(text),
+    });
+    result
+}
+
+enum Part<'a> {
+    Text(&'a str),
+    Placeholder(&'a str),
+}
+
+/// Split a snippet into text and placeholders.
+fn convert<'a>(mut apply: &'a str, mut emit: impl FnMut(Part<'a>)) {
+    while let Some(start) = apply.find("${") {
+        let Some(end) = apply[start..].find('}').map(|it| it + start) else {
+            break;
+        };
+        emit(Part::Text(&apply[..start]));
+        emit(Part::Placeholder(&apply[start + 2..end]));
+        apply = &apply[end + 1..];
+    }
+    emit(Part::Text(apply));
+}
@@ -0,0 +1,147 @@
+//! Syntax helpers.
+//!
+//! Typst has three syntactic modes (markup, math, and code) and many language
+//! features depend on the mode at cursor.
+
+use std::ops::Range;
+
+use typst::syntax::{
+    ast, is_newline, LinkedNode, Source, SyntaxKind, SyntaxNode,
+};
+
+/// Units in which columns of positions are counted. LSP clients count them
+/// in UTF-16 code units unless other encoding is negotiated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PositionEncoding {
+    /// Bytes.
+    Utf8,
+    /// UTF-16 code units.
+    #[default]
+    Utf16,
+    /// Characters (code points).
+    Utf32,
+}
+
+impl PositionEncoding {
+    /// Name of encoding in LSP (e.g. `utf-16`).
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Utf8 => "utf-8",
+            Self::Utf16 => "utf-16",
+            Self::Utf32 => "utf-32",
+        }
+    }
+
+    /// Length of a character in units of encoding.
+    fn len(self, ch: char) -> usize {
+        match self {
+            Self::Utf8 => ch.len_utf8(),
+            Self::Utf16 => ch.len_utf16(),
+            Self::Utf32 => 1,
+        }
+    }
+}
+
+/// Convert a column of a line to byte offset within the line. Columns past
+/// the end of the line or in the middle of a character are clamped to the end
+/// of the line and to the start of the character respectively.
+pub fn column_to_byte(
+    text: &str,
+    column: usize,
+    encoding: PositionEncoding,
+) -> usize {
+    let mut units = 0;
+    for (offset, ch) in text.char_indices() {
+        units += encoding.len(ch);
+        if units > column {
+            return offset;
+        }
+    }
+    text.len()
+}
+
+/// Convert a position (line and column in units of encoding) to byte offset.
+/// Unlike [`Source::line_column_to_byte`], positions past the end of a line
+/// are clamped to the end of the line and positions past the last line are
+/// clamped to the end of the document.
+pub fn position_to_byte(
+    source: &Source,
+    line: usize,
+    column: usize,
+    encoding: PositionEncoding,
+) -> usize {
+    let Some(range) = source.line_to_range(line) else {
+        return source.len_bytes();
+    };
+    let text = source.text()[range.clone()].trim_end_matches(is_newline);
+    range.start + column_to_byte(text, column, encoding)
+}
+
+/// Convert byte offset to a position (line and column in units of encoding).
+pub fn byte_to_position(
+    source: &Source,
+    offset: usize,
+    encoding: PositionEncoding,
+) -> Option<(usize, usize)> {
+    let line = source.byte_to_line(offset)?;
+    let start = source.line_to_byte(line)?;
+    let text = source.text().get(start..offset)?;
+    let column = text.chars().map(|ch| encoding.len(ch)).sum();
+    Some((line, column))
+}
+
+/// Find a byte range of a partially typed target of a reference at cursor
+/// (e.g. `ke` in `@ke`).
+pub fn ref_prefix_at(source: &Source, cursor: usize) -> Option<Range<usize>> {
+    let leaf = LinkedNode::new(source.root()).leaf_at(cursor)?;
+    if leaf.kind() != SyntaxKind::RefMarker {
+        return None;
+    }
+    let start = leaf.offset() + 1;
+    (start <= cursor).then_some(start..cursor)
+}
+
+/// Syntactic mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Markup,
+    Math,
+    Code,
+}
+
+/// Determine syntactic mode at cursor.
+pub fn mode_at(source: &Source, cursor: usize) -> Mode {
+    let Some(leaf) = LinkedNode::new(source.root()).leaf_at(cursor) else {
+        return Mode::Markup;
+    };
+    let mut node = Some(leaf);
+    while let Some(current) = node {
+        match current.kind() {
+            SyntaxKind::Markup | SyntaxKind::ContentBlock => {
+                return Mode::Markup
+            }
+            SyntaxKind::Math | SyntaxKind::Equation => return Mode::Math,
+            SyntaxKind::Code
+            | SyntaxKind::CodeBlock
+            | SyntaxKind::Args
+            | SyntaxKind::Params
+            | SyntaxKind::Array
+            | SyntaxKind::Dict
+            | SyntaxKind::Parenthesized => return Mode::Code,
+            _ => node = current.parent().cloned(),
+        }
+    }
+    Mode::Markup
+}
+
+/// Check whether a name is bound by a `let` binding anywhere in a source. It
+/// is a cheap syntactic check whether a name shadows standard library.
+pub fn is_bound(source: &Source, name: &str) -> bool {
+    fn visit(node: &SyntaxNode, name: &str) -> bool {
+        let bound = node.cast::<ast::LetBinding>().is_some_and(|binding| {
+            binding.kind().bindings().iter().any(|it| it.get() == name)
+        });
+        bound || node.children().any(|child| visit(child, name))
+    }
+    visit(source.root(), name)
+}
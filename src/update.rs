@@ -0,0 +1,180 @@
+//! Package updates.
+//!
+//! Packages imported by a workspace are checked against package index for
+//! newer compatible versions (see [`lock::is_compatible`]). Updates are
+//! recorded to lockfile and, optionally, version numbers in import statements
+//! are rewritten.
+
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+
+use typst::syntax::ast::AstNode;
+use typst::syntax::package::{PackageSpec, PackageVersion};
+use typst::syntax::{ast, FileId, LinkedNode, Source, VirtualPath};
+use typst::World;
+
+use crate::actions::{make_edit, TextEdit};
+use crate::deps::{self, NodeKind};
+use crate::lock;
+use crate::package::{IndexEntry, NAMESPACE};
+use crate::syntax::PositionEncoding;
+
+/// Update of a package imported by a workspace. Version is the same as the
+/// imported one if there is no newer compatible version.
+#[derive(Clone, Debug)]
+pub struct PackageUpdate {
+    /// Package specification as it is imported.
+    pub spec: PackageSpec,
+    /// The latest compatible version.
+    pub version: PackageVersion,
+    /// Edits of import statements which rewrite version.
+    pub edits: Vec<TextEdit>,
+}
+
+impl PackageUpdate {
+    /// Check whether there is a newer version than the imported one.
+    pub fn is_newer(&self) -> bool {
+        self.version != self.spec.version
+    }
+}
+
+/// Find updates of all packages imported by files of a world.
+pub fn find_updates(
+    world: &dyn World,
+    root_dir: &Path,
+    index: &[IndexEntry],
+    encoding: PositionEncoding,
+) -> Vec<PackageUpdate> {
+    let mut updates = Vec::<PackageUpdate>::new();
+    let graph = deps::dependency_graph(world, root_dir, encoding);
+    for node in graph.nodes.iter() {
+        let (NodeKind::File, Some(path)) = (node.kind, &node.path) else {
+            continue;
+        };
+        let id = FileId::new(None, VirtualPath::new(&node.id));
+        let Ok(source) = world.source(id) else {
+            continue;
+        };
+        for (spec, range) in find_package_imports(&source) {
+            let version =
+                latest_compatible(index, &spec).unwrap_or(spec.version);
+            let text =
+                format!("\"@{}/{}:{}\"", spec.namespace, spec.name, version);
+            let edit = match version != spec.version {
+                true => make_edit(&source, path, range, text, encoding),
+                false => None,
+            };
+            match updates.iter_mut().find(|it| it.spec == spec) {
+                Some(update) => update.edits.extend(edit),
+                None => updates.push(PackageUpdate {
+                    spec,
+                    version,
+                    edits: edit.into_iter().collect(),
+                }),
+            }
+        }
+    }
+    updates
+}
+
+/// Import of a package which has newer versions in index.
+#[derive(Clone, Debug)]
+pub struct OutdatedImport {
+    pub spec: PackageSpec,
+    /// Byte range of a string literal of an import.
+    pub range: Range<usize>,
+    /// The latest compatible version if any.
+    pub compatible: Option<PackageVersion>,
+    /// The latest version.
+    pub latest: PackageVersion,
+}
+
+impl OutdatedImport {
+    pub fn message(&self) -> String {
+        let name = format!("@{}/{}", self.spec.namespace, self.spec.name);
+        match self.compatible {
+            Some(version) if version == self.latest => {
+                format!("newer version {version} of {name} is available")
+            }
+            Some(version) => format!(
+                "newer compatible version {version} of {name} is available \
+                 (the latest one is {})",
+                self.latest,
+            ),
+            None => format!(
+                "newer version {} of {name} is available (it is not \
+                 compatible with {})",
+                self.latest, self.spec.version,
+            ),
+        }
+    }
+}
+
+/// Find imports of a source which have newer versions in index.
+pub fn find_outdated(
+    source: &Source,
+    index: &[IndexEntry],
+) -> Vec<OutdatedImport> {
+    find_package_imports(source)
+        .into_iter()
+        .filter(|(spec, _)| spec.namespace == NAMESPACE)
+        .filter_map(|(spec, range)| {
+            let latest = index
+                .iter()
+                .filter(|it| it.name == spec.name)
+                .map(|it| it.version)
+                .max()
+                .filter(|it| *it > spec.version)?;
+            Some(OutdatedImport {
+                compatible: latest_compatible(index, &spec),
+                spec,
+                range,
+                latest,
+            })
+        })
+        .collect()
+}
+
+/// Find the latest version of a package in index which is compatible with
+/// and newer than the requested one.
+fn latest_compatible(
+    index: &[IndexEntry],
+    spec: &PackageSpec,
+) -> Option<PackageVersion> {
+    if spec.namespace != NAMESPACE {
+        return None;
+    }
+    index
+        .iter()
+        .filter(|it| it.name == spec.name)
+        .map(|it| it.version)
+        .filter(|it| *it > spec.version)
+        .filter(|it| lock::is_compatible(spec.version, *it))
+        .max()
+}
+
+/// Find package imports and byte ranges of their string literals.
+fn find_package_imports(source: &Source) -> Vec<(PackageSpec, Range<usize>)> {
+    fn visit(
+        node: &LinkedNode,
+        imports: &mut Vec<(PackageSpec, Range<usize>)>,
+    ) {
+        if let Some(import) = node.cast::<ast::ModuleImport>() {
+            if let ast::Expr::Str(path) = import.source() {
+                let literal = path.get();
+                let spec = PackageSpec::from_str(&literal);
+                let child = node.find(path.span());
+                if let (Ok(spec), Some(child)) = (spec, child) {
+                    imports.push((spec, child.range()));
+                }
+            }
+        }
+        for child in node.children() {
+            visit(&child, imports);
+        }
+    }
+    let mut imports = Vec::new();
+    visit(&LinkedNode::new(source.root()), &mut imports);
+    imports
+}
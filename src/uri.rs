@@ -0,0 +1,158 @@
+//! Conversion between document URIs and file system paths.
+//!
+//! Clients identify text documents with URIs which are percent-encoded (e.g.
+//! spaces or non-ASCII characters in file names) and have platform-specific
+//! layout (e.g. drive letters on Windows). So, the raw URI path is not a valid
+//! file system path in general. All conversions should go through this module.
+//!
+//! New documents which have not been saved yet have `untitled:` URIs. They are
+//! mapped to virtual paths which do not exist on disk so that they are served
+//! by in-memory worlds like any other document.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use tower_lsp::lsp_types::Url;
+
+/// Filename of virtual paths of untitled documents.
+static UNTITLED_FILENAME: &str = "untitled.typ";
+
+/// Directory of virtual paths of untitled documents. Each document gets a
+/// subdirectory of its own and so a world of its own.
+fn untitled_dir() -> PathBuf {
+    env::temp_dir().join("typstd-untitled")
+}
+
+/// Convert `file://` URI to an absolute file system path and `untitled:` URI
+/// to a virtual path. Other schemes have no file system counterpart so `None`
+/// is returned.
+pub fn to_path(uri: &Url) -> Option<PathBuf> {
+    match uri.scheme() {
+        "file" => uri.to_file_path().ok(),
+        "untitled" => {
+            let name = escape(uri.path());
+            if name.is_empty() || name == "." || name == ".." {
+                return None;
+            }
+            Some(untitled_dir().join(name).join(UNTITLED_FILENAME))
+        }
+        _ => None,
+    }
+}
+
+/// Convert an absolute file system path to `file://` URI or a virtual path
+/// of untitled document back to `untitled:` URI.
+pub fn from_path(path: &Path) -> Option<Url> {
+    let Ok(relpath) = path.strip_prefix(untitled_dir()) else {
+        return Url::from_file_path(path).ok();
+    };
+    let mut components = relpath.components();
+    let name = components.next()?.as_os_str().to_str()?;
+    if components.as_path() != Path::new(UNTITLED_FILENAME) {
+        return None;
+    }
+    Url::parse(&format!("untitled:{}", unescape(name)?)).ok()
+}
+
+/// Check whether a path is a virtual path of untitled document.
+pub fn is_untitled(path: &Path) -> bool {
+    path.starts_with(untitled_dir())
+}
+
+/// Escape characters of URI path which are not allowed in filenames.
+fn escape(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for ch in path.chars() {
+        match ch {
+            '%' => escaped.push_str("%25"),
+            '/' => escaped.push_str("%2F"),
+            '\\' => escaped.push_str("%5C"),
+            ':' => escaped.push_str("%3A"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+fn unescape(name: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut rest = name;
+    while let Some(index) = rest.find('%') {
+        unescaped.push_str(&rest[..index]);
+        let ch = match rest.get(index..index + 3)? {
+            "%25" => '%',
+            "%2F" => '/',
+            "%5C" => '\\',
+            "%3A" => ':',
+            _ => return None,
+        };
+        unescaped.push(ch);
+        rest = &rest[index + 3..];
+    }
+    unescaped.push_str(rest);
+    Some(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Convert URI to path and back and check that path is preserved.
+    fn round_trip(uri: &str, path: &Path) {
+        let uri = Url::parse(uri).unwrap();
+        assert_eq!(to_path(&uri).as_deref(), Some(path));
+        let back = from_path(path).unwrap();
+        assert_eq!(to_path(&back).as_deref(), Some(path));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spaces() {
+        let path = Path::new("/home/user/my thesis/main file.typ");
+        round_trip("file:///home/user/my%20thesis/main%20file.typ", path);
+        let uri = from_path(path).unwrap();
+        assert_eq!(uri.path(), "/home/user/my%20thesis/main%20file.typ");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_ascii() {
+        let path = Path::new("/home/user/диплом/главы/введение.typ");
+        round_trip(
+            "file:///home/user/%D0%B4%D0%B8%D0%BF%D0%BB%D0%BE%D0%BC/\
+             %D0%B3%D0%BB%D0%B0%D0%B2%D1%8B/\
+             %D0%B2%D0%B2%D0%B5%D0%B4%D0%B5%D0%BD%D0%B8%D0%B5.typ",
+            path,
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn drive_letters() {
+        let path = Path::new(r"c:\Users\user\my thesis\main.typ");
+        round_trip("file:///c%3A/Users/user/my%20thesis/main.typ", path);
+        round_trip("file:///c:/Users/user/my%20thesis/main.typ", path);
+    }
+
+    #[test]
+    fn untitled() {
+        let uri = Url::parse("untitled:Untitled-1").unwrap();
+        let path = to_path(&uri).unwrap();
+        assert!(is_untitled(&path));
+        assert_eq!(from_path(&path), Some(uri));
+    }
+
+    #[test]
+    fn untitled_with_separators() {
+        let uri = Url::parse("untitled:notes/draft%3A1").unwrap();
+        let path = to_path(&uri).unwrap();
+        assert_eq!(path.parent().unwrap().parent(), Some(&*untitled_dir()));
+        assert_eq!(from_path(&path), Some(uri));
+    }
+
+    #[test]
+    fn other_schemes() {
+        let uri = Url::parse("vscode-notebook-cell:/a.ipynb#x").unwrap();
+        assert_eq!(to_path(&uri), None);
+    }
+}
@@ -0,0 +1,70 @@
+//! Changes of files outside of editor.
+//!
+//! Sources which a world reads from file system are cached and other files
+//! (e.g. data files and images) are memoized by Typst so that changes made
+//! outside of editor (e.g. by `git checkout` or by a script which generates
+//! data) go unnoticed. A watcher keeps modification times of files at the
+//! moment they have been read and reports files which have been changed,
+//! created, or removed since then. File system is polled since there is no
+//! portable notification mechanism.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Modification times of files read from file system. Missing files are
+/// tracked as well so that their creation is noticed.
+#[derive(Debug, Default)]
+pub struct Watcher {
+    files: HashMap<PathBuf, Option<SystemTime>>,
+}
+
+impl Watcher {
+    /// Remember modification time of a file which has been just read.
+    pub fn track(&mut self, path: &Path) {
+        self.files.insert(path.to_path_buf(), modified(path));
+    }
+
+    /// Remember modification time of a tracked file once again (e.g. since
+    /// its change has been already handled). It returns `false` if a file is
+    /// not tracked.
+    pub fn update(&mut self, path: &Path) -> bool {
+        match self.files.get_mut(path) {
+            Some(time) => {
+                *time = modified(path);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Check whether a file is watched.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    /// Stop watching a file (e.g. since editor owns its content).
+    pub fn untrack(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+
+    /// Find files which have been changed since they were read or since the
+    /// previous check.
+    pub fn changed(&mut self) -> Vec<PathBuf> {
+        self.files
+            .iter_mut()
+            .filter_map(|(path, time)| {
+                let current = modified(path);
+                (current != *time).then(|| {
+                    *time = current;
+                    path.clone()
+                })
+            })
+            .collect()
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).ok()?.modified().ok()
+}
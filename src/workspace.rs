@@ -3,6 +3,7 @@
 //! This module contains basic methods to search and load workspaces and
 //! copilation targets.
 
+use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::result::Result;
@@ -24,13 +25,25 @@ pub struct TypstPackage {
     pub entrypoint: String,
 }
 
+/// TypstBook is a document which consists of chapters. Each chapter is
+/// compiled individually for fast feedback while the entrypoint includes all
+/// of them and produces the full artifact.
+#[derive(Debug, Deserialize)]
+pub struct TypstBook {
+    pub entrypoint: String,
+    pub root_dir: Option<String>,
+    #[serde(default)]
+    pub chapters: Vec<String>,
+}
+
 /// TypstProject type represents a configuration file deserialized from
 /// `typst.toml` which describes a list of documents to compile or package(s).
 #[derive(Debug, Deserialize)]
 pub struct TypstProject {
-    #[serde(rename = "document")]
+    #[serde(default, rename = "document")]
     pub documents: Vec<TypstDocument>,
     pub package: Option<TypstPackage>,
+    pub book: Option<TypstBook>,
 }
 
 /// Target represents a compilation target for a particular main file located
@@ -38,6 +51,8 @@ pub struct TypstProject {
 pub struct Target {
     pub root_dir: PathBuf,
     pub main_file: PathBuf,
+    /// Chapters of a book which are compiled individually (if any).
+    pub chapters: Vec<PathBuf>,
 }
 
 pub fn load_targets(root_dir: &Path) -> Result<Vec<Target>, String> {
@@ -49,7 +64,7 @@ pub fn load_targets(root_dir: &Path) -> Result<Vec<Target>, String> {
     let config = toml::from_str::<TypstProject>(runes)
         .map_err(|err| format!("failed to parse toml at {path:?}: {err}"))?;
 
-    let targets = config
+    let mut targets = config
         .documents
         .iter()
         .map(|doc| Target {
@@ -58,8 +73,24 @@ pub fn load_targets(root_dir: &Path) -> Result<Vec<Target>, String> {
                 .clone()
                 .map_or_else(|| root_dir.to_path_buf(), PathBuf::from),
             main_file: root_dir.join(&doc.entrypoint),
+            chapters: vec![],
         })
-        .collect();
+        .collect::<Vec<_>>();
+
+    if let Some(book) = config.book {
+        targets.push(Target {
+            root_dir: book
+                .root_dir
+                .clone()
+                .map_or_else(|| root_dir.to_path_buf(), PathBuf::from),
+            main_file: root_dir.join(&book.entrypoint),
+            chapters: book
+                .chapters
+                .iter()
+                .map(|chapter| root_dir.join(chapter))
+                .collect(),
+        });
+    }
 
     Ok(targets)
 }
@@ -79,6 +110,39 @@ pub fn search_targets(root_dirs: Vec<&Path>) -> Vec<Target> {
     targets
 }
 
+/// Root directory from `TYPST_ROOT` environment variable (as in typst-cli) if
+/// it contains a main file.
+pub fn env_root_dir(main_file: &Path) -> Option<PathBuf> {
+    let root_dir = PathBuf::from(env::var_os("TYPST_ROOT")?);
+    let root_dir = env::current_dir().ok()?.join(root_dir);
+    main_file.starts_with(&root_dir).then_some(root_dir)
+}
+
+/// Find files next to a file which include or import it. They are candidates
+/// for main file of a document outside of workspaces.
+pub fn find_main_candidates(path: &Path) -> Vec<PathBuf> {
+    let Some(dir) = path.parent() else {
+        return vec![];
+    };
+    let Some(name) = path.file_name().and_then(|it| it.to_str()) else {
+        return vec![];
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    let pattern = format!("\"{name}\"");
+    let mut candidates = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|it| it != path && it.extension().is_some_and(|it| it == "typ"))
+        .filter(|it| {
+            fs::read_to_string(it).is_ok_and(|text| text.contains(&pattern))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort();
+    candidates
+}
+
 // Search workspace which is determined by `typst.toml` file.
 pub fn search_workspace(start_dir: &Path) -> Option<&Path> {
     let mut root_dir = start_dir;